@@ -0,0 +1,217 @@
+// Runs a ROM with no display, for tests in CI.
+//
+//   gb-headless <rom> [--frames n] [--ld-b-b] [--png file] [--serial file]
+//
+// Runs n frames (default 600), or with --ld-b-b until the ROM executes
+// ld b, b, the usual software breakpoint in test ROMs. Writes the last frame
+// as a PNG and everything sent over the link cable to the given files.
+//
+// Exits with 0 when done, 1 on bad arguments or I/O errors and 2 when
+// --ld-b-b is given but the breakpoint isn't reached in time.
+use std::{env, fs, process::ExitCode};
+
+use emu::gb::{
+    GameBoy,
+    color::{ColorCorrection, frame_to_rgb888},
+    cpu::registers::{Register8Bit, Register16Bit},
+    png,
+    serial::CaptureSink,
+};
+
+const DEFAULT_FRAMES: u64 = 600;
+const SCREEN_WIDTH: u32 = 160;
+const SCREEN_HEIGHT: u32 = 144;
+const LD_B_B: u8 = 0x40;
+const EXIT_TIMEOUT: u8 = 2;
+
+const USAGE: &str = "usage: gb-headless <rom> [--frames n] [--ld-b-b] [--png file] [--serial file]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    // Ran every frame asked for
+    Finished,
+    Breakpoint,
+    TimedOut,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    rom: String,
+    frames: u64,
+    ld_b_b: bool,
+    png: Option<String>,
+    serial: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        frames: DEFAULT_FRAMES,
+        ..Options::default()
+    };
+    let mut rom = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--frames" => {
+                let frames = value()?;
+                options.frames = frames
+                    .parse()
+                    .map_err(|_| format!("invalid frame count {}", frames))?;
+            }
+            "--ld-b-b" => options.ld_b_b = true,
+            "--png" => options.png = Some(value()?),
+            "--serial" => options.serial = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    options.rom = rom.ok_or(USAGE)?;
+    Ok(options)
+}
+
+// Runs the frames, checking for ld b, b before each instruction when asked to
+fn run(gb: &mut GameBoy, frames: u64, stop_at_ld_b_b: bool) -> (Outcome, u64) {
+    let at_ld_b_b = |gb: &GameBoy| {
+        let pc = gb.cpu().registers().get_register_16bit(Register16Bit::PC);
+        gb.bus().borrow().read(pc as usize) == LD_B_B
+    };
+    for frame in 0..frames {
+        if gb.run_frame_until(|gb| stop_at_ld_b_b && at_ld_b_b(gb)) {
+            return (Outcome::Breakpoint, frame);
+        }
+    }
+    let outcome = if stop_at_ld_b_b {
+        Outcome::TimedOut
+    } else {
+        Outcome::Finished
+    };
+    (outcome, frames)
+}
+
+fn registers(gb: &GameBoy) -> String {
+    let registers = gb.cpu().registers();
+    let register8 = |register| registers.get_register_8bit(register);
+    format!(
+        "A=${:02X} B=${:02X} C=${:02X} D=${:02X} E=${:02X} H=${:02X} L=${:02X} SP=${:04X} PC=${:04X}",
+        register8(Register8Bit::A),
+        register8(Register8Bit::B),
+        register8(Register8Bit::C),
+        register8(Register8Bit::D),
+        register8(Register8Bit::E),
+        register8(Register8Bit::H),
+        register8(Register8Bit::L),
+        registers.get_register_16bit(Register16Bit::SP),
+        registers.get_register_16bit(Register16Bit::PC)
+    )
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let gb = match fs::read(&options.rom) {
+        Ok(rom) => GameBoy::new(rom).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let mut gb = match gb {
+        Ok(gb) => gb,
+        Err(err) => {
+            eprintln!("{}: {}", options.rom, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let sink = CaptureSink::new();
+    let serial = sink.output();
+    gb.connect_serial(Box::new(sink));
+
+    let (outcome, frames) = run(&mut gb, options.frames, options.ld_b_b);
+    match outcome {
+        Outcome::Finished => println!("Ran {} frames", frames),
+        Outcome::Breakpoint => println!("Hit ld b, b after {} frames", frames),
+        Outcome::TimedOut => println!("Timed out after {} frames", frames),
+    }
+    println!("{}", registers(&gb));
+
+    // The outputs are written even on timeout, since that's when they're most useful
+    let mut result = ExitCode::SUCCESS;
+    if let Some(path) = &options.png {
+        let rgb = frame_to_rgb888(&gb.framebuffer(), ColorCorrection::None);
+        let png = png::encode_rgb(SCREEN_WIDTH, SCREEN_HEIGHT, &rgb);
+        if let Err(err) = fs::write(path, png) {
+            eprintln!("{}: {}", path, err);
+            result = ExitCode::FAILURE;
+        }
+    }
+    if let Some(path) = &options.serial
+        && let Err(err) = fs::write(path, &*serial.borrow())
+    {
+        eprintln!("{}: {}", path, err);
+        result = ExitCode::FAILURE;
+    }
+    if outcome == Outcome::TimedOut {
+        return ExitCode::from(EXIT_TIMEOUT);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::gb::gameboy::test_rom;
+
+    fn headless_gb(program: &[u8]) -> GameBoy {
+        GameBoy::new(test_rom(program, 2)).unwrap()
+    }
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_args() {
+        let options = args("test.gb --frames 30 --ld-b-b --png out.png").unwrap();
+        assert_eq!(options.rom, "test.gb");
+        assert_eq!(options.frames, 30);
+        assert!(options.ld_b_b);
+        assert_eq!(options.png.as_deref(), Some("out.png"));
+        assert_eq!(options.serial, None);
+        assert_eq!(args("test.gb").unwrap().frames, DEFAULT_FRAMES);
+
+        assert!(args("").is_err());
+        assert!(args("test.gb --frames").is_err());
+        assert!(args("test.gb --frames ten").is_err());
+        assert!(args("test.gb --fast").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        // Sends a byte over serial, waits for it to go and then breaks
+        let mut gb = headless_gb(&emu::gb_asm!(
+            origin = 0x0100;
+            "ld a, $42",
+            "ldh [$01], a",
+            "ld a, $81",
+            "ldh [$02], a",
+            "Wait: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait",
+            "ld b, b",
+        ));
+        let sink = CaptureSink::new();
+        let serial = sink.output();
+        gb.connect_serial(Box::new(sink));
+        assert_eq!(run(&mut gb, 10, true), (Outcome::Breakpoint, 0));
+        assert_eq!(*serial.borrow(), [0x42]);
+        assert!(registers(&gb).ends_with("PC=$010E"));
+
+        let mut gb = headless_gb(&emu::gb_asm!(origin = 0x0100; "Loop: jr Loop"));
+        assert_eq!(run(&mut gb, 3, true), (Outcome::TimedOut, 3));
+        assert_eq!(run(&mut gb, 2, false), (Outcome::Finished, 2));
+    }
+}
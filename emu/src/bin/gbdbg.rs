@@ -0,0 +1,933 @@
+// Terminal debugger for Game Boy ROMs.
+//
+//   gbdbg <rom> [-s <symbol file>] [-x <command file>] [--batch]
+//
+// -s loads rgblink symbols, which otherwise come from a .sym file next to the
+// ROM if there is one. -x runs the file's commands before the prompt; --batch
+// exits after them.
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::ExitCode,
+};
+
+use emu::gb::{
+    GameBoy,
+    call_stack::{Frame, FrameKind},
+    cpu::{
+        AccessKind,
+        asm::assemble,
+        cdl::{CdlFlags, CdlLayout, CdlTarget, CodeDataLog},
+        disasm::disassemble_bus,
+        registers::{FlagsRegister, Register8Bit, Register16Bit},
+    },
+    debugger::{Debugger, StopReason, WatchKind, rom_bank},
+    gameboy::M_CYCLES_PER_FRAME,
+    profiler::Profiler,
+    symbols::SymbolTable,
+};
+
+const HELP: &str = "\
+Addresses and values are assembler expressions: $C000, 0xC000, %101, hl+2,
+or symbols from a .sym file: Main, wBuffer+2
+  s, step [n]               run n instructions
+  n, next                   step over calls
+  finish                    run until the current function returns
+  c, continue [frames]      run until a break, at most frames (default 600)
+  b, break [bank:]addr [if cond]
+                            break at addr, optionally only in a ROM bank
+                            and when the condition holds; a symbol in
+                            switchable ROM brings its own bank
+  watch addr[..end] [r|w|rw]
+                            break on reads and/or writes (default w)
+  d, delete id              remove a breakpoint or watchpoint
+  info                      list breakpoints and watchpoints
+  r, regs                   show registers
+  x[/n] addr                dump n bytes (default 16)
+  disas [addr] [n]          disassemble n instructions (default 8) from addr or pc
+  asm addr instruction      assemble an instruction into memory or ROM
+  set reg value             set a, b, c, d, e, f, h, l, af, bc, de, hl, sp or pc
+  bt, backtrace             show the calls and interrupts that led here
+  catch ret on|off          stop on returns that skip or fake a stack frame
+  symbols file              load an rgblink .sym file
+  profile on|off|reset      count cycles by function, address and bank
+  profile [n]               show the top n of each (default 10)
+  profile save file         write collapsed stacks for flamegraph tools
+  cdl on|off                log what each ROM byte is used for
+  cdl save file [native|mesen|bizhawk]
+                            write the code/data log (default native)
+  source file               run commands from a file
+  history                   list commands; !n reruns one, an empty line repeats the last
+  q, quit
+";
+
+const DEFAULT_FRAMES: u64 = 600;
+const DEFAULT_DUMP: usize = 16;
+const DEFAULT_DISASSEMBLY: usize = 8;
+const DEFAULT_PROFILE: usize = 10;
+
+struct Session {
+    gb: GameBoy,
+    debugger: Debugger,
+    history: Vec<String>,
+    quit: bool,
+}
+
+impl Session {
+    fn new(gb: GameBoy) -> Self {
+        Self {
+            gb,
+            debugger: Debugger::new(),
+            history: Vec::new(),
+            quit: false,
+        }
+    }
+
+    // Runs a line typed at the prompt, with history expansion
+    fn execute(&mut self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            match self.history.last() {
+                Some(last) => last.clone(),
+                None => return Ok(String::new()),
+            }
+        } else if let Some(index) = line.strip_prefix('!') {
+            index
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| self.history.get(index.checked_sub(1)?))
+                .cloned()
+                .ok_or_else(|| format!("no command {} in history", line))?
+        } else {
+            line.to_string()
+        };
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        self.run_command(&line)
+    }
+
+    fn run_command(&mut self, line: &str) -> Result<String, String> {
+        let (command, args) = match line.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        let args: Vec<&str> = args.split_whitespace().collect();
+
+        match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => 1,
+                };
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = self.debugger.step_in(&mut self.gb);
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                Ok(self.stopped(reason, 0))
+            }
+            "n" | "next" => {
+                let reason = self
+                    .debugger
+                    .step_over(&mut self.gb, DEFAULT_FRAMES * M_CYCLES_PER_FRAME);
+                Ok(self.stopped(reason, DEFAULT_FRAMES))
+            }
+            "finish" => {
+                let reason = self
+                    .debugger
+                    .step_out(&mut self.gb, DEFAULT_FRAMES * M_CYCLES_PER_FRAME);
+                Ok(self.stopped(reason, DEFAULT_FRAMES))
+            }
+            "c" | "continue" => {
+                let frames = match args.first() {
+                    Some(frames) => frames
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid frame count {}", frames))?,
+                    None => DEFAULT_FRAMES,
+                };
+                let reason = self
+                    .debugger
+                    .resume(&mut self.gb, frames * M_CYCLES_PER_FRAME);
+                Ok(self.stopped(reason, frames))
+            }
+            "b" | "break" => self.add_breakpoint(&args),
+            "watch" => self.add_watchpoint(&args),
+            "d" | "delete" => {
+                let id = args
+                    .first()
+                    .and_then(|id| id.parse::<usize>().ok())
+                    .ok_or("usage: delete id")?;
+                if !self.debugger.remove(id) {
+                    return Err(format!("no breakpoint or watchpoint {}", id));
+                }
+                Ok(String::new())
+            }
+            "info" => Ok(self.info()),
+            "r" | "regs" => Ok(self.registers()),
+            _ if command == "x" || command.starts_with("x/") => {
+                let count = match command.strip_prefix("x/") {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => DEFAULT_DUMP,
+                };
+                let addr = self.addr(args.first().ok_or("usage: x[/n] addr")?)?;
+                Ok(self.dump(addr, count))
+            }
+            "disas" => {
+                let addr = match args.first() {
+                    Some(addr) => self.addr(addr)?,
+                    None => self.pc(),
+                };
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => DEFAULT_DISASSEMBLY,
+                };
+                Ok(self.disassembly(addr, count))
+            }
+            "asm" => {
+                let (_, rest) = line.split_once(char::is_whitespace).unwrap_or_default();
+                let (addr, source) = rest
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .ok_or("usage: asm addr instruction")?;
+                let addr = self.addr(addr)?;
+                let bytes = assemble(source, addr).map_err(|err| err.message)?;
+                let mut bus = self.gb.bus().borrow_mut();
+                for (offset, byte) in bytes.iter().enumerate() {
+                    bus.patch(addr.wrapping_add(offset as u16) as usize, *byte);
+                }
+                Ok(format!("${:04X}: {}\n", addr, hex_bytes(&bytes)))
+            }
+            "set" => match args.as_slice() {
+                [register, value] => {
+                    let value = self.value(value)?;
+                    self.set_register(register, value)?;
+                    Ok(String::new())
+                }
+                _ => Err("usage: set reg value".to_string()),
+            },
+            "bt" | "backtrace" => Ok(self.backtrace()),
+            "catch" => match args[..] {
+                ["ret", setting @ ("on" | "off")] => {
+                    self.debugger.set_stop_on_mismatch(setting == "on");
+                    Ok(String::new())
+                }
+                _ => Err("usage: catch ret on|off".to_string()),
+            },
+            "cdl" => self.code_data_log(&args),
+            "profile" => self.profile(&args),
+            "symbols" => {
+                let path = args.first().ok_or("usage: symbols file")?;
+                let count = self.load_symbols(Path::new(path))?;
+                Ok(format!("Loaded {} symbols\n", count))
+            }
+            "source" => {
+                let path = args.first().ok_or("usage: source file")?;
+                let script =
+                    fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+                self.source(&script)
+            }
+            "history" => Ok(self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(i, line)| format!("{:4}  {}\n", i + 1, line))
+                .collect()),
+            "h" | "help" => Ok(HELP.to_string()),
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!("unknown command '{}', try help", command)),
+        }
+    }
+
+    // Runs each line of a script, stopping at the first error. # starts a comment.
+    fn source(&mut self, script: &str) -> Result<String, String> {
+        let mut output = String::new();
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            output += &self
+                .run_command(line)
+                .map_err(|err| format!("{}line {}: {}", output, number + 1, err))?;
+            if self.quit {
+                break;
+            }
+        }
+        Ok(output)
+    }
+
+    fn value(&self, text: &str) -> Result<i64, String> {
+        self.debugger
+            .evaluate(&self.gb, text)
+            .map_err(|err| err.message)
+    }
+
+    fn addr(&self, text: &str) -> Result<u16, String> {
+        let value = self.value(text)?;
+        u16::try_from(value).map_err(|_| format!("address {} out of range", value))
+    }
+
+    fn code_data_log(&mut self, args: &[&str]) -> Result<String, String> {
+        let cpu = self.gb.cpu_mut();
+        match args {
+            ["on"] => {
+                if cpu.code_data_log().is_none() {
+                    let rom_size = self
+                        .gb
+                        .bus()
+                        .borrow()
+                        .cartridge()
+                        .map_or(0, |cartridge| cartridge.rom().len());
+                    self.gb
+                        .cpu_mut()
+                        .set_code_data_log(Some(CodeDataLog::new(rom_size)));
+                }
+                Ok(String::new())
+            }
+            ["off"] => {
+                cpu.set_code_data_log(None);
+                Ok(String::new())
+            }
+            ["save", path, layout @ ..] => {
+                let layout = match layout {
+                    [] | ["native"] => CdlLayout::Native,
+                    ["mesen"] => CdlLayout::Mesen,
+                    ["bizhawk"] => CdlLayout::BizHawk,
+                    _ => return Err(format!("unknown layout {}", layout.join(" "))),
+                };
+                let target = CdlTarget::from_game_boy(&self.gb);
+                let log = self
+                    .gb
+                    .cpu()
+                    .code_data_log()
+                    .ok_or("the code/data log is off")?;
+                fs::write(path, log.to_bytes(layout, &target))
+                    .map_err(|err| format!("{}: {}", path, err))?;
+                Ok(format!(
+                    "{} code and {} data bytes of {}\n",
+                    log.count(CdlFlags::Code),
+                    log.count(CdlFlags::Data),
+                    log.len()
+                ))
+            }
+            _ => Err("usage: cdl on|off or cdl save file [native|mesen|bizhawk]".to_string()),
+        }
+    }
+
+    fn profile(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            ["on"] => {
+                if self.debugger.profiler().is_none() {
+                    self.debugger.set_profiler(Some(Profiler::new()));
+                }
+            }
+            ["off"] => self.debugger.set_profiler(None),
+            ["reset"] => {
+                if self.debugger.take_profiler().is_some() {
+                    self.debugger.set_profiler(Some(Profiler::new()));
+                }
+            }
+            ["save", path] => {
+                let profiler = self.debugger.profiler().ok_or("the profiler is off")?;
+                fs::write(path, profiler.collapsed(self.debugger.symbols()))
+                    .map_err(|err| format!("{}: {}", path, err))?;
+            }
+            [] | [_] => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => DEFAULT_PROFILE,
+                };
+                let profiler = self.debugger.profiler().ok_or("the profiler is off")?;
+                return Ok(profiler.report(self.debugger.symbols(), count));
+            }
+            _ => return Err("usage: profile on|off|reset|save file|[n]".to_string()),
+        }
+        Ok(String::new())
+    }
+
+    fn load_symbols(&mut self, path: &Path) -> Result<usize, String> {
+        let text =
+            fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let symbols =
+            SymbolTable::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let count = symbols.len();
+        self.debugger.set_symbols(symbols);
+        Ok(count)
+    }
+
+    // Call site by symbol, or by address with the bank when it's in
+    // switchable ROM
+    fn frame_location(&self, frame: &Frame) -> String {
+        if let Some(name) = self
+            .debugger
+            .symbols()
+            .describe(frame.call_site, frame.bank)
+        {
+            return name;
+        }
+        match frame.bank {
+            Some(bank) if frame.call_site >= 0x4000 => {
+                format!("${:02X}:{:04X}", bank, frame.call_site)
+            }
+            _ => format!("${:04X}", frame.call_site),
+        }
+    }
+
+    // Symbol for an address, or the address itself
+    fn location(&self, addr: u16, bank: Option<usize>) -> String {
+        self.debugger
+            .symbols()
+            .describe(addr, bank)
+            .unwrap_or_else(|| format!("${:04X}", addr))
+    }
+
+    fn pc(&self) -> u16 {
+        self.gb
+            .cpu()
+            .registers()
+            .get_register_16bit(Register16Bit::PC)
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let (location, condition) = match args {
+            [location] => (*location, None),
+            [location, "if", condition @ ..] if !condition.is_empty() => {
+                (*location, Some(condition.join(" ")))
+            }
+            _ => return Err("usage: break [bank:]addr [if cond]".to_string()),
+        };
+        let (bank, addr) = match location.split_once(':') {
+            Some((bank, addr)) => (Some(self.value(bank)? as usize), self.addr(addr)?),
+            None => match self.debugger.symbols().lookup(location) {
+                Some(symbol) if (0x4000..0x8000).contains(&symbol.addr) => {
+                    (Some(symbol.bank), symbol.addr)
+                }
+                _ => (None, self.addr(location)?),
+            },
+        };
+        let id = match condition {
+            Some(condition) => self
+                .debugger
+                .add_conditional_breakpoint(addr, bank, &condition)
+                .map_err(|err| err.message)?,
+            None => self.debugger.add_breakpoint(addr, bank),
+        };
+        Ok(format!("Breakpoint {} at ${:04X}\n", id, addr))
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let (range, kind) = match args {
+            [range] => (*range, WatchKind::Write),
+            [range, "r"] => (*range, WatchKind::Read),
+            [range, "w"] => (*range, WatchKind::Write),
+            [range, "rw"] => (*range, WatchKind::ReadWrite),
+            _ => return Err("usage: watch addr[..end] [r|w|rw]".to_string()),
+        };
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (self.addr(start)?, self.addr(end)?),
+            None => {
+                let addr = self.addr(range)?;
+                (addr, addr)
+            }
+        };
+        if end < start {
+            return Err("watch range ends before it starts".to_string());
+        }
+        let id = self.debugger.add_watchpoint(start..=end, kind);
+        Ok(format!(
+            "Watchpoint {} at ${:04X}..${:04X}\n",
+            id, start, end
+        ))
+    }
+
+    fn info(&self) -> String {
+        let mut out = String::new();
+        for breakpoint in self.debugger.breakpoints() {
+            out += &format!("{:3}  break ${:04X}", breakpoint.id, breakpoint.addr);
+            if let Some(bank) = breakpoint.bank {
+                out += &format!(" in bank {}", bank);
+            }
+            if let Some(condition) = &breakpoint.condition {
+                out += &format!(" if {}", condition.text());
+            }
+            if !breakpoint.enabled {
+                out += " (disabled)";
+            }
+            out.push('\n');
+        }
+        for watchpoint in self.debugger.watchpoints() {
+            let kind = match watchpoint.kind {
+                WatchKind::Read => "r",
+                WatchKind::Write => "w",
+                WatchKind::ReadWrite => "rw",
+            };
+            out += &format!(
+                "{:3}  watch ${:04X}..${:04X} {}\n",
+                watchpoint.id,
+                watchpoint.range.start(),
+                watchpoint.range.end(),
+                kind
+            );
+        }
+        if out.is_empty() {
+            out = "No breakpoints or watchpoints\n".to_string();
+        }
+        out
+    }
+
+    // Says why execution stopped and where it is now
+    fn stopped(&self, reason: StopReason, frames: u64) -> String {
+        let mut out = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(id) => format!("Breakpoint {}\n", id),
+            StopReason::Watchpoint { id, access } => format!(
+                "Watchpoint {}: {} ${:04X} = ${:02X}\n",
+                id,
+                match access.kind {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                },
+                access.addr,
+                access.value
+            ),
+            StopReason::Locked(opcode) => {
+                format!("CPU locked up on illegal opcode ${:02X}\n", opcode)
+            }
+            StopReason::ReturnMismatch(mismatch) => format!(
+                "Return at ${:04X} to ${:04X}, expected ${:04X} from frame at {}\n",
+                mismatch.at,
+                mismatch.to,
+                mismatch.expected.return_addr,
+                self.frame_location(&mismatch.expected)
+            ),
+            StopReason::CycleLimit => format!("Still running after {} frames\n", frames),
+        };
+        out += &self.disassembly(self.pc(), 1);
+        out
+    }
+
+    fn registers(&self) -> String {
+        let cpu = self.gb.cpu();
+        let registers = cpu.registers();
+        let flags = registers.get_flags();
+        let flag = |flag, name| if flags.contains(flag) { name } else { '-' };
+        let mut out = format!(
+            "AF=${:04X} BC=${:04X} DE=${:04X} HL=${:04X} SP=${:04X} PC=${:04X}\n",
+            registers.get_register_16bit(Register16Bit::AF),
+            registers.get_register_16bit(Register16Bit::BC),
+            registers.get_register_16bit(Register16Bit::DE),
+            registers.get_register_16bit(Register16Bit::HL),
+            registers.get_register_16bit(Register16Bit::SP),
+            registers.get_register_16bit(Register16Bit::PC),
+        );
+        out += &format!(
+            "Flags {}{}{}{}  IME={}  cycles {}",
+            flag(FlagsRegister::Zero, 'Z'),
+            flag(FlagsRegister::Subtraction, 'N'),
+            flag(FlagsRegister::HalfCarry, 'H'),
+            flag(FlagsRegister::Carry, 'C'),
+            cpu.ime() as u8,
+            self.gb.cycles()
+        );
+        if cpu.is_halted() {
+            out += "  halted";
+        }
+        out.push('\n');
+        out
+    }
+
+    fn dump(&self, addr: u16, count: usize) -> String {
+        let bus = self.gb.bus().borrow();
+        let bytes: Vec<u8> = (0..count)
+            .map(|offset| bus.read(addr.wrapping_add(offset as u16) as usize))
+            .collect();
+        bytes
+            .chunks(16)
+            .enumerate()
+            .map(|(row, chunk)| {
+                format!(
+                    "${:04X}: {}\n",
+                    addr.wrapping_add(row as u16 * 16),
+                    hex_bytes(chunk)
+                )
+            })
+            .collect()
+    }
+
+    fn disassembly(&self, addr: u16, count: usize) -> String {
+        let bus = self.gb.bus().borrow();
+        let pc = self.pc();
+        let mut addr = addr;
+        let mut out = String::new();
+        let symbols = self.debugger.symbols();
+        let bank = |addr| rom_bank(&self.gb, addr);
+        for _ in 0..count {
+            let instruction = disassemble_bus(&bus, addr);
+            if let Some(name) = symbols.name_at(addr, bank(addr)) {
+                out += &format!("{}:\n", name);
+            }
+            let marker = if addr == pc { "=>" } else { "  " };
+            let text = symbols.annotate(&instruction.text, bank);
+            out += &format!("{} ${:04X}  {}\n", marker, addr, text);
+            addr = instruction.next_addr();
+        }
+        out
+    }
+
+    fn set_register(&mut self, name: &str, value: i64) -> Result<(), String> {
+        let registers = self.gb.cpu_mut().registers_mut();
+        let register8 = match name.to_ascii_lowercase().as_str() {
+            "a" => Some(Register8Bit::A),
+            "b" => Some(Register8Bit::B),
+            "c" => Some(Register8Bit::C),
+            "d" => Some(Register8Bit::D),
+            "e" => Some(Register8Bit::E),
+            "h" => Some(Register8Bit::H),
+            "l" => Some(Register8Bit::L),
+            _ => None,
+        };
+        if let Some(register) = register8 {
+            let value =
+                u8::try_from(value).map_err(|_| format!("{} doesn't fit in 8 bits", value))?;
+            registers.set_register_8bit(register, value);
+            return Ok(());
+        }
+        let value =
+            u16::try_from(value).map_err(|_| format!("{} doesn't fit in 16 bits", value))?;
+        let register16 = match name.to_ascii_lowercase().as_str() {
+            "f" => {
+                let af = registers.get_register_16bit(Register16Bit::AF);
+                let value =
+                    u8::try_from(value).map_err(|_| format!("{} doesn't fit in 8 bits", value))?;
+                registers.set_register_16bit(Register16Bit::AF, af & 0xFF00 | value as u16);
+                return Ok(());
+            }
+            "af" => Register16Bit::AF,
+            "bc" => Register16Bit::BC,
+            "de" => Register16Bit::DE,
+            "hl" => Register16Bit::HL,
+            "sp" => Register16Bit::SP,
+            "pc" => Register16Bit::PC,
+            _ => return Err(format!("unknown register {}", name)),
+        };
+        registers.set_register_16bit(register16, value);
+        Ok(())
+    }
+
+    // Innermost first, from the debugger's shadow call stack, so only calls
+    // made since the session started show up
+    fn backtrace(&self) -> String {
+        let pc = self.pc();
+        let mut out = format!("#0  {}\n", self.location(pc, rom_bank(&self.gb, pc)));
+        let frames = self.debugger.call_stack().frames();
+        for (depth, frame) in frames.iter().rev().enumerate() {
+            let target = self
+                .debugger
+                .symbols()
+                .name_at(frame.target, frame.target_bank);
+            let what = match (frame.kind, target) {
+                (FrameKind::Interrupt, _) => format!("interrupt ${:04X}", frame.target),
+                // rst is the only one byte call
+                (FrameKind::Call, _) if frame.return_addr == frame.call_site.wrapping_add(1) => {
+                    format!("rst ${:02X}", frame.target)
+                }
+                (FrameKind::Call, Some(name)) => format!("call {}", name),
+                (FrameKind::Call, None) => format!("call ${:04X}", frame.target),
+            };
+            out += &format!(
+                "#{:<2} {}  {}\n",
+                depth + 1,
+                self.frame_location(frame),
+                what
+            );
+        }
+        out
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn main() -> ExitCode {
+    let mut rom_path = None;
+    let mut script = None;
+    let mut symbols = None;
+    let mut batch = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-x" => script = args.next(),
+            "-s" => symbols = args.next(),
+            "--batch" => batch = true,
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => {
+                eprintln!("unexpected argument {}", arg);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        eprintln!("usage: gbdbg <rom> [-s <symbol file>] [-x <command file>] [--batch]");
+        return ExitCode::FAILURE;
+    };
+
+    let gb = match fs::read(&rom_path) {
+        Ok(rom) => GameBoy::new(rom).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let gb = match gb {
+        Ok(gb) => gb,
+        Err(err) => {
+            eprintln!("{}: {}", rom_path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut session = Session::new(gb);
+
+    let sym_path = Path::new(&rom_path).with_extension("sym");
+    let symbols = match symbols {
+        Some(path) => Some(Path::new(&path).to_path_buf()),
+        None => sym_path.exists().then_some(sym_path),
+    };
+    if let Some(path) = symbols {
+        match session.load_symbols(&path) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, path.display()),
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Some(script) = script {
+        match session.run_command(&format!("source {}", script)) {
+            Ok(output) => print!("{}", output),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                if batch {
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+    if batch || session.quit {
+        return ExitCode::SUCCESS;
+    }
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    while !session.quit {
+        print!("(gbdbg) ");
+        io::stdout().flush().ok();
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+        match session.execute(&line) {
+            Ok(output) => print!("{}", output),
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::gb::gameboy::test_rom;
+
+    fn session(program: &[u8]) -> Session {
+        Session::new(GameBoy::new(test_rom(program, 2)).unwrap())
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut session = session(&emu::gb_asm!(
+            origin = 0x0100;
+            "ld sp, $DFFF",
+            "call Sub",
+            "Done: jr Done",
+            "Sub: ld hl, $C000",
+            "ld [hl], $42",
+            "rst $38",
+        ));
+        assert_eq!(
+            session.execute("disas $0100 2").unwrap(),
+            "=> $0100  ld sp, $DFFF\n   $0103  call $0108\n"
+        );
+        assert_eq!(
+            session.execute("break $0108 if sp == $DFFD").unwrap(),
+            "Breakpoint 1 at $0108\n"
+        );
+        assert_eq!(
+            session.execute("c").unwrap(),
+            "Breakpoint 1\n=> $0108  ld hl, $C000\n"
+        );
+        assert_eq!(
+            session.execute("watch $C000 w").unwrap(),
+            "Watchpoint 2 at $C000..$C000\n"
+        );
+        assert_eq!(
+            session.execute("continue 1").unwrap(),
+            "Watchpoint 2: write $C000 = $42\n=> $010D  rst $38\n"
+        );
+        assert_eq!(session.execute("x/4 hl").unwrap(), "$C000: 42 00 00 00\n");
+        assert!(
+            session
+                .execute("regs")
+                .unwrap()
+                .contains("HL=$C000 SP=$DFFD PC=$010D")
+        );
+
+        session.execute("s").unwrap();
+        assert_eq!(
+            session.execute("bt").unwrap(),
+            "#0  $0038\n#1  $010D  rst $38\n#2  $0103  call $0108\n"
+        );
+        session.execute("catch ret on").unwrap();
+        session.execute("asm $C000 ld hl, $0150").unwrap();
+        session.execute("asm $C003 push hl").unwrap();
+        session.execute("asm $C004 ret").unwrap();
+        session.execute("set pc $C000").unwrap();
+        assert_eq!(
+            session.execute("c").unwrap(),
+            "Return at $C004 to $0150, expected $010E from frame at $010D\n=> $0150  nop\n"
+        );
+        assert!(session.execute("cdl save unused").is_err());
+        session.execute("cdl on").unwrap();
+        session.execute("s 2").unwrap();
+        let path = env::temp_dir().join("gbdbg_test.cdl");
+        assert_eq!(
+            session
+                .execute(&format!("cdl save {} mesen", path.display()))
+                .unwrap(),
+            "2 code and 0 data bytes of 32768\n"
+        );
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(saved[..5], *b"CDLv2");
+        assert_eq!(saved[9 + 0x150..9 + 0x153], [0x01, 0x01, 0x00]);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            session.execute("info").unwrap(),
+            "  1  break $0108 if sp == $DFFD\n  2  watch $C000..$C000 w\n"
+        );
+    }
+
+    #[test]
+    fn test_asm_patches_rom() {
+        // MBC1 with four banks
+        let mut rom = vec![0x00; 0x10000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        let mut session = Session::new(GameBoy::new(rom).unwrap());
+
+        // Both would select another bank if they went through the mapper
+        session.execute("asm $2000 ld a, 3").unwrap();
+        session.execute("asm $4000 jp $0150").unwrap();
+        assert_eq!(
+            session.execute("disas $2000 1").unwrap(),
+            "   $2000  ld a, $03\n"
+        );
+        assert_eq!(
+            session.execute("disas $4000 1").unwrap(),
+            "   $4000  jp $0150\n"
+        );
+        assert_eq!(rom_bank(&session.gb, 0x4000), Some(1));
+        let bus = session.gb.bus().borrow();
+        assert_eq!(
+            bus.cartridge().unwrap().rom()[0x4000..0x4003],
+            [0xC3, 0x50, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut session = session(&emu::gb_asm!(
+            origin = 0x0100;
+            "ld hl, $C000",
+            "call $0150",
+        ));
+        session.debugger.set_symbols(
+            SymbolTable::parse("00:0100 Start\n00:0150 Sub\n00:C000 wBuffer").unwrap(),
+        );
+        assert_eq!(
+            session.execute("disas Start 2").unwrap(),
+            "Start:\n=> $0100  ld hl, wBuffer\n   $0103  call Sub\n"
+        );
+        assert_eq!(
+            session.execute("b Sub if hl == wBuffer").unwrap(),
+            "Breakpoint 1 at $0150\n"
+        );
+        assert_eq!(
+            session.execute("c").unwrap(),
+            "Breakpoint 1\nSub:\n=> $0150  nop\n"
+        );
+        assert_eq!(
+            session.execute("bt").unwrap(),
+            "#0  Sub\n#1  Start+$3  call Sub\n"
+        );
+        assert_eq!(session.execute("x/1 wBuffer+1").unwrap(), "$C001: 00\n");
+
+        assert!(session.execute("profile").is_err());
+        session.execute("profile on").unwrap();
+        session.execute("s 3").unwrap();
+        let report = session.execute("profile 1").unwrap();
+        assert!(report.starts_with("3 M-cycles over 0.0 frames\n"));
+        assert!(report.contains("100.0   100.0             3  Sub\n"));
+    }
+
+    #[test]
+    fn test_history_and_scripts() {
+        let mut session = session(&emu::gb_asm!(origin = 0x0100; "inc a", "inc a", "inc b"));
+        session.execute("set a 0").unwrap();
+        session.execute("step").unwrap();
+        session.execute("").unwrap();
+        assert_eq!(
+            session.execute("r").unwrap().lines().next(),
+            Some("AF=$0210 BC=$0013 DE=$00D8 HL=$014D SP=$FFFE PC=$0102")
+        );
+        session.execute("!2").unwrap();
+        assert_eq!(
+            session.execute("history").unwrap(),
+            "   1  set a 0\n   2  step\n   3  r\n   4  step\n   5  history\n"
+        );
+
+        assert_eq!(
+            session.execute("asm $C000 ld a, [hl+]").unwrap(),
+            "$C000: 2A\n"
+        );
+        let output = session
+            .source("# comment\nset pc $C000\nset hl $C100\nstep\nregs\nquit\nstep")
+            .unwrap();
+        assert!(output.contains("HL=$C101"));
+        assert!(session.quit);
+        assert!(session.source("set q 1").is_err());
+        assert!(session.execute("frobnicate").is_err());
+    }
+}
@@ -0,0 +1,739 @@
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const NR10_ADDR: usize = 0xFF10;
+pub const NR52_ADDR: usize = 0xFF26;
+pub const WAVE_RAM_START: usize = 0xFF30;
+pub const WAVE_RAM_END: usize = 0xFF3F;
+
+pub const SAMPLE_RATE: u32 = 48000;
+const CPU_CLOCK: u64 = 4194304;
+
+// Samples are interleaved left/right; beyond a second of unread audio new samples are dropped
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+// Bits that always read back as 1, for 0xFF10-0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const DUTY_PATTERNS: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Default)]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    // Returns true when the counter runs out and the channel should turn off
+    fn tick(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = nrx2 >> 4;
+        self.increase = nrx2 & 0x08 != 0;
+        self.period = nrx2 & 0b111;
+        self.timer = self.period;
+    }
+
+    fn tick(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+#[derive(Default)]
+struct SquareChannel {
+    enabled: bool,
+    frequency: u16,
+    timer: u32,
+    duty_step: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Sweep,
+}
+
+impl SquareChannel {
+    fn tick(&mut self, t_cycles: u32, duty: u8) -> u8 {
+        let period = (2048 - self.frequency as u32) * 4;
+        let mut remaining = t_cycles;
+        while remaining >= self.timer {
+            remaining -= self.timer;
+            self.timer = period;
+            self.duty_step = (self.duty_step + 1) & 0b111;
+        }
+        self.timer -= remaining;
+
+        let high = (DUTY_PATTERNS[duty as usize] >> self.duty_step) & 0b1 != 0;
+        if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct WaveChannel {
+    enabled: bool,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    length: LengthCounter,
+}
+
+#[derive(Default)]
+struct NoiseChannel {
+    enabled: bool,
+    lfsr: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+pub struct Apu {
+    // Raw register contents for 0xFF10-0xFF2F, read back through READ_MASKS
+    registers: [u8; 0x20],
+    wave_ram: [u8; 0x10],
+    powered: bool,
+    cgb: bool,
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+    sample_counter: u64,
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self::with_cgb(false)
+    }
+
+    pub fn new_cgb() -> Self {
+        Self::with_cgb(true)
+    }
+
+    fn with_cgb(cgb: bool) -> Self {
+        Self {
+            registers: [0x00; 0x20],
+            wave_ram: [0x00; 0x10],
+            powered: false,
+            cgb,
+            square1: SquareChannel::default(),
+            square2: SquareChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+            sample_counter: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            NR52_ADDR => {
+                0x70 | ((self.powered as u8) << 7)
+                    | (self.square1.enabled as u8)
+                    | ((self.square2.enabled as u8) << 1)
+                    | ((self.wave.enabled as u8) << 2)
+                    | ((self.noise.enabled as u8) << 3)
+            }
+            NR10_ADDR..=0xFF2F => {
+                let index = addr - NR10_ADDR;
+                self.registers[index] | READ_MASKS[index]
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave_ram[addr - WAVE_RAM_START],
+            _ => unreachable!("Invalid APU address {:#06X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        match addr {
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave_ram[addr - WAVE_RAM_START] = data,
+            NR52_ADDR => {
+                let power = data & 0x80 != 0;
+                if self.powered && !power {
+                    // Powering off clears every register, but the DMG keeps its length counters
+                    let lengths = [
+                        self.square1.length.counter,
+                        self.square2.length.counter,
+                        self.wave.length.counter,
+                        self.noise.length.counter,
+                    ];
+                    for addr in NR10_ADDR..NR52_ADDR {
+                        self.write(addr, 0x00);
+                    }
+                    if !self.cgb {
+                        self.square1.length.counter = lengths[0];
+                        self.square2.length.counter = lengths[1];
+                        self.wave.length.counter = lengths[2];
+                        self.noise.length.counter = lengths[3];
+                    }
+                    self.square1.enabled = false;
+                    self.square2.enabled = false;
+                    self.wave.enabled = false;
+                    self.noise.enabled = false;
+                } else if !self.powered && power {
+                    self.frame_sequencer_step = 0;
+                }
+                self.powered = power;
+            }
+            // The DMG still lets lengths be loaded while powered off
+            0xFF11 | 0xFF16 | 0xFF1B | 0xFF20 if !self.powered && !self.cgb => {
+                self.write_channel(addr, data);
+            }
+            // Everything else is read only while the APU is off
+            _ if !self.powered => {}
+            NR10_ADDR..=0xFF2F => {
+                self.registers[addr - NR10_ADDR] = data;
+                self.write_channel(addr, data);
+            }
+            _ => unreachable!("Invalid APU address {:#06X}", addr),
+        }
+    }
+
+    // Advances the APU by the given number of T-cycles at the normal speed clock
+    pub fn tick(&mut self, t_cycles: u32) {
+        if !self.powered {
+            self.output_samples(t_cycles, [0, 0, 0, 0]);
+            return;
+        }
+
+        self.frame_sequencer_timer = self.frame_sequencer_timer.saturating_sub(t_cycles);
+        if self.frame_sequencer_timer == 0 {
+            self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+            self.step_frame_sequencer();
+        }
+
+        let outputs = [
+            self.square1.tick(t_cycles, self.reg(0xFF11) >> 6),
+            self.square2.tick(t_cycles, self.reg(0xFF16) >> 6),
+            self.tick_wave(t_cycles),
+            self.tick_noise(t_cycles),
+        ];
+        self.output_samples(t_cycles, outputs);
+    }
+
+    // Drains the interleaved stereo samples produced so far
+    #[inline]
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    #[inline]
+    fn reg(&self, addr: usize) -> u8 {
+        self.registers[addr - NR10_ADDR]
+    }
+
+    fn write_channel(&mut self, addr: usize, data: u8) {
+        match addr {
+            0xFF11 => self.square1.length.counter = 64 - (data & 0x3F) as u16,
+            0xFF16 => self.square2.length.counter = 64 - (data & 0x3F) as u16,
+            0xFF1B => self.wave.length.counter = 256 - data as u16,
+            0xFF20 => self.noise.length.counter = 64 - (data & 0x3F) as u16,
+            // Turning a DAC off also turns its channel off
+            0xFF12 if data & 0xF8 == 0 => self.square1.enabled = false,
+            0xFF17 if data & 0xF8 == 0 => self.square2.enabled = false,
+            0xFF1A if data & 0x80 == 0 => self.wave.enabled = false,
+            0xFF21 if data & 0xF8 == 0 => self.noise.enabled = false,
+            0xFF13 => self.square1.frequency = (self.square1.frequency & 0x700) | data as u16,
+            0xFF18 => self.square2.frequency = (self.square2.frequency & 0x700) | data as u16,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | data as u16,
+            0xFF14 => {
+                self.square1.frequency =
+                    (self.square1.frequency & 0xFF) | (((data & 0b111) as u16) << 8);
+                self.square1.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger_square1();
+                }
+            }
+            0xFF19 => {
+                self.square2.frequency =
+                    (self.square2.frequency & 0xFF) | (((data & 0b111) as u16) << 8);
+                self.square2.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    let nr22 = self.reg(0xFF17);
+                    let square = &mut self.square2;
+                    square.enabled = nr22 & 0xF8 != 0;
+                    square.length.trigger(64);
+                    square.timer = (2048 - square.frequency as u32) * 4;
+                    square.envelope.trigger(nr22);
+                }
+            }
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | (((data & 0b111) as u16) << 8);
+                self.wave.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    let wave = &mut self.wave;
+                    wave.enabled = self.registers[0x0A] & 0x80 != 0;
+                    wave.length.trigger(256);
+                    wave.timer = (2048 - wave.frequency as u32) * 2;
+                    wave.position = 0;
+                }
+            }
+            0xFF23 => {
+                self.noise.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    let nr42 = self.reg(0xFF21);
+                    let period = self.noise_period();
+                    let noise = &mut self.noise;
+                    noise.enabled = nr42 & 0xF8 != 0;
+                    noise.length.trigger(64);
+                    noise.envelope.trigger(nr42);
+                    noise.lfsr = 0x7FFF;
+                    noise.timer = period;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger_square1(&mut self) {
+        let nr10 = self.reg(NR10_ADDR);
+        let nr12 = self.reg(0xFF12);
+        let square = &mut self.square1;
+        square.enabled = nr12 & 0xF8 != 0;
+        square.length.trigger(64);
+        square.timer = (2048 - square.frequency as u32) * 4;
+        square.envelope.trigger(nr12);
+
+        let period = (nr10 >> 4) & 0b111;
+        let shift = nr10 & 0b111;
+        square.sweep.shadow = square.frequency;
+        square.sweep.timer = if period == 0 { 8 } else { period };
+        square.sweep.enabled = period != 0 || shift != 0;
+        if shift != 0 && self.sweep_frequency() > 2047 {
+            self.square1.enabled = false;
+        }
+    }
+
+    fn sweep_frequency(&self) -> u16 {
+        let nr10 = self.reg(NR10_ADDR);
+        let shadow = self.square1.sweep.shadow;
+        let delta = shadow >> (nr10 & 0b111);
+        if nr10 & 0x08 != 0 {
+            shadow - delta
+        } else {
+            shadow + delta
+        }
+    }
+
+    fn tick_sweep(&mut self) {
+        let nr10 = self.reg(NR10_ADDR);
+        let period = (nr10 >> 4) & 0b111;
+        let sweep = &mut self.square1.sweep;
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.timer = if period == 0 { 8 } else { period };
+        if !sweep.enabled || period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep_frequency();
+        if frequency > 2047 {
+            self.square1.enabled = false;
+        } else if nr10 & 0b111 != 0 {
+            self.square1.sweep.shadow = frequency;
+            self.square1.frequency = frequency;
+            // The new frequency is checked again straight away
+            if self.sweep_frequency() > 2047 {
+                self.square1.enabled = false;
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step & 0b1 == 0 {
+            if self.square1.length.tick() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.tick() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.tick() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.tick() {
+                self.noise.enabled = false;
+            }
+        }
+        if step == 2 || step == 6 {
+            self.tick_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.tick();
+            self.square2.envelope.tick();
+            self.noise.envelope.tick();
+        }
+        self.frame_sequencer_step = (step + 1) & 0b111;
+    }
+
+    fn tick_wave(&mut self, t_cycles: u32) -> u8 {
+        let period = (2048 - self.wave.frequency as u32) * 2;
+        let mut remaining = t_cycles;
+        while remaining >= self.wave.timer {
+            remaining -= self.wave.timer;
+            self.wave.timer = period;
+            self.wave.position = (self.wave.position + 1) & 0x1F;
+        }
+        self.wave.timer -= remaining;
+
+        if !self.wave.enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[(self.wave.position / 2) as usize];
+        let sample = if self.wave.position & 0b1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match (self.reg(0xFF1C) >> 5) & 0b11 {
+            0 => 0,
+            volume => sample >> (volume - 1),
+        }
+    }
+
+    fn noise_period(&self) -> u32 {
+        let nr43 = self.reg(0xFF22);
+        NOISE_DIVISORS[(nr43 & 0b111) as usize] << (nr43 >> 4)
+    }
+
+    fn tick_noise(&mut self, t_cycles: u32) -> u8 {
+        let period = self.noise_period();
+        let short_mode = self.reg(0xFF22) & 0x08 != 0;
+        let noise = &mut self.noise;
+        let mut remaining = t_cycles;
+        while remaining >= noise.timer {
+            remaining -= noise.timer;
+            noise.timer = period;
+
+            let feedback = (noise.lfsr ^ (noise.lfsr >> 1)) & 0b1;
+            noise.lfsr = (noise.lfsr >> 1) | (feedback << 14);
+            if short_mode {
+                noise.lfsr = (noise.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        noise.timer -= remaining;
+
+        if noise.enabled && noise.lfsr & 0b1 == 0 {
+            noise.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    fn output_samples(&mut self, t_cycles: u32, outputs: [u8; 4]) {
+        self.sample_counter += t_cycles as u64 * SAMPLE_RATE as u64;
+        if self.sample_counter < CPU_CLOCK {
+            return;
+        }
+
+        let nr50 = self.reg(0xFF24);
+        let nr51 = self.reg(0xFF25);
+        let dacs = [
+            self.reg(0xFF12) & 0xF8 != 0,
+            self.reg(0xFF17) & 0xF8 != 0,
+            self.reg(0xFF1A) & 0x80 != 0,
+            self.reg(0xFF21) & 0xF8 != 0,
+        ];
+        let mut left: i32 = 0;
+        let mut right: i32 = 0;
+        for (channel, (&output, &dac)) in outputs.iter().zip(dacs.iter()).enumerate() {
+            if !self.powered || !dac {
+                continue;
+            }
+            // Each DAC maps 0-15 onto a signed level
+            let level = output as i32 * 2 - 15;
+            if nr51 & (0x10 << channel) != 0 {
+                left += level;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                right += level;
+            }
+        }
+        let left = (left * (((nr50 >> 4) & 0b111) as i32 + 1) * 64) as i16;
+        let right = (right * ((nr50 & 0b111) as i32 + 1) * 64) as i16;
+
+        while self.sample_counter >= CPU_CLOCK {
+            self.sample_counter -= CPU_CLOCK;
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u16(self.counter);
+        writer.put_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.u16()?;
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.volume);
+        writer.put_bool(self.increase);
+        writer.put_u8(self.period);
+        writer.put_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.volume = reader.u8()? & 0x0F;
+        self.increase = reader.bool()?;
+        self.period = reader.u8()? & 0b111;
+        self.timer = reader.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.enabled);
+        writer.put_u16(self.frequency);
+        writer.put_u32(self.timer);
+        writer.put_u8(self.duty_step);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.put_bool(self.sweep.enabled);
+        writer.put_u16(self.sweep.shadow);
+        writer.put_u8(self.sweep.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?;
+        self.duty_step = reader.u8()? & 0b111;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.sweep.enabled = reader.bool()?;
+        self.sweep.shadow = reader.u16()?;
+        self.sweep.timer = reader.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.enabled);
+        writer.put_u16(self.frequency);
+        writer.put_u32(self.timer);
+        writer.put_u8(self.position);
+        self.length.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.u32()?;
+        self.position = reader.u8()? & 0x1F;
+        self.length.load_state(reader)
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.enabled);
+        writer.put_u16(self.lfsr);
+        writer.put_u32(self.timer);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.lfsr = reader.u16()?;
+        self.timer = reader.u32()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)
+    }
+}
+
+// Buffered samples belong to the frontend and aren't part of the machine
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.registers);
+        writer.put_bytes(&self.wave_ram);
+        writer.put_bool(self.powered);
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+        writer.put_u32(self.frame_sequencer_timer);
+        writer.put_u8(self.frame_sequencer_step);
+        writer.put_u64(self.sample_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes_into(&mut self.registers)?;
+        reader.bytes_into(&mut self.wave_ram)?;
+        self.powered = reader.bool()?;
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.frame_sequencer_timer = reader.u32()?;
+        self.frame_sequencer_step = reader.u8()? & 0b111;
+        self.sample_counter = reader.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xFF);
+        apu
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = Apu::new();
+        for _ in 0..CPU_CLOCK / 4 {
+            apu.tick(4);
+        }
+        assert_eq!(apu.take_samples().len(), SAMPLE_RATE as usize * 2);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_register_reads() {
+        let mut apu = Apu::new();
+        assert_eq!(apu.read(NR52_ADDR), 0x70);
+        // Writes are ignored while powered off
+        apu.write(0xFF12, 0xF3);
+        assert_eq!(apu.read(0xFF12), 0x00);
+
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(0xFF11, 0x80);
+        assert_eq!(apu.read(0xFF11), 0xBF);
+        apu.write(0xFF12, 0xF3);
+        apu.write(0xFF14, 0x80);
+        assert_eq!(apu.read(NR52_ADDR), 0xF1);
+
+        apu.write(NR52_ADDR, 0x00);
+        assert_eq!(apu.read(NR52_ADDR), 0x70);
+        assert_eq!(apu.read(0xFF12), 0x00);
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = powered_apu();
+        apu.write(0xFF17, 0xF0);
+        // Two length ticks left, with length enabled
+        apu.write(0xFF16, 62);
+        apu.write(0xFF19, 0xC0);
+        assert_eq!(apu.read(NR52_ADDR) & 0b10, 0b10);
+
+        // Length clocks on every other frame sequencer step
+        for _ in 0..FRAME_SEQUENCER_PERIOD {
+            apu.tick(4);
+        }
+        assert_eq!(apu.read(NR52_ADDR) & 0b10, 0);
+    }
+
+    #[test]
+    fn test_square_output() {
+        let mut apu = powered_apu();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF19, 0x87);
+        for _ in 0..10000 {
+            apu.tick(4);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|&sample| sample > 0));
+        assert!(samples.iter().any(|&sample| sample < 0));
+    }
+
+    #[test]
+    fn test_dmg_length_while_off() {
+        let mut apu = Apu::new();
+        apu.write(0xFF16, 62);
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0xC0);
+        for _ in 0..FRAME_SEQUENCER_PERIOD {
+            apu.tick(4);
+        }
+        assert_eq!(apu.read(NR52_ADDR) & 0b10, 0);
+
+        let mut apu = Apu::new_cgb();
+        apu.write(0xFF16, 62);
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0xC0);
+        for _ in 0..FRAME_SEQUENCER_PERIOD {
+            apu.tick(4);
+        }
+        assert_eq!(apu.read(NR52_ADDR) & 0b10, 0b10);
+    }
+}
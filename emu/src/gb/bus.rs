@@ -0,0 +1,637 @@
+use crate::{
+    gb::{
+        apu::{Apu, NR10_ADDR, WAVE_RAM_END},
+        cartridge::Cartridge,
+        hdma::{HDMA_BLOCK_SIZE, HDMA1_ADDR, HDMA5_ADDR, Hdma},
+        interrupts::Interrupt,
+        joypad::{ButtonState, Joypad},
+        model::Model,
+        ppu::{BCPS_ADDR, LCDC_ADDR, OCPD_ADDR, Ppu, PpuEvent, WX_ADDR},
+        serial::{LinkPartner, SB_ADDR, SC_ADDR, Serial},
+        sgb::Sgb,
+        state::{SaveState, StateError, StateReader, StateWriter},
+        timer::{DIV_ADDR, TAC_ADDR, Timer},
+    },
+    ram::Ram,
+};
+
+pub const P1_ADDR: usize = 0xFF00;
+pub const IF_ADDR: usize = 0xFF0F;
+pub const DMA_ADDR: usize = 0xFF46;
+pub const KEY1_ADDR: usize = 0xFF4D;
+pub const VBK_ADDR: usize = 0xFF4F;
+pub const SVBK_ADDR: usize = 0xFF70;
+pub const IE_ADDR: usize = 0xFFFF;
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+pub struct Bus {
+    model: Model,
+    ram: Ram<u8>,
+    cartridge: Option<Cartridge>,
+    wram: Vec<u8>,
+    wram_bank: usize,
+    ppu: Ppu,
+    hdma: Hdma,
+    joypad: Joypad,
+    serial: Serial,
+    timer: Timer,
+    apu: Apu,
+    sgb: Option<Sgb>,
+    interrupt_flag: Interrupt,
+    interrupt_enable: u8,
+    dma_source: u8,
+    cgb_mode: bool,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    stall_cycles: u32,
+    frame_ready: bool,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::with_model(Model::Dmg, false)
+    }
+
+    pub fn new_cgb() -> Self {
+        Self::with_model(Model::Cgb, true)
+    }
+
+    pub fn new_sgb() -> Self {
+        Self::with_model(Model::Sgb, false)
+    }
+
+    // CGB hardware runs DMG cartridges with the CGB features turned off
+    pub fn with_model(model: Model, cgb_mode: bool) -> Self {
+        let cgb_mode = cgb_mode && model.is_cgb();
+        Self {
+            model,
+            ram: Ram::new(0x10000),
+            cartridge: None,
+            wram: vec![0x00; WRAM_BANK_SIZE * 8],
+            wram_bank: 1,
+            ppu: if cgb_mode { Ppu::new_cgb() } else { Ppu::new() },
+            hdma: Hdma::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            apu: if model.is_cgb() {
+                Apu::new_cgb()
+            } else {
+                Apu::new()
+            },
+            sgb: model.is_sgb().then(Sgb::new),
+            interrupt_flag: Interrupt::empty(),
+            interrupt_enable: 0x00,
+            dma_source: 0x00,
+            cgb_mode,
+            double_speed: false,
+            speed_switch_armed: false,
+            stall_cycles: 0,
+            frame_ready: false,
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if self.cartridge.is_some() => {
+                self.cartridge.as_ref().unwrap().read(addr)
+            }
+            0x8000..=0x9FFF => self.ppu.read_vram(addr),
+            0xC000..=0xCFFF => self.wram[addr - 0xC000],
+            0xD000..=0xDFFF => self.wram[self.wram_bank * WRAM_BANK_SIZE + addr - 0xD000],
+            0xE000..=0xFDFF => self.read(addr - 0x2000),
+            0xFE00..=0xFE9F => self.ppu.read_oam(addr),
+            P1_ADDR => self.read_p1(),
+            SB_ADDR | SC_ADDR => self.serial.read(addr),
+            DIV_ADDR..=TAC_ADDR => self.timer.read(addr),
+            NR10_ADDR..=WAVE_RAM_END => self.apu.read(addr),
+            IF_ADDR => 0xE0 | self.interrupt_flag.bits(),
+            DMA_ADDR => self.dma_source,
+            LCDC_ADDR..=WX_ADDR => self.ppu.read(addr),
+            KEY1_ADDR if self.cgb_mode => {
+                0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+            }
+            VBK_ADDR if self.cgb_mode => 0xFE | self.ppu.vram_bank() as u8,
+            HDMA1_ADDR..=HDMA5_ADDR if self.cgb_mode => self.hdma.read(addr),
+            BCPS_ADDR..=OCPD_ADDR if self.cgb_mode => self.ppu.read(addr),
+            SVBK_ADDR if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            KEY1_ADDR | VBK_ADDR | HDMA1_ADDR..=HDMA5_ADDR | BCPS_ADDR..=OCPD_ADDR | SVBK_ADDR => {
+                0xFF
+            }
+            IE_ADDR => self.interrupt_enable,
+            _ => self.ram.read(addr),
+        }
+    }
+
+    // Writes the way a debugger means it: ROM addresses change the byte mapped
+    // there instead of the mapper registers
+    pub fn patch(&mut self, addr: usize, data: u8) {
+        match (addr, &mut self.cartridge) {
+            (0x0000..=0x7FFF, Some(cartridge)) => cartridge.patch_rom(addr, data),
+            _ => self.write(addr, data),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if self.cartridge.is_some() => {
+                self.cartridge.as_mut().unwrap().write(addr, data)
+            }
+            0x8000..=0x9FFF => self.ppu.write_vram(addr, data),
+            0xC000..=0xCFFF => self.wram[addr - 0xC000] = data,
+            0xD000..=0xDFFF => {
+                self.wram[self.wram_bank * WRAM_BANK_SIZE + addr - 0xD000] = data;
+            }
+            0xE000..=0xFDFF => self.write(addr - 0x2000, data),
+            0xFE00..=0xFE9F => self.ppu.write_oam(addr, data),
+            P1_ADDR => self.write_p1(data),
+            SB_ADDR | SC_ADDR => self.serial.write(addr, data),
+            DIV_ADDR..=TAC_ADDR => self.timer.write(addr, data),
+            NR10_ADDR..=WAVE_RAM_END => self.apu.write(addr, data),
+            IF_ADDR => self.interrupt_flag = Interrupt::from_bits_truncate(data),
+            DMA_ADDR => self.oam_dma(data),
+            LCDC_ADDR..=WX_ADDR => self.ppu.write(addr, data),
+            KEY1_ADDR if self.cgb_mode => self.speed_switch_armed = data & 0b1 != 0,
+            VBK_ADDR if self.cgb_mode => self.ppu.set_vram_bank((data & 0b1) as usize),
+            HDMA1_ADDR..=HDMA5_ADDR if self.cgb_mode => {
+                if let Some(blocks) = self.hdma.write(addr, data) {
+                    for _ in 0..blocks {
+                        self.hdma_block();
+                    }
+                }
+            }
+            BCPS_ADDR..=OCPD_ADDR if self.cgb_mode => self.ppu.write(addr, data),
+            SVBK_ADDR if self.cgb_mode => self.wram_bank = ((data & 0b111) as usize).max(1),
+            KEY1_ADDR | VBK_ADDR | HDMA1_ADDR..=HDMA5_ADDR | BCPS_ADDR..=OCPD_ADDR | SVBK_ADDR => {}
+            IE_ADDR => self.interrupt_enable = data,
+            _ => self.ram.write(addr, data),
+        }
+    }
+
+    // Advances every device by one M-cycle
+    pub fn tick(&mut self) {
+        // The PPU keeps real time while the CPU runs twice as fast in double speed
+        let dots = if self.double_speed { 2 } else { 4 };
+        let events = self.ppu.tick(dots);
+        if events.contains(PpuEvent::VBlank) {
+            self.request_interrupt(Interrupt::VBlank);
+            self.frame_ready = true;
+            if let Some(sgb) = &mut self.sgb {
+                sgb.on_vblank(&self.ppu);
+            }
+        }
+        if events.contains(PpuEvent::Stat) {
+            self.request_interrupt(Interrupt::Lcd);
+        }
+        if events.contains(PpuEvent::HBlank) && self.hdma.is_hblank_active() {
+            self.hdma_block();
+        }
+
+        if self.serial.tick(4) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        if self.timer.tick() {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        // Sound and the cartridge clock run in real time like the PPU
+        self.apu.tick(dots);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick_rtc(dots);
+        }
+    }
+
+    // Advances what still runs during STOP by one M-cycle
+    pub fn tick_stopped(&mut self) {
+        self.serial.idle(4);
+    }
+
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+
+    #[inline]
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    #[inline]
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+
+    // True once per frame, the first time it is asked after VBlank starts
+    #[inline]
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    #[inline]
+    pub fn timer_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    #[inline]
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.apu.take_samples()
+    }
+
+    // M-cycles the CPU must sit out for DMA transfers since it last asked
+    #[inline]
+    pub fn take_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.stall_cycles)
+    }
+
+    // Performs a pending KEY1 speed switch, as triggered by STOP
+    pub fn try_speed_switch(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    #[inline]
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    #[inline]
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    #[inline]
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    #[inline]
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    fn oam_dma(&mut self, source: u8) {
+        self.dma_source = source;
+        let base = (source as usize) << 8;
+        for offset in 0..0xA0 {
+            let data = self.read(base + offset);
+            self.ppu.write_oam(0xFE00 + offset, data);
+        }
+    }
+
+    fn hdma_block(&mut self) {
+        let (source, dest) = self.hdma.next_block();
+        for offset in 0..HDMA_BLOCK_SIZE {
+            let data = self.read(source.wrapping_add(offset) as usize);
+            self.ppu.write_vram((dest + offset) as usize, data);
+        }
+        // 32 dots per block, whatever the CPU speed
+        self.stall_cycles += if self.double_speed { 16 } else { 8 };
+    }
+
+    pub fn connect_serial(&mut self, partner: Box<dyn LinkPartner>) {
+        self.serial.connect(partner);
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        self.set_player_buttons(0, buttons);
+    }
+
+    // Fills work RAM and high RAM with a pattern derived from the seed, like
+    // the garbage real hardware powers on with but reproducible
+    pub fn seed_ram(&mut self, seed: u64) {
+        // xorshift64*, which only needs a nonzero state
+        let mut state = seed ^ 0x9E3779B97F4A7C15;
+        let mut next = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+        };
+        for byte in self.wram.iter_mut() {
+            *byte = next();
+        }
+        for addr in 0xFF80..=0xFFFE {
+            self.ram.write(addr, next());
+        }
+    }
+
+    // Only the SGB multiplayer adapter has more than one controller
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) {
+        let current_player = match &mut self.sgb {
+            Some(sgb) => {
+                sgb.set_player_buttons(player, buttons);
+                sgb.current_player()
+            }
+            None => 0,
+        };
+        if player == current_player && self.joypad.set_buttons(buttons) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    fn read_p1(&self) -> u8 {
+        let value = self.joypad.read();
+        // With no row selected the multiplayer adapter reports which controller is active
+        match self.sgb.as_ref().and_then(Sgb::joypad_id) {
+            Some(id) if value & 0x30 == 0x30 => 0xF0 | id,
+            _ => value,
+        }
+    }
+
+    fn write_p1(&mut self, data: u8) {
+        let mut interrupt = self.joypad.write(data);
+        if let Some(sgb) = &mut self.sgb
+            && sgb.write_p1(data)
+        {
+            let buttons = sgb.player_buttons(sgb.current_player());
+            interrupt |= self.joypad.set_buttons(buttons);
+        }
+        if interrupt {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    #[inline]
+    pub fn is_sgb(&self) -> bool {
+        self.sgb.is_some()
+    }
+
+    // The 256x224 picture the SGB sends to the TV, border included
+    pub fn sgb_frame(&self) -> Option<Vec<u16>> {
+        self.sgb.as_ref().map(Sgb::frame)
+    }
+
+    #[inline]
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    #[inline]
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag.insert(interrupt);
+    }
+
+    #[inline]
+    pub fn acknowledge_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag.remove(interrupt);
+    }
+
+    // Interrupts that are both requested and enabled
+    #[inline]
+    pub fn pending_interrupts(&self) -> Interrupt {
+        self.interrupt_flag & Interrupt::from_bits_truncate(self.interrupt_enable)
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Bus {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.model as u8);
+        writer.put_bool(self.cgb_mode);
+        self.ram.save_state(writer);
+        writer.put_bool(self.cartridge.is_some());
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(writer);
+        }
+        writer.put_bytes(&self.wram);
+        writer.put_u8(self.wram_bank as u8);
+        self.ppu.save_state(writer);
+        self.hdma.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+        self.timer.save_state(writer);
+        self.apu.save_state(writer);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(writer);
+        }
+        writer.put_u8(self.interrupt_flag.bits());
+        writer.put_u8(self.interrupt_enable);
+        writer.put_u8(self.dma_source);
+        writer.put_bool(self.double_speed);
+        writer.put_bool(self.speed_switch_armed);
+        writer.put_u32(self.stall_cycles);
+        writer.put_bool(self.frame_ready);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        // Model and CGB mode decide the shape of everything after them
+        if reader.u8()? != self.model as u8 || reader.bool()? != self.cgb_mode {
+            return Err(StateError::Mismatch("model"));
+        }
+        self.ram.load_state(reader)?;
+        if reader.bool()? != self.cartridge.is_some() {
+            return Err(StateError::Mismatch("cartridge"));
+        }
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load_state(reader)?;
+        }
+        reader.bytes_into(&mut self.wram)?;
+        self.wram_bank = (reader.u8()? & 0b111).max(1) as usize;
+        self.ppu.load_state(reader)?;
+        self.hdma.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(reader)?;
+        }
+        self.interrupt_flag = Interrupt::from_bits_retain(reader.u8()?);
+        self.interrupt_enable = reader.u8()?;
+        self.dma_source = reader.u8()?;
+        self.double_speed = reader.bool()?;
+        self.speed_switch_armed = reader.bool()?;
+        self.stall_cycles = reader.u32()?;
+        self.frame_ready = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write_ram() {
+        let mut bus = Bus::new();
+        bus.write(0xC000, 0x42);
+        assert_eq!(bus.read(0xC000), 0x42);
+    }
+
+    #[test]
+    fn test_joypad_interrupt() {
+        let mut bus = Bus::new();
+        bus.write(P1_ADDR, 0x10);
+        bus.set_buttons(ButtonState::Start);
+        assert_eq!(bus.read(P1_ADDR), 0xD7);
+        assert_eq!(bus.read(IF_ADDR), 0xE0 | Interrupt::Joypad.bits());
+    }
+
+    #[test]
+    fn test_serial_interrupt() {
+        let mut bus = Bus::new();
+        bus.write(SC_ADDR, 0x81);
+        for _ in 0..1023 {
+            bus.tick();
+        }
+        assert!(!bus.interrupt_flag.contains(Interrupt::Serial));
+        bus.tick();
+        assert!(bus.interrupt_flag.contains(Interrupt::Serial));
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut bus = Bus::new_cgb();
+        bus.write(0xD000, 0x11);
+        bus.write(SVBK_ADDR, 0x07);
+        bus.write(0xD000, 0x77);
+        assert_eq!(bus.read(SVBK_ADDR), 0xFF);
+        bus.write(SVBK_ADDR, 0x00);
+        assert_eq!(bus.read(0xD000), 0x11);
+        assert_eq!(bus.read(0xF000), 0x11);
+        bus.write(SVBK_ADDR, 0x07);
+        assert_eq!(bus.read(0xD000), 0x77);
+    }
+
+    #[test]
+    fn test_dmg_ignores_cgb_registers() {
+        let mut bus = Bus::new();
+        bus.write(SVBK_ADDR, 0x03);
+        bus.write(VBK_ADDR, 0x01);
+        bus.write(0xD000, 0x42);
+        assert_eq!(bus.read(SVBK_ADDR), 0xFF);
+        assert_eq!(bus.read(KEY1_ADDR), 0xFF);
+        assert!(!bus.try_speed_switch());
+        assert_eq!(bus.wram[WRAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn test_general_purpose_hdma() {
+        let mut bus = Bus::new_cgb();
+        for offset in 0..0x20 {
+            bus.write(0xC000 + offset, offset as u8);
+        }
+        bus.write(VBK_ADDR, 0x01);
+        bus.write(HDMA1_ADDR, 0xC0);
+        bus.write(HDMA1_ADDR + 1, 0x00);
+        bus.write(HDMA1_ADDR + 2, 0x00);
+        bus.write(HDMA1_ADDR + 3, 0x40);
+        bus.write(HDMA5_ADDR, 0x01);
+
+        assert_eq!(bus.read(HDMA5_ADDR), 0xFF);
+        assert_eq!(bus.take_stall_cycles(), 16);
+        assert_eq!(bus.read(0x8040), 0x00);
+        assert_eq!(bus.read(0x805F), 0x1F);
+        bus.write(VBK_ADDR, 0x00);
+        assert_eq!(bus.read(0x805F), 0x00);
+    }
+
+    #[test]
+    fn test_hblank_hdma() {
+        let mut bus = Bus::new_cgb();
+        for offset in 0..0x20 {
+            bus.write(0xC000 + offset, 0xA0 | offset as u8);
+        }
+        bus.write(LCDC_ADDR, 0x80);
+        bus.write(HDMA1_ADDR, 0xC0);
+        bus.write(HDMA1_ADDR + 1, 0x00);
+        bus.write(HDMA1_ADDR + 2, 0x00);
+        bus.write(HDMA1_ADDR + 3, 0x00);
+        bus.write(HDMA5_ADDR, 0x81);
+        assert_eq!(bus.read(0x8000), 0x00);
+
+        // One line worth of M-cycles reaches exactly one HBlank
+        for _ in 0..114 {
+            bus.tick();
+        }
+        assert_eq!(bus.read(0x800F), 0xAF);
+        assert_eq!(bus.read(0x8010), 0x00);
+        assert_eq!(bus.read(HDMA5_ADDR), 0x00);
+
+        for _ in 0..114 {
+            bus.tick();
+        }
+        assert_eq!(bus.read(0x8010), 0xB0);
+        assert_eq!(bus.read(HDMA5_ADDR), 0xFF);
+    }
+
+    #[test]
+    fn test_double_speed_ppu() {
+        let mut bus = Bus::new_cgb();
+        bus.write(LCDC_ADDR, 0x80);
+        bus.write(KEY1_ADDR, 0x01);
+        assert_eq!(bus.read(KEY1_ADDR), 0x7F);
+        assert!(bus.try_speed_switch());
+        assert_eq!(bus.read(KEY1_ADDR), 0xFE);
+
+        for _ in 0..114 {
+            bus.tick();
+        }
+        assert_eq!(bus.ppu().ly(), 0);
+        for _ in 0..114 {
+            bus.tick();
+        }
+        assert_eq!(bus.ppu().ly(), 1);
+    }
+
+    #[test]
+    fn test_pending_interrupts() {
+        let mut bus = Bus::new();
+        bus.request_interrupt(Interrupt::Joypad | Interrupt::Timer);
+        bus.write(IE_ADDR, Interrupt::Timer.bits());
+        assert_eq!(bus.pending_interrupts(), Interrupt::Timer);
+        bus.acknowledge_interrupt(Interrupt::Timer);
+        assert!(bus.pending_interrupts().is_empty());
+    }
+
+    #[test]
+    fn test_seed_ram() {
+        let mut bus = Bus::new();
+        bus.seed_ram(1);
+        let first: Vec<u8> = (0xC000..0xC100).map(|addr| bus.read(addr)).collect();
+        assert!(first.iter().any(|&byte| byte != first[0]));
+
+        let mut other = Bus::new();
+        other.seed_ram(1);
+        assert!((0xC000..0xC100).all(|addr| other.read(addr) == first[addr - 0xC000]));
+        other.seed_ram(2);
+        assert!((0xC000..0xC100).any(|addr| other.read(addr) != first[addr - 0xC000]));
+    }
+
+    #[test]
+    fn test_sgb_multiplayer() {
+        let mut bus = Bus::new_sgb();
+        bus.set_player_buttons(1, ButtonState::A);
+
+        // MLT_REQ for two players, bit by bit through P1
+        let mut packet = [0x00u8; 16];
+        packet[0] = 0x89;
+        packet[1] = 0x01;
+        bus.write(P1_ADDR, 0x00);
+        bus.write(P1_ADDR, 0x30);
+        for byte in packet {
+            for bit in 0..8 {
+                bus.write(P1_ADDR, if byte & (1 << bit) != 0 { 0x10 } else { 0x20 });
+                bus.write(P1_ADDR, 0x30);
+            }
+        }
+        bus.write(P1_ADDR, 0x20);
+        bus.write(P1_ADDR, 0x30);
+        assert_eq!(bus.read(P1_ADDR), 0xFF);
+
+        bus.write(P1_ADDR, 0x10);
+        bus.write(P1_ADDR, 0x30);
+        assert_eq!(bus.read(P1_ADDR), 0xFE);
+        bus.write(P1_ADDR, 0x10);
+        assert_eq!(bus.read(P1_ADDR), 0xDE);
+        assert_eq!(bus.sgb_frame().map(|frame| frame.len()), Some(256 * 224));
+    }
+}
@@ -0,0 +1,190 @@
+// Shadow of the program's call stack, built from the calls, returns and
+// interrupt dispatches the CPU reports rather than by guessing at what's on
+// the stack. Code that moves SP around or returns through a pushed address
+// can't corrupt the backtrace; its returns are reported as mismatches.
+use crate::gb::cpu::CallEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    // call or rst
+    Call,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // The call or rst, or the instruction an interrupt came in before
+    pub call_site: u16,
+    // ROM bank mapped at the call site, None outside ROM
+    pub bank: Option<usize>,
+    // Start of the function or interrupt handler, and the bank mapped there
+    // at the time
+    pub target: u16,
+    pub target_bank: Option<usize>,
+    pub return_addr: u16,
+    // Where the return address was pushed
+    pub sp: u16,
+}
+
+// A return that didn't come back through the frame on top of the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReturnMismatch {
+    // Address of the ret or reti
+    pub at: u16,
+    pub to: u16,
+    pub expected: Frame,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    // Outermost first
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Outermost frame first
+    #[inline]
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Updates the stack for an event. `bank` gives the ROM bank mapped at an
+    // address. Returns from frames we never saw, e.g. ones made before
+    // tracking started, pass silently.
+    pub fn apply(
+        &mut self,
+        event: CallEvent,
+        bank: impl Fn(u16) -> Option<usize>,
+    ) -> Option<ReturnMismatch> {
+        match event {
+            CallEvent::Call {
+                from,
+                target,
+                return_addr,
+                sp,
+            } => {
+                self.push(Frame {
+                    kind: FrameKind::Call,
+                    call_site: from,
+                    bank: bank(from),
+                    target,
+                    target_bank: bank(target),
+                    return_addr,
+                    sp,
+                });
+                None
+            }
+            CallEvent::Interrupt { from, vector, sp } => {
+                self.push(Frame {
+                    kind: FrameKind::Interrupt,
+                    call_site: from,
+                    bank: bank(from),
+                    target: vector,
+                    target_bank: bank(vector),
+                    return_addr: from,
+                    sp,
+                });
+                None
+            }
+            CallEvent::Return { from, to, sp } => {
+                let expected = *self.frames.last()?;
+                // Frames at or below the popped slot are gone either way
+                let live = self.frames.partition_point(|frame| frame.sp > sp);
+                self.frames.truncate(live);
+                (expected.sp != sp || expected.return_addr != to).then_some(ReturnMismatch {
+                    at: from,
+                    to,
+                    expected,
+                })
+            }
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        // A push at or above an existing frame means SP was moved past it
+        // without returning, e.g. by a longjmp style stack reset
+        let live = self
+            .frames
+            .partition_point(|existing| existing.sp > frame.sp);
+        self.frames.truncate(live);
+        self.frames.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(from: u16, target: u16, sp: u16) -> CallEvent {
+        CallEvent::Call {
+            from,
+            target,
+            return_addr: from + 3,
+            sp,
+        }
+    }
+
+    fn ret(from: u16, to: u16, sp: u16) -> CallEvent {
+        CallEvent::Return { from, to, sp }
+    }
+
+    #[test]
+    fn test_calls_and_returns() {
+        let mut stack = CallStack::new();
+        let bank = |addr: u16| (addr < 0x8000).then_some(1);
+        assert_eq!(stack.apply(call(0x0150, 0x4000, 0xDFFC), bank), None);
+        assert_eq!(
+            stack.apply(
+                CallEvent::Interrupt {
+                    from: 0x4005,
+                    vector: 0x0040,
+                    sp: 0xDFFA,
+                },
+                bank,
+            ),
+            None
+        );
+        assert_eq!(stack.frames().len(), 2);
+        assert_eq!(stack.frames()[1].kind, FrameKind::Interrupt);
+        assert_eq!(stack.frames()[1].return_addr, 0x4005);
+
+        assert_eq!(stack.apply(ret(0x0045, 0x4005, 0xDFFA), bank), None);
+        assert_eq!(stack.apply(ret(0x4010, 0x0153, 0xDFFC), bank), None);
+        assert!(stack.frames().is_empty());
+        // Returning past where tracking started isn't a mismatch
+        assert_eq!(stack.apply(ret(0x0160, 0x1234, 0xDFFE), bank), None);
+    }
+
+    #[test]
+    fn test_mismatched_returns() {
+        let mut stack = CallStack::new();
+        let bank = |_| None;
+        stack.apply(call(0x0150, 0x0200, 0xDFFC), bank);
+
+        // push hl; ret jumps without leaving the function
+        let mismatch = stack.apply(ret(0x0210, 0x0300, 0xDFFA), bank).unwrap();
+        assert_eq!(mismatch.at, 0x0210);
+        assert_eq!(mismatch.expected.call_site, 0x0150);
+        assert_eq!(stack.frames().len(), 1);
+
+        // An overwritten return address
+        assert!(stack.apply(ret(0x0310, 0x0400, 0xDFFC), bank).is_some());
+        assert!(stack.frames().is_empty());
+
+        // Frames SP was moved past are dropped by the next call
+        stack.apply(call(0x0150, 0x0200, 0xDFFC), bank);
+        stack.apply(call(0x0203, 0x0300, 0xDFFA), bank);
+        stack.apply(call(0x0500, 0x0600, 0xDFFC), bank);
+        assert_eq!(stack.frames().len(), 1);
+        assert_eq!(stack.frames()[0].call_site, 0x0500);
+    }
+}
@@ -0,0 +1,487 @@
+use std::{error::Error, fmt};
+
+use crate::gb::{
+    rtc::Rtc,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+pub(crate) const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 0x200;
+
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    // Too short to hold a header
+    TooSmall(usize),
+    UnsupportedType(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(len) => {
+                write!(f, "ROM is {} bytes, too small for a cartridge header", len)
+            }
+            CartridgeError::UnsupportedType(kind) => {
+                write!(f, "unsupported cartridge type {:#04X}", kind)
+            }
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let title = rom[0x134..0x144]
+            .iter()
+            .take_while(|&&byte| byte != 0x00 && byte.is_ascii_graphic() || byte == b' ')
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        let ram_size = match rom[0x149] {
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => 0,
+        };
+
+        Ok(Self {
+            title,
+            cgb_flag: rom[0x143],
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size: (ROM_BANK_SIZE * 2) << (rom[0x148] & 0x0F),
+            ram_size,
+            header_checksum: rom[0x14D],
+        })
+    }
+
+    #[inline]
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    #[inline]
+    pub fn requires_cgb(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    #[inline]
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    pub fn mbc_kind(&self) -> Result<MbcKind, CartridgeError> {
+        match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => Ok(MbcKind::RomOnly),
+            0x01..=0x03 => Ok(MbcKind::Mbc1),
+            0x05 | 0x06 => Ok(MbcKind::Mbc2),
+            0x0F..=0x13 => Ok(MbcKind::Mbc3),
+            0x19..=0x1E => Ok(MbcKind::Mbc5),
+            kind => Err(CartridgeError::UnsupportedType(kind)),
+        }
+    }
+
+    #[inline]
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E
+        )
+    }
+
+    #[inline]
+    pub fn has_rtc(&self) -> bool {
+        matches!(self.cartridge_type, 0x0F | 0x10)
+    }
+}
+
+pub struct Cartridge {
+    header: CartridgeHeader,
+    kind: MbcKind,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    ram_enabled: bool,
+    // Raw mapper registers, interpreted per MBC when working out banks
+    bank_low: u16,
+    bank_high: u8,
+    banking_mode: bool,
+}
+
+impl Cartridge {
+    pub fn from_rom(mut rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let kind = header.mbc_kind()?;
+
+        // Pad to whole banks so bank arithmetic never runs off the end
+        let banks = rom.len().div_ceil(ROM_BANK_SIZE).next_power_of_two().max(2);
+        rom.resize(banks * ROM_BANK_SIZE, 0xFF);
+
+        let ram_size = match kind {
+            MbcKind::Mbc2 => MBC2_RAM_SIZE,
+            _ => header.ram_size,
+        };
+
+        Ok(Self {
+            rtc: header.has_rtc().then(Rtc::new),
+            header,
+            kind,
+            rom,
+            ram: vec![0x00; ram_size],
+            ram_enabled: false,
+            bank_low: 0x0001,
+            bank_high: 0x00,
+            banking_mode: false,
+        })
+    }
+
+    #[inline]
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    #[inline]
+    pub fn kind(&self) -> MbcKind {
+        self.kind
+    }
+
+    #[inline]
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // Battery backed RAM for save files
+    #[inline]
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    #[inline]
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    #[inline]
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    // ROM bank mapped at the given address, for bank-aware tooling
+    pub fn rom_bank(&self, addr: usize) -> usize {
+        let bank = match (self.kind, addr) {
+            (MbcKind::Mbc1, 0x0000..=0x3FFF) if self.banking_mode => (self.bank_high as usize) << 5,
+            (_, 0x0000..=0x3FFF) => 0,
+            (MbcKind::RomOnly, _) => 1,
+            (MbcKind::Mbc1, _) => {
+                ((self.bank_high as usize) << 5) | ((self.bank_low as usize) & 0x1F).max(1)
+            }
+            (MbcKind::Mbc2, _) => ((self.bank_low as usize) & 0x0F).max(1),
+            (MbcKind::Mbc3, _) => ((self.bank_low as usize) & 0x7F).max(1),
+            (MbcKind::Mbc5, _) => (self.bank_low as usize) & 0x1FF,
+        };
+        bank % (self.rom.len() / ROM_BANK_SIZE)
+    }
+
+    // Offset into the ROM of the byte mapped at a 0x0000-0x7FFF address
+    #[inline]
+    pub fn rom_offset(&self, addr: usize) -> usize {
+        self.rom_bank(addr) * ROM_BANK_SIZE + (addr & (ROM_BANK_SIZE - 1))
+    }
+
+    // Changes the ROM byte mapped at a 0x0000-0x7FFF address, for debuggers
+    // patching code. The mapper registers are left alone.
+    pub fn patch_rom(&mut self, addr: usize, data: u8) {
+        let offset = self.rom_offset(addr);
+        self.rom[offset] = data;
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => self.rom[self.rom_offset(addr)],
+            0xA000..=0xBFFF => self.read_ram(addr),
+            _ => unreachable!("Invalid cartridge address {:#06X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        match (self.kind, addr) {
+            (MbcKind::RomOnly, 0x0000..=0x7FFF) => {}
+            // MBC2 picks the register with address bit 8 instead of the range
+            (MbcKind::Mbc2, 0x0000..=0x3FFF) => {
+                if addr & 0x0100 == 0 {
+                    self.ram_enabled = data & 0x0F == 0x0A;
+                } else {
+                    self.bank_low = (data & 0x0F) as u16;
+                }
+            }
+            (MbcKind::Mbc2, 0x4000..=0x7FFF) => {}
+            (_, 0x0000..=0x1FFF) => self.ram_enabled = data & 0x0F == 0x0A,
+            (MbcKind::Mbc1, 0x2000..=0x3FFF) => self.bank_low = (data & 0x1F) as u16,
+            (MbcKind::Mbc3, 0x2000..=0x3FFF) => self.bank_low = (data & 0x7F) as u16,
+            (MbcKind::Mbc5, 0x2000..=0x2FFF) => {
+                self.bank_low = (self.bank_low & 0x100) | data as u16;
+            }
+            (MbcKind::Mbc5, 0x3000..=0x3FFF) => {
+                self.bank_low = (self.bank_low & 0xFF) | (((data & 0b1) as u16) << 8);
+            }
+            (MbcKind::Mbc1, 0x4000..=0x5FFF) => self.bank_high = data & 0b11,
+            (MbcKind::Mbc3, 0x4000..=0x5FFF) => self.bank_high = data & 0x0F,
+            (MbcKind::Mbc5, 0x4000..=0x5FFF) => self.bank_high = data & 0x0F,
+            (MbcKind::Mbc1, 0x6000..=0x7FFF) => self.banking_mode = data & 0b1 != 0,
+            (MbcKind::Mbc3, 0x6000..=0x7FFF) => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(data);
+                }
+            }
+            (MbcKind::Mbc5, 0x6000..=0x7FFF) => {}
+            (_, 0xA000..=0xBFFF) => self.write_ram(addr, data),
+            _ => unreachable!("Invalid cartridge address {:#06X}", addr),
+        }
+    }
+
+    #[inline]
+    pub fn tick_rtc(&mut self, t_cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(t_cycles);
+        }
+    }
+
+    fn rtc_select(&self) -> Option<u8> {
+        (self.kind == MbcKind::Mbc3 && (0x08..=0x0C).contains(&self.bank_high))
+            .then_some(self.bank_high)
+    }
+
+    fn ram_offset(&self, addr: usize) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = match self.kind {
+            MbcKind::Mbc2 => (addr - 0xA000) & (MBC2_RAM_SIZE - 1),
+            _ => {
+                let bank = match self.kind {
+                    MbcKind::Mbc1 if self.banking_mode => self.bank_high as usize,
+                    MbcKind::Mbc1 | MbcKind::RomOnly => 0,
+                    _ => self.bank_high as usize,
+                };
+                bank * RAM_BANK_SIZE + (addr - 0xA000)
+            }
+        };
+        Some(offset % self.ram.len())
+    }
+
+    fn read_ram(&self, addr: usize) -> u8 {
+        if let Some(select) = self.rtc_select() {
+            return match &self.rtc {
+                Some(rtc) if self.ram_enabled => rtc.read(select),
+                _ => 0xFF,
+            };
+        }
+        match self.ram_offset(addr) {
+            // MBC2 RAM is only four bits wide
+            Some(offset) if self.kind == MbcKind::Mbc2 => 0xF0 | self.ram[offset],
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, data: u8) {
+        if let Some(select) = self.rtc_select() {
+            if let Some(rtc) = &mut self.rtc
+                && self.ram_enabled
+            {
+                rtc.write(select, data);
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset] = if self.kind == MbcKind::Mbc2 {
+                data & 0x0F
+            } else {
+                data
+            };
+        }
+    }
+}
+
+// The ROM itself isn't saved; a state only loads back into the same game
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(self.header.title.as_bytes());
+        writer.put_u8(self.header.header_checksum);
+        writer.put_bytes(&self.ram);
+        writer.put_bool(self.ram_enabled);
+        writer.put_u16(self.bank_low);
+        writer.put_u8(self.bank_high);
+        writer.put_bool(self.banking_mode);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let title = reader.bytes()?;
+        let header_checksum = reader.u8()?;
+        if title != self.header.title.as_bytes() || header_checksum != self.header.header_checksum {
+            return Err(StateError::Mismatch("cartridge"));
+        }
+        reader.bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.bool()?;
+        self.bank_low = reader.u16()?;
+        self.bank_high = reader.u8()?;
+        self.banking_mode = reader.bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let banks = 2 << rom_size;
+        let mut rom = vec![0x00; banks * ROM_BANK_SIZE];
+        // Tag every bank with its own number
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn test_header() {
+        let mut rom = test_rom(0x13, 0x02, 0x03);
+        rom[0x143] = 0x80;
+        rom[0x146] = 0x03;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.rom_size, 0x20000);
+        assert_eq!(header.ram_size, 0x8000);
+        assert!(header.supports_cgb());
+        assert!(!header.requires_cgb());
+        assert!(header.supports_sgb());
+        assert!(header.has_battery());
+        assert_eq!(header.mbc_kind(), Ok(MbcKind::Mbc3));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Cartridge::from_rom(vec![0x00; 0x100]).err(),
+            Some(CartridgeError::TooSmall(0x100))
+        );
+        assert_eq!(
+            Cartridge::from_rom(test_rom(0xFC, 0x00, 0x00)).err(),
+            Some(CartridgeError::UnsupportedType(0xFC))
+        );
+    }
+
+    #[test]
+    fn test_mbc1_banking() {
+        let mut cart = Cartridge::from_rom(test_rom(0x03, 0x06, 0x03)).unwrap();
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x2000, 0x05);
+        cart.write(0x4000, 0x01);
+        assert_eq!(cart.read(0x4000), 0x25);
+        assert_eq!(cart.read(0x0000), 0);
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0x0000), 0x20);
+
+        // RAM is disabled until 0x0A is written
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0xFF);
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0x42);
+        assert_eq!(cart.ram()[RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn test_mbc2_ram() {
+        let mut cart = Cartridge::from_rom(test_rom(0x06, 0x02, 0x00)).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0xAB);
+        assert_eq!(cart.read(0xA200), 0xFB);
+        cart.write(0x2100, 0x03);
+        assert_eq!(cart.read(0x4000), 3);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let mut cart = Cartridge::from_rom(test_rom(0x10, 0x02, 0x03)).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.tick_rtc(crate::gb::rtc::RTC_T_CYCLES_PER_SECOND * 5);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        cart.write(0x4000, 0x08);
+        assert_eq!(cart.read(0xA000), 5);
+        cart.write(0x4000, 0x01);
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_mbc5_banking() {
+        let mut cart = Cartridge::from_rom(test_rom(0x19, 0x08, 0x00)).unwrap();
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0);
+        cart.write(0x2000, 0x02);
+        cart.write(0x3000, 0x01);
+        assert_eq!(cart.rom_bank(0x4000), 0x102);
+        assert_eq!(cart.read(0x4000), 0x02);
+    }
+
+    #[test]
+    fn test_patch_rom() {
+        let mut cart = Cartridge::from_rom(test_rom(0x01, 0x01, 0x00)).unwrap();
+        cart.write(0x2000, 0x02);
+        cart.patch_rom(0x2000, 0x3E);
+        cart.patch_rom(0x4001, 0x42);
+        assert_eq!(cart.read(0x2000), 0x3E);
+        assert_eq!(cart.rom()[ROM_BANK_SIZE * 2 + 1], 0x42);
+        assert_eq!(cart.rom_bank(0x4000), 2);
+    }
+}
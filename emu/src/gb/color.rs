@@ -0,0 +1,88 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorCorrection {
+    // Scale each channel straight to 8 bits
+    #[default]
+    None,
+    // Mimic the washed out, blended colors of the CGB LCD
+    Cgb,
+}
+
+// Channels of an RGB555 color as stored in palette RAM, red in the low bits
+#[inline]
+pub fn rgb555_channels(color: u16) -> (u8, u8, u8) {
+    (
+        (color & 0x1F) as u8,
+        ((color >> 5) & 0x1F) as u8,
+        ((color >> 10) & 0x1F) as u8,
+    )
+}
+
+pub fn rgb555_to_rgb888(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    let (r, g, b) = rgb555_channels(color);
+    match correction {
+        ColorCorrection::None => [scale_channel(r), scale_channel(g), scale_channel(b)],
+        ColorCorrection::Cgb => {
+            let (r, g, b) = (r as u32, g as u32, b as u32);
+            let corrected_r = r * 26 + g * 4 + b * 2;
+            let corrected_g = g * 24 + b * 8;
+            let corrected_b = r * 6 + g * 4 + b * 22;
+            [
+                (corrected_r.min(960) >> 2) as u8,
+                (corrected_g.min(960) >> 2) as u8,
+                (corrected_b.min(960) >> 2) as u8,
+            ]
+        }
+    }
+}
+
+// Converts a whole RGB555 frame into packed RGB888 bytes
+pub fn frame_to_rgb888(frame: &[u16], correction: ColorCorrection) -> Vec<u8> {
+    frame
+        .iter()
+        .flat_map(|color| rgb555_to_rgb888(*color, correction))
+        .collect()
+}
+
+#[inline]
+fn scale_channel(channel: u8) -> u8 {
+    (channel << 3) | (channel >> 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_correction() {
+        assert_eq!(
+            rgb555_to_rgb888(0x7FFF, ColorCorrection::None),
+            [0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            rgb555_to_rgb888(0x001F, ColorCorrection::None),
+            [0xFF, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_cgb_correction() {
+        assert_eq!(
+            rgb555_to_rgb888(0x7FFF, ColorCorrection::Cgb),
+            [0xF0, 0xF0, 0xF0]
+        );
+        // Pure red bleeds into blue on the real LCD
+        assert_eq!(
+            rgb555_to_rgb888(0x001F, ColorCorrection::Cgb),
+            [0xC9, 0x00, 0x2E]
+        );
+    }
+
+    #[test]
+    fn test_frame_to_rgb888() {
+        let frame = [0x0000, 0x7FFF];
+        assert_eq!(
+            frame_to_rgb888(&frame, ColorCorrection::None),
+            vec![0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF]
+        );
+    }
+}
@@ -116,6 +116,37 @@ pub fn bitwise_not(num: u8) -> AluResult {
     AluResult { res, info }
 }
 
+pub fn shift_left_arithmetic(num: u8) -> AluResult {
+    let res = num << 1;
+    let mut info = AluResultInfo::empty();
+    info.set(AluResultInfo::Zero, res == 0);
+    info.set(AluResultInfo::Carry, (num >> 7) & 0b1 == 1);
+    AluResult { res, info }
+}
+
+pub fn shift_right_arithmetic(num: u8) -> AluResult {
+    let res = (num >> 1) | (num & 0x80);
+    let mut info = AluResultInfo::empty();
+    info.set(AluResultInfo::Zero, res == 0);
+    info.set(AluResultInfo::Carry, (num & 0b1) == 1);
+    AluResult { res, info }
+}
+
+pub fn shift_right_logical(num: u8) -> AluResult {
+    let res = num >> 1;
+    let mut info = AluResultInfo::empty();
+    info.set(AluResultInfo::Zero, res == 0);
+    info.set(AluResultInfo::Carry, (num & 0b1) == 1);
+    AluResult { res, info }
+}
+
+pub fn swap_nibbles(num: u8) -> AluResult {
+    let res = num.rotate_left(4);
+    let mut info = AluResultInfo::empty();
+    info.set(AluResultInfo::Zero, res == 0);
+    AluResult { res, info }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -425,4 +456,96 @@ mod tests {
         assert!(out.info.contains(AluResultInfo::HalfCarry));
         assert!(out.info.contains(AluResultInfo::Subtraction));
     }
+
+    #[test]
+    fn test_shift_left_arithmetic() {
+        // 01000001
+        // --------
+        // 10000010 with carry = 0
+
+        let out: AluResult = shift_left_arithmetic(0b01000001);
+        assert_eq!(out.res, 0b10000010);
+        assert!(!out.info.contains(AluResultInfo::Carry));
+        assert!(!out.info.contains(AluResultInfo::Zero));
+        assert!(!out.info.contains(AluResultInfo::HalfCarry));
+        assert!(!out.info.contains(AluResultInfo::Subtraction));
+    }
+
+    #[test]
+    fn test_shift_left_arithmetic_zero() {
+        // 10000000
+        // --------
+        // 00000000 with carry = 1
+
+        let out: AluResult = shift_left_arithmetic(0b10000000);
+        assert_eq!(out.res, 0x00);
+        assert!(out.info.contains(AluResultInfo::Carry));
+        assert!(out.info.contains(AluResultInfo::Zero));
+    }
+
+    #[test]
+    fn test_shift_right_arithmetic() {
+        // 10000001
+        // --------
+        // 11000000 with carry = 1
+
+        let out: AluResult = shift_right_arithmetic(0b10000001);
+        assert_eq!(out.res, 0b11000000);
+        assert!(out.info.contains(AluResultInfo::Carry));
+        assert!(!out.info.contains(AluResultInfo::Zero));
+    }
+
+    #[test]
+    fn test_shift_right_arithmetic_positive() {
+        // 01111110
+        // --------
+        // 00111111 with carry = 0
+
+        let out: AluResult = shift_right_arithmetic(0b01111110);
+        assert_eq!(out.res, 0b00111111);
+        assert!(!out.info.contains(AluResultInfo::Carry));
+        assert!(!out.info.contains(AluResultInfo::Zero));
+    }
+
+    #[test]
+    fn test_shift_right_logical() {
+        // 10000010
+        // --------
+        // 01000001 with carry = 0
+
+        let out: AluResult = shift_right_logical(0b10000010);
+        assert_eq!(out.res, 0b01000001);
+        assert!(!out.info.contains(AluResultInfo::Carry));
+        assert!(!out.info.contains(AluResultInfo::Zero));
+        assert!(!out.info.contains(AluResultInfo::HalfCarry));
+        assert!(!out.info.contains(AluResultInfo::Subtraction));
+    }
+
+    #[test]
+    fn test_shift_right_logical_zero() {
+        // 00000001
+        // --------
+        // 00000000 with carry = 1
+
+        let out: AluResult = shift_right_logical(0b00000001);
+        assert_eq!(out.res, 0x00);
+        assert!(out.info.contains(AluResultInfo::Carry));
+        assert!(out.info.contains(AluResultInfo::Zero));
+    }
+
+    #[test]
+    fn test_swap_nibbles() {
+        let out: AluResult = swap_nibbles(0xF1);
+        assert_eq!(out.res, 0x1F);
+        assert!(!out.info.contains(AluResultInfo::Carry));
+        assert!(!out.info.contains(AluResultInfo::Zero));
+    }
+
+    #[test]
+    fn test_swap_nibbles_zero() {
+        let out: AluResult = swap_nibbles(0x00);
+        assert_eq!(out.res, 0x00);
+        assert!(out.info.contains(AluResultInfo::Zero));
+        assert!(!out.info.contains(AluResultInfo::Carry));
+    }
 }
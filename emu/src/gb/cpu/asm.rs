@@ -0,0 +1,1118 @@
+// Two-pass assembler for RGBDS style source, accepting everything disasm
+// prints. The first pass sizes each line and collects labels, the second
+// evaluates operands and encodes.
+use std::{collections::HashMap, error, fmt, ops::RangeInclusive};
+
+const ALU_MNEMONICS: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROTATE_MNEMONICS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACCUMULATOR_MNEMONICS: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const R8_NAMES: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+
+// Register operand codes, as they appear in the opcode bits
+const R8_C: u8 = 1;
+const R8_HL_MEM: u8 = 6;
+const R8_A: u8 = 7;
+const COND_C: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    // 1-based source line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+// Assembles `source` as if loaded at `origin`. SECTION lines with a fixed
+// address move the location counter, but the output is always the bytes of
+// every line back to back.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    Assembler::new(origin).assemble(source)
+}
+
+// Assembles a list of string literals, one line each, starting at address
+// zero or the given origin. Only the lines are joined by concat!; the
+// assembling happens at runtime through assemble, so the macro can't be used
+// for a const or static. Any error panics with its line, which is what tests
+// want.
+//
+// gb_asm!(origin = 0xC000; "Loop:", "dec a", "jr nz, Loop")
+#[macro_export]
+macro_rules! gb_asm {
+    (origin = $origin:expr; $($line:literal),* $(,)?) => {
+        $crate::gb::cpu::asm::assemble(concat!($($line, "\n"),*), $origin)
+            .unwrap_or_else(|err| panic!("{}", err))
+    };
+    ($($line:literal),* $(,)?) => {
+        $crate::gb_asm!(origin = 0x0000; $($line),*)
+    };
+}
+
+pub struct Assembler {
+    origin: u16,
+    // Symbols known before the first line, e.g. from a debugger's symbol file
+    predefined: HashMap<String, i64>,
+}
+
+impl Assembler {
+    pub fn new(origin: u16) -> Self {
+        Self {
+            origin,
+            predefined: HashMap::new(),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.predefined.insert(name.to_string(), value);
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, AsmError> {
+        let mut symbols = self.predefined.clone();
+        let mut lines = Vec::new();
+        let mut scope = String::new();
+        let mut addr = self.origin;
+
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let error = |message: String| AsmError { line, message };
+            let Some(statement) =
+                parse_line(text, &mut scope, addr, &mut symbols).map_err(error)?
+            else {
+                continue;
+            };
+            if let Statement::Section(section_addr) = statement {
+                addr = section_addr;
+                continue;
+            }
+            let context = Context {
+                symbols: &|name| symbols.get(name).copied(),
+                addr,
+                final_pass: false,
+            };
+            let size = context.encode(&statement).map_err(error)?.len();
+            lines.push((line, addr, statement));
+            addr = addr.wrapping_add(size as u16);
+        }
+
+        let mut bytes = Vec::new();
+        for (line, addr, statement) in &lines {
+            let context = Context {
+                symbols: &|name| symbols.get(name).copied(),
+                addr: *addr,
+                final_pass: true,
+            };
+            let encoded = context.encode(statement).map_err(|message| AsmError {
+                line: *line,
+                message,
+            })?;
+            bytes.extend_from_slice(&encoded);
+        }
+        Ok(bytes)
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new(0x0000)
+    }
+}
+
+// A lone expression outside of any source, such as a breakpoint condition.
+// Errors are reported on line 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    text: String,
+    expr: Expr,
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Self, AsmError> {
+        let expr = parse_expr(text, "").map_err(|message| AsmError { line: 1, message })?;
+        Ok(Self {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    // The source the expression was parsed from
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // Names of the symbols the expression refers to, in order
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.expr.collect_symbols(&mut names);
+        names
+    }
+
+    // Every symbol is looked up through `symbols`; @ reads as 0
+    pub fn eval(&self, symbols: impl Fn(&str) -> Option<i64>) -> Result<i64, AsmError> {
+        Context {
+            symbols: &symbols,
+            addr: 0,
+            final_pass: true,
+        }
+        .eval(&self.expr)
+        .map_err(|message| AsmError { line: 1, message })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    LogicalOr,
+    LogicalAnd,
+    Or,
+    Xor,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    // @, the address of the current line
+    Here,
+    Negate(Box<Expr>),
+    Complement(Box<Expr>),
+    Not(Box<Expr>),
+    High(Box<Expr>),
+    Low(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn collect_symbols<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) | Expr::Here => {}
+            Expr::Symbol(name) => names.push(name),
+            Expr::Negate(expr)
+            | Expr::Complement(expr)
+            | Expr::Not(expr)
+            | Expr::High(expr)
+            | Expr::Low(expr) => expr.collect_symbols(names),
+            Expr::Binary(_, left, right) => {
+                left.collect_symbols(names);
+                right.collect_symbols(names);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    // b, c, d, e, h, l, [hl], a by opcode code
+    R8(u8),
+    // bc, de, hl, sp, af; af is 4 so it never passes for an r16
+    R16(u8),
+    // [bc], [de], [hl+], [hl-] by opcode code
+    R16Mem(u8),
+    // [c] and [$FF00+c]
+    HighC,
+    // nz, z, nc; c is parsed as the register and converted by jumps
+    Cond(u8),
+    // sp+e8 in ld hl, sp+e8
+    SpOffset(Expr),
+    Mem(Expr),
+    Imm(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DataItem {
+    Expr(Expr),
+    String(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Statement {
+    Instruction(String, Vec<Operand>),
+    Bytes(Vec<DataItem>),
+    Words(Vec<Expr>),
+    Space(usize, Option<Expr>),
+    Section(u16),
+}
+
+// Parses one line, defining any label or constant on it. Returns None for
+// lines with nothing to emit.
+fn parse_line(
+    text: &str,
+    scope: &mut String,
+    addr: u16,
+    symbols: &mut HashMap<String, i64>,
+) -> Result<Option<Statement>, String> {
+    let mut rest = strip_comment(text).trim();
+
+    // Label: or Label:: or .local:
+    let word_end = rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+    if let Some(label) = rest[..word_end].strip_suffix(':') {
+        let label = label.strip_suffix(':').unwrap_or(label);
+        if !is_identifier(label) {
+            return Err(format!("invalid label '{}'", label));
+        }
+        let name = qualify(label, scope)?;
+        if !label.starts_with('.') {
+            *scope = name.clone();
+        }
+        define(symbols, name, addr as i64)?;
+        rest = rest[word_end..].trim_start();
+    }
+    if rest.is_empty() {
+        return Ok(None);
+    }
+
+    let (mnemonic, operands) = match rest.find(|c: char| c.is_whitespace()) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, ""),
+    };
+    let mut mnemonic = mnemonic.to_ascii_lowercase();
+
+    // NAME EQU expr, optionally with RGBDS's DEF in front
+    let mut operands = operands;
+    if mnemonic == "def"
+        && let Some(end) = operands.find(|c: char| c.is_whitespace())
+    {
+        mnemonic = operands[..end].to_string();
+        operands = operands[end..].trim();
+    }
+    if let Some(value) = strip_keyword(operands, "equ") {
+        if !is_identifier(&mnemonic) || mnemonic.starts_with('.') {
+            return Err(format!("invalid constant name '{}'", mnemonic));
+        }
+        // Take the name as written, not lowercased
+        let name = rest[..rest.len() - operands.len()]
+            .split_whitespace()
+            .last()
+            .unwrap()
+            .to_string();
+        let expr = parse_expr(value, scope)?;
+        let value = Context {
+            symbols: &|name| symbols.get(name).copied(),
+            addr,
+            final_pass: true,
+        }
+        .eval(&expr)?;
+        define(symbols, name, value)?;
+        return Ok(None);
+    }
+
+    let operands = split_operands(operands)?;
+    let statement = match mnemonic.as_str() {
+        "db" => Statement::Bytes(
+            operands
+                .iter()
+                .map(|operand| match parse_string(operand)? {
+                    Some(string) => Ok(DataItem::String(string)),
+                    None => Ok(DataItem::Expr(parse_expr(operand, scope)?)),
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+        "dw" => Statement::Words(
+            operands
+                .iter()
+                .map(|operand| parse_expr(operand, scope))
+                .collect::<Result<_, _>>()?,
+        ),
+        "ds" => {
+            let (count, fill) = match operands.as_slice() {
+                [count] => (count, None),
+                [count, fill] => (count, Some(parse_expr(fill, scope)?)),
+                _ => return Err("ds takes a count and an optional fill byte".to_string()),
+            };
+            // The count decides where later labels land, so it can't be a forward reference
+            let count = Context {
+                symbols: &|name| symbols.get(name).copied(),
+                addr,
+                final_pass: true,
+            }
+            .eval(&parse_expr(count, scope)?)?;
+            if !(0..=0x10000).contains(&count) {
+                return Err(format!("ds count {} out of range", count));
+            }
+            Statement::Space(count as usize, fill)
+        }
+        "section" => Statement::Section(parse_section(&operands, scope, symbols)?),
+        _ => Statement::Instruction(
+            mnemonic,
+            operands
+                .iter()
+                .map(|operand| parse_operand(operand, scope))
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+    Ok(Some(statement))
+}
+
+fn define(symbols: &mut HashMap<String, i64>, name: String, value: i64) -> Result<(), String> {
+    if symbols.contains_key(&name) {
+        return Err(format!("'{}' is already defined", name));
+    }
+    symbols.insert(name, value);
+    Ok(())
+}
+
+// Local labels are stored under their full Global.local name
+fn qualify(name: &str, scope: &str) -> Result<String, String> {
+    if !name.starts_with('.') {
+        return Ok(name.to_string());
+    }
+    if scope.is_empty() {
+        return Err(format!("local label '{}' outside of a global label", name));
+    }
+    Ok(format!("{}{}", scope, name))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '#' | '@'))
+}
+
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let end = text.find(|c: char| c.is_whitespace()).unwrap_or(text.len());
+    text[..end]
+        .eq_ignore_ascii_case(keyword)
+        .then(|| text[end..].trim())
+}
+
+// Drops a ; comment, leaving semicolons inside string and character literals
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &text[..i],
+            None => {}
+        }
+    }
+    text
+}
+
+// Splits on commas outside brackets, parentheses and literals
+fn split_operands(text: &str) -> Result<Vec<&str>, String> {
+    let mut operands = Vec::new();
+    if text.is_empty() {
+        return Ok(operands);
+    }
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    operands.push(text[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            },
+        }
+    }
+    operands.push(text[start..].trim());
+    if operands.iter().any(|operand| operand.is_empty()) {
+        return Err("empty operand".to_string());
+    }
+    Ok(operands)
+}
+
+fn parse_string(text: &str) -> Result<Option<Vec<u8>>, String> {
+    let Some(inner) = text.strip_prefix('"') else {
+        return Ok(None);
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| format!("unterminated string {}", text))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            unescape(chars.next())?
+        } else {
+            c
+        };
+        if !c.is_ascii() {
+            return Err(format!("non-ASCII character '{}' in string", c));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(Some(bytes))
+}
+
+fn unescape(c: Option<char>) -> Result<char, String> {
+    match c {
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some(c @ ('\\' | '"' | '\'')) => Ok(c),
+        Some(c) => Err(format!("unknown escape '\\{}'", c)),
+        None => Err("escape at end of literal".to_string()),
+    }
+}
+
+// SECTION "name", TYPE[$addr] with an optional BANK[n], which only matters to a linker
+fn parse_section(
+    operands: &[&str],
+    scope: &str,
+    symbols: &HashMap<String, i64>,
+) -> Result<u16, String> {
+    let [name, kind, ..] = operands else {
+        return Err("section needs a name and a type".to_string());
+    };
+    if parse_string(name)?.is_none() {
+        return Err("section name must be a string".to_string());
+    }
+    let addr = kind
+        .find('[')
+        .and_then(|open| kind.strip_suffix(']').map(|inner| &inner[open + 1..]))
+        .ok_or_else(|| format!("section '{}' needs a fixed address", name))?;
+    let addr = Context {
+        symbols: &|name| symbols.get(name).copied(),
+        addr: 0,
+        final_pass: true,
+    }
+    .eval(&parse_expr(addr, scope)?)?;
+    u16::try_from(addr).map_err(|_| format!("section address {} out of range", addr))
+}
+
+fn parse_operand(text: &str, scope: &str) -> Result<Operand, String> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(code) = R8_NAMES.iter().position(|&name| name == lower) {
+        return Ok(Operand::R8(code as u8));
+    }
+    if let Some(code) = ["bc", "de", "hl", "sp", "af"]
+        .iter()
+        .position(|&name| name == lower)
+    {
+        return Ok(Operand::R16(code as u8));
+    }
+    if let Some(code) = ["nz", "z", "nc"].iter().position(|&name| name == lower) {
+        return Ok(Operand::Cond(code as u8));
+    }
+    if let Some(offset) = lower.strip_prefix("sp")
+        && let offset = offset.trim_start()
+        && (offset.starts_with('+') || offset.starts_with('-'))
+    {
+        return Ok(Operand::SpOffset(parse_expr(
+            &text[text.len() - offset.len()..],
+            scope,
+        )?));
+    }
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = inner
+            .strip_suffix(']')
+            .ok_or_else(|| format!("missing ] in {}", text))?;
+        let compact: String = inner
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        return Ok(match compact.as_str() {
+            "hl" => Operand::R8(R8_HL_MEM),
+            "bc" => Operand::R16Mem(0),
+            "de" => Operand::R16Mem(1),
+            "hl+" | "hli" => Operand::R16Mem(2),
+            "hl-" | "hld" => Operand::R16Mem(3),
+            "c" | "$ff00+c" | "0xff00+c" => Operand::HighC,
+            _ => Operand::Mem(parse_expr(inner, scope)?),
+        });
+    }
+    Ok(Operand::Imm(parse_expr(text, scope)?))
+}
+
+fn parse_expr(text: &str, scope: &str) -> Result<Expr, String> {
+    let mut parser = ExprParser {
+        text,
+        pos: 0,
+        scope,
+    };
+    let expr = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.pos < text.len() {
+        return Err(format!("unexpected '{}' in {}", &text[parser.pos..], text));
+    }
+    Ok(expr)
+}
+
+// Binary operators from loosest to tightest, as in C
+const PRECEDENCE: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::LogicalOr)],
+    &[("&&", BinaryOp::LogicalAnd)],
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("==", BinaryOp::Equal), ("!=", BinaryOp::NotEqual)],
+    &[
+        ("<=", BinaryOp::LessEqual),
+        (">=", BinaryOp::GreaterEqual),
+        ("<", BinaryOp::Less),
+        (">", BinaryOp::Greater),
+    ],
+    &[("<<", BinaryOp::ShiftLeft), (">>", BinaryOp::ShiftRight)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+    &[
+        ("*", BinaryOp::Multiply),
+        ("/", BinaryOp::Divide),
+        ("%", BinaryOp::Modulo),
+    ],
+];
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+    scope: &'a str,
+}
+
+impl<'a> ExprParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.pos = self.text.len() - self.rest().trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        // < isn't the start of << or <=, nor & of &&
+        let longer = |next: char| {
+            token.len() == 1 && "<>&|!".contains(token) && (token.starts_with(next) || next == '=')
+        };
+        if self.rest().starts_with(token) && !self.rest()[token.len()..].starts_with(longer) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'operators: loop {
+            for &(token, op) in PRECEDENCE[level] {
+                if self.eat(token) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        if self.eat("~") {
+            return Ok(Expr::Complement(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            if !self.eat(")") {
+                return Err(format!("missing ) in {}", self.text));
+            }
+            return Ok(expr);
+        }
+
+        let (radix, prefix) = if rest.starts_with('$') {
+            (16, 1)
+        } else if rest.starts_with("0x") || rest.starts_with("0X") {
+            (16, 2)
+        } else if rest.starts_with('%') {
+            (2, 1)
+        } else if rest.starts_with("0b") || rest.starts_with("0B") {
+            (2, 2)
+        } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            (10, 0)
+        } else {
+            (0, 0)
+        };
+        if radix != 0 {
+            let digits: String = rest[prefix..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            self.pos += prefix + digits.len();
+            let value = i64::from_str_radix(&digits.replace('_', ""), radix)
+                .map_err(|_| format!("invalid number {}", &rest[..prefix + digits.len()]))?;
+            return Ok(Expr::Number(value));
+        }
+
+        if let Some(literal) = rest.strip_prefix('\'') {
+            let mut chars = literal.chars();
+            let c = match chars.next() {
+                Some('\\') => unescape(chars.next())?,
+                Some(c) => c,
+                None => return Err("unterminated character".to_string()),
+            };
+            if chars.next() != Some('\'') || !c.is_ascii() {
+                return Err(format!("invalid character literal in {}", self.text));
+            }
+            self.pos = self.text.len() - chars.as_str().len();
+            return Ok(Expr::Number(c as i64));
+        }
+
+        if rest.starts_with('@') {
+            self.pos += 1;
+            return Ok(Expr::Here);
+        }
+
+        let name: String = rest
+            .chars()
+            .take_while(|&c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '#' | '@'))
+            .collect();
+        if !is_identifier(&name) {
+            return Err(format!("expected a value in {}", self.text));
+        }
+        self.pos += name.len();
+        let lower = name.to_ascii_lowercase();
+        if (lower == "high" || lower == "low") && self.eat("(") {
+            let expr = Box::new(self.binary(0)?);
+            if !self.eat(")") {
+                return Err(format!("missing ) in {}", self.text));
+            }
+            return Ok(if lower == "high" {
+                Expr::High(expr)
+            } else {
+                Expr::Low(expr)
+            });
+        }
+        Ok(Expr::Symbol(qualify(&name, self.scope)?))
+    }
+}
+
+struct Context<'a> {
+    symbols: &'a dyn Fn(&str) -> Option<i64>,
+    addr: u16,
+    // Before labels are all known, unknown symbols read as 0 and ranges aren't checked
+    final_pass: bool,
+}
+
+impl Context<'_> {
+    fn eval(&self, expr: &Expr) -> Result<i64, String> {
+        let value = |expr: &Expr| self.eval(expr);
+        Ok(match expr {
+            Expr::Number(value) => *value,
+            Expr::Symbol(name) => match (self.symbols)(name) {
+                Some(value) => value,
+                None if self.final_pass => return Err(format!("unknown symbol '{}'", name)),
+                None => 0,
+            },
+            Expr::Here => self.addr as i64,
+            Expr::Negate(expr) => value(expr)?.wrapping_neg(),
+            Expr::Complement(expr) => !value(expr)?,
+            Expr::Not(expr) => (value(expr)? == 0) as i64,
+            Expr::High(expr) => (value(expr)? >> 8) & 0xFF,
+            Expr::Low(expr) => value(expr)? & 0xFF,
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (value(lhs)?, value(rhs)?);
+                match op {
+                    BinaryOp::LogicalOr => (lhs != 0 || rhs != 0) as i64,
+                    BinaryOp::LogicalAnd => (lhs != 0 && rhs != 0) as i64,
+                    BinaryOp::Or => lhs | rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Equal => (lhs == rhs) as i64,
+                    BinaryOp::NotEqual => (lhs != rhs) as i64,
+                    BinaryOp::Less => (lhs < rhs) as i64,
+                    BinaryOp::LessEqual => (lhs <= rhs) as i64,
+                    BinaryOp::Greater => (lhs > rhs) as i64,
+                    BinaryOp::GreaterEqual => (lhs >= rhs) as i64,
+                    BinaryOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Subtract => lhs.wrapping_sub(rhs),
+                    BinaryOp::Multiply => lhs.wrapping_mul(rhs),
+                    BinaryOp::Divide | BinaryOp::Modulo if rhs == 0 => {
+                        if self.final_pass {
+                            return Err("division by zero".to_string());
+                        }
+                        0
+                    }
+                    BinaryOp::Divide => lhs.wrapping_div(rhs),
+                    BinaryOp::Modulo => lhs.wrapping_rem(rhs),
+                }
+            }
+        })
+    }
+
+    fn ranged(&self, expr: &Expr, range: RangeInclusive<i64>) -> Result<i64, String> {
+        let value = self.eval(expr)?;
+        if self.final_pass && !range.contains(&value) {
+            return Err(format!("value {} out of range", value));
+        }
+        Ok(value)
+    }
+
+    fn imm8(&self, expr: &Expr) -> Result<u8, String> {
+        Ok(self.ranged(expr, -0x80..=0xFF)? as u8)
+    }
+
+    fn imm16(&self, expr: &Expr) -> Result<[u8; 2], String> {
+        Ok((self.ranged(expr, -0x8000..=0xFFFF)? as u16).to_le_bytes())
+    }
+
+    fn signed8(&self, expr: &Expr) -> Result<u8, String> {
+        Ok(self.ranged(expr, -0x80..=0x7F)? as u8)
+    }
+
+    // jr operands are absolute targets, stored relative to the next instruction
+    fn relative(&self, expr: &Expr) -> Result<u8, String> {
+        let target = self.eval(expr)?;
+        let offset = target - (self.addr as i64 + 2);
+        if self.final_pass && !(-0x80..=0x7F).contains(&offset) {
+            return Err(format!("jr target ${:04X} out of reach", target));
+        }
+        Ok(offset as u8)
+    }
+
+    // ldh takes either the full $FFxx address or just its low byte
+    fn high_page(&self, expr: &Expr) -> Result<u8, String> {
+        let value = self.eval(expr)?;
+        if self.final_pass && !(0x00..=0xFF).contains(&value) && !(0xFF00..=0xFFFF).contains(&value)
+        {
+            return Err(format!("ldh address ${:04X} outside $FF00-$FFFF", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, String> {
+        match statement {
+            Statement::Instruction(mnemonic, operands) => {
+                self.encode_instruction(mnemonic, operands)
+            }
+            Statement::Bytes(items) => {
+                let mut bytes = Vec::new();
+                for item in items {
+                    match item {
+                        DataItem::Expr(expr) => bytes.push(self.imm8(expr)?),
+                        DataItem::String(string) => bytes.extend_from_slice(string),
+                    }
+                }
+                Ok(bytes)
+            }
+            Statement::Words(exprs) => Ok(exprs
+                .iter()
+                .map(|expr| self.imm16(expr))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat()),
+            Statement::Space(count, fill) => {
+                let fill = match fill {
+                    Some(fill) => self.imm8(fill)?,
+                    None => 0x00,
+                };
+                Ok(vec![fill; *count])
+            }
+            Statement::Section(_) => Ok(Vec::new()),
+        }
+    }
+
+    fn encode_instruction(&self, mnemonic: &str, operands: &[Operand]) -> Result<Vec<u8>, String> {
+        use Operand::*;
+
+        // ldi/ldd are ld with [hl+]/[hl-]
+        let alias;
+        let (mnemonic, operands) = match (mnemonic, operands) {
+            ("ldi" | "ldd", [R8(R8_HL_MEM), R8(R8_A)] | [R8(R8_A), R8(R8_HL_MEM)]) => {
+                let hl = R16Mem(if mnemonic == "ldi" { 2 } else { 3 });
+                alias = if operands[0] == R8(R8_A) {
+                    [R8(R8_A), hl]
+                } else {
+                    [hl, R8(R8_A)]
+                };
+                ("ld", &alias[..])
+            }
+            _ => (mnemonic, operands),
+        };
+
+        // Conditions for jumps, calls and returns, where c means carry
+        let cond = |operand: &Operand| match operand {
+            Cond(cond) => Some(*cond),
+            R8(R8_C) => Some(COND_C),
+            _ => None,
+        };
+        let invalid = || format!("invalid operands for {}", mnemonic);
+
+        if let Some(opcode) = ACCUMULATOR_MNEMONICS.iter().position(|&m| m == mnemonic) {
+            return match operands {
+                [] => Ok(vec![0x07 | (opcode as u8) << 3]),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some(op) = ALU_MNEMONICS.iter().position(|&m| m == mnemonic) {
+            let op = op as u8;
+            // The a, is optional
+            let source = match operands {
+                [R8(R8_A), source] | [source] => Some(source),
+                _ => None,
+            };
+            return match (mnemonic, operands, source) {
+                ("add", [R16(2), R16(pair)], _) if *pair < 4 => Ok(vec![0x09 | pair << 4]),
+                ("add", [R16(3), Imm(offset)], _) => Ok(vec![0xE8, self.signed8(offset)?]),
+                (_, _, Some(R8(register))) => Ok(vec![0x80 | op << 3 | register]),
+                (_, _, Some(Imm(value))) => Ok(vec![0xC6 | op << 3, self.imm8(value)?]),
+                _ => Err(invalid()),
+            };
+        }
+        if let Some(op) = ROTATE_MNEMONICS.iter().position(|&m| m == mnemonic) {
+            return match operands {
+                [R8(register)] => Ok(vec![0xCB, (op as u8) << 3 | register]),
+                _ => Err(invalid()),
+            };
+        }
+
+        let bytes = match (mnemonic, operands) {
+            ("nop", []) => vec![0x00],
+            ("stop", []) => vec![0x10, 0x00],
+            ("halt", []) => vec![0x76],
+            ("di", []) => vec![0xF3],
+            ("ei", []) => vec![0xFB],
+            ("reti", []) => vec![0xD9],
+            ("ret", []) => vec![0xC9],
+            ("ret", [condition]) => vec![0xC0 | cond(condition).ok_or_else(invalid)? << 3],
+
+            ("jp", [R16(2)]) => vec![0xE9],
+            ("jp", [Imm(target)]) => [&[0xC3][..], &self.imm16(target)?].concat(),
+            ("jp", [condition, Imm(target)]) => [
+                &[0xC2 | cond(condition).ok_or_else(invalid)? << 3][..],
+                &self.imm16(target)?,
+            ]
+            .concat(),
+            ("jr", [Imm(target)]) => vec![0x18, self.relative(target)?],
+            ("jr", [condition, Imm(target)]) => vec![
+                0x20 | cond(condition).ok_or_else(invalid)? << 3,
+                self.relative(target)?,
+            ],
+            ("call", [Imm(target)]) => [&[0xCD][..], &self.imm16(target)?].concat(),
+            ("call", [condition, Imm(target)]) => [
+                &[0xC4 | cond(condition).ok_or_else(invalid)? << 3][..],
+                &self.imm16(target)?,
+            ]
+            .concat(),
+            ("rst", [Imm(vector)]) => {
+                let vector = self.eval(vector)?;
+                if self.final_pass && vector & !0x38 != 0 {
+                    return Err(format!("invalid rst vector ${:02X}", vector));
+                }
+                vec![0xC7 | (vector as u8 & 0x38)]
+            }
+
+            // af takes sp's slot in push and pop
+            ("push", [R16(pair)]) if *pair != 3 => vec![0xC5 | (*pair).min(3) << 4],
+            ("pop", [R16(pair)]) if *pair != 3 => vec![0xC1 | (*pair).min(3) << 4],
+            ("inc", [R8(register)]) => vec![0x04 | register << 3],
+            ("dec", [R8(register)]) => vec![0x05 | register << 3],
+            ("inc", [R16(pair)]) if *pair < 4 => vec![0x03 | pair << 4],
+            ("dec", [R16(pair)]) if *pair < 4 => vec![0x0B | pair << 4],
+
+            ("bit" | "res" | "set", [Imm(bit), R8(register)]) => {
+                let bit = self.eval(bit)?;
+                if self.final_pass && !(0..=7).contains(&bit) {
+                    return Err(format!("bit number {} out of range", bit));
+                }
+                let op = match mnemonic {
+                    "bit" => 0x40,
+                    "res" => 0x80,
+                    _ => 0xC0,
+                };
+                vec![0xCB, op | (bit as u8 & 7) << 3 | register]
+            }
+
+            ("ld", [R8(R8_HL_MEM), R8(R8_HL_MEM)]) => return Err(invalid()),
+            ("ld", [R8(target), R8(source)]) => vec![0x40 | target << 3 | source],
+            ("ld", [R8(target), Imm(value)]) => vec![0x06 | target << 3, self.imm8(value)?],
+            ("ld", [R16(pair), Imm(value)]) if *pair < 4 => {
+                [&[0x01 | pair << 4][..], &self.imm16(value)?].concat()
+            }
+            ("ld", [R16Mem(pair), R8(R8_A)]) => vec![0x02 | pair << 4],
+            ("ld", [R8(R8_A), R16Mem(pair)]) => vec![0x0A | pair << 4],
+            ("ld", [Mem(addr), R8(R8_A)]) => [&[0xEA][..], &self.imm16(addr)?].concat(),
+            ("ld", [R8(R8_A), Mem(addr)]) => [&[0xFA][..], &self.imm16(addr)?].concat(),
+            ("ld", [Mem(addr), R16(3)]) => [&[0x08][..], &self.imm16(addr)?].concat(),
+            ("ld" | "ldh", [HighC, R8(R8_A)]) => vec![0xE2],
+            ("ld" | "ldh", [R8(R8_A), HighC]) => vec![0xF2],
+            ("ldh", [Mem(addr), R8(R8_A)]) => vec![0xE0, self.high_page(addr)?],
+            ("ldh", [R8(R8_A), Mem(addr)]) => vec![0xF0, self.high_page(addr)?],
+            ("ld", [R16(3), R16(2)]) => vec![0xF9],
+            ("ld", [R16(2), SpOffset(offset)]) => vec![0xF8, self.signed8(offset)?],
+
+            (
+                "nop" | "stop" | "halt" | "di" | "ei" | "reti" | "ret" | "jp" | "jr" | "call"
+                | "rst" | "push" | "pop" | "inc" | "dec" | "bit" | "res" | "set" | "ld" | "ldh"
+                | "ldi" | "ldd",
+                _,
+            ) => return Err(invalid()),
+            _ => return Err(format!("unknown instruction '{}'", mnemonic)),
+        };
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::cpu::disasm::{disassemble, disassemble_all};
+
+    #[test]
+    fn test_instructions() {
+        let bytes = assemble(
+            "ld a, $42\n\
+             ld [$C000], a\n\
+             ld [hl+], a\n\
+             ld a, [hld]\n\
+             ldh [$FF44], a\n\
+             ldh a, [$44]\n\
+             ld [$FF00+c], a\n\
+             add sp, -2\n\
+             ld hl, sp+5\n\
+             sub a, [hl]\n\
+             cp 10\n\
+             bit 7, [hl]\n\
+             swap a\n\
+             push af\n\
+             rst $38\n\
+             stop",
+            0x0000,
+        )
+        .unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x22, 0x3A, 0xE0, 0x44, 0xF0, 0x44, 0xE2, 0xE8, 0xFE,
+                0xF8, 0x05, 0x96, 0xFE, 0x0A, 0xCB, 0x7E, 0xCB, 0x37, 0xF5, 0xFF, 0x10, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_expressions() {
+        let bytes = crate::gb_asm!(
+            origin = 0xC000;
+            "SCREEN EQU $9800",
+            "Main:",
+            "    ld hl, SCREEN + 32 * 2",
+            ".loop: dec a ; comment",
+            "    jr nz, .loop",
+            "    jp Data",
+            "Data:: db \"Hi;\", 'x', HIGH(Data), LOW(@)",
+            "    dw Main.loop, (1 << 4) | %11",
+            "    ds 2, $FF",
+        );
+        assert_eq!(
+            bytes,
+            [
+                0x21, 0x40, 0x98, 0x3D, 0x20, 0xFD, 0xC3, 0x09, 0xC0, 0x48, 0x69, 0x3B, 0x78, 0xC0,
+                0x09, 0x03, 0xC0, 0x13, 0x00, 0xFF, 0xFF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expression() {
+        let expression =
+            Expression::parse("a == $42 && (hl >= $C000 || !z) && 1 << 2 < 5").unwrap();
+        let registers = |a: i64, hl: i64| {
+            move |name: &str| match name {
+                "a" => Some(a),
+                "hl" => Some(hl),
+                "z" => Some(1),
+                _ => None,
+            }
+        };
+        assert_eq!(expression.eval(registers(0x42, 0xC000)), Ok(1));
+        assert_eq!(expression.eval(registers(0x42, 0x8000)), Ok(0));
+        assert_eq!(expression.eval(registers(0x41, 0xC000)), Ok(0));
+        assert_eq!(
+            Expression::parse("4 - -1 != 5").unwrap().eval(|_| None),
+            Ok(0)
+        );
+        assert!(Expression::parse("b").unwrap().eval(|_| None).is_err());
+        assert!(Expression::parse("a ==").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble("nop\njr Far\nds 200\nFar:", 0).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(assemble("ld [hl], [hl]", 0).unwrap_err().line, 1);
+        assert!(assemble("jp Nowhere", 0).is_err());
+        assert!(assemble("A:\nA:", 0).is_err());
+        assert!(assemble(".local:", 0).is_err());
+        assert!(assemble("ld a, 256", 0).is_err());
+        assert!(assemble("rst $39", 0).is_err());
+        assert!(assemble("frob a", 0).is_err());
+        assert!(assemble("push sp", 0).is_err());
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        // Every opcode, with operands that don't hit jr's range at the edges
+        for opcode in 0x00..=0xFF_u8 {
+            for operand in [0x00, 0x7F, 0xA5] {
+                let bytes = [opcode, operand, 0x12];
+                let Some(instruction) = disassemble(&bytes, 0x4000) else {
+                    continue;
+                };
+                let length = instruction.length as usize;
+                let reassembled = assemble(&instruction.text, 0x4000)
+                    .unwrap_or_else(|err| panic!("{}: {}", instruction.text, err));
+                // stop always assembles with a zero operand
+                if opcode != 0x10 {
+                    assert_eq!(reassembled, bytes[..length], "{}", instruction.text);
+                }
+            }
+        }
+        for prefixed in 0x00..=0xFF_u8 {
+            let text = &disassemble_all(&[0xCB, prefixed], 0)[0].text;
+            assert_eq!(assemble(text, 0).unwrap(), [0xCB, prefixed], "{}", text);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::{error::Error, fmt};
+
+use bitflags::bitflags;
+
+use crate::gb::{GameBoy, crc::crc32};
+
+bitflags! {
+    // What a ROM byte was used for. The low four bits match Mesen's Game Boy
+    // code/data log; Opcode is ours.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct CdlFlags: u8 {
+        // Executed, as an opcode or an operand
+        const Code = 0x01;
+        // Read by an instruction
+        const Data = 0x02;
+        // Where a jump or call landed
+        const JumpTarget = 0x04;
+        // Called, or an interrupt vector
+        const SubEntryPoint = 0x08;
+        // Executed as the first byte of an instruction
+        const Opcode = 0x10;
+    }
+}
+
+// File layouts. Each has one byte per ROM byte, the other two behind the
+// header their tool expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CdlLayout {
+    // Every flag above with no header; readers that only know Mesen's bits
+    // ignore the rest
+    Native,
+    // Mesen 2's .cdl: "CDLv2", the ROM's CRC32, then the code, data, jump
+    // target and sub entry point bits
+    Mesen,
+    // BizHawk's Gambatte log: a BIZHAWK-CDL-2 container of named blocks, with
+    // 0x01 first byte of an instruction, 0x02 operand and 0x04 data in ROM
+    BizHawk,
+}
+
+const MESEN_MAGIC: &[u8; 5] = b"CDLv2";
+const BIZHAWK_MAGIC: &str = "BIZHAWK-CDL-2";
+const BIZHAWK_SUBTYPE: &str = "GB";
+const BIZHAWK_SUBTYPE_WIDTH: usize = 15;
+const BIZHAWK_ROM: &str = "ROM";
+
+// What a log file has to match. Mesen checks the ROM's CRC32; BizHawk wants
+// a block for every memory area its Game Boy core logs, even if we leave the
+// RAM ones empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdlTarget {
+    pub rom_size: usize,
+    pub rom_crc32: u32,
+    pub wram_size: usize,
+    pub cart_ram_size: usize,
+}
+
+impl CdlTarget {
+    pub fn new(rom: &[u8], wram_size: usize, cart_ram_size: usize) -> Self {
+        Self {
+            rom_size: rom.len(),
+            rom_crc32: crc32(rom),
+            wram_size,
+            cart_ram_size,
+        }
+    }
+
+    pub fn from_game_boy(gb: &GameBoy) -> Self {
+        let bus = gb.bus().borrow();
+        let (rom, cart_ram) = bus.cartridge().map_or((&[][..], 0), |cartridge| {
+            (cartridge.rom(), cartridge.ram().len())
+        });
+        let wram_size = if gb.model().is_cgb() { 0x8000 } else { 0x2000 };
+        Self::new(rom, wram_size, cart_ram)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CdlError {
+    // Not a file in the layout asked for
+    BadHeader,
+    Truncated,
+    // Made for a ROM with a different CRC32
+    WrongRom,
+    // Covers a different number of ROM bytes
+    Size { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdlError::BadHeader => write!(f, "not a code/data log in that layout"),
+            CdlError::Truncated => write!(f, "code/data log is truncated"),
+            CdlError::WrongRom => write!(f, "code/data log was made for a different ROM"),
+            CdlError::Size { expected, actual } => write!(
+                f,
+                "code/data log covers {} bytes but the ROM has {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for CdlError {}
+
+// Records what each ROM byte was used for while the CPU ran, by ROM offset so
+// banked code is told apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLog {
+    flags: Vec<CdlFlags>,
+}
+
+impl CodeDataLog {
+    pub fn new(rom_size: usize) -> Self {
+        Self {
+            flags: vec![CdlFlags::empty(); rom_size],
+        }
+    }
+
+    // Loads a saved log, e.g. to keep adding to it over several sessions
+    pub fn from_bytes(
+        bytes: &[u8],
+        layout: CdlLayout,
+        target: &CdlTarget,
+    ) -> Result<Self, CdlError> {
+        let rom = match layout {
+            CdlLayout::Native => bytes,
+            CdlLayout::Mesen => {
+                let rom = bytes.strip_prefix(MESEN_MAGIC).ok_or(CdlError::BadHeader)?;
+                let (crc, rom) = rom.split_first_chunk::<4>().ok_or(CdlError::Truncated)?;
+                if u32::from_le_bytes(*crc) != target.rom_crc32 {
+                    return Err(CdlError::WrongRom);
+                }
+                rom
+            }
+            CdlLayout::BizHawk => bizhawk_rom_block(bytes)?,
+        };
+        if rom.len() != target.rom_size {
+            return Err(CdlError::Size {
+                expected: target.rom_size,
+                actual: rom.len(),
+            });
+        }
+
+        let flags = rom
+            .iter()
+            .map(|&byte| match layout {
+                CdlLayout::Native | CdlLayout::Mesen => CdlFlags::from_bits_truncate(byte),
+                CdlLayout::BizHawk => {
+                    let mut flags = CdlFlags::empty();
+                    if byte & 0x01 != 0 {
+                        flags |= CdlFlags::Code | CdlFlags::Opcode;
+                    }
+                    if byte & 0x02 != 0 {
+                        flags |= CdlFlags::Code;
+                    }
+                    if byte & 0x04 != 0 {
+                        flags |= CdlFlags::Data;
+                    }
+                    flags
+                }
+            })
+            .collect();
+        Ok(Self { flags })
+    }
+
+    pub fn to_bytes(&self, layout: CdlLayout, target: &CdlTarget) -> Vec<u8> {
+        let rom = self.flags.iter().map(|flags| match layout {
+            CdlLayout::Native => flags.bits(),
+            CdlLayout::Mesen => flags.bits() & 0x0F,
+            CdlLayout::BizHawk => {
+                let opcode = flags.contains(CdlFlags::Opcode);
+                let operand = flags.contains(CdlFlags::Code) && !opcode;
+                opcode as u8 | (operand as u8) << 1 | (flags.contains(CdlFlags::Data) as u8) << 2
+            }
+        });
+
+        let mut out = Vec::with_capacity(self.flags.len() + 0x40);
+        match layout {
+            CdlLayout::Native => out.extend(rom),
+            CdlLayout::Mesen => {
+                out.extend_from_slice(MESEN_MAGIC);
+                out.extend_from_slice(&target.rom_crc32.to_le_bytes());
+                out.extend(rom);
+            }
+            CdlLayout::BizHawk => {
+                let mut blocks = vec![(BIZHAWK_ROM, rom.collect::<Vec<u8>>())];
+                blocks.push(("WRAM", vec![0x00; target.wram_size]));
+                if target.cart_ram_size > 0 {
+                    blocks.push(("CartRAM", vec![0x00; target.cart_ram_size]));
+                }
+                put_dotnet_string(&mut out, BIZHAWK_MAGIC);
+                put_dotnet_string(
+                    &mut out,
+                    &format!("{:<width$}", BIZHAWK_SUBTYPE, width = BIZHAWK_SUBTYPE_WIDTH),
+                );
+                out.extend_from_slice(&(blocks.len() as i32).to_le_bytes());
+                for (name, data) in blocks {
+                    put_dotnet_string(&mut out, name);
+                    out.extend_from_slice(&(data.len() as i32).to_le_bytes());
+                    out.extend_from_slice(&data);
+                }
+            }
+        }
+        out
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    // Empty past the end of the ROM
+    pub fn flags(&self, offset: usize) -> CdlFlags {
+        self.flags.get(offset).copied().unwrap_or_default()
+    }
+
+    pub fn mark(&mut self, offset: usize, flags: CdlFlags) {
+        if let Some(existing) = self.flags.get_mut(offset) {
+            *existing |= flags;
+        }
+    }
+
+    // Adds what another log of the same ROM saw
+    pub fn merge(&mut self, other: &CodeDataLog) {
+        for (flags, other) in self.flags.iter_mut().zip(&other.flags) {
+            *flags |= *other;
+        }
+    }
+
+    // Bytes with any of the flags set
+    pub fn count(&self, flags: CdlFlags) -> usize {
+        self.flags
+            .iter()
+            .filter(|existing| existing.intersects(flags))
+            .count()
+    }
+}
+
+// Strings as .NET's BinaryWriter writes them: a 7-bit varint byte count, then UTF-8
+fn put_dotnet_string(out: &mut Vec<u8>, text: &str) {
+    let mut len = text.len();
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+    out.extend_from_slice(text.as_bytes());
+}
+
+struct DotNetReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DotNetReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CdlError> {
+        if self.data.len() < len {
+            return Err(CdlError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn i32(&mut self) -> Result<usize, CdlError> {
+        let value = i32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| CdlError::BadHeader)
+    }
+
+    fn string(&mut self) -> Result<&'a str, CdlError> {
+        let mut len = 0usize;
+        for shift in (0..35).step_by(7) {
+            let byte = self.take(1)?[0];
+            len |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return std::str::from_utf8(self.take(len)?).map_err(|_| CdlError::BadHeader);
+            }
+        }
+        Err(CdlError::BadHeader)
+    }
+}
+
+// Finds the ROM block in a BizHawk log, checking it's from a Game Boy core
+fn bizhawk_rom_block(bytes: &[u8]) -> Result<&[u8], CdlError> {
+    let mut reader = DotNetReader { data: bytes };
+    if reader.string().map_err(|_| CdlError::BadHeader)? != BIZHAWK_MAGIC {
+        return Err(CdlError::BadHeader);
+    }
+    if reader.string()?.trim_end() != BIZHAWK_SUBTYPE {
+        return Err(CdlError::BadHeader);
+    }
+    let count = reader.i32()?;
+    for _ in 0..count {
+        let name = reader.string()?;
+        let len = reader.i32()?;
+        let data = reader.take(len)?;
+        if name == BIZHAWK_ROM {
+            return Ok(data);
+        }
+    }
+    Err(CdlError::BadHeader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::{
+        GameBoy, cartridge::ROM_BANK_SIZE, cpu::registers::Register16Bit, gameboy::test_rom,
+    };
+
+    #[test]
+    fn test_logs_cpu() {
+        let program = crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, 2",
+            "ld [$2000], a",
+            "call $4000",
+            "Done: jr Done",
+        );
+        let mut rom = test_rom(&program, 4);
+        // Bank 2: ld a, [$4010]; ret
+        rom[ROM_BANK_SIZE * 2..ROM_BANK_SIZE * 2 + 4].copy_from_slice(&[0xFA, 0x10, 0x40, 0xC9]);
+        let mut gb = GameBoy::new(rom.clone()).unwrap();
+        gb.cpu_mut()
+            .set_code_data_log(Some(CodeDataLog::new(rom.len())));
+        while gb.cpu().registers().get_register_16bit(Register16Bit::PC) != 0x0108 {
+            gb.step();
+        }
+        gb.step();
+
+        let log = gb.cpu_mut().take_code_data_log().unwrap();
+        assert_eq!(log.flags(0x0100), CdlFlags::Code | CdlFlags::Opcode);
+        assert_eq!(log.flags(0x0101), CdlFlags::Code);
+        // Banked code is logged at its offset in the ROM
+        let routine = ROM_BANK_SIZE * 2;
+        assert_eq!(
+            log.flags(routine),
+            CdlFlags::Code | CdlFlags::Opcode | CdlFlags::JumpTarget | CdlFlags::SubEntryPoint
+        );
+        assert_eq!(log.flags(routine + 0x10), CdlFlags::Data);
+        assert_eq!(
+            log.flags(0x0108),
+            CdlFlags::Code | CdlFlags::Opcode | CdlFlags::JumpTarget
+        );
+        assert_eq!(log.flags(ROM_BANK_SIZE), CdlFlags::empty());
+        assert_eq!(log.count(CdlFlags::Opcode), 6);
+    }
+
+    #[test]
+    fn test_layouts() {
+        let mut log = CodeDataLog::new(4);
+        log.mark(0, CdlFlags::Code | CdlFlags::Opcode | CdlFlags::JumpTarget);
+        log.mark(1, CdlFlags::Code);
+        log.mark(2, CdlFlags::Data);
+        log.mark(9, CdlFlags::Data);
+        let target = CdlTarget::new(&[0x00, 0x01, 0x02, 0x03], 0x10, 0);
+        assert_eq!(target.rom_crc32, 0x8BB98613);
+
+        let native = log.to_bytes(CdlLayout::Native, &target);
+        assert_eq!(native, [0x15, 0x01, 0x02, 0x00]);
+        assert_eq!(
+            CodeDataLog::from_bytes(&native, CdlLayout::Native, &target),
+            Ok(log.clone())
+        );
+
+        let mesen = log.to_bytes(CdlLayout::Mesen, &target);
+        assert_eq!(
+            mesen,
+            [
+                b'C', b'D', b'L', b'v', b'2', 0x13, 0x86, 0xB9, 0x8B, 0x05, 0x01, 0x02, 0x00
+            ]
+        );
+        let mut expected = log.clone();
+        expected.flags[0].remove(CdlFlags::Opcode);
+        assert_eq!(
+            CodeDataLog::from_bytes(&mesen, CdlLayout::Mesen, &target),
+            Ok(expected)
+        );
+
+        let bizhawk = log.to_bytes(CdlLayout::BizHawk, &target);
+        let mut expected = vec![0x0D];
+        expected.extend_from_slice(b"BIZHAWK-CDL-2");
+        expected.push(0x0F);
+        expected.extend_from_slice(b"GB             ");
+        expected.extend_from_slice(&[0x02, 0x00, 0x00, 0x00]);
+        expected.push(0x03);
+        expected.extend_from_slice(b"ROM");
+        expected.extend_from_slice(&[0x04, 0x00, 0x00, 0x00, 0x01, 0x02, 0x04, 0x00]);
+        expected.push(0x04);
+        expected.extend_from_slice(b"WRAM");
+        expected.extend_from_slice(&[0x10, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x00; 0x10]);
+        assert_eq!(bizhawk, expected);
+        let loaded = CodeDataLog::from_bytes(&bizhawk, CdlLayout::BizHawk, &target).unwrap();
+        assert_eq!(loaded.flags(0), CdlFlags::Code | CdlFlags::Opcode);
+        assert_eq!(loaded.flags(1), CdlFlags::Code);
+        assert_eq!(loaded.flags(2), CdlFlags::Data);
+
+        // A cartridge with RAM gets a block for it too
+        let with_ram = CdlTarget::new(&[0x00; 4], 0x10, 0x20);
+        let bizhawk_ram = log.to_bytes(CdlLayout::BizHawk, &with_ram);
+        assert_eq!(bizhawk_ram[30..34], [0x03, 0x00, 0x00, 0x00]);
+        assert!(
+            bizhawk_ram
+                .ends_with(&[[0x07].as_slice(), b"CartRAM", &[0x20, 0, 0, 0], &[0; 0x20]].concat())
+        );
+        assert_eq!(
+            CodeDataLog::from_bytes(&mesen, CdlLayout::Mesen, &with_ram),
+            Err(CdlError::WrongRom)
+        );
+
+        let bigger = CdlTarget::new(&[0x00; 8], 0x10, 0);
+        assert_eq!(
+            CodeDataLog::from_bytes(&native, CdlLayout::Native, &bigger),
+            Err(CdlError::Size {
+                expected: 8,
+                actual: 4
+            })
+        );
+        assert_eq!(
+            CodeDataLog::from_bytes(&native, CdlLayout::Mesen, &target),
+            Err(CdlError::BadHeader)
+        );
+        assert_eq!(
+            CodeDataLog::from_bytes(&bizhawk[..40], CdlLayout::BizHawk, &target),
+            Err(CdlError::Truncated)
+        );
+
+        let mut merged = CodeDataLog::new(4);
+        merged.mark(3, CdlFlags::Data);
+        merged.merge(&log);
+        assert_eq!(merged.count(CdlFlags::Data), 2);
+    }
+}
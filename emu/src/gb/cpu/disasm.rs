@@ -0,0 +1,379 @@
+use crate::gb::{
+    bus::Bus,
+    cpu::instruction::{Cond, DecodedOpcode, R8, R16, R16Mem, R16Stk},
+};
+
+const ALU_MNEMONICS: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROTATE_MNEMONICS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const ACCUMULATOR_MNEMONICS: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+
+// Where execution can go after an instruction, for tools that follow code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Jump { target: u16, conditional: bool },
+    // Includes rst, which calls a fixed vector
+    Call { target: u16, conditional: bool },
+    Return { conditional: bool },
+    // jp hl, whose target is only known at run time
+    JumpIndirect,
+    // Locks up the CPU
+    Illegal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub addr: u16,
+    // RGBDS syntax, e.g. "ld a, [$C000]"
+    pub text: String,
+    pub length: u16,
+    pub flow: Flow,
+}
+
+impl Disassembly {
+    // Address of the instruction after this one
+    #[inline]
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.length)
+    }
+}
+
+// Decodes the instruction at the start of `bytes`, which was loaded at `addr`.
+// Returns None if the buffer ends partway through the instruction.
+pub fn disassemble(bytes: &[u8], addr: u16) -> Option<Disassembly> {
+    let opcode = *bytes.first()?;
+    let length = instruction_length(opcode);
+    if bytes.len() < length as usize {
+        return None;
+    }
+    let imm8 = || bytes[1];
+    let imm16 = || u16::from_le_bytes([bytes[1], bytes[2]]);
+    let relative = || addr.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16);
+
+    let decoded = DecodedOpcode::from(opcode);
+    let y = decoded.y;
+    let mut flow = Flow::Continue;
+    let text = match (decoded.x, decoded.z) {
+        (0b00, 0b000) => match y {
+            0 => "nop".to_string(),
+            1 => format!("ld [${:04X}], sp", imm16()),
+            2 => "stop".to_string(),
+            3 => {
+                flow = Flow::Jump {
+                    target: relative(),
+                    conditional: false,
+                };
+                format!("jr ${:04X}", relative())
+            }
+            _ => {
+                flow = Flow::Jump {
+                    target: relative(),
+                    conditional: true,
+                };
+                format!("jr {}, ${:04X}", cond_name(decoded.cond()), relative())
+            }
+        },
+        (0b00, 0b001) if decoded.q() == 0 => {
+            format!("ld {}, ${:04X}", r16_name(decoded.r16_p()), imm16())
+        }
+        (0b00, 0b001) => format!("add hl, {}", r16_name(decoded.r16_p())),
+        (0b00, 0b010) if decoded.q() == 0 => {
+            format!("ld [{}], a", r16mem_name(decoded.r16mem_p()))
+        }
+        (0b00, 0b010) => format!("ld a, [{}]", r16mem_name(decoded.r16mem_p())),
+        (0b00, 0b011) if decoded.q() == 0 => format!("inc {}", r16_name(decoded.r16_p())),
+        (0b00, 0b011) => format!("dec {}", r16_name(decoded.r16_p())),
+        (0b00, 0b100) => format!("inc {}", r8_name(decoded.r8_y())),
+        (0b00, 0b101) => format!("dec {}", r8_name(decoded.r8_y())),
+        (0b00, 0b110) => format!("ld {}, ${:02X}", r8_name(decoded.r8_y()), imm8()),
+        (0b00, 0b111) => ACCUMULATOR_MNEMONICS[y as usize].to_string(),
+
+        (0b01, 0b110) if y == 0b110 => "halt".to_string(),
+        (0b01, _) => format!(
+            "ld {}, {}",
+            r8_name(decoded.r8_y()),
+            r8_name(decoded.r8_z())
+        ),
+
+        (0b10, _) => format!(
+            "{} a, {}",
+            ALU_MNEMONICS[y as usize],
+            r8_name(decoded.r8_z())
+        ),
+
+        (0b11, 0b000) => match y {
+            0..=3 => {
+                flow = Flow::Return { conditional: true };
+                format!("ret {}", cond_name(decoded.cond()))
+            }
+            4 => format!("ldh [${:04X}], a", 0xFF00 | imm8() as u16),
+            5 => format!("add sp, {}", imm8() as i8),
+            6 => format!("ldh a, [${:04X}]", 0xFF00 | imm8() as u16),
+            _ => format!("ld hl, sp{:+}", imm8() as i8),
+        },
+        (0b11, 0b001) if decoded.q() == 0 => format!("pop {}", r16stk_name(decoded.r16stk_p())),
+        (0b11, 0b001) => match decoded.p() {
+            0 | 1 => {
+                flow = Flow::Return { conditional: false };
+                if decoded.p() == 0 { "ret" } else { "reti" }.to_string()
+            }
+            2 => {
+                flow = Flow::JumpIndirect;
+                "jp hl".to_string()
+            }
+            _ => "ld sp, hl".to_string(),
+        },
+        (0b11, 0b010) => match y {
+            0..=3 => {
+                flow = Flow::Jump {
+                    target: imm16(),
+                    conditional: true,
+                };
+                format!("jp {}, ${:04X}", cond_name(decoded.cond()), imm16())
+            }
+            4 => "ldh [c], a".to_string(),
+            5 => format!("ld [${:04X}], a", imm16()),
+            6 => "ldh a, [c]".to_string(),
+            _ => format!("ld a, [${:04X}]", imm16()),
+        },
+        (0b11, 0b011) => match y {
+            0 => {
+                flow = Flow::Jump {
+                    target: imm16(),
+                    conditional: false,
+                };
+                format!("jp ${:04X}", imm16())
+            }
+            1 => prefixed_text(bytes[1]),
+            6 => "di".to_string(),
+            7 => "ei".to_string(),
+            _ => illegal(opcode, &mut flow),
+        },
+        (0b11, 0b100) if y < 4 => {
+            flow = Flow::Call {
+                target: imm16(),
+                conditional: true,
+            };
+            format!("call {}, ${:04X}", cond_name(decoded.cond()), imm16())
+        }
+        (0b11, 0b101) if decoded.q() == 0 => {
+            format!("push {}", r16stk_name(decoded.r16stk_p()))
+        }
+        (0b11, 0b101) if decoded.p() == 0 => {
+            flow = Flow::Call {
+                target: imm16(),
+                conditional: false,
+            };
+            format!("call ${:04X}", imm16())
+        }
+        (0b11, 0b100 | 0b101) => illegal(opcode, &mut flow),
+        (0b11, 0b110) => format!("{} a, ${:02X}", ALU_MNEMONICS[y as usize], imm8()),
+        (0b11, 0b111) => {
+            flow = Flow::Call {
+                target: decoded.tgt3_y() as u16,
+                conditional: false,
+            };
+            format!("rst ${:02X}", decoded.tgt3_y())
+        }
+        _ => unreachable!("Invalid decoded opcode {:#04X}", opcode),
+    };
+
+    Some(Disassembly {
+        addr,
+        text,
+        length,
+        flow,
+    })
+}
+
+// Decodes the instruction at `addr` as the CPU would see it right now
+pub fn disassemble_bus(bus: &Bus, addr: u16) -> Disassembly {
+    let bytes: Vec<u8> = (0..3)
+        .map(|offset| bus.read(addr.wrapping_add(offset) as usize))
+        .collect();
+    disassemble(&bytes, addr).unwrap()
+}
+
+// Decodes instructions back to back until the buffer runs out. A trailing
+// partial instruction comes out as a db.
+pub fn disassemble_all(bytes: &[u8], start: u16) -> Vec<Disassembly> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = start.wrapping_add(offset as u16);
+        let instruction = disassemble(&bytes[offset..], addr).unwrap_or_else(|| Disassembly {
+            addr,
+            text: format!("db ${:02X}", bytes[offset]),
+            length: 1,
+            flow: Flow::Continue,
+        });
+        offset += instruction.length as usize;
+        result.push(instruction);
+    }
+    result
+}
+
+// Bytes taken by the instruction starting with this opcode, including any prefix and operands
+pub fn instruction_length(opcode: u8) -> u16 {
+    match opcode {
+        // CB prefix, stop and 8-bit immediates
+        0xCB | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xE0 | 0xE8 | 0xF0 | 0xF8 => 2,
+        _ if opcode & 0b11000111 == 0b00000110 || opcode & 0b11000111 == 0b11000110 => 2,
+        // 16-bit immediates
+        0x01 | 0x11 | 0x21 | 0x31 | 0x08 | 0xEA | 0xFA | 0xC3 | 0xCD => 3,
+        _ if opcode & 0b11100111 == 0b11000010 || opcode & 0b11100111 == 0b11000100 => 3,
+        _ => 1,
+    }
+}
+
+fn prefixed_text(opcode: u8) -> String {
+    let decoded = DecodedOpcode::from(opcode);
+    let operand = r8_name(decoded.r8_z());
+    match decoded.x {
+        0b00 => format!("{} {}", ROTATE_MNEMONICS[decoded.y as usize], operand),
+        0b01 => format!("bit {}, {}", decoded.y, operand),
+        0b10 => format!("res {}, {}", decoded.y, operand),
+        _ => format!("set {}, {}", decoded.y, operand),
+    }
+}
+
+fn illegal(opcode: u8, flow: &mut Flow) -> String {
+    *flow = Flow::Illegal;
+    format!("db ${:02X}", opcode)
+}
+
+fn r8_name(register: R8) -> &'static str {
+    match register {
+        R8::B => "b",
+        R8::C => "c",
+        R8::D => "d",
+        R8::E => "e",
+        R8::H => "h",
+        R8::L => "l",
+        R8::HLMem => "[hl]",
+        R8::A => "a",
+    }
+}
+
+fn r16_name(register: R16) -> &'static str {
+    match register {
+        R16::BC => "bc",
+        R16::DE => "de",
+        R16::HL => "hl",
+        R16::SP => "sp",
+    }
+}
+
+fn r16mem_name(register: R16Mem) -> &'static str {
+    match register {
+        R16Mem::BC => "bc",
+        R16Mem::DE => "de",
+        R16Mem::HLInc => "hl+",
+        R16Mem::HLDec => "hl-",
+    }
+}
+
+fn r16stk_name(register: R16Stk) -> &'static str {
+    match register {
+        R16Stk::BC => "bc",
+        R16Stk::DE => "de",
+        R16Stk::HL => "hl",
+        R16Stk::AF => "af",
+    }
+}
+
+fn cond_name(cond: Cond) -> &'static str {
+    match cond {
+        Cond::NZ => "nz",
+        Cond::Z => "z",
+        Cond::NC => "nc",
+        Cond::C => "c",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8], addr: u16) -> String {
+        disassemble(bytes, addr).unwrap().text
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert_eq!(text(&[0x00], 0x0000), "nop");
+        assert_eq!(text(&[0x08, 0x34, 0x12], 0x0000), "ld [$1234], sp");
+        assert_eq!(text(&[0x21, 0x00, 0xC0], 0x0000), "ld hl, $C000");
+        assert_eq!(text(&[0x2A], 0x0000), "ld a, [hl+]");
+        assert_eq!(text(&[0x36, 0x42], 0x0000), "ld [hl], $42");
+        assert_eq!(text(&[0x76], 0x0000), "halt");
+        assert_eq!(text(&[0x78], 0x0000), "ld a, b");
+        assert_eq!(text(&[0x96], 0x0000), "sub a, [hl]");
+        assert_eq!(text(&[0xE0, 0x44], 0x0000), "ldh [$FF44], a");
+        assert_eq!(text(&[0xF2], 0x0000), "ldh a, [c]");
+        assert_eq!(text(&[0xE8, 0xFE], 0x0000), "add sp, -2");
+        assert_eq!(text(&[0xF8, 0x05], 0x0000), "ld hl, sp+5");
+        assert_eq!(text(&[0xF5], 0x0000), "push af");
+        assert_eq!(text(&[0xFE, 0x90], 0x0000), "cp a, $90");
+        assert_eq!(text(&[0xFF], 0x0000), "rst $38");
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let jr = disassemble(&[0x20, 0xFE], 0x0150).unwrap();
+        assert_eq!(jr.text, "jr nz, $0150");
+        assert_eq!(
+            jr.flow,
+            Flow::Jump {
+                target: 0x0150,
+                conditional: true
+            }
+        );
+        let call = disassemble(&[0xCD, 0x00, 0x40], 0x0100).unwrap();
+        assert_eq!(call.text, "call $4000");
+        assert_eq!(call.next_addr(), 0x0103);
+        assert_eq!(
+            disassemble(&[0xD9], 0).unwrap().flow,
+            Flow::Return { conditional: false }
+        );
+        assert_eq!(disassemble(&[0xE9], 0).unwrap().flow, Flow::JumpIndirect);
+    }
+
+    #[test]
+    fn test_prefixed_and_illegal() {
+        assert_eq!(text(&[0xCB, 0x37], 0x0000), "swap a");
+        assert_eq!(text(&[0xCB, 0x7E], 0x0000), "bit 7, [hl]");
+        assert_eq!(text(&[0xCB, 0xC1], 0x0000), "set 0, c");
+        let illegal = disassemble(&[0xDD], 0x0000).unwrap();
+        assert_eq!(
+            (illegal.text.as_str(), illegal.flow),
+            ("db $DD", Flow::Illegal)
+        );
+    }
+
+    #[test]
+    fn test_lengths() {
+        // Every opcode decodes and the length agrees with the operands shown
+        for opcode in 0..=0xFFu8 {
+            let instruction = disassemble(&[opcode, 0x00, 0x00], 0x0000).unwrap();
+            assert_eq!(instruction.length, instruction_length(opcode));
+        }
+        assert_eq!(instruction_length(0xC2), 3);
+        assert_eq!(instruction_length(0xC4), 3);
+        assert_eq!(instruction_length(0x3E), 2);
+        assert!(disassemble(&[0xC3, 0x00], 0x0000).is_none());
+
+        let all = disassemble_all(&[0x00, 0x3E, 0x01, 0xC3], 0x0100);
+        let texts: Vec<&str> = all.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(texts, ["nop", "ld a, $01", "db $C3"]);
+    }
+
+    #[test]
+    fn test_live_bus() {
+        let mut bus = Bus::new();
+        bus.write(0xC000, 0xFA);
+        bus.write(0xC001, 0x00);
+        bus.write(0xC002, 0xD0);
+        assert_eq!(disassemble_bus(&bus, 0xC000).text, "ld a, [$D000]");
+    }
+}
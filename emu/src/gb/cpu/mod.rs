@@ -1,40 +1,153 @@
 mod alu;
+pub mod asm;
+pub mod cdl;
+pub mod disasm;
 mod instruction;
-mod registers;
+pub mod registers;
+pub mod trace;
 
 use std::{cell::RefCell, rc::Rc};
 
-use bitflags::Flags;
-
-use crate::{
-    gb::cpu::{
+use crate::gb::{
+    bus::Bus,
+    cpu::{
         alu::{
             AluResultInfo, add_with_carry, bitwise_and, bitwise_not, bitwise_or, bitwise_xor,
             rotate_left, rotate_left_through_carry, rotate_right, rotate_right_through_carry,
-            subtract_with_carry,
+            shift_left_arithmetic, shift_right_arithmetic, shift_right_logical,
+            subtract_with_carry, swap_nibbles,
         },
+        cdl::{CdlFlags, CodeDataLog},
+        disasm::{Flow, disassemble, instruction_length},
         instruction::{Cond, Instruction, R16Mem},
         registers::{FlagsRegister, Register8Bit, Register16Bit, Registers},
+        trace::Tracer,
     },
-    ram::Ram,
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A data access made by an instruction; opcode and operand fetches aren't included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+}
+
+// Changes to the call stack, for debuggers that shadow it. `sp` is where
+// the return address was pushed to or popped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallEvent {
+    // call or rst at `from`
+    Call {
+        from: u16,
+        target: u16,
+        return_addr: u16,
+        sp: u16,
+    },
+    // Dispatch to `vector`, interrupting the code at `from`
+    Interrupt {
+        from: u16,
+        vector: u16,
+        sp: u16,
+    },
+    // ret or reti at `from`
+    Return {
+        from: u16,
+        to: u16,
+        sp: u16,
+    },
+}
+
 pub struct LR35902 {
-    ram: Rc<RefCell<Ram<u8>>>,
+    bus: Rc<RefCell<Bus>>,
     registers: registers::Registers,
     ime: bool,
+    ime_pending: bool,
+    stopped: bool,
+    halted: bool,
+    halt_bug: bool,
+    locked_opcode: Option<u8>,
+    step_cycles: u32,
+    tracer: Option<Tracer>,
+    access_log: Option<Vec<MemoryAccess>>,
+    call_log: Option<Vec<CallEvent>>,
+    code_data_log: Option<CodeDataLog>,
+    // Address of the instruction being executed
+    opcode_pc: u16,
 }
 
 impl LR35902 {
-    pub fn new(sys_ram: Rc<RefCell<Ram<u8>>>) -> Self {
+    pub fn new(sys_bus: Rc<RefCell<Bus>>) -> Self {
         Self {
-            ram: sys_ram,
+            bus: sys_bus,
             registers: Registers::new(),
             ime: false,
+            ime_pending: false,
+            stopped: false,
+            halted: false,
+            halt_bug: false,
+            locked_opcode: None,
+            step_cycles: 0,
+            tracer: None,
+            access_log: None,
+            call_log: None,
+            code_data_log: None,
+            opcode_pc: 0,
         }
     }
 
-    pub fn step(&mut self) {
+    // Executes one instruction or interrupt dispatch and returns the M-cycles it took
+    pub fn step(&mut self) -> u32 {
+        self.step_cycles = 0;
+        if let Some(access_log) = &mut self.access_log {
+            access_log.clear();
+        }
+        if let Some(call_log) = &mut self.call_log {
+            call_log.clear();
+        }
+
+        if self.stopped {
+            // Any selected joypad line going low brings the CPU out of STOP
+            if !self.bus.borrow().joypad().any_line_low() {
+                self.bus.borrow_mut().tick_stopped();
+                return 1;
+            }
+            self.stopped = false;
+        }
+
+        // Illegal opcodes hang the CPU until power off
+        if self.locked_opcode.is_some() {
+            self.internal_cycle();
+            return self.step_cycles;
+        }
+
+        if self.halted {
+            // A pending interrupt ends halt whether or not it gets serviced
+            if self.bus.borrow().pending_interrupts().is_empty() {
+                self.internal_cycle();
+                return self.step_cycles;
+            }
+            self.halted = false;
+        }
+
+        if self.handle_interrupts() {
+            return self.step_cycles;
+        }
+
+        // ei takes effect after the instruction following it
+        let enable_ime = self.ime_pending;
+
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        self.opcode_pc = self.registers.get_register_16bit(Register16Bit::PC);
         let opcode = self.fetch_imm8();
         let instruction = Instruction::from(opcode);
         match instruction.decoded.x {
@@ -44,13 +157,243 @@ impl LR35902 {
             0b11 => self.handle_block3(&instruction),
             _ => unreachable!("Invalid decoded x value"),
         }
+        if self.code_data_log.is_some() {
+            self.log_code(opcode);
+        }
+
+        if enable_ime && self.ime_pending {
+            self.ime = true;
+            self.ime_pending = false;
+        }
+
+        let stall_cycles = self.bus.borrow_mut().take_stall_cycles();
+        for _ in 0..stall_cycles {
+            self.internal_cycle();
+        }
+
+        self.step_cycles
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    #[inline]
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    // The illegal opcode that hung the CPU, if any
+    #[inline]
+    pub fn locked_opcode(&self) -> Option<u8> {
+        self.locked_opcode
+    }
+
+    #[inline]
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    #[inline]
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    // Logs every instruction before it runs until the tracer is taken back
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // Records the memory accesses of each step for watchpoints
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.access_log = enabled.then(Vec::new);
+    }
+
+    // Accesses made by the last step, empty unless logging is on
+    pub fn memory_accesses(&self) -> &[MemoryAccess] {
+        self.access_log.as_deref().unwrap_or_default()
+    }
+
+    // Records calls, returns and interrupt dispatches of each step
+    pub fn set_call_logging(&mut self, enabled: bool) {
+        if enabled != self.call_log.is_some() {
+            self.call_log = enabled.then(Vec::new);
+        }
+    }
+
+    // Call stack changes made by the last step, empty unless logging is on
+    pub fn call_events(&self) -> &[CallEvent] {
+        self.call_log.as_deref().unwrap_or_default()
+    }
+
+    // Marks what ROM bytes get used for until the log is taken back
+    pub fn set_code_data_log(&mut self, log: Option<CodeDataLog>) {
+        self.code_data_log = log;
+    }
+
+    #[inline]
+    pub fn code_data_log(&self) -> Option<&CodeDataLog> {
+        self.code_data_log.as_ref()
+    }
+
+    pub fn take_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.code_data_log.take()
+    }
+
+    // Kept out of line so step stays small when tracing is off
+    #[cold]
+    #[inline(never)]
+    fn trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.registers, &self.bus.borrow());
+        }
+    }
+
+    // Logs the instruction that just ran, and where it went if it jumped
+    #[cold]
+    #[inline(never)]
+    fn log_code(&mut self, opcode: u8) {
+        let length = instruction_length(opcode);
+        for offset in 0..length {
+            let flags = match offset {
+                0 => CdlFlags::Code | CdlFlags::Opcode,
+                _ => CdlFlags::Code,
+            };
+            self.log_rom(self.opcode_pc.wrapping_add(offset), flags);
+        }
+        let pc = self.registers.get_register_16bit(Register16Bit::PC);
+        if pc != self.opcode_pc.wrapping_add(length) {
+            // Only the flow kind matters, so the operands can be anything
+            match disassemble(&[opcode, 0, 0], self.opcode_pc).map(|op| op.flow) {
+                Some(Flow::Jump { .. } | Flow::JumpIndirect) => {
+                    self.log_rom(pc, CdlFlags::JumpTarget)
+                }
+                Some(Flow::Call { .. }) => {
+                    self.log_rom(pc, CdlFlags::JumpTarget | CdlFlags::SubEntryPoint)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn log_rom(&mut self, addr: u16, flags: CdlFlags) {
+        if addr >= 0x8000 {
+            return;
+        }
+        if let Some(log) = &mut self.code_data_log
+            && let Some(cartridge) = self.bus.borrow().cartridge()
+        {
+            log.mark(cartridge.rom_offset(addr as usize), flags);
+        }
+    }
+
+    fn handle_interrupts(&mut self) -> bool {
+        let pending = self.bus.borrow().pending_interrupts();
+        if !self.ime || pending.is_empty() {
+            return false;
+        }
+
+        let interrupt = pending.highest_priority();
+        self.ime = false;
+        self.bus.borrow_mut().acknowledge_interrupt(interrupt);
+        self.internal_cycle();
+        self.internal_cycle();
+        let from = self.registers.get_register_16bit(Register16Bit::PC);
+        let vector = interrupt.vector().unwrap();
+        self.push(from);
+        self.registers.set_register_16bit(Register16Bit::PC, vector);
+        self.log_call(|sp| CallEvent::Interrupt { from, vector, sp });
+        if self.code_data_log.is_some() {
+            self.log_rom(vector, CdlFlags::JumpTarget | CdlFlags::SubEntryPoint);
+        }
+        true
+    }
+
+    fn read_mem(&mut self, addr: usize) -> u8 {
+        let data = self.fetch_mem(addr);
+        self.log_access(addr, data, AccessKind::Read);
+        if self.code_data_log.is_some() {
+            self.log_rom(addr as u16, CdlFlags::Data);
+        }
+        data
+    }
+
+    fn fetch_mem(&mut self, addr: usize) -> u8 {
+        let data = self.bus.borrow().read(addr);
+        self.internal_cycle();
+        data
+    }
+
+    fn write_mem(&mut self, addr: usize, data: u8) {
+        self.log_access(addr, data, AccessKind::Write);
+        self.bus.borrow_mut().write(addr, data);
+        self.internal_cycle();
+    }
+
+    #[inline]
+    fn log_call(&mut self, event: impl FnOnce(u16) -> CallEvent) {
+        if let Some(call_log) = &mut self.call_log {
+            call_log.push(event(self.registers.get_register_16bit(Register16Bit::SP)));
+        }
+    }
+
+    // Pops the return address of ret and reti
+    fn pop_return(&mut self) -> u16 {
+        let sp = self.registers.get_register_16bit(Register16Bit::SP);
+        let to = self.pop();
+        let from = self.opcode_pc;
+        self.log_call(|_| CallEvent::Return { from, to, sp });
+        to
+    }
+
+    // Pushes the return address of call and rst
+    fn push_return(&mut self, target: u16) {
+        let return_addr = self.registers.get_register_16bit(Register16Bit::PC);
+        self.push(return_addr);
+        self.registers.set_register_16bit(Register16Bit::PC, target);
+        let from = self.opcode_pc;
+        self.log_call(|sp| CallEvent::Call {
+            from,
+            target,
+            return_addr,
+            sp,
+        });
+    }
+
+    #[inline]
+    fn log_access(&mut self, addr: usize, value: u8, kind: AccessKind) {
+        if let Some(access_log) = &mut self.access_log {
+            access_log.push(MemoryAccess {
+                addr: addr as u16,
+                value,
+                kind,
+            });
+        }
+    }
+
+    // Every M-cycle, memory access or not, advances the rest of the system
+    #[inline]
+    fn internal_cycle(&mut self) {
+        self.step_cycles += 1;
+        self.bus.borrow_mut().tick();
     }
 
     fn fetch_imm8(&mut self) -> u8 {
-        let data = self
-            .ram
-            .borrow()
-            .read(self.registers.get_register_16bit(Register16Bit::PC) as usize);
+        let data = self.fetch_mem(self.registers.get_register_16bit(Register16Bit::PC) as usize);
+        // The halt bug reads the byte after halt twice
+        if std::mem::take(&mut self.halt_bug) {
+            return data;
+        }
         self.registers.set_register_16bit(
             Register16Bit::PC,
             self.registers
@@ -87,7 +430,7 @@ impl LR35902 {
                 let dest_reg: R16Mem = instruction.decoded.r16mem_p();
                 let dest_addr = self.registers.get_register_16bit(dest_reg.clone().into());
                 let a = self.registers.get_register_8bit(Register8Bit::A);
-                self.ram.borrow_mut().write(dest_addr as usize, a);
+                self.write_mem(dest_addr as usize, a);
 
                 match dest_reg {
                     R16Mem::HLInc => self
@@ -103,7 +446,7 @@ impl LR35902 {
             (_, 0b1, 0b010) => {
                 let src_reg: R16Mem = instruction.decoded.r16mem_p();
                 let src_addr = self.registers.get_register_16bit(src_reg.clone().into());
-                let src_data = self.ram.borrow().read(src_addr as usize);
+                let src_data = self.read_mem(src_addr as usize);
                 self.registers.set_register_8bit(Register8Bit::A, src_data);
 
                 match src_reg {
@@ -120,12 +463,8 @@ impl LR35902 {
             (0b00, 0b1, 0b000) => {
                 let dest_addr = self.fetch_imm16();
                 let sp = self.registers.get_register_16bit(Register16Bit::SP);
-                self.ram
-                    .borrow_mut()
-                    .write(dest_addr as usize, (sp & 0xFF) as u8);
-                self.ram
-                    .borrow_mut()
-                    .write(dest_addr.wrapping_add(1) as usize, (sp >> 8) as u8);
+                self.write_mem(dest_addr as usize, (sp & 0xFF) as u8);
+                self.write_mem(dest_addr.wrapping_add(1) as usize, (sp >> 8) as u8);
             }
             // inc r16
             (_, 0b0, 0b011) => {
@@ -136,6 +475,7 @@ impl LR35902 {
                     instruction.decoded.r16_p().into(),
                     cur_reg_val.wrapping_add(1),
                 );
+                self.internal_cycle();
             }
             // dec r16
             (_, 0b1, 0b011) => {
@@ -146,6 +486,7 @@ impl LR35902 {
                     instruction.decoded.r16_p().into(),
                     cur_reg_val.wrapping_sub(1),
                 );
+                self.internal_cycle();
             }
             // add hl, r16
             (_, 0b1, 0b001) => {
@@ -161,6 +502,7 @@ impl LR35902 {
                 );
                 let new_hl = ((upper.res as u16) << 8) | (lower.res as u16);
                 self.registers.set_register_16bit(Register16Bit::HL, new_hl);
+                self.internal_cycle();
                 self.registers.set_flags_from_alu_res_info(
                     &upper.info,
                     FlagsRegister::Carry | FlagsRegister::HalfCarry | FlagsRegister::Subtraction,
@@ -171,17 +513,16 @@ impl LR35902 {
                 let reg_or_mem = Register8Bit::try_from(instruction.decoded.r8_y());
                 let cur_val: u8 = match reg_or_mem.clone() {
                     Ok(reg) => self.registers.get_register_8bit(reg),
-                    Err(_) => self
-                        .ram
-                        .borrow()
-                        .read(self.registers.get_register_16bit(Register16Bit::HL) as usize),
+                    Err(_) => {
+                        self.read_mem(self.registers.get_register_16bit(Register16Bit::HL) as usize)
+                    }
                 };
 
                 let inc_val = add_with_carry(cur_val, 1, false);
 
                 match reg_or_mem {
                     Ok(reg) => self.registers.set_register_8bit(reg, inc_val.res),
-                    Err(_) => self.ram.borrow_mut().write(
+                    Err(_) => self.write_mem(
                         self.registers.get_register_16bit(Register16Bit::HL) as usize,
                         inc_val.res,
                     ),
@@ -196,17 +537,16 @@ impl LR35902 {
                 let reg_or_mem = Register8Bit::try_from(instruction.decoded.r8_y());
                 let cur_val: u8 = match reg_or_mem.clone() {
                     Ok(reg) => self.registers.get_register_8bit(reg),
-                    Err(_) => self
-                        .ram
-                        .borrow()
-                        .read(self.registers.get_register_16bit(Register16Bit::HL) as usize),
+                    Err(_) => {
+                        self.read_mem(self.registers.get_register_16bit(Register16Bit::HL) as usize)
+                    }
                 };
 
                 let dec_val = subtract_with_carry(cur_val, 1, false);
 
                 match reg_or_mem {
                     Ok(reg) => self.registers.set_register_8bit(reg, dec_val.res),
-                    Err(_) => self.ram.borrow_mut().write(
+                    Err(_) => self.write_mem(
                         self.registers.get_register_16bit(Register16Bit::HL) as usize,
                         dec_val.res,
                     ),
@@ -221,7 +561,7 @@ impl LR35902 {
                 let src = self.fetch_imm8();
                 match Register8Bit::try_from(instruction.decoded.r8_y()) {
                     Ok(reg) => self.registers.set_register_8bit(reg, src),
-                    Err(_) => self.ram.borrow_mut().write(
+                    Err(_) => self.write_mem(
                         self.registers.get_register_16bit(Register16Bit::HL) as usize,
                         src,
                     ),
@@ -334,8 +674,9 @@ impl LR35902 {
                     Register16Bit::PC,
                     self.registers
                         .get_register_16bit(Register16Bit::PC)
-                        .wrapping_add(offset as i16 as u16),
+                        .wrapping_add(offset as i8 as u16),
                 );
+                self.internal_cycle();
             }
             // jr cond, imm8
             (0b10 | 0b11, _, 0b000) => {
@@ -354,12 +695,19 @@ impl LR35902 {
                         Register16Bit::PC,
                         self.registers
                             .get_register_16bit(Register16Bit::PC)
-                            .wrapping_add(offset as i16 as u16),
+                            .wrapping_add(offset as i8 as u16),
                     );
+                    self.internal_cycle();
                 }
             }
             // stop
-            (0b01, 0b0, 0b000) => unimplemented!("stop"),
+            (0b01, 0b0, 0b000) => {
+                self.fetch_imm8();
+                // With KEY1 armed on CGB, stop switches speed instead of stopping
+                if !self.bus.borrow_mut().try_speed_switch() {
+                    self.stopped = true;
+                }
+            }
             (_, _, _) => unreachable!(),
         }
     }
@@ -369,21 +717,23 @@ impl LR35902 {
 
         // halt
         if instruction.decoded.y == 0b110 && instruction.decoded.z == 0b110 {
-            unimplemented!("HALT");
+            if !self.ime && !self.bus.borrow().pending_interrupts().is_empty() {
+                self.halt_bug = true;
+            } else {
+                self.halted = true;
+            }
+            return;
         }
 
         // ld r8, r8
         let src: u8 = match Register8Bit::try_from(instruction.decoded.r8_z()) {
             Ok(reg) => self.registers.get_register_8bit(reg),
-            Err(_) => self
-                .ram
-                .borrow()
-                .read(self.registers.get_register_16bit(Register16Bit::HL) as usize),
+            Err(_) => self.read_mem(self.registers.get_register_16bit(Register16Bit::HL) as usize),
         };
 
         match Register8Bit::try_from(instruction.decoded.r8_y()) {
             Ok(reg) => self.registers.set_register_8bit(reg, src),
-            Err(_) => self.ram.borrow_mut().write(
+            Err(_) => self.write_mem(
                 self.registers.get_register_16bit(Register16Bit::HL) as usize,
                 src,
             ),
@@ -398,10 +748,7 @@ impl LR35902 {
 
         let src: u8 = match Register8Bit::try_from(instruction.decoded.r8_z()) {
             Ok(reg) => self.registers.get_register_8bit(reg),
-            Err(_) => self
-                .ram
-                .borrow()
-                .read(self.registers.get_register_16bit(Register16Bit::HL) as usize),
+            Err(_) => self.read_mem(self.registers.get_register_16bit(Register16Bit::HL) as usize),
         };
 
         let alu_res = match instruction.decoded.y {
@@ -483,21 +830,25 @@ impl LR35902 {
                     Cond::NC => !f.contains(FlagsRegister::Carry),
                 };
 
+                self.internal_cycle();
                 if should_ret {
-                    let new_pc = self.pop();
+                    let new_pc = self.pop_return();
                     self.registers.set_register_16bit(Register16Bit::PC, new_pc);
+                    self.internal_cycle();
                 }
             }
             // ret
             (0b00, 0b1, 0b001) => {
-                let new_pc = self.pop();
+                let new_pc = self.pop_return();
                 self.registers.set_register_16bit(Register16Bit::PC, new_pc);
+                self.internal_cycle();
             }
             // reti
             (0b01, 0b1, 0b001) => {
                 self.ime = true;
-                let new_pc = self.pop();
+                let new_pc = self.pop_return();
                 self.registers.set_register_16bit(Register16Bit::PC, new_pc);
+                self.internal_cycle();
             }
             // jp cond, imm16
             (0b00 | 0b01, _, 0b010) => {
@@ -514,6 +865,7 @@ impl LR35902 {
                 if should_jump {
                     self.registers
                         .set_register_16bit(Register16Bit::PC, new_addr);
+                    self.internal_cycle();
                 }
             }
             // jp imm16
@@ -521,6 +873,7 @@ impl LR35902 {
                 let new_addr = self.fetch_imm16();
                 self.registers
                     .set_register_16bit(Register16Bit::PC, new_addr);
+                self.internal_cycle();
             }
             // jp hl
             (0b10, 0b1, 0b001) => {
@@ -541,25 +894,18 @@ impl LR35902 {
                 };
 
                 if should_jump {
-                    self.push(self.registers.get_register_16bit(Register16Bit::PC));
-                    self.registers
-                        .set_register_16bit(Register16Bit::PC, new_addr);
+                    self.push_return(new_addr);
                 }
             }
             // call imm16
             (0b00, 0b1, 0b101) => {
                 let new_addr = self.fetch_imm16();
-
-                self.push(self.registers.get_register_16bit(Register16Bit::PC));
-                self.registers
-                    .set_register_16bit(Register16Bit::PC, new_addr);
+                self.push_return(new_addr);
             }
             // rst tgt3
             (_, _, 0b111) => {
                 let new_addr = instruction.decoded.tgt3_y() as u16;
-                self.push(self.registers.get_register_16bit(Register16Bit::PC));
-                self.registers
-                    .set_register_16bit(Register16Bit::PC, new_addr);
+                self.push_return(new_addr);
             }
             // pop r16stk
             (_, 0b0, 0b001) => {
@@ -574,15 +920,156 @@ impl LR35902 {
                     .get_register_16bit(instruction.decoded.r16stk_p().into());
                 self.push(push_val);
             }
-            _ => unreachable!(),
+            // di
+            (0b11, 0b0, 0b011) => {
+                self.ime = false;
+                self.ime_pending = false;
+            }
+            // ei
+            (0b11, 0b1, 0b011) => self.ime_pending = true,
+            // ldh [imm8], a
+            (0b10, 0b0, 0b000) => {
+                let offset = self.fetch_imm8();
+                let a = self.registers.get_register_8bit(Register8Bit::A);
+                self.write_mem(0xFF00 | offset as usize, a);
+            }
+            // ldh a, [imm8]
+            (0b11, 0b0, 0b000) => {
+                let offset = self.fetch_imm8();
+                let data = self.read_mem(0xFF00 | offset as usize);
+                self.registers.set_register_8bit(Register8Bit::A, data);
+            }
+            // add sp, imm8
+            (0b10, 0b1, 0b000) => {
+                let new_sp = self.sp_plus_imm8();
+                self.internal_cycle();
+                self.internal_cycle();
+                self.registers.set_register_16bit(Register16Bit::SP, new_sp);
+            }
+            // ld hl, sp + imm8
+            (0b11, 0b1, 0b000) => {
+                let new_hl = self.sp_plus_imm8();
+                self.internal_cycle();
+                self.registers.set_register_16bit(Register16Bit::HL, new_hl);
+            }
+            // ld sp, hl
+            (0b11, 0b1, 0b001) => {
+                let hl = self.registers.get_register_16bit(Register16Bit::HL);
+                self.registers.set_register_16bit(Register16Bit::SP, hl);
+                self.internal_cycle();
+            }
+            // ldh [c], a
+            (0b10, 0b0, 0b010) => {
+                let c = self.registers.get_register_8bit(Register8Bit::C);
+                let a = self.registers.get_register_8bit(Register8Bit::A);
+                self.write_mem(0xFF00 | c as usize, a);
+            }
+            // ldh a, [c]
+            (0b11, 0b0, 0b010) => {
+                let c = self.registers.get_register_8bit(Register8Bit::C);
+                let data = self.read_mem(0xFF00 | c as usize);
+                self.registers.set_register_8bit(Register8Bit::A, data);
+            }
+            // ld [imm16], a
+            (0b10, 0b1, 0b010) => {
+                let dest_addr = self.fetch_imm16();
+                let a = self.registers.get_register_8bit(Register8Bit::A);
+                self.write_mem(dest_addr as usize, a);
+            }
+            // ld a, [imm16]
+            (0b11, 0b1, 0b010) => {
+                let src_addr = self.fetch_imm16();
+                let data = self.read_mem(src_addr as usize);
+                self.registers.set_register_8bit(Register8Bit::A, data);
+            }
+            // prefix
+            (0b00, 0b1, 0b011) => {
+                let opcode = self.fetch_imm8();
+                self.handle_prefixed(&Instruction::from(opcode));
+            }
+            // Illegal opcodes
+            (_, _, _) => self.locked_opcode = Some(instruction.opcode),
+        }
+    }
+
+    fn handle_prefixed(&mut self, instruction: &Instruction) {
+        let reg_or_mem = Register8Bit::try_from(instruction.decoded.r8_z());
+        let cur_val: u8 = match reg_or_mem.clone() {
+            Ok(reg) => self.registers.get_register_8bit(reg),
+            Err(_) => self.read_mem(self.registers.get_register_16bit(Register16Bit::HL) as usize),
+        };
+
+        let bit = instruction.decoded.y;
+        let new_val = match instruction.decoded.x {
+            0b00 => {
+                let carry = self.registers.get_flags().contains(FlagsRegister::Carry);
+                let mut res = match bit {
+                    // rlc r8
+                    0b000 => rotate_left(cur_val),
+                    // rrc r8
+                    0b001 => rotate_right(cur_val),
+                    // rl r8
+                    0b010 => rotate_left_through_carry(cur_val, carry),
+                    // rr r8
+                    0b011 => rotate_right_through_carry(cur_val, carry),
+                    // sla r8
+                    0b100 => shift_left_arithmetic(cur_val),
+                    // sra r8
+                    0b101 => shift_right_arithmetic(cur_val),
+                    // swap r8
+                    0b110 => swap_nibbles(cur_val),
+                    // srl r8
+                    0b111 => shift_right_logical(cur_val),
+                    _ => unreachable!(),
+                };
+                // Unlike rlca and friends, the prefixed rotates set zero
+                res.info.set(AluResultInfo::Zero, res.res == 0);
+                self.registers
+                    .set_flags_from_alu_res_info(&res.info, FlagsRegister::all());
+                res.res
+            }
+            // bit u3, r8
+            0b01 => {
+                let mut info = AluResultInfo::HalfCarry;
+                info.set(AluResultInfo::Zero, cur_val & (1 << bit) == 0);
+                self.registers.set_flags_from_alu_res_info(
+                    &info,
+                    FlagsRegister::Zero | FlagsRegister::Subtraction | FlagsRegister::HalfCarry,
+                );
+                return;
+            }
+            // res u3, r8
+            0b10 => cur_val & !(1 << bit),
+            // set u3, r8
+            0b11 => cur_val | (1 << bit),
+            _ => unreachable!("Invalid decoded x value"),
+        };
+
+        match reg_or_mem {
+            Ok(reg) => self.registers.set_register_8bit(reg, new_val),
+            Err(_) => self.write_mem(
+                self.registers.get_register_16bit(Register16Bit::HL) as usize,
+                new_val,
+            ),
         }
     }
 
+    // Shared by add sp, imm8 and ld hl, sp + imm8, which take their flags from the low byte
+    fn sp_plus_imm8(&mut self) -> u16 {
+        let offset = self.fetch_imm8();
+        let sp = self.registers.get_register_16bit(Register16Bit::SP);
+        let mut info = add_with_carry((sp & 0xFF) as u8, offset, false).info;
+        info.remove(AluResultInfo::Zero);
+        self.registers
+            .set_flags_from_alu_res_info(&info, FlagsRegister::all());
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
     fn pop(&mut self) -> u16 {
         let mut sp = self.registers.get_register_16bit(Register16Bit::SP);
-        let pop_low = self.ram.borrow().read(sp as usize);
+        let pop_low = self.read_mem(sp as usize);
         sp = sp.wrapping_add(1);
-        let pop_high = self.ram.borrow().read(sp as usize);
+        let pop_high = self.read_mem(sp as usize);
         sp = sp.wrapping_add(1);
         self.registers.set_register_16bit(Register16Bit::SP, sp);
 
@@ -593,33 +1080,64 @@ impl LR35902 {
         let push_val_high = ((push_val >> 8) & 0xFF) as u8;
         let push_val_low = (push_val & 0xFF) as u8;
 
+        self.internal_cycle();
+
         let mut sp = self.registers.get_register_16bit(Register16Bit::SP);
         sp = sp.wrapping_sub(1);
-        self.ram.borrow_mut().write(sp as usize, push_val_high);
+        self.write_mem(sp as usize, push_val_high);
         sp = sp.wrapping_sub(1);
-        self.ram.borrow_mut().write(sp as usize, push_val_low);
+        self.write_mem(sp as usize, push_val_low);
 
         self.registers.set_register_16bit(Register16Bit::SP, sp);
     }
 }
 
+// The bus is saved separately by its owner
+impl SaveState for LR35902 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        writer.put_bool(self.ime);
+        writer.put_bool(self.ime_pending);
+        writer.put_bool(self.stopped);
+        writer.put_bool(self.halted);
+        writer.put_bool(self.halt_bug);
+        writer.put_bool(self.locked_opcode.is_some());
+        writer.put_u8(self.locked_opcode.unwrap_or(0x00));
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(reader)?;
+        self.ime = reader.bool()?;
+        self.ime_pending = reader.bool()?;
+        self.stopped = reader.bool()?;
+        self.halted = reader.bool()?;
+        self.halt_bug = reader.bool()?;
+        let locked = reader.bool()?;
+        let opcode = reader.u8()?;
+        self.locked_opcode = locked.then_some(opcode);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use bitflags::Flags;
-
-    use crate::gb::cpu::{alu::AluResultInfo, registers::Register16Bit};
+    use crate::gb::{
+        cpu::{alu::AluResultInfo, registers::Register16Bit},
+        interrupts::Interrupt,
+        joypad::ButtonState,
+    };
 
     use super::*;
 
     fn init_test_cpu() -> LR35902 {
-        let test_ram = Rc::new(RefCell::new(Ram::new(0x10000)));
-        LR35902::new(Rc::clone(&test_ram))
+        let test_bus = Rc::new(RefCell::new(Bus::new()));
+        LR35902::new(Rc::clone(&test_bus))
     }
 
     #[test]
     fn test_fetch_imm8() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x0000, 0x42);
+        test_cpu.bus.borrow_mut().write(0x0000, 0x42);
 
         let data = test_cpu.fetch_imm8();
         assert_eq!(data, 0x42);
@@ -632,8 +1150,8 @@ mod tests {
     #[test]
     fn test_fetch_imm16() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x0000, 0x18);
-        test_cpu.ram.borrow_mut().write(0x0001, 0x12);
+        test_cpu.bus.borrow_mut().write(0x0000, 0x18);
+        test_cpu.bus.borrow_mut().write(0x0001, 0x12);
 
         let data = test_cpu.fetch_imm16();
         assert_eq!(data, 0x1218);
@@ -660,7 +1178,7 @@ mod tests {
     #[test]
     fn test_handle_block1_reg_mem() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x2112, 0x18);
+        test_cpu.bus.borrow_mut().write(0x2112, 0x18);
 
         let opcode = 0b01000110;
         let instruction = Instruction::from(opcode);
@@ -677,7 +1195,7 @@ mod tests {
     #[test]
     fn test_handle_block1_mem_reg() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x2112, 0x42);
+        test_cpu.bus.borrow_mut().write(0x2112, 0x42);
 
         let opcode = 0b01110000;
         let instruction = Instruction::from(opcode);
@@ -688,18 +1206,27 @@ mod tests {
             .set_register_16bit(Register16Bit::HL, 0x2112);
 
         test_cpu.handle_block1(&instruction);
-        assert_eq!(test_cpu.ram.borrow().read(0x2112), 0x18);
+        assert_eq!(test_cpu.bus.borrow().read(0x2112), 0x18);
     }
 
     #[test]
-    #[should_panic]
     fn test_handle_block1_halt() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x2112, 0x42);
 
-        let opcode = 0b0111000;
+        let opcode = 0b01110110;
         let instruction = Instruction::from(opcode);
         test_cpu.handle_block1(&instruction);
+        assert!(test_cpu.is_halted());
+        assert!(!test_cpu.halt_bug);
+
+        // With IME off and an interrupt already pending, halt doesn't halt
+        // and the next byte is read twice instead
+        let mut test_cpu = init_test_cpu();
+        test_cpu.bus.borrow_mut().write(0xFFFF, 0x01);
+        test_cpu.bus.borrow_mut().write(0xFF0F, 0x01);
+        test_cpu.handle_block1(&instruction);
+        assert!(!test_cpu.is_halted());
+        assert!(test_cpu.halt_bug);
     }
 
     #[test]
@@ -774,7 +1301,7 @@ mod tests {
     #[test]
     fn test_handle_block2_mem() {
         let mut test_cpu = init_test_cpu();
-        test_cpu.ram.borrow_mut().write(0x2112, 0x0D);
+        test_cpu.bus.borrow_mut().write(0x2112, 0x0D);
 
         let opcode = 0b10000110;
         let instruction = Instruction::from(opcode);
@@ -839,4 +1366,324 @@ mod tests {
                 .contains(FlagsRegister::Subtraction)
         );
     }
+
+    #[test]
+    fn test_stop_wakes_on_joypad() {
+        let mut test_cpu = init_test_cpu();
+        test_cpu.bus.borrow_mut().write(0x0000, 0x10);
+        test_cpu.bus.borrow_mut().write(0x0001, 0x00);
+        test_cpu.bus.borrow_mut().write(0xFF00, 0x20);
+
+        test_cpu.step();
+        assert!(test_cpu.is_stopped());
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0002
+        );
+
+        test_cpu.bus.borrow_mut().set_buttons(ButtonState::Down);
+        test_cpu.step();
+        assert!(!test_cpu.is_stopped());
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0003
+        );
+    }
+
+    #[test]
+    fn test_joypad_interrupt_after_ei() {
+        let mut test_cpu = init_test_cpu();
+        test_cpu.bus.borrow_mut().write(0x0000, 0xFB);
+        test_cpu
+            .bus
+            .borrow_mut()
+            .write(0xFFFF, Interrupt::Joypad.bits());
+        test_cpu.bus.borrow_mut().write(0xFF00, 0x10);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::SP, 0xFFFE);
+        test_cpu.bus.borrow_mut().set_buttons(ButtonState::A);
+
+        // ei, then one more instruction before the interrupt is taken
+        test_cpu.step();
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0002
+        );
+
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0060
+        );
+        assert_eq!(test_cpu.bus.borrow().read(0xFFFC), 0x02);
+        assert!(test_cpu.bus.borrow().pending_interrupts().is_empty());
+        assert!(!test_cpu.ime);
+    }
+
+    #[test]
+    fn test_step_cycles() {
+        let mut test_cpu = init_test_cpu();
+        // ld bc, imm16; inc bc; call imm16; ret
+        let program = [0x01, 0x00, 0x00, 0x03, 0xCD, 0x10, 0x00];
+        for (addr, byte) in program.iter().enumerate() {
+            test_cpu.bus.borrow_mut().write(addr, *byte);
+        }
+        test_cpu.bus.borrow_mut().write(0x0010, 0xC9);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::SP, 0xD000);
+
+        assert_eq!(test_cpu.step(), 3);
+        assert_eq!(test_cpu.step(), 2);
+        assert_eq!(test_cpu.step(), 6);
+        assert_eq!(test_cpu.step(), 4);
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0007
+        );
+    }
+
+    #[test]
+    fn test_stop_speed_switch() {
+        let test_bus = Rc::new(RefCell::new(Bus::new_cgb()));
+        let mut test_cpu = LR35902::new(Rc::clone(&test_bus));
+        test_bus.borrow_mut().write(0x0000, 0x10);
+        test_bus.borrow_mut().write(0xFF4D, 0x01);
+
+        test_cpu.step();
+        assert!(!test_cpu.is_stopped());
+        assert!(test_bus.borrow().is_double_speed());
+        assert_eq!(test_bus.borrow().read(0xFF4D), 0xFE);
+    }
+
+    #[test]
+    fn test_general_purpose_hdma_stalls_cpu() {
+        let test_bus = Rc::new(RefCell::new(Bus::new_cgb()));
+        let mut test_cpu = LR35902::new(Rc::clone(&test_bus));
+        // hl = HDMA5
+        let program = crate::gb_asm!("ld a, $03", "ld [hl], a");
+        for (addr, byte) in program.iter().enumerate() {
+            test_bus.borrow_mut().write(addr, *byte);
+        }
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::HL, 0xFF55);
+
+        assert_eq!(test_cpu.step(), 2);
+        assert_eq!(test_cpu.step(), 2 + 4 * 8);
+    }
+
+    fn load_program(test_cpu: &LR35902, program: &[u8]) {
+        for (addr, byte) in program.iter().enumerate() {
+            test_cpu.bus.borrow_mut().write(addr, *byte);
+        }
+    }
+
+    #[test]
+    fn test_jr_backwards() {
+        let mut test_cpu = init_test_cpu();
+        load_program(
+            &test_cpu,
+            &crate::gb_asm!("Start:", "nop", "nop", "jr Start"),
+        );
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::PC, 0x0002);
+
+        assert_eq!(test_cpu.step(), 3);
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0000
+        );
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let mut test_cpu = init_test_cpu();
+        load_program(
+            &test_cpu,
+            &crate::gb_asm!("swap a", "bit 7, [hl]", "set 0, [hl]", "srl b"),
+        );
+        test_cpu.registers.set_register_8bit(Register8Bit::A, 0xF1);
+        test_cpu.registers.set_register_8bit(Register8Bit::B, 0x01);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::HL, 0xC000);
+
+        assert_eq!(test_cpu.step(), 2);
+        assert_eq!(test_cpu.registers.get_register_8bit(Register8Bit::A), 0x1F);
+        assert_eq!(test_cpu.step(), 3);
+        assert!(test_cpu.registers.get_flags().contains(FlagsRegister::Zero));
+        assert_eq!(test_cpu.step(), 4);
+        assert_eq!(test_cpu.bus.borrow().read(0xC000), 0x01);
+        test_cpu.step();
+        assert_eq!(test_cpu.registers.get_register_8bit(Register8Bit::B), 0x00);
+        assert!(
+            test_cpu
+                .registers
+                .get_flags()
+                .contains(FlagsRegister::Zero | FlagsRegister::Carry)
+        );
+    }
+
+    #[test]
+    fn test_high_ram_loads() {
+        let mut test_cpu = init_test_cpu();
+        load_program(
+            &test_cpu,
+            &crate::gb_asm!("ldh [$FF80], a", "xor a", "ld a, [$FF80]", "ld hl, sp-1",),
+        );
+        test_cpu.registers.set_register_8bit(Register8Bit::A, 0x42);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::SP, 0xFFFE);
+
+        assert_eq!(test_cpu.step(), 3);
+        test_cpu.step();
+        assert_eq!(test_cpu.step(), 4);
+        assert_eq!(test_cpu.registers.get_register_8bit(Register8Bit::A), 0x42);
+        assert_eq!(test_cpu.step(), 3);
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::HL),
+            0xFFFD
+        );
+        assert!(
+            test_cpu
+                .registers
+                .get_flags()
+                .contains(FlagsRegister::Carry | FlagsRegister::HalfCarry)
+        );
+    }
+
+    #[test]
+    fn test_halt_wakes_without_ime() {
+        let mut test_cpu = init_test_cpu();
+        load_program(&test_cpu, &crate::gb_asm!("halt", "nop"));
+        test_cpu
+            .bus
+            .borrow_mut()
+            .write(0xFFFF, Interrupt::Timer.bits());
+
+        test_cpu.step();
+        assert!(test_cpu.is_halted());
+        assert_eq!(test_cpu.step(), 1);
+        assert!(test_cpu.is_halted());
+
+        test_cpu
+            .bus
+            .borrow_mut()
+            .request_interrupt(Interrupt::Timer);
+        test_cpu.step();
+        assert!(!test_cpu.is_halted());
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0002
+        );
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut test_cpu = init_test_cpu();
+        load_program(&test_cpu, &crate::gb_asm!("halt", "inc a"));
+        test_cpu
+            .bus
+            .borrow_mut()
+            .write(0xFFFF, Interrupt::Timer.bits());
+        test_cpu
+            .bus
+            .borrow_mut()
+            .request_interrupt(Interrupt::Timer);
+
+        test_cpu.step();
+        assert!(!test_cpu.is_halted());
+        test_cpu.step();
+        test_cpu.step();
+        assert_eq!(test_cpu.registers.get_register_8bit(Register8Bit::A), 0x02);
+    }
+
+    #[test]
+    fn test_access_log() {
+        let mut test_cpu = init_test_cpu();
+        load_program(
+            &test_cpu,
+            &crate::gb_asm!("ld a, [$C000]", "push bc", "nop"),
+        );
+        test_cpu.bus.borrow_mut().write(0xC000, 0x42);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::SP, 0xFFFE);
+        test_cpu.registers.set_register_8bit(Register8Bit::B, 0x12);
+        test_cpu.set_access_logging(true);
+
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.memory_accesses(),
+            [MemoryAccess {
+                addr: 0xC000,
+                value: 0x42,
+                kind: AccessKind::Read,
+            }]
+        );
+        test_cpu.step();
+        assert_eq!(test_cpu.memory_accesses().len(), 2);
+        assert_eq!(test_cpu.memory_accesses()[0].addr, 0xFFFD);
+        assert_eq!(test_cpu.memory_accesses()[0].value, 0x12);
+        test_cpu.step();
+        assert!(test_cpu.memory_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_call_log() {
+        let mut test_cpu = init_test_cpu();
+        load_program(
+            &test_cpu,
+            &crate::gb_asm!("call Sub", "nop", "Sub: rst $08", "ret z"),
+        );
+        test_cpu.bus.borrow_mut().write(0x0008, 0xC9);
+        test_cpu
+            .registers
+            .set_register_16bit(Register16Bit::SP, 0xFFFE);
+        test_cpu.set_call_logging(true);
+
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.call_events(),
+            [CallEvent::Call {
+                from: 0x0000,
+                target: 0x0004,
+                return_addr: 0x0003,
+                sp: 0xFFFC,
+            }]
+        );
+        test_cpu.step();
+        test_cpu.step();
+        assert_eq!(
+            test_cpu.call_events(),
+            [CallEvent::Return {
+                from: 0x0008,
+                to: 0x0005,
+                sp: 0xFFFA,
+            }]
+        );
+        // A return that isn't taken doesn't change the stack
+        test_cpu.step();
+        assert!(test_cpu.call_events().is_empty());
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks() {
+        let mut test_cpu = init_test_cpu();
+        load_program(&test_cpu, &crate::gb_asm!("db $DD", "inc a"));
+
+        test_cpu.step();
+        test_cpu.step();
+        assert_eq!(test_cpu.locked_opcode(), Some(0xDD));
+        assert_eq!(
+            test_cpu.registers.get_register_16bit(Register16Bit::PC),
+            0x0001
+        );
+    }
 }
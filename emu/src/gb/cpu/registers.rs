@@ -1,8 +1,11 @@
 use bitflags::bitflags;
 
-use crate::gb::cpu::{
-    alu::AluResultInfo,
-    instruction::{R8, R16, R16Mem, R16Stk},
+use crate::gb::{
+    cpu::{
+        alu::AluResultInfo,
+        instruction::{R8, R16, R16Mem, R16Stk},
+    },
+    state::{SaveState, StateError, StateReader, StateWriter},
 };
 
 #[derive(Debug, Clone)]
@@ -207,6 +210,35 @@ impl Default for Registers {
     }
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in [
+            Register16Bit::AF,
+            Register16Bit::BC,
+            Register16Bit::DE,
+            Register16Bit::HL,
+            Register16Bit::SP,
+            Register16Bit::PC,
+        ] {
+            writer.put_u16(self.get_register_16bit(register));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for register in [
+            Register16Bit::AF,
+            Register16Bit::BC,
+            Register16Bit::DE,
+            Register16Bit::HL,
+            Register16Bit::SP,
+            Register16Bit::PC,
+        ] {
+            self.set_register_16bit(register, reader.u16()?);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
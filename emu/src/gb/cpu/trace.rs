@@ -0,0 +1,192 @@
+use std::{
+    io::{self, Write},
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use crate::gb::{
+    bus::Bus,
+    cpu::registers::{Register8Bit, Register16Bit, Registers},
+    symbols::SymbolTable,
+};
+
+// Writes the CPU state before each instruction in Gameboy Doctor's format:
+// A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD
+pub struct Tracer {
+    output: Box<dyn Write>,
+    pc_range: RangeInclusive<u16>,
+    symbols: Option<Rc<SymbolTable>>,
+    // The first write error stops the trace so a full disk doesn't slow every step
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(output: impl Write + 'static) -> Self {
+        Self {
+            output: Box::new(output),
+            pc_range: 0x0000..=0xFFFF,
+            symbols: None,
+            error: None,
+        }
+    }
+
+    // Only logs instructions whose address falls in the range
+    pub fn with_pc_range(mut self, pc_range: RangeInclusive<u16>) -> Self {
+        self.pc_range = pc_range;
+        self
+    }
+
+    // Writes a "Label:" line before each instruction a symbol points at. The
+    // extra lines mean the log no longer diffs against Gameboy Doctor's.
+    pub fn with_symbols(mut self, symbols: Rc<SymbolTable>) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    #[inline]
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    pub(super) fn trace(&mut self, registers: &Registers, bus: &Bus) {
+        let pc = registers.get_register_16bit(Register16Bit::PC);
+        if self.error.is_some() || !self.pc_range.contains(&pc) {
+            return;
+        }
+
+        if let Some(symbols) = &self.symbols {
+            let bank = bus
+                .cartridge()
+                .filter(|_| pc < 0x8000)
+                .map(|cartridge| cartridge.rom_bank(pc as usize));
+            if let Some(name) = symbols.name_at(pc, bank)
+                && let Err(err) = writeln!(self.output, "{}:", name)
+            {
+                self.error = Some(err);
+                return;
+            }
+        }
+
+        let reg = |register| registers.get_register_8bit(register);
+        let mem = |offset: u16| bus.read(pc.wrapping_add(offset) as usize);
+        let result = writeln!(
+            self.output,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            reg(Register8Bit::A),
+            registers.get_register_16bit(Register16Bit::AF) as u8,
+            reg(Register8Bit::B),
+            reg(Register8Bit::C),
+            reg(Register8Bit::D),
+            reg(Register8Bit::E),
+            reg(Register8Bit::H),
+            reg(Register8Bit::L),
+            registers.get_register_16bit(Register16Bit::SP),
+            pc,
+            mem(0),
+            mem(1),
+            mem(2),
+            mem(3),
+        );
+        if let Err(err) = result {
+            self.error = Some(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::gb::cpu::LR35902;
+
+    // Writer the test can still read after handing it to the tracer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_cpu(program: &[u8]) -> LR35902 {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        for (i, byte) in program.iter().enumerate() {
+            bus.borrow_mut().write(0xC000 + i, *byte);
+        }
+        let mut cpu = LR35902::new(bus);
+        cpu.registers_mut()
+            .set_register_16bit(Register16Bit::PC, 0xC000);
+        cpu.registers_mut()
+            .set_register_16bit(Register16Bit::SP, 0xFFFE);
+        cpu
+    }
+
+    #[test]
+    fn test_doctor_format() {
+        // ld a, 0x42; ld b, a; nop
+        let mut cpu = test_cpu(&[0x3E, 0x42, 0x47, 0x00]);
+        let buffer = SharedBuffer::default();
+        cpu.set_tracer(Some(Tracer::new(buffer.clone())));
+        cpu.step();
+        cpu.step();
+
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C000 PCMEM:3E,42,47,00",
+                "A:42 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C002 PCMEM:47,00,00,00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pc_range() {
+        // nop; nop; nop; nop
+        let mut cpu = test_cpu(&[0x00; 4]);
+        let buffer = SharedBuffer::default();
+        cpu.set_tracer(Some(
+            Tracer::new(buffer.clone()).with_pc_range(0xC001..=0xC002),
+        ));
+        for _ in 0..4 {
+            cpu.step();
+        }
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.starts_with("A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:C001"));
+
+        assert!(cpu.take_tracer().is_some());
+        cpu.step();
+        assert_eq!(buffer.0.borrow().len(), log.len());
+    }
+
+    #[test]
+    fn test_symbol_labels() {
+        // nop; nop
+        let mut cpu = test_cpu(&[0x00; 2]);
+        let buffer = SharedBuffer::default();
+        let symbols = SymbolTable::parse("00:C001 wCode").unwrap();
+        cpu.set_tracer(Some(
+            Tracer::new(buffer.clone()).with_symbols(Rc::new(symbols)),
+        ));
+        cpu.step();
+        cpu.step();
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "wCode:");
+        assert!(lines[2].contains("PC:C001"));
+    }
+}
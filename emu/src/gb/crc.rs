@@ -0,0 +1,28 @@
+// CRC-32 as used by PNG, zip and Mesen's code/data logs. Bit by bit, since
+// it only ever runs over screenshots and ROMs once per save.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(crc32(&[]), 0);
+    }
+}
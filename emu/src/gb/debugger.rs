@@ -0,0 +1,689 @@
+// Breakpoints, watchpoints and stepping around a GameBoy. Frontends own the
+// machine and hand it to each call, so the same debugger can drive a CLI, a
+// GDB stub or a GUI.
+use std::ops::RangeInclusive;
+
+use crate::gb::{
+    GameBoy,
+    call_stack::{CallStack, ReturnMismatch},
+    cpu::{
+        AccessKind, CallEvent, MemoryAccess,
+        asm::{AsmError, Expression},
+        disasm::{Flow, disassemble_bus},
+        registers::{Register8Bit, Register16Bit},
+    },
+    profiler::Profiler,
+    symbols::SymbolTable,
+};
+
+// Names a breakpoint condition can use, in any case
+const REGISTER_NAMES: [&str; 14] = [
+    "a", "f", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "pc",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+    // ROM bank that must be mapped at the address; only checked below 0x8000
+    pub bank: Option<usize>,
+    // Expression over registers and symbols that must be nonzero, e.g.
+    // "a == $42 && hl >= wBuffer"
+    pub condition: Option<Expression>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => kind == AccessKind::Read,
+            WatchKind::Write => kind == AccessKind::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The step asked for is done
+    Step,
+    // The CPU is about to run the instruction at a breakpoint
+    Breakpoint(usize),
+    // The last instruction made a watched access
+    Watchpoint { id: usize, access: MemoryAccess },
+    // An illegal opcode hung the CPU
+    Locked(u8),
+    // A return didn't match the shadow call stack, with stop_on_mismatch set
+    ReturnMismatch(ReturnMismatch),
+    // Ran for the allowed cycles without stopping
+    CycleLimit,
+}
+
+#[derive(Debug)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    // Only follows what the debugger runs
+    call_stack: CallStack,
+    stop_on_mismatch: bool,
+    symbols: SymbolTable,
+    profiler: Option<Profiler>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 0,
+            call_stack: CallStack::new(),
+            stop_on_mismatch: false,
+            symbols: SymbolTable::new(),
+            profiler: None,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16, bank: Option<usize>) -> usize {
+        let id = self.take_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            bank,
+            condition: None,
+            enabled: true,
+        });
+        id
+    }
+
+    // Fails if the condition doesn't parse or names anything but a register
+    // or symbol
+    pub fn add_conditional_breakpoint(
+        &mut self,
+        addr: u16,
+        bank: Option<usize>,
+        condition: &str,
+    ) -> Result<usize, AsmError> {
+        let condition = Expression::parse(condition)?;
+        if let Some(name) = condition.symbols().into_iter().find(|name| {
+            !REGISTER_NAMES.contains(&name.to_ascii_lowercase().as_str())
+                && self.symbols.lookup(name).is_none()
+        }) {
+            return Err(AsmError {
+                line: 1,
+                message: format!("unknown symbol '{}'", name),
+            });
+        }
+        let id = self.add_breakpoint(addr, bank);
+        self.breakpoints.last_mut().unwrap().condition = Some(condition);
+        Ok(id)
+    }
+
+    // Evaluates an assembler expression over the current registers and
+    // symbols, e.g. "hl + 2" or "wBuffer + 2"
+    pub fn evaluate(&self, gb: &GameBoy, text: &str) -> Result<i64, AsmError> {
+        Expression::parse(text)?.eval(|name| self.lookup(gb, name))
+    }
+
+    // Registers shadow symbols of the same name
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    #[inline]
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // Names an address by the closest symbol before it, taking the bank from
+    // what's mapped right now
+    pub fn describe(&self, gb: &GameBoy, addr: u16) -> Option<String> {
+        self.symbols.describe(addr, rom_bank(gb, addr))
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> usize {
+        let id = self.take_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            range,
+            kind,
+            enabled: true,
+        });
+        id
+    }
+
+    // Removes a breakpoint or watchpoint, returning whether it existed
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            breakpoint.enabled = enabled;
+            return true;
+        }
+        if let Some(watchpoint) = self.watchpoints.iter_mut().find(|w| w.id == id) {
+            watchpoint.enabled = enabled;
+            return true;
+        }
+        false
+    }
+
+    #[inline]
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    #[inline]
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    #[inline]
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    // For when the machine is reset or swapped out under the debugger
+    pub fn reset_call_stack(&mut self) {
+        self.call_stack.clear();
+    }
+
+    // Stops on returns that don't unwind the frame on top of the call stack
+    pub fn set_stop_on_mismatch(&mut self, enabled: bool) {
+        self.stop_on_mismatch = enabled;
+    }
+
+    // Profiles everything the debugger runs until taken back
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    #[inline]
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // Runs one instruction, or one interrupt dispatch or halted cycle
+    pub fn step_in(&mut self, gb: &mut GameBoy) -> StopReason {
+        self.run(gb, 0, |_, _| true)
+    }
+
+    // Like step_in, but runs a call through to its return
+    pub fn step_over(&mut self, gb: &mut GameBoy, max_cycles: u64) -> StopReason {
+        let pc = register(gb, Register16Bit::PC);
+        let sp = register(gb, Register16Bit::SP);
+        let instruction = disassemble_bus(&gb.bus().borrow(), pc);
+        match instruction.flow {
+            Flow::Call { .. } => {
+                let return_addr = instruction.next_addr();
+                // The SP check keeps recursion from stopping in a deeper frame
+                self.run(gb, max_cycles, |gb, _| {
+                    register(gb, Register16Bit::PC) == return_addr
+                        && register(gb, Register16Bit::SP) >= sp
+                })
+            }
+            _ => self.step_in(gb),
+        }
+    }
+
+    // Runs until the current function returns: a ret that pops the slot SP
+    // points at now or one above it, or one that unwinds a frame the shadow
+    // call stack already had. Pushes, pops and add sp inside the function
+    // don't count.
+    pub fn step_out(&mut self, gb: &mut GameBoy, max_cycles: u64) -> StopReason {
+        let sp = register(gb, Register16Bit::SP);
+        let depth = self.call_stack.frames().len();
+        self.run(gb, max_cycles, |gb, call_stack| {
+            call_stack.frames().len() < depth
+                || gb.cpu().call_events().iter().any(
+                    |event| matches!(*event, CallEvent::Return { sp: popped, .. } if popped >= sp),
+                )
+        })
+    }
+
+    // Runs until a breakpoint or watchpoint hits, or the cycles run out
+    pub fn resume(&mut self, gb: &mut GameBoy, max_cycles: u64) -> StopReason {
+        self.run(gb, max_cycles, |_, _| false)
+    }
+
+    fn take_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Always runs at least one step, so resuming from a breakpoint moves on
+    fn run(
+        &mut self,
+        gb: &mut GameBoy,
+        max_cycles: u64,
+        done: impl Fn(&GameBoy, &CallStack) -> bool,
+    ) -> StopReason {
+        let watching = self.watchpoints.iter().any(|watchpoint| watchpoint.enabled);
+        gb.cpu_mut().set_access_logging(watching);
+        gb.cpu_mut().set_call_logging(true);
+        let limit = gb.cycles().saturating_add(max_cycles);
+
+        let reason = loop {
+            match &mut self.profiler {
+                Some(profiler) => profiler.step(gb),
+                None => gb.step(),
+            };
+            let mismatch = self.track_calls(gb);
+            if let Some(opcode) = gb.cpu().locked_opcode() {
+                break StopReason::Locked(opcode);
+            }
+            if let Some(reason) = self.watchpoint_hit(gb) {
+                break reason;
+            }
+            if let Some(mismatch) = mismatch
+                && self.stop_on_mismatch
+            {
+                break StopReason::ReturnMismatch(mismatch);
+            }
+            if done(gb, &self.call_stack) {
+                break StopReason::Step;
+            }
+            if let Some(id) = self.breakpoint_hit(gb) {
+                break StopReason::Breakpoint(id);
+            }
+            if gb.cycles() >= limit {
+                break StopReason::CycleLimit;
+            }
+        };
+
+        gb.cpu_mut().set_access_logging(false);
+        gb.cpu_mut().set_call_logging(false);
+        reason
+    }
+
+    fn lookup(&self, gb: &GameBoy, name: &str) -> Option<i64> {
+        register_by_name(gb, name)
+            .or_else(|| self.symbols.lookup(name).map(|symbol| symbol.addr as i64))
+    }
+
+    fn track_calls(&mut self, gb: &GameBoy) -> Option<ReturnMismatch> {
+        let mut mismatch = None;
+        for &event in gb.cpu().call_events() {
+            mismatch = self
+                .call_stack
+                .apply(event, |addr| rom_bank(gb, addr))
+                .or(mismatch);
+        }
+        mismatch
+    }
+
+    fn watchpoint_hit(&self, gb: &GameBoy) -> Option<StopReason> {
+        gb.cpu().memory_accesses().iter().find_map(|access| {
+            self.watchpoints
+                .iter()
+                .find(|watchpoint| {
+                    watchpoint.enabled
+                        && watchpoint.kind.matches(access.kind)
+                        && watchpoint.range.contains(&access.addr)
+                })
+                .map(|watchpoint| StopReason::Watchpoint {
+                    id: watchpoint.id,
+                    access: *access,
+                })
+        })
+    }
+
+    fn breakpoint_hit(&self, gb: &GameBoy) -> Option<usize> {
+        let pc = register(gb, Register16Bit::PC);
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| {
+                breakpoint.enabled
+                    && breakpoint.addr == pc
+                    && breakpoint
+                        .bank
+                        .is_none_or(|bank| pc >= 0x8000 || rom_bank(gb, pc) == Some(bank))
+                    && breakpoint.condition.as_ref().is_none_or(|condition| {
+                        // A condition that can't be evaluated, e.g. on division by zero, stops
+                        condition.eval(|name| self.lookup(gb, name)) != Ok(0)
+                    })
+            })
+            .map(|breakpoint| breakpoint.id)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn register(gb: &GameBoy, register: Register16Bit) -> u16 {
+    gb.cpu().registers().get_register_16bit(register)
+}
+
+// None outside ROM
+pub fn rom_bank(gb: &GameBoy, addr: u16) -> Option<usize> {
+    if addr >= 0x8000 {
+        return None;
+    }
+    gb.bus()
+        .borrow()
+        .cartridge()
+        .map(|cartridge| cartridge.rom_bank(addr as usize))
+}
+
+fn register_by_name(gb: &GameBoy, name: &str) -> Option<i64> {
+    let registers = gb.cpu().registers();
+    let register8 = |register| registers.get_register_8bit(register) as i64;
+    let register16 = |register| registers.get_register_16bit(register) as i64;
+    Some(match name.to_ascii_lowercase().as_str() {
+        "a" => register8(Register8Bit::A),
+        "f" => register16(Register16Bit::AF) & 0xFF,
+        "b" => register8(Register8Bit::B),
+        "c" => register8(Register8Bit::C),
+        "d" => register8(Register8Bit::D),
+        "e" => register8(Register8Bit::E),
+        "h" => register8(Register8Bit::H),
+        "l" => register8(Register8Bit::L),
+        "af" => register16(Register16Bit::AF),
+        "bc" => register16(Register16Bit::BC),
+        "de" => register16(Register16Bit::DE),
+        "hl" => register16(Register16Bit::HL),
+        "sp" => register16(Register16Bit::SP),
+        "pc" => register16(Register16Bit::PC),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::{cartridge::ROM_BANK_SIZE, gameboy::test_rom};
+
+    // MBC1 ROM with four banks, the program at the entry point and a
+    // ld a, $n2; ret routine at the start of each switchable bank
+    fn debug_gb(program: &[u8]) -> GameBoy {
+        let mut rom = test_rom(program, 4);
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        for bank in 1..4 {
+            rom[ROM_BANK_SIZE * bank..ROM_BANK_SIZE * bank + 3].copy_from_slice(&[
+                0x3E,
+                (bank as u8) << 4 | 2,
+                0xC9,
+            ]);
+        }
+        GameBoy::new(rom).unwrap()
+    }
+
+    fn pc(gb: &GameBoy) -> u16 {
+        register(gb, Register16Bit::PC)
+    }
+
+    fn a(gb: &GameBoy) -> u8 {
+        gb.cpu().registers().get_register_8bit(Register8Bit::A)
+    }
+
+    #[test]
+    fn test_bank_aware_breakpoint() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, 2",
+            "ld [$2000], a",
+            "call $4000",
+            "ld a, 3",
+            "ld [$2000], a",
+            "call $4000",
+            "Done: jr Done",
+        ));
+        let mut debugger = Debugger::new();
+        let id = debugger.add_breakpoint(0x4000, Some(3));
+
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::Breakpoint(id));
+        assert_eq!(pc(&gb), 0x4000);
+        assert_eq!(a(&gb), 3);
+        assert_eq!(debugger.step_in(&mut gb), StopReason::Step);
+        assert_eq!(a(&gb), 0x32);
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::CycleLimit);
+
+        assert!(debugger.remove(id));
+        assert!(!debugger.remove(id));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "xor a",
+            "Loop: inc a",
+            "jr Loop",
+        ));
+        let mut debugger = Debugger::new();
+        let id = debugger
+            .add_conditional_breakpoint(0x0102, None, "A == 5 && hl != 0")
+            .unwrap();
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::Breakpoint(id));
+        assert_eq!(a(&gb), 5);
+
+        debugger.set_enabled(id, false);
+        assert_eq!(debugger.resume(&mut gb, 100), StopReason::CycleLimit);
+        assert_eq!(
+            debugger
+                .add_conditional_breakpoint(0x0102, None, "ix == 1")
+                .map_err(|err| err.message),
+            Err("unknown symbol 'ix'".to_string())
+        );
+        // Only checked for names, so a division that would trip on some
+        // register values is fine
+        assert!(
+            debugger
+                .add_conditional_breakpoint(0x0102, None, "a / (b - 1) == 2")
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "ld hl, $C000",
+            "ld a, [hl]",
+            "ld [hl], $12",
+            "ld a, [$C001]",
+            "Done: jr Done",
+        ));
+        let mut debugger = Debugger::new();
+        let write = debugger.add_watchpoint(0xC000..=0xC000, WatchKind::Write);
+        let read = debugger.add_watchpoint(0xC001..=0xC0FF, WatchKind::Read);
+
+        assert_eq!(
+            debugger.resume(&mut gb, 1000),
+            StopReason::Watchpoint {
+                id: write,
+                access: MemoryAccess {
+                    addr: 0xC000,
+                    value: 0x12,
+                    kind: AccessKind::Write,
+                },
+            }
+        );
+        assert_eq!(pc(&gb), 0x0106);
+        assert!(matches!(
+            debugger.resume(&mut gb, 1000),
+            StopReason::Watchpoint { id, .. } if id == read
+        ));
+        assert!(gb.cpu().memory_accesses().is_empty());
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "call Sub",
+            "ld b, a",
+            "Done: jr Done",
+            "Sub: ld a, $42",
+            "call Leaf",
+            "ret",
+            "Leaf: ld c, a",
+            "ret",
+        ));
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.step_over(&mut gb, 1000), StopReason::Step);
+        assert_eq!(pc(&gb), 0x0103);
+        assert_eq!(a(&gb), 0x42);
+
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "call Sub",
+            "ld b, a",
+            "Done: jr Done",
+            "Sub: ld a, $42",
+            "call Leaf",
+            "ret",
+            "Leaf: ld c, a",
+            "ret",
+        ));
+        debugger.step_in(&mut gb);
+        debugger.step_in(&mut gb);
+        assert_eq!(pc(&gb), 0x0108);
+        assert_eq!(debugger.step_out(&mut gb, 1000), StopReason::Step);
+        assert_eq!(pc(&gb), 0x0103);
+
+        // A breakpoint inside the call still stops step over
+        let mut gb = debug_gb(&crate::gb_asm!(origin = 0x0100; "call $4000"));
+        let id = debugger.add_breakpoint(0x4002, None);
+        assert_eq!(
+            debugger.step_over(&mut gb, 1000),
+            StopReason::Breakpoint(id)
+        );
+    }
+
+    #[test]
+    fn test_step_out_past_stack_juggling() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "call Sub",
+            "ld b, a",
+            "Done: jr Done",
+            "Sub: push bc",
+            "pop bc",
+            "add sp, 2",
+            "add sp, -2",
+            "call Leaf",
+            "ret",
+            "Leaf: ld a, $42",
+            "ret",
+        ));
+        let mut debugger = Debugger::new();
+        debugger.step_in(&mut gb);
+        assert_eq!(pc(&gb), 0x0106);
+        // add sp, 2 moves SP above where it started, and Leaf's ret pops the
+        // slot just below
+        assert_eq!(debugger.step_out(&mut gb, 1000), StopReason::Step);
+        assert_eq!(pc(&gb), 0x0103);
+        assert_eq!(a(&gb), 0x42);
+
+        // Without a shadow frame to unwind, only the ret counts
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "call Sub",
+            "ld b, a",
+            "Done: jr Done",
+            "Sub: push bc",
+            "pop bc",
+            "ret",
+        ));
+        gb.step();
+        gb.step();
+        debugger.reset_call_stack();
+        assert_eq!(pc(&gb), 0x0107);
+        assert_eq!(debugger.step_out(&mut gb, 1000), StopReason::Step);
+        assert_eq!(pc(&gb), 0x0103);
+    }
+
+    #[test]
+    fn test_locked_cpu() {
+        let mut gb = debug_gb(&crate::gb_asm!(origin = 0x0100; "nop", "db $DD"));
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::Locked(0xDD));
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, 2",
+            "ld [$2000], a",
+            "call Sub",
+            "Done: jr Done",
+            "Sub: call $4000",
+            "ld hl, Done",
+            "push hl",
+            "ret",
+        ));
+        let mut debugger = Debugger::new();
+        let id = debugger.add_breakpoint(0x4002, None);
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::Breakpoint(id));
+
+        let frames = debugger.call_stack().frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].call_site, 0x0105);
+        assert_eq!(frames[1].call_site, 0x010A);
+        assert_eq!(frames[1].target, 0x4000);
+        assert_eq!(frames[1].bank, Some(0));
+
+        // push hl; ret jumps out of Sub without returning through its frame
+        debugger.set_stop_on_mismatch(true);
+        match debugger.resume(&mut gb, 1000) {
+            StopReason::ReturnMismatch(mismatch) => {
+                assert_eq!(mismatch.at, 0x0111);
+                assert_eq!(mismatch.to, 0x0108);
+                assert_eq!(mismatch.expected.return_addr, 0x0108);
+            }
+            reason => panic!("unexpected stop {:?}", reason),
+        }
+        assert_eq!(pc(&gb), 0x0108);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut gb = debug_gb(&crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, 2",
+            "ld [$2000], a",
+            "ld hl, $C000",
+            "call $4000",
+            "Done: jr Done",
+        ));
+        let mut debugger = Debugger::new();
+        debugger
+            .set_symbols(SymbolTable::parse("01:4000 One\n02:4000 Two\n00:C000 wBuffer").unwrap());
+        let id = debugger
+            .add_conditional_breakpoint(0x4002, Some(2), "hl == wBuffer")
+            .unwrap();
+        assert_eq!(debugger.evaluate(&gb, "wBuffer + 2"), Ok(0xC002));
+        assert_eq!(debugger.resume(&mut gb, 1000), StopReason::Breakpoint(id));
+        assert_eq!(debugger.describe(&gb, 0x4002).as_deref(), Some("Two+$2"));
+        assert_eq!(debugger.call_stack().frames()[0].bank, Some(0));
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    cell::{Ref, RefCell},
+    rc::Rc,
+};
+
+use crate::gb::{
+    bus::Bus,
+    cartridge::{Cartridge, CartridgeError, ROM_BANK_SIZE},
+    cpu::LR35902,
+    joypad::ButtonState,
+    model::Model,
+    rewind::RewindBuffer,
+    serial::LinkPartner,
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+// 154 lines of 456 dots
+pub const M_CYCLES_PER_FRAME: u64 = 17556;
+
+// The whole machine: CPU, bus, cartridge and every device, with no boot ROM
+pub struct GameBoy {
+    cpu: LR35902,
+    bus: Rc<RefCell<Bus>>,
+    cycles: u64,
+    // Snapshot taken after every frame while rewinding is enabled
+    rewind: Option<RewindBuffer>,
+}
+
+impl GameBoy {
+    // Emulates the model the cartridge header asks for
+    pub fn new(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let cartridge = Cartridge::from_rom(rom)?;
+        let model = Model::from_header(cartridge.header());
+        Ok(Self::from_cartridge(cartridge, model))
+    }
+
+    pub fn with_model(rom: Vec<u8>, model: Model) -> Result<Self, CartridgeError> {
+        Ok(Self::from_cartridge(Cartridge::from_rom(rom)?, model))
+    }
+
+    pub fn from_cartridge(cartridge: Cartridge, model: Model) -> Self {
+        let cgb_mode = model.is_cgb() && cartridge.header().supports_cgb();
+        let mut bus = Bus::with_model(model, cgb_mode);
+        bus.load_cartridge(cartridge);
+
+        let bus = Rc::new(RefCell::new(bus));
+        let mut cpu = LR35902::new(Rc::clone(&bus));
+        *cpu.registers_mut() = model.post_boot_registers(cgb_mode);
+        Self::apply_post_boot_io(model, &mut bus.borrow_mut());
+
+        Self {
+            cpu,
+            bus,
+            cycles: 0,
+            rewind: None,
+        }
+    }
+
+    // Leaves the devices the way the boot ROM would hand them over
+    fn apply_post_boot_io(model: Model, bus: &mut Bus) {
+        bus.timer_mut().set_counter(model.post_boot_timer_counter());
+        for &(addr, data) in model.post_boot_io() {
+            bus.write(addr, data);
+        }
+    }
+
+    #[inline]
+    pub fn model(&self) -> Model {
+        self.bus.borrow().model()
+    }
+
+    // Executes one instruction and returns the M-cycles it took
+    pub fn step(&mut self) -> u32 {
+        let cycles = self.cpu.step();
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // Runs until the next VBlank, or for a frame's worth of time while the LCD is off
+    pub fn run_frame(&mut self) {
+        self.run_frame_until(|_| false);
+    }
+
+    // Runs a frame like run_frame, asking `stop` before each instruction.
+    // Returns true if it stopped the frame early.
+    pub fn run_frame_until(&mut self, mut stop: impl FnMut(&GameBoy) -> bool) -> bool {
+        self.bus.borrow_mut().take_frame_ready();
+        let speed = if self.bus.borrow().is_double_speed() {
+            2
+        } else {
+            1
+        };
+        let limit = self.cycles + M_CYCLES_PER_FRAME * speed;
+        while self.cycles < limit {
+            if stop(self) {
+                return true;
+            }
+            self.step();
+            if self.bus.borrow_mut().take_frame_ready() {
+                break;
+            }
+        }
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.push(self.save_state());
+            self.rewind = Some(rewind);
+        }
+        false
+    }
+
+    // Runs until the total M-cycle count reaches the given value
+    pub fn run_until(&mut self, cycles: u64) {
+        while self.cycles < cycles {
+            self.step();
+        }
+    }
+
+    // M-cycles run since power on
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // 160x144 RGB555 pixels
+    pub fn framebuffer(&self) -> Ref<'_, [u16]> {
+        Ref::map(self.bus.borrow(), |bus| bus.ppu().framebuffer())
+    }
+
+    // FNV-1a over the framebuffer, stable across runs and builds for comparing screens in tests
+    pub fn framebuffer_hash(&self) -> u64 {
+        self.framebuffer()
+            .iter()
+            .flat_map(|pixel| pixel.to_le_bytes())
+            .fold(0xCBF29CE484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001B3)
+            })
+    }
+
+    // Interleaved stereo samples at apu::SAMPLE_RATE since the last call
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        self.bus.borrow_mut().take_audio_samples()
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        self.bus.borrow_mut().set_buttons(buttons);
+    }
+
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) {
+        self.bus.borrow_mut().set_player_buttons(player, buttons);
+    }
+
+    pub fn connect_serial(&mut self, partner: Box<dyn LinkPartner>) {
+        self.bus.borrow_mut().connect_serial(partner);
+    }
+
+    // Snapshot of the whole machine. The ROM and any link partner aren't included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        self.bus.borrow().save_state(&mut writer);
+        self.cpu.save_state(&mut writer);
+        writer.put_u64(self.cycles);
+        writer.into_bytes()
+    }
+
+    // Leaves the machine untouched if the state can't be loaded
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.load_state_unchecked(data);
+        if result.is_err() {
+            self.load_state_unchecked(&backup)
+                .expect("restoring a state saved by this machine");
+        }
+        result
+    }
+
+    fn load_state_unchecked(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data)?;
+        self.bus.borrow_mut().load_state(&mut reader)?;
+        self.cpu.load_state(&mut reader)?;
+        self.cycles = reader.u64()?;
+        Ok(())
+    }
+
+    // Keeps the given number of frames of history for rewind; 0 turns it off
+    pub fn set_rewind_depth(&mut self, frames: usize) {
+        match (&mut self.rewind, frames) {
+            (_, 0) => self.rewind = None,
+            (Some(rewind), _) => rewind.set_depth(frames),
+            (None, _) => self.rewind = Some(RewindBuffer::new(frames)),
+        }
+    }
+
+    #[inline]
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    // Goes back to the end of an earlier frame and returns how many frames it went back
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        let Some(rewind) = &mut self.rewind else {
+            return 0;
+        };
+        let Some((state, count)) = rewind.rewind(frames) else {
+            return 0;
+        };
+        let state = state.to_vec();
+        self.load_state(&state)
+            .expect("rewinding to a state saved by this machine");
+        count
+    }
+
+    #[inline]
+    pub fn cpu(&self) -> &LR35902 {
+        &self.cpu
+    }
+
+    #[inline]
+    pub fn cpu_mut(&mut self) -> &mut LR35902 {
+        &mut self.cpu
+    }
+
+    #[inline]
+    pub fn bus(&self) -> &Rc<RefCell<Bus>> {
+        &self.bus
+    }
+}
+
+// Cartridge for tests and tools with the given number of 16 KiB ROM banks
+// and the code at the entry point. Past two banks it gets an MBC1 so the rest
+// can be switched in.
+pub fn test_rom(code: &[u8], banks: usize) -> Vec<u8> {
+    let mut rom = vec![0x00; ROM_BANK_SIZE * banks];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    if banks > 2 {
+        rom[0x147] = 0x01;
+        rom[0x148] = (banks / 2).trailing_zeros() as u8;
+    }
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::{
+        apu::{NR52_ADDR, SAMPLE_RATE},
+        cpu::registers::{Register8Bit, Register16Bit},
+        ppu::{LCDC_ADDR, SCREEN_HEIGHT, SCREEN_WIDTH},
+        timer::DIV_ADDR,
+    };
+
+    #[test]
+    fn test_post_boot_state() {
+        let gb = GameBoy::new(test_rom(&[], 2)).unwrap();
+        let registers = gb.cpu().registers();
+        assert_eq!(registers.get_register_16bit(Register16Bit::AF), 0x01B0);
+        assert_eq!(registers.get_register_16bit(Register16Bit::PC), 0x0100);
+        assert_eq!(gb.bus().borrow().read(LCDC_ADDR), 0x91);
+        assert_eq!(gb.bus().borrow().read(DIV_ADDR), 0xAB);
+        assert_eq!(gb.model(), Model::Dmg);
+    }
+
+    #[test]
+    fn test_model_from_header() {
+        let mut rom = test_rom(&[], 2);
+        rom[0x143] = 0x80;
+        let gb = GameBoy::new(rom.clone()).unwrap();
+        assert_eq!(gb.model(), Model::Cgb);
+        assert!(gb.bus().borrow().is_cgb_mode());
+
+        // A CGB cartridge on a DMG runs without the CGB features
+        let gb = GameBoy::with_model(rom, Model::Dmg).unwrap();
+        assert!(!gb.bus().borrow().is_cgb_mode());
+
+        // A DMG cartridge on an AGB gets the GBA flags but not CGB mode
+        let gb = GameBoy::with_model(test_rom(&[], 2), Model::Agb).unwrap();
+        assert!(!gb.bus().borrow().is_cgb_mode());
+        assert_eq!(
+            gb.cpu().registers().get_register_16bit(Register16Bit::BC),
+            0x0100
+        );
+    }
+
+    #[test]
+    fn test_sgb_model() {
+        let gb = GameBoy::with_model(test_rom(&[], 2), Model::Sgb2).unwrap();
+        assert!(gb.bus().borrow().is_sgb());
+    }
+
+    #[test]
+    fn test_post_boot_sound() {
+        // Channel 1 is left on after the chime, silently; the SGB has no chime
+        for (model, nr52) in [
+            (Model::Dmg, 0xF1),
+            (Model::Cgb, 0xF1),
+            (Model::Sgb, 0xF0),
+            (Model::Sgb2, 0xF0),
+        ] {
+            let mut gb = GameBoy::with_model(test_rom(&[0x18, 0xFE], 2), model).unwrap();
+            let bus = gb.bus().borrow();
+            assert_eq!(bus.read(NR52_ADDR), nr52, "{:?}", model);
+            assert_eq!(bus.read(0xFF12), 0xF3, "{:?}", model);
+            assert_eq!(bus.read(0xFF25), 0xF3, "{:?}", model);
+            drop(bus);
+
+            gb.run_frame();
+            // A flat line, whatever DAC offset the channels are left with
+            let samples = gb.audio_samples();
+            assert!(samples.windows(2).all(|pair| pair[0] == pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // ld a, 0x42; ld [0xC000], a; halt
+        let mut gb = GameBoy::new(test_rom(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x76], 2)).unwrap();
+        gb.run_frame();
+        assert_eq!(gb.bus().borrow().read(0xC000), 0x42);
+        assert!(gb.cpu().is_halted());
+        assert_eq!(gb.framebuffer().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+
+        // A whole frame from one VBlank to the next
+        let start = gb.cycles();
+        gb.audio_samples();
+        gb.run_frame();
+        assert_eq!(gb.cycles() - start, M_CYCLES_PER_FRAME);
+        let expected = M_CYCLES_PER_FRAME * 4 * SAMPLE_RATE as u64 / 4194304;
+        assert!(gb.audio_samples().len().abs_diff(expected as usize * 2) <= 2);
+    }
+
+    #[test]
+    fn test_run_until() {
+        // jr -2
+        let mut gb = GameBoy::new(test_rom(&[0x18, 0xFE], 2)).unwrap();
+        gb.run_until(1000);
+        assert!(gb.cycles() >= 1000 && gb.cycles() < 1003);
+        assert_eq!(
+            gb.cpu().registers().get_register_16bit(Register16Bit::PC),
+            0x0100
+        );
+    }
+
+    #[test]
+    fn test_run_frame_until() {
+        // inc a; jr -3
+        let mut gb = GameBoy::new(test_rom(&[0x3C, 0x18, 0xFD], 2)).unwrap();
+        gb.set_rewind_depth(4);
+        let a = |gb: &GameBoy| gb.cpu().registers().get_register_8bit(Register8Bit::A);
+        assert!(gb.run_frame_until(|gb| a(gb) == 0x05));
+        assert_eq!(a(&gb), 0x05);
+
+        // Only whole frames are snapshotted for rewind
+        assert!(!gb.run_frame_until(|_| false));
+        assert!(!gb.run_frame_until(|_| false));
+        assert_eq!(gb.rewind_buffer().unwrap().len(), 1);
+    }
+
+    // Registers and cycle count after each of the given number of steps
+    fn trace(gb: &mut GameBoy, steps: usize) -> Vec<(u16, u16, u16, u16, u16, u64)> {
+        (0..steps)
+            .map(|_| {
+                gb.step();
+                let registers = gb.cpu().registers();
+                (
+                    registers.get_register_16bit(Register16Bit::AF),
+                    registers.get_register_16bit(Register16Bit::BC),
+                    registers.get_register_16bit(Register16Bit::HL),
+                    registers.get_register_16bit(Register16Bit::SP),
+                    registers.get_register_16bit(Register16Bit::PC),
+                    gb.cycles(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        // Fills VRAM with a counter that also drives the timer and sound:
+        // ld hl, 0x8000; ld a, 0x05; ldh [0x07], a; loop: inc b; ld a, b; ld [hl+], a;
+        // ldh [0x12], a; ldh a, [0x05]; add a, b; ld c, a; jr loop
+        let code = [
+            0x21, 0x00, 0x80, 0x3E, 0x05, 0xE0, 0x07, 0x04, 0x78, 0x22, 0xE0, 0x12, 0xF0, 0x05,
+            0x80, 0x4F, 0x18, 0xF5,
+        ];
+        let mut gb = GameBoy::new(test_rom(&code, 2)).unwrap();
+        gb.run_frame();
+        gb.run_until(gb.cycles() + 5000);
+
+        let state = gb.save_state();
+        let expected = trace(&mut gb, 20000);
+        let expected_frame = gb.framebuffer().to_vec();
+
+        gb.load_state(&state).unwrap();
+        assert_eq!(trace(&mut gb, 20000), expected);
+        assert_eq!(*gb.framebuffer(), *expected_frame);
+        // A fresh machine picks up from the same state too
+        let mut other = GameBoy::new(test_rom(&code, 2)).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(trace(&mut other, 20000), expected);
+        assert_eq!(other.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn test_state_mismatch() {
+        let gb = GameBoy::new(test_rom(&[], 2)).unwrap();
+        let state = gb.save_state();
+
+        let mut rom = test_rom(&[], 2);
+        rom[0x134..0x138].copy_from_slice(b"GAME");
+        let mut other = GameBoy::new(rom).unwrap();
+        assert_eq!(
+            other.load_state(&state),
+            Err(StateError::Mismatch("cartridge"))
+        );
+
+        let mut other = GameBoy::with_model(test_rom(&[], 2), Model::Cgb).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::Mismatch("model")));
+    }
+
+    #[test]
+    fn test_truncated_state() {
+        // ld a, 0x42; ld [0xC000], a; halt
+        let mut gb = GameBoy::new(test_rom(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x76], 2)).unwrap();
+        let state = gb.save_state();
+        gb.run_frame();
+        let before = gb.save_state();
+
+        assert_eq!(
+            gb.load_state(&state[..state.len() - 1]),
+            Err(StateError::UnexpectedEof)
+        );
+        // A failed load leaves the machine as it was
+        assert_eq!(gb.save_state(), before);
+        assert_eq!(gb.bus().borrow().read(0xC000), 0x42);
+    }
+
+    #[test]
+    fn test_rewind() {
+        // inc a; ld [hl+], a; jr -4
+        let mut gb = GameBoy::new(test_rom(&[0x3C, 0x22, 0x18, 0xFC], 2)).unwrap();
+        assert_eq!(gb.rewind(1), 0);
+        gb.set_rewind_depth(4);
+
+        let mut states = Vec::new();
+        for _ in 0..6 {
+            gb.run_frame();
+            states.push(gb.save_state());
+        }
+        assert_eq!(gb.rewind(2), 2);
+        assert_eq!(gb.save_state(), states[3]);
+        assert_eq!(gb.rewind(10), 2);
+        assert_eq!(gb.save_state(), states[1]);
+
+        // Running again after a rewind records on top of the restored frame
+        gb.run_frame();
+        assert_eq!(gb.save_state(), states[2]);
+        assert_eq!(gb.rewind(1), 1);
+        assert_eq!(gb.save_state(), states[1]);
+    }
+
+    #[test]
+    fn test_bad_rom() {
+        assert!(GameBoy::new(vec![0x00; 0x10]).is_err());
+    }
+}
@@ -0,0 +1,569 @@
+// GDB remote serial protocol stub, so gdb or another RSP client can attach
+// over TCP with `target remote localhost:2159`.
+//
+// Registers are AF, BC, DE, HL, SP and PC, 16 bits each in that order.
+// Addresses are 16-bit bus addresses.
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::gb::{
+    GameBoy,
+    cpu::{AccessKind, registers::Register16Bit},
+    debugger::{Debugger, StopReason, WatchKind},
+    gameboy::M_CYCLES_PER_FRAME,
+};
+
+const REGISTERS: [Register16Bit; 6] = [
+    Register16Bit::AF,
+    Register16Bit::BC,
+    Register16Bit::DE,
+    Register16Bit::HL,
+    Register16Bit::SP,
+    Register16Bit::PC,
+];
+
+const PACKET_SIZE: usize = 0x1000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty-retro.sm83.core">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+enum Packet {
+    Command(Vec<u8>),
+    // Ctrl-C from the client
+    Interrupt,
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    no_ack: bool,
+    // Breakpoint and watchpoint ids by (Z type, address, length)
+    points: Vec<((u8, u16, usize), usize)>,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            debugger: Debugger::new(),
+            no_ack: false,
+            points: Vec::new(),
+        }
+    }
+
+    // Breakpoints set here are hit along with the client's
+    #[inline]
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    // Serves the client until it detaches, kills the session or disconnects
+    pub fn run(&mut self, gb: &mut GameBoy) -> io::Result<()> {
+        loop {
+            let data = match self.read_packet() {
+                Ok(Packet::Command(data)) => data,
+                Ok(Packet::Interrupt) => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let command = String::from_utf8_lossy(&data).into_owned();
+            match command.as_bytes().first() {
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => {}
+            }
+            let reply = self.handle(gb, &command)?;
+            self.send(&reply)?;
+            // Acks stop after the reply to this one
+            if command == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+
+    fn handle(&mut self, gb: &mut GameBoy, command: &str) -> io::Result<String> {
+        // Empty packets, or ones starting with a byte that isn't ASCII, aren't
+        // anything we support
+        let Some(kind) = command.get(..1) else {
+            return Ok(String::new());
+        };
+        let args = &command[1..];
+        let reply = match kind {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => REGISTERS
+                .iter()
+                .map(|register| {
+                    hex(&gb
+                        .cpu()
+                        .registers()
+                        .get_register_16bit(register.clone())
+                        .to_le_bytes())
+                })
+                .collect(),
+            "G" => match parse_hex_bytes(args) {
+                Some(bytes) if bytes.len() == REGISTERS.len() * 2 => {
+                    for (register, value) in REGISTERS.iter().zip(bytes.chunks_exact(2)) {
+                        let value = u16::from_le_bytes([value[0], value[1]]);
+                        gb.cpu_mut()
+                            .registers_mut()
+                            .set_register_16bit(register.clone(), value);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match parse_number(args).and_then(|index| REGISTERS.get(index)) {
+                Some(register) => hex(&gb
+                    .cpu()
+                    .registers()
+                    .get_register_16bit(register.clone())
+                    .to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(index, value)| {
+                    let register = REGISTERS.get(parse_number(index)?)?;
+                    let value = parse_hex_bytes(value)?;
+                    Some((register.clone(), u16::from_le_bytes(value.try_into().ok()?)))
+                });
+                match parsed {
+                    Some((register, value)) => {
+                        gb.cpu_mut()
+                            .registers_mut()
+                            .set_register_16bit(register, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let bus = gb.bus().borrow();
+                    let bytes: Vec<u8> = (0..len.min(PACKET_SIZE / 2))
+                        .map(|offset| bus.read(addr.wrapping_add(offset as u16) as usize))
+                        .collect();
+                    hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let data = parse_hex_bytes(data)?;
+                    (data.len() == len).then_some((addr, data))
+                });
+                match parsed {
+                    Some((addr, data)) => {
+                        // ROM is patched rather than written, which would switch banks
+                        let mut bus = gb.bus().borrow_mut();
+                        for (offset, byte) in data.iter().enumerate() {
+                            bus.patch(addr.wrapping_add(offset as u16) as usize, *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                // c and s may carry an address to resume from
+                if let Some(addr) = parse_number(args) {
+                    gb.cpu_mut()
+                        .registers_mut()
+                        .set_register_16bit(Register16Bit::PC, addr as u16);
+                }
+                if kind == "s" {
+                    let reason = self.debugger.step_in(gb);
+                    self.stop_reply(reason)
+                } else {
+                    self.resume(gb)?
+                }
+            }
+            "Z" | "z" => self.breakpoint(kind == "Z", args),
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(command),
+            // Unsupported, including vCont and X, which gdb then avoids
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, command: &str) -> String {
+        if command.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = command.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = range
+                .split_once(',')
+                .and_then(|(offset, len)| Some((parse_number(offset)?, parse_number(len)?)))
+            else {
+                return "E01".to_string();
+            };
+            let xml = TARGET_XML.as_bytes();
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(len).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match command {
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2/Z3/Z4 write, read and access watchpoints.
+    // The last field is the instruction size for breakpoints and the number
+    // of bytes watched for watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next().and_then(|kind| kind.parse::<u8>().ok()),
+            fields.next().and_then(parse_number),
+            fields.next().and_then(parse_number),
+        ) else {
+            return "E01".to_string();
+        };
+        if kind > 4 {
+            return String::new();
+        }
+        let key = (kind, addr as u16, len);
+        if !insert {
+            if let Some(index) = self.points.iter().position(|(point, _)| *point == key) {
+                let (_, id) = self.points.remove(index);
+                self.debugger.remove(id);
+            }
+            return "OK".to_string();
+        }
+        let id = match kind {
+            0 | 1 => self.debugger.add_breakpoint(addr as u16, None),
+            _ => {
+                let Some(last) = len
+                    .checked_sub(1)
+                    .and_then(|extra| addr.checked_add(extra))
+                    .filter(|&last| last <= 0xFFFF)
+                else {
+                    return "E01".to_string();
+                };
+                let watch = match kind {
+                    2 => WatchKind::Write,
+                    3 => WatchKind::Read,
+                    _ => WatchKind::ReadWrite,
+                };
+                self.debugger
+                    .add_watchpoint(addr as u16..=last as u16, watch)
+            }
+        };
+        self.points.push((key, id));
+        "OK".to_string()
+    }
+
+    // Runs a frame at a time so a Ctrl-C from the client gets through
+    fn resume(&mut self, gb: &mut GameBoy) -> io::Result<String> {
+        loop {
+            match self.debugger.resume(gb, M_CYCLES_PER_FRAME) {
+                StopReason::CycleLimit => {
+                    if self.interrupted()? {
+                        return Ok(format!("S{:02x}", SIGINT));
+                    }
+                }
+                reason => return Ok(self.stop_reply(reason)),
+            }
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Watchpoint { id, access } => {
+                let kind = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .find(|watchpoint| watchpoint.id == id)
+                    .map(|watchpoint| watchpoint.kind);
+                let name = match (kind, access.kind) {
+                    (Some(WatchKind::ReadWrite), _) => "awatch",
+                    (_, AccessKind::Read) => "rwatch",
+                    (_, AccessKind::Write) => "watch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, access.addr)
+            }
+            StopReason::Locked(_) => format!("S{:02x}", SIGILL),
+            _ => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_packet(&mut self) -> io::Result<Packet> {
+        loop {
+            // Skip acks and noise up to the start of a packet
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok(Packet::Interrupt),
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                if byte == b'}' {
+                    let escaped = self.read_byte()?;
+                    checksum = checksum.wrapping_add(escaped);
+                    data.push(escaped ^ 0x20);
+                } else {
+                    data.push(byte);
+                }
+            }
+            let expected = [self.read_byte()?, self.read_byte()?];
+            let valid = std::str::from_utf8(&expected)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok())
+                == Some(checksum);
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Packet::Command(data));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, checksum)?;
+        self.stream.flush()
+    }
+}
+
+// Waits for one client on `addr` and serves it
+pub fn listen(gb: &mut GameBoy, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(stream).run(gb)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_number(addr)? as u16, parse_number(len)?))
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::gameboy::test_rom;
+    use std::thread;
+
+    // Sends a packet and returns the reply's data
+    fn command(stream: &mut TcpStream, data: &str) -> String {
+        command_bytes(stream, data.as_bytes())
+    }
+
+    fn command_bytes(stream: &mut TcpStream, data: &[u8]) -> String {
+        let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        stream.write_all(b"$").unwrap();
+        stream.write_all(data).unwrap();
+        write!(stream, "#{:02x}", checksum).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'$' if reply.is_empty() => reply.push(b'$'),
+                b'#' => break,
+                _ => reply.push(byte[0]),
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let program = crate::gb_asm!(
+            origin = 0x0100;
+            "ld hl, $C000",
+            "ld a, $42",
+            "ld [hl], a",
+            "inc a",
+            "Done: jr Done",
+        );
+        let mut gb = GameBoy::new(test_rom(&program, 2)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let script = [
+                "qSupported:swbreak+",
+                "qXfer:features:read:target.xml:0,20",
+                "?",
+                "p5",
+                "m100,3",
+                // The write to $C000 is the second byte watched
+                "Z2,bfff,2",
+                "c",
+                "z2,bfff,2",
+                "Z2,10000,1",
+                "Z3,ffff,2",
+                "Z0,107,1",
+                "c",
+                "g",
+                "Mc001,2:beef",
+                "mc000,3",
+                "P3=3412",
+                "p3",
+                "s",
+                "vCont?",
+            ];
+            let replies: Vec<String> = script
+                .iter()
+                .map(|packet| command(&mut stream, packet))
+                .collect();
+            command(&mut stream, "D");
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        GdbStub::new(stream).run(&mut gb).unwrap();
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+",
+                "m<?xml version=\"1.0\"?>\n<!DOCTYPE ",
+                "S05",
+                "0001",
+                "2100c0",
+                "OK",
+                "T05watch:c000;",
+                "OK",
+                "E01",
+                "E01",
+                "OK",
+                "S05",
+                "10431300d80000c0feff0701",
+                "OK",
+                "42beef",
+                "OK",
+                "3412",
+                "S05",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_packets_and_rom_writes() {
+        // MBC1 with RAM
+        let mut rom = test_rom(&[], 4);
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut gb = GameBoy::new(rom).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let replies = [
+                command_bytes(&mut stream, b""),
+                command_bytes(&mut stream, &[0xFF, b'?']),
+                command(&mut stream, "?"),
+                // Would select bank 3 and enable RAM if they reached the MBC
+                command(&mut stream, "M2000,1:03"),
+                command(&mut stream, "M0000,1:0a"),
+                command(&mut stream, "m2000,1"),
+                command(&mut stream, "M4000,2:c9c9"),
+                command(&mut stream, "m4000,2"),
+            ];
+            command(&mut stream, "D");
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(stream).run(&mut gb).unwrap();
+        assert_eq!(
+            client.join().unwrap(),
+            ["", "", "S05", "OK", "OK", "03", "OK", "c9c9"]
+        );
+        let bus = gb.bus().borrow();
+        let cartridge = bus.cartridge().unwrap();
+        assert_eq!(cartridge.rom_bank(0x4000), 1);
+        assert_eq!(cartridge.rom()[0x4000..0x4002], [0xC9, 0xC9]);
+        assert_eq!(bus.read(0xA000), 0xFF);
+    }
+}
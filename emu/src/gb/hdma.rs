@@ -0,0 +1,138 @@
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const HDMA1_ADDR: usize = 0xFF51;
+pub const HDMA2_ADDR: usize = 0xFF52;
+pub const HDMA3_ADDR: usize = 0xFF53;
+pub const HDMA4_ADDR: usize = 0xFF54;
+pub const HDMA5_ADDR: usize = 0xFF55;
+
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+pub struct Hdma {
+    source: u16,
+    dest: u16,
+    remaining_blocks: u8,
+    hblank_active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Self {
+            source: 0x0000,
+            dest: 0x0000,
+            remaining_blocks: 0,
+            hblank_active: false,
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            HDMA1_ADDR..=HDMA4_ADDR => 0xFF,
+            HDMA5_ADDR => {
+                let length = self.remaining_blocks.wrapping_sub(1) & 0x7F;
+                if self.hblank_active {
+                    length
+                } else {
+                    0x80 | length
+                }
+            }
+            _ => unreachable!("Invalid HDMA address {:#06X}", addr),
+        }
+    }
+
+    // Returns the number of blocks to copy right away for a general purpose transfer
+    pub fn write(&mut self, addr: usize, data: u8) -> Option<u8> {
+        match addr {
+            HDMA1_ADDR => self.source = (self.source & 0x00FF) | ((data as u16) << 8),
+            HDMA2_ADDR => self.source = (self.source & 0xFF00) | (data & 0xF0) as u16,
+            HDMA3_ADDR => self.dest = (self.dest & 0x00FF) | (((data & 0x1F) as u16) << 8),
+            HDMA4_ADDR => self.dest = (self.dest & 0xFF00) | (data & 0xF0) as u16,
+            HDMA5_ADDR => {
+                // Clearing bit 7 during an HBlank transfer stops it
+                if self.hblank_active && data & 0x80 == 0 {
+                    self.hblank_active = false;
+                    return None;
+                }
+
+                self.remaining_blocks = (data & 0x7F) + 1;
+                if data & 0x80 != 0 {
+                    self.hblank_active = true;
+                } else {
+                    return Some(self.remaining_blocks);
+                }
+            }
+            _ => unreachable!("Invalid HDMA address {:#06X}", addr),
+        }
+        None
+    }
+
+    #[inline]
+    pub fn is_hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    // Source and VRAM destination of the next block, advancing past it
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | (self.dest & 0x1FF0));
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.dest = self.dest.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0;
+        self.remaining_blocks -= 1;
+        if self.remaining_blocks == 0 {
+            self.hblank_active = false;
+        }
+        block
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Hdma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u16(self.source);
+        writer.put_u16(self.dest);
+        writer.put_u8(self.remaining_blocks);
+        writer.put_bool(self.hblank_active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.u16()?;
+        self.dest = reader.u16()?;
+        self.remaining_blocks = reader.u8()?;
+        self.hblank_active = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_general_purpose() {
+        let mut hdma = Hdma::new();
+        hdma.write(HDMA1_ADDR, 0xC1);
+        hdma.write(HDMA2_ADDR, 0x2F);
+        hdma.write(HDMA3_ADDR, 0xE1);
+        hdma.write(HDMA4_ADDR, 0x00);
+        assert_eq!(hdma.write(HDMA5_ADDR, 0x01), Some(2));
+        assert_eq!(hdma.next_block(), (0xC120, 0x8100));
+        assert_eq!(hdma.next_block(), (0xC130, 0x8110));
+        assert_eq!(hdma.read(HDMA5_ADDR), 0xFF);
+    }
+
+    #[test]
+    fn test_hblank_cancel() {
+        let mut hdma = Hdma::new();
+        assert_eq!(hdma.write(HDMA5_ADDR, 0x83), None);
+        assert!(hdma.is_hblank_active());
+        hdma.next_block();
+        assert_eq!(hdma.read(HDMA5_ADDR), 0x02);
+        hdma.write(HDMA5_ADDR, 0x00);
+        assert!(!hdma.is_hblank_active());
+        assert_eq!(hdma.read(HDMA5_ADDR), 0x82);
+    }
+}
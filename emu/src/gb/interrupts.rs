@@ -0,0 +1,54 @@
+use bitflags::bitflags;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Interrupt: u8 {
+        const VBlank = 0b00000001;
+        const Lcd = 0b00000010;
+        const Timer = 0b00000100;
+        const Serial = 0b00001000;
+        const Joypad = 0b00010000;
+    }
+}
+
+impl Interrupt {
+    // Vector of the highest priority interrupt in the set
+    pub fn vector(&self) -> Option<u16> {
+        match self.highest_priority() {
+            Interrupt::VBlank => Some(0x0040),
+            Interrupt::Lcd => Some(0x0048),
+            Interrupt::Timer => Some(0x0050),
+            Interrupt::Serial => Some(0x0058),
+            Interrupt::Joypad => Some(0x0060),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn highest_priority(&self) -> Interrupt {
+        Interrupt::from_bits_truncate(self.bits() & self.bits().wrapping_neg())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector() {
+        assert_eq!(Interrupt::Joypad.vector(), Some(0x0060));
+        assert_eq!(
+            (Interrupt::Timer | Interrupt::Serial).vector(),
+            Some(0x0050)
+        );
+        assert_eq!(Interrupt::empty().vector(), None);
+    }
+
+    #[test]
+    fn test_highest_priority() {
+        assert_eq!(
+            (Interrupt::VBlank | Interrupt::Joypad).highest_priority(),
+            Interrupt::VBlank
+        );
+    }
+}
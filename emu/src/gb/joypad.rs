@@ -0,0 +1,144 @@
+use bitflags::bitflags;
+
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct ButtonState: u8 {
+        const Right = 0b00000001;
+        const Left = 0b00000010;
+        const Up = 0b00000100;
+        const Down = 0b00001000;
+        const A = 0b00010000;
+        const B = 0b00100000;
+        const Select = 0b01000000;
+        const Start = 0b10000000;
+    }
+}
+
+const SELECT_DPAD: u8 = 0b00010000;
+const SELECT_BUTTONS: u8 = 0b00100000;
+const SELECT_MASK: u8 = SELECT_DPAD | SELECT_BUTTONS;
+
+pub struct Joypad {
+    select: u8,
+    buttons: ButtonState,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_MASK,
+            buttons: ButtonState::empty(),
+        }
+    }
+
+    #[inline]
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.input_lines()
+    }
+
+    // Returns true if any input line went from high to low
+    pub fn write(&mut self, data: u8) -> bool {
+        let old_lines = self.input_lines();
+        self.select = data & SELECT_MASK;
+        Self::has_falling_edge(old_lines, self.input_lines())
+    }
+
+    // Returns true if any input line went from high to low
+    pub fn set_buttons(&mut self, buttons: ButtonState) -> bool {
+        let old_lines = self.input_lines();
+        self.buttons = buttons;
+        Self::has_falling_edge(old_lines, self.input_lines())
+    }
+
+    #[inline]
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    #[inline]
+    pub fn any_line_low(&self) -> bool {
+        self.input_lines() != 0x0F
+    }
+
+    fn input_lines(&self) -> u8 {
+        let mut pressed: u8 = 0x00;
+        if self.select & SELECT_DPAD == 0 {
+            pressed |= self.buttons.bits() & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            pressed |= self.buttons.bits() >> 4;
+        }
+        !pressed & 0x0F
+    }
+
+    #[inline]
+    fn has_falling_edge(old_lines: u8, new_lines: u8) -> bool {
+        old_lines & !new_lines != 0
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.select);
+        writer.put_u8(self.buttons.bits());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.select = reader.u8()? & SELECT_MASK;
+        self.buttons = ButtonState::from_bits_retain(reader.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_nothing_selected() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(ButtonState::A | ButtonState::Up);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+
+    #[test]
+    fn test_read_dpad() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        joypad.set_buttons(ButtonState::A | ButtonState::Up);
+        assert_eq!(joypad.read(), 0xEB);
+    }
+
+    #[test]
+    fn test_read_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x10);
+        joypad.set_buttons(ButtonState::A | ButtonState::Start | ButtonState::Up);
+        assert_eq!(joypad.read(), 0xD6);
+    }
+
+    #[test]
+    fn test_falling_edge() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        assert!(!joypad.set_buttons(ButtonState::A));
+        assert!(joypad.set_buttons(ButtonState::A | ButtonState::Left));
+        assert!(!joypad.set_buttons(ButtonState::empty()));
+    }
+
+    #[test]
+    fn test_falling_edge_on_select() {
+        let mut joypad = Joypad::new();
+        assert!(!joypad.set_buttons(ButtonState::B));
+        assert!(!joypad.write(0x20));
+        assert!(joypad.write(0x10));
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+};
+
+use crate::gb::serial::{LinkPartner, TRANSFER_T_CYCLES};
+
+// Furthest either side may run ahead of the other, in T-cycles. It stays under
+// a transfer's length so the start of a transfer always arrives before its end.
+const MAX_LEAD: u64 = TRANSFER_T_CYCLES as u64 / 2;
+// How often each side tells the other how far it has run
+const SYNC_INTERVAL: u64 = MAX_LEAD / 2;
+
+const MSG_SYNC: u8 = 0x01;
+const MSG_START: u8 = 0x02;
+const MSG_CANCEL: u8 = 0x03;
+const MSG_REPLY: u8 = 0x04;
+
+// A transfer the other side is clocking
+struct IncomingTransfer {
+    end: u64,
+    data: u8,
+}
+
+// Link cable to another emulator over a byte stream such as a TCP or Unix socket.
+//
+// Either side can clock a transfer, whichever set SC's internal clock bit
+// when it started. Time is kept separately: both sides count T-cycles from
+// power on, stamp everything they send with their count and never run more
+// than MAX_LEAD ahead of the other. A transfer is announced when it starts and
+// answered when it ends, so every byte lands on the same cycle on every run
+// however the two processes get scheduled.
+pub struct SocketLink<S: Read + Write> {
+    reader: BufReader<S>,
+    writer: BufWriter<S>,
+    time: u64,
+    peer_time: u64,
+    last_sent: u64,
+    incoming: Option<IncomingTransfer>,
+    // The other side's answer to the transfer we're clocking
+    reply: Option<u8>,
+    connected: bool,
+}
+
+impl SocketLink<TcpStream> {
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        // Every transfer is a round trip, so don't let Nagle batch them up
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Ok(Self::new(reader, stream))
+    }
+}
+
+#[cfg(unix)]
+impl SocketLink<std::os::unix::net::UnixStream> {
+    pub fn unix(stream: std::os::unix::net::UnixStream) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        Ok(Self::new(reader, stream))
+    }
+}
+
+impl<S: Read + Write> SocketLink<S> {
+    pub fn new(reader: S, writer: S) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            time: 0,
+            peer_time: 0,
+            last_sent: 0,
+            incoming: None,
+            reply: None,
+            connected: true,
+        }
+    }
+
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, tag: u8, data: Option<u8>) {
+        let mut msg = Vec::with_capacity(10);
+        msg.push(tag);
+        msg.extend_from_slice(&self.time.to_le_bytes());
+        msg.extend(data);
+        if self
+            .writer
+            .write_all(&msg)
+            .and_then(|_| self.writer.flush())
+            .is_err()
+        {
+            self.connected = false;
+        }
+        self.last_sent = self.time;
+    }
+
+    fn recv_u8(&mut self) -> Option<u8> {
+        let mut buf = [0u8; 1];
+        match self.reader.read_exact(&mut buf) {
+            Ok(_) => Some(buf[0]),
+            Err(_) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+
+    fn recv_u64(&mut self) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        match self.reader.read_exact(&mut buf) {
+            Ok(_) => Some(u64::from_le_bytes(buf)),
+            Err(_) => {
+                self.connected = false;
+                None
+            }
+        }
+    }
+
+    // Blocks for the next message from the other side
+    fn receive(&mut self) {
+        let Some(tag) = self.recv_u8() else {
+            return;
+        };
+        let Some(timestamp) = self.recv_u64() else {
+            return;
+        };
+        self.peer_time = timestamp;
+
+        match tag {
+            MSG_SYNC => {}
+            MSG_START => {
+                if let Some(data) = self.recv_u8() {
+                    self.incoming = Some(IncomingTransfer {
+                        end: timestamp + TRANSFER_T_CYCLES as u64,
+                        data,
+                    });
+                }
+            }
+            MSG_CANCEL => self.incoming = None,
+            MSG_REPLY => self.reply = self.recv_u8(),
+            _ => self.connected = false,
+        }
+    }
+
+    // Blocks until the other side has run to `time`, so everything it sent
+    // before then has arrived. Says how far we've got first, in case it's
+    // waiting on us too.
+    fn wait_for_peer(&mut self, time: u64) {
+        if self.peer_time >= time {
+            return;
+        }
+        if self.last_sent < self.time {
+            self.send(MSG_SYNC, None);
+        }
+        while self.connected && self.peer_time < time {
+            self.receive();
+        }
+    }
+
+    // Takes the other side's transfer once it has ended, unless it was cancelled
+    fn take_ended_transfer(&mut self) -> Option<IncomingTransfer> {
+        let end = self.incoming.as_ref()?.end;
+        if end > self.time {
+            return None;
+        }
+        self.wait_for_peer(end);
+        self.incoming.take()
+    }
+}
+
+impl<S: Read + Write> LinkPartner for SocketLink<S> {
+    fn advance(&mut self, t_cycles: u32) {
+        if !self.connected {
+            return;
+        }
+
+        // A transfer that ended while we weren't listening sees an open line
+        if self.take_ended_transfer().is_some() {
+            self.send(MSG_REPLY, Some(0xFF));
+        }
+
+        let time = self.time + t_cycles as u64;
+        self.wait_for_peer(time.saturating_sub(MAX_LEAD));
+        self.time = time;
+        if self.time - self.last_sent >= SYNC_INTERVAL {
+            self.send(MSG_SYNC, None);
+        }
+    }
+
+    fn start_transfer(&mut self, outgoing: u8) {
+        if self.connected {
+            self.reply = None;
+            self.send(MSG_START, Some(outgoing));
+        }
+    }
+
+    fn cancel_transfer(&mut self) {
+        if self.connected {
+            self.send(MSG_CANCEL, None);
+        }
+    }
+
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        if !self.connected {
+            return 0xFF;
+        }
+
+        // Both ends clocking at once: we weren't listening to theirs either
+        if self.take_ended_transfer().is_some() {
+            self.send(MSG_REPLY, Some(0xFF));
+        }
+        if self.reply.is_none() && self.last_sent < self.time {
+            self.send(MSG_SYNC, None);
+        }
+        while self.connected && self.reply.is_none() {
+            self.receive();
+        }
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        if !self.connected {
+            return None;
+        }
+
+        let transfer = self.take_ended_transfer()?;
+        self.send(MSG_REPLY, Some(outgoing));
+        Some(transfer.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::gb::{
+        GameBoy,
+        gameboy::test_rom,
+        serial::{SB_ADDR, SC_ADDR, Serial},
+    };
+
+    // Runs a serial port until its transfer completes and returns the cycle it finished on
+    fn run_transfer(serial: &mut Serial, sb: u8, sc: u8) -> u64 {
+        serial.write(SB_ADDR, sb);
+        serial.write(SC_ADDR, sc);
+        let mut t_cycles = 0;
+        loop {
+            t_cycles += 4;
+            if serial.tick(4) {
+                return t_cycles;
+            }
+        }
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    // Runs two linked Game Boys, each on its own thread like two processes
+    // would, until both have run the given number of frames. Returns their
+    // first two bytes of WRAM.
+    fn run_linked(programs: [Vec<u8>; 2], frames: usize) -> [[u8; 2]; 2] {
+        let (stream_a, stream_b) = tcp_pair();
+        // Neither side hangs up until both are done, so a side that stops
+        // keeping time blocks the other instead of looking disconnected
+        let finished = Arc::new(AtomicUsize::new(0));
+        let (results, received) = mpsc::channel();
+        for (index, (program, stream)) in programs.into_iter().zip([stream_a, stream_b]).enumerate()
+        {
+            let finished = Arc::clone(&finished);
+            let results = results.clone();
+            thread::spawn(move || {
+                let mut gb = GameBoy::new(test_rom(&program, 2)).unwrap();
+                gb.connect_serial(Box::new(SocketLink::tcp(stream).unwrap()));
+                for _ in 0..frames {
+                    gb.run_frame();
+                }
+                finished.fetch_add(1, Ordering::SeqCst);
+                while finished.load(Ordering::SeqCst) < 2 {
+                    gb.run_frame();
+                }
+                let bus = gb.bus().borrow();
+                results
+                    .send((index, [bus.read(0xC000), bus.read(0xC001)]))
+                    .unwrap();
+            });
+        }
+
+        let mut wram = [[0; 2]; 2];
+        for _ in 0..2 {
+            let (index, bytes) = received
+                .recv_timeout(Duration::from_secs(30))
+                .expect("linked Game Boys stopped making progress");
+            wram[index] = bytes;
+        }
+        wram
+    }
+
+    #[test]
+    fn test_tcp_link() {
+        let (master_stream, slave_stream) = tcp_pair();
+
+        let slave = thread::spawn(move || {
+            let mut serial = Serial::new();
+            serial.connect(Box::new(SocketLink::tcp(slave_stream).unwrap()));
+            let finished = run_transfer(&mut serial, 0x18, 0x80);
+            (finished, serial.read(SB_ADDR))
+        });
+
+        let mut serial = Serial::new();
+        serial.connect(Box::new(SocketLink::tcp(master_stream).unwrap()));
+        let master_finished = run_transfer(&mut serial, 0x12, 0x81);
+        let master_received = serial.read(SB_ADDR);
+
+        let (slave_finished, slave_received) = slave.join().unwrap();
+        assert_eq!(master_received, 0x18);
+        assert_eq!(slave_received, 0x12);
+        assert_eq!(master_finished, slave_finished);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_link_slave_not_listening() {
+        use std::os::unix::net::UnixStream;
+
+        let (master_stream, slave_stream) = UnixStream::pair().unwrap();
+
+        let slave = thread::spawn(move || {
+            let mut serial = Serial::new();
+            serial.connect(Box::new(SocketLink::unix(slave_stream).unwrap()));
+            serial.write(SB_ADDR, 0x18);
+            // Not listening for the first transfer, which the master sees as an open line
+            for _ in 0..2048 {
+                serial.tick(4);
+            }
+            run_transfer(&mut serial, 0x18, 0x80)
+        });
+
+        let mut serial = Serial::new();
+        serial.connect(Box::new(SocketLink::unix(master_stream).unwrap()));
+        let first_finished = run_transfer(&mut serial, 0x12, 0x81);
+        assert_eq!(serial.read(SB_ADDR), 0xFF);
+
+        for _ in 0..1024 {
+            serial.tick(4);
+        }
+        let second_finished = run_transfer(&mut serial, 0x34, 0x81);
+        assert_eq!(serial.read(SB_ADDR), 0x18);
+
+        let slave_finished = slave.join().unwrap();
+        assert_eq!(
+            first_finished + 4096 + second_finished,
+            8192 + slave_finished
+        );
+    }
+
+    #[test]
+    fn test_linked_game_boys_swap_clocks() {
+        // Each side sends one byte on its own clock and receives one on the
+        // other's, in opposite orders, keeping what it received in WRAM
+        let listen_then_send = crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, $A1",
+            "ldh [$01], a",
+            "ld a, $80",
+            "ldh [$02], a",
+            "Wait1: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait1",
+            "ldh a, [$01]",
+            "ld [$C000], a",
+            "ld a, $A2",
+            "ldh [$01], a",
+            "ld a, $81",
+            "ldh [$02], a",
+            "Wait2: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait2",
+            "ldh a, [$01]",
+            "ld [$C001], a",
+            "Done: jr Done",
+        );
+        let send_then_listen = crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, $B1",
+            "ldh [$01], a",
+            "ld a, $81",
+            "ldh [$02], a",
+            "Wait1: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait1",
+            "ldh a, [$01]",
+            "ld [$C000], a",
+            "ld a, $B2",
+            "ldh [$01], a",
+            "ld a, $80",
+            "ldh [$02], a",
+            "Wait2: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait2",
+            "ldh a, [$01]",
+            "ld [$C001], a",
+            "Done: jr Done",
+        );
+        let [a, b] = run_linked([listen_then_send, send_then_listen], 5);
+        assert_eq!(a, [0xB1, 0xB2]);
+        assert_eq!(b, [0xA1, 0xA2]);
+    }
+
+    #[test]
+    fn test_linked_game_boy_halted_or_stopped() {
+        let sender = crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, $42",
+            "ldh [$01], a",
+            "ld a, $81",
+            "ldh [$02], a",
+            "Wait: ldh a, [$02]",
+            "bit 7, a",
+            "jr nz, Wait",
+            "ldh a, [$01]",
+            "ld [$C000], a",
+            "Done: jr Done",
+        );
+        // Neither wakes up: no interrupts are enabled and no buttons pressed
+        let halted =
+            crate::gb_asm!(origin = 0x0100; "xor a", "ldh [$FF], a", "halt", "Done: jr Done");
+        let stopped =
+            crate::gb_asm!(origin = 0x0100; "ld a, $30", "ldh [$00], a", "stop", "Done: jr Done");
+
+        // The sleeping side still keeps time, so the sender runs on and sees an open line
+        for sleeper in [halted, stopped] {
+            let [sender, _] = run_linked([sender.clone(), sleeper], 60);
+            assert_eq!(sender[0], 0xFF);
+        }
+    }
+}
@@ -1 +1,30 @@
-mod cpu;
+pub mod apu;
+pub mod bus;
+pub mod call_stack;
+pub mod cartridge;
+pub mod color;
+pub mod cpu;
+pub mod crc;
+pub mod debugger;
+pub mod gameboy;
+pub mod gdb;
+pub mod hdma;
+pub mod interrupts;
+pub mod joypad;
+pub mod link;
+pub mod model;
+pub mod movie;
+pub mod png;
+pub mod ppu;
+pub mod profiler;
+pub mod rewind;
+pub mod rom_disasm;
+pub mod rtc;
+pub mod serial;
+pub mod sgb;
+pub mod state;
+pub mod symbols;
+pub mod timer;
+
+pub use gameboy::GameBoy;
+pub use model::Model;
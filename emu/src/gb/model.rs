@@ -0,0 +1,142 @@
+use crate::gb::{
+    apu::NR52_ADDR,
+    cartridge::CartridgeHeader,
+    cpu::registers::{Register16Bit, Registers},
+    ppu::{BGP_ADDR, LCDC_ADDR},
+};
+
+// Sound and LCD registers the boot ROM leaves behind, written in order. The
+// chime ends with channel 1 still on at zero volume, so NR52 reads $F1.
+const POST_BOOT_IO: [(usize, u8); 9] = [
+    (NR52_ADDR, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0x08),
+    (0xFF14, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (LCDC_ADDR, 0x91),
+    (BGP_ADDR, 0xFC),
+];
+
+// The SGB boot ROM plays no chime, so channel 1 stays off and NR52 reads $F0
+const SGB_POST_BOOT_IO: [(usize, u8); 7] = [
+    (NR52_ADDR, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (LCDC_ADDR, 0x91),
+    (BGP_ADDR, 0xFC),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    // Original DMG with the early boot ROM
+    Dmg0,
+    #[default]
+    Dmg,
+    // Game Boy Pocket
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    // Game Boy Advance running Game Boy software
+    Agb,
+}
+
+impl Model {
+    // Picks the hardware a cartridge was made for from its CGB and SGB flags
+    pub fn from_header(header: &CartridgeHeader) -> Self {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else if header.supports_sgb() {
+            Model::Sgb
+        } else {
+            Model::Dmg
+        }
+    }
+
+    #[inline]
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+
+    #[inline]
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    // Register values the boot ROM leaves behind when it jumps to 0x0100
+    pub fn post_boot_registers(&self, cgb_mode: bool) -> Registers {
+        let (af, bc, de, hl) = match (self, cgb_mode) {
+            (Model::Dmg0, _) => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            (Model::Dmg, _) => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            (Model::Mgb, _) => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+            (Model::Sgb, _) => (0x0100, 0x0014, 0x0000, 0xC060),
+            (Model::Sgb2, _) => (0xFF00, 0x0014, 0x0000, 0xC060),
+            (Model::Cgb, true) => (0x1180, 0x0000, 0xFF56, 0x000D),
+            (Model::Cgb, false) => (0x1180, 0x0000, 0x0008, 0x007C),
+            // The GBA boot ROM sets bit 0 of B and leaves the flags clear, which games check for
+            (Model::Agb, true) => (0x1100, 0x0100, 0xFF56, 0x000D),
+            (Model::Agb, false) => (0x1100, 0x0100, 0x0008, 0x007C),
+        };
+
+        let mut registers = Registers::new();
+        registers.set_register_16bit(Register16Bit::AF, af);
+        registers.set_register_16bit(Register16Bit::BC, bc);
+        registers.set_register_16bit(Register16Bit::DE, de);
+        registers.set_register_16bit(Register16Bit::HL, hl);
+        registers.set_register_16bit(Register16Bit::SP, 0xFFFE);
+        registers.set_register_16bit(Register16Bit::PC, 0x0100);
+        registers
+    }
+
+    // Internal timer counter when the boot ROM hands over; DIV is its upper byte
+    pub fn post_boot_timer_counter(&self) -> u16 {
+        match self {
+            // The DMG-0 boot ROM skips the logo check delay and finishes much earlier
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 => 0x0000,
+            Model::Cgb | Model::Agb => 0x1EA0,
+        }
+    }
+
+    // IO register writes that leave the devices the way the boot ROM hands
+    // them over. The PPU registers are the same on every model.
+    pub fn post_boot_io(&self) -> &'static [(usize, u8)] {
+        if self.is_sgb() {
+            &SGB_POST_BOOT_IO
+        } else {
+            &POST_BOOT_IO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_header() {
+        let mut rom = vec![0x00; 0x150];
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(Model::from_header(&header), Model::Dmg);
+
+        rom[0x146] = 0x03;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(Model::from_header(&header), Model::Sgb);
+
+        rom[0x143] = 0x80;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(Model::from_header(&header), Model::Cgb);
+    }
+
+    #[test]
+    fn test_agb_flags() {
+        let registers = Model::Agb.post_boot_registers(true);
+        assert_eq!(registers.get_register_16bit(Register16Bit::BC) >> 8, 0x01);
+        assert_eq!(registers.get_register_16bit(Register16Bit::AF) & 0xFF, 0x00);
+    }
+}
@@ -0,0 +1,342 @@
+// BizHawk .bk2 movies: a zip archive holding a text header, the core's sync
+// settings as JSON and an input log with one line per frame
+use miniz_oxide::inflate::decompress_to_vec;
+
+use crate::gb::{
+    cartridge::CartridgeHeader,
+    joypad::ButtonState,
+    model::Model,
+    movie::{Movie, MovieError, MovieStart},
+};
+
+const EOCD_SIGNATURE: u32 = 0x06054B50;
+const CENTRAL_SIGNATURE: u32 = 0x02014B50;
+const LOCAL_SIGNATURE: u32 = 0x04034B50;
+
+// Cores whose frames end at VBlank like ours
+const SUPPORTED_CORES: [&str; 2] = ["Gambatte", "GBHawk"];
+
+pub fn import(data: &[u8], header: &CartridgeHeader) -> Result<Movie, MovieError> {
+    let archive = ZipArchive::new(data)?;
+    let header_txt = archive.text("Header.txt")?;
+    let input_log = archive.text("Input Log.txt")?;
+    let sync_settings = archive.text("SyncSettings.json").unwrap_or_default();
+
+    let value = |key: &str| {
+        header_txt
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+            .map(str::trim)
+    };
+    let platform = value("Platform").unwrap_or("GB");
+    if !matches!(platform, "GB" | "GBC") {
+        return Err(MovieError::Unsupported(format!("platform {}", platform)));
+    }
+    let core = value("Core").unwrap_or("Gambatte");
+    if !SUPPORTED_CORES.contains(&core) {
+        return Err(MovieError::Unsupported(format!("core {}", core)));
+    }
+    if value("StartsFromSavestate").is_some_and(|flag| flag.eq_ignore_ascii_case("true")) {
+        return Err(MovieError::Unsupported(
+            "starts from a BizHawk save state".to_string(),
+        ));
+    }
+
+    let model = sync_settings_model(&sync_settings, header)?;
+    let start = match archive.file("SaveRam") {
+        Ok(sram) => MovieStart::Sram(sram),
+        Err(_) => MovieStart::PowerOn { ram_seed: 0 },
+    };
+
+    let mut movie = Movie::new(header, model, start);
+    movie.frames = parse_input_log(&input_log)?;
+    Ok(movie)
+}
+
+fn sync_settings_model(json: &str, header: &CartridgeHeader) -> Result<Model, MovieError> {
+    // There's no boot ROM here, and its timing would shift the whole movie
+    if json_value(json, "EnableBIOS") == Some("true") {
+        return Err(MovieError::Unsupported(
+            "EnableBIOS, recorded with a boot ROM".to_string(),
+        ));
+    }
+    if json_value(json, "RealTimeRTC") == Some("true") {
+        return Err(MovieError::Unsupported(
+            "RealTimeRTC, the clock followed the host".to_string(),
+        ));
+    }
+    if json_value(json, "EqualLengthFrames") == Some("true") {
+        return Err(MovieError::Unsupported(
+            "EqualLengthFrames, frames don't end at VBlank".to_string(),
+        ));
+    }
+    match json_value(json, "ConsoleMode") {
+        None | Some("0") => Ok(Model::from_header(header)),
+        Some("1") => Ok(Model::Dmg),
+        Some("2") => Ok(Model::Cgb),
+        Some("3") => Ok(Model::Agb),
+        Some(mode) => Err(MovieError::Unsupported(format!("ConsoleMode {}", mode))),
+    }
+}
+
+// Raw text of a scalar value anywhere in the JSON. Sync settings are a flat
+// object of numbers and booleans, so this is all that's needed.
+fn json_value<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("\"{}\"", key);
+    let rest = &json[json.find(&pattern)? + pattern.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?;
+    let end = rest.find([',', '}', '\n']).unwrap_or(rest.len());
+    Some(rest[..end].trim())
+}
+
+fn parse_input_log(log: &str) -> Result<Vec<ButtonState>, MovieError> {
+    let key = log
+        .lines()
+        .find_map(|line| line.strip_prefix("LogKey:"))
+        .ok_or(MovieError::Invalid("input log key"))?;
+    let names: Vec<&str> = key
+        .split(['#', '|'])
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let mut frames = Vec::new();
+    for line in log.lines().filter(|line| line.starts_with('|')) {
+        let marks: Vec<char> = line.chars().filter(|&c| c != '|').collect();
+        if marks.len() != names.len() {
+            return Err(MovieError::Invalid("input log line"));
+        }
+
+        let mut buttons = ButtonState::empty();
+        for (name, mark) in names.iter().zip(marks) {
+            if mark == '.' || mark == ' ' {
+                continue;
+            }
+            buttons |= match name.strip_prefix("P1 ").unwrap_or(name) {
+                "Up" => ButtonState::Up,
+                "Down" => ButtonState::Down,
+                "Left" => ButtonState::Left,
+                "Right" => ButtonState::Right,
+                "Start" => ButtonState::Start,
+                "Select" => ButtonState::Select,
+                "B" => ButtonState::B,
+                "A" => ButtonState::A,
+                "Power" => {
+                    return Err(MovieError::Unsupported(format!(
+                        "power cycle on frame {}",
+                        frames.len()
+                    )));
+                }
+                other => {
+                    return Err(MovieError::Unsupported(format!("input {}", other)));
+                }
+            };
+        }
+        frames.push(buttons);
+    }
+    Ok(frames)
+}
+
+// Just enough of the zip format to pull stored or deflated files out by name
+struct ZipArchive<'a> {
+    data: &'a [u8],
+    // Name, compression method, compressed size and local header offset
+    entries: Vec<(String, u16, usize, usize)>,
+}
+
+impl<'a> ZipArchive<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, MovieError> {
+        let eocd = (0..data.len().saturating_sub(21))
+            .rev()
+            .find(|&offset| read_u32(data, offset) == Some(EOCD_SIGNATURE))
+            .ok_or(MovieError::BadMagic)?;
+        let entry_count = read_u16(data, eocd + 10).ok_or(MovieError::UnexpectedEof)?;
+        let mut offset = read_u32(data, eocd + 16).ok_or(MovieError::UnexpectedEof)? as usize;
+
+        let mut entries = Vec::new();
+        for _ in 0..entry_count {
+            if read_u32(data, offset) != Some(CENTRAL_SIGNATURE) {
+                return Err(MovieError::Invalid("zip central directory"));
+            }
+            let field = |at: usize| read_u16(data, offset + at).ok_or(MovieError::UnexpectedEof);
+            let method = field(10)?;
+            let compressed_size =
+                read_u32(data, offset + 20).ok_or(MovieError::UnexpectedEof)? as usize;
+            let name_len = field(28)? as usize;
+            let extra_len = field(30)? as usize;
+            let comment_len = field(32)? as usize;
+            let local_offset =
+                read_u32(data, offset + 42).ok_or(MovieError::UnexpectedEof)? as usize;
+            let name = data
+                .get(offset + 46..offset + 46 + name_len)
+                .ok_or(MovieError::UnexpectedEof)?;
+            entries.push((
+                String::from_utf8_lossy(name).into_owned(),
+                method,
+                compressed_size,
+                local_offset,
+            ));
+            offset += 46 + name_len + extra_len + comment_len;
+        }
+        Ok(Self { data, entries })
+    }
+
+    fn file(&self, name: &str) -> Result<Vec<u8>, MovieError> {
+        let &(_, method, size, offset) = self
+            .entries
+            .iter()
+            .find(|entry| entry.0 == name)
+            .ok_or(MovieError::Invalid("missing file in archive"))?;
+        if read_u32(self.data, offset) != Some(LOCAL_SIGNATURE) {
+            return Err(MovieError::Invalid("zip local header"));
+        }
+        let name_len = read_u16(self.data, offset + 26).ok_or(MovieError::UnexpectedEof)?;
+        let extra_len = read_u16(self.data, offset + 28).ok_or(MovieError::UnexpectedEof)?;
+        let start = offset + 30 + name_len as usize + extra_len as usize;
+        let contents = self
+            .data
+            .get(start..start + size)
+            .ok_or(MovieError::UnexpectedEof)?;
+        match method {
+            0 => Ok(contents.to_vec()),
+            8 => decompress_to_vec(contents).map_err(|_| MovieError::Invalid("deflate stream")),
+            _ => Err(MovieError::Unsupported(format!(
+                "zip compression method {}",
+                method
+            ))),
+        }
+    }
+
+    fn text(&self, name: &str) -> Result<String, MovieError> {
+        String::from_utf8(self.file(name)?).map_err(|_| MovieError::Invalid("text file"))
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+
+    // Deflated zip archive with the given files
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut central = Vec::new();
+        for (name, contents) in files {
+            let compressed = compress_to_vec(contents.as_bytes(), 6);
+            let offset = data.len() as u32;
+            data.extend_from_slice(&LOCAL_SIGNATURE.to_le_bytes());
+            data.extend_from_slice(&[0x00; 4]);
+            data.extend_from_slice(&8u16.to_le_bytes());
+            data.extend_from_slice(&[0x00; 16]);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&0u16.to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&compressed);
+
+            central.extend_from_slice(&CENTRAL_SIGNATURE.to_le_bytes());
+            central.extend_from_slice(&[0x00; 6]);
+            central.extend_from_slice(&8u16.to_le_bytes());
+            central.extend_from_slice(&[0x00; 8]);
+            central.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0x00; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = data.len() as u32;
+        data.extend_from_slice(&central);
+        data.extend_from_slice(&EOCD_SIGNATURE.to_le_bytes());
+        data.extend_from_slice(&[0x00; 6]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        data.extend_from_slice(&central_offset.to_le_bytes());
+        data.extend_from_slice(&[0x00; 2]);
+        data
+    }
+
+    fn test_header() -> CartridgeHeader {
+        CartridgeHeader::parse(&[0x00; 0x150]).unwrap()
+    }
+
+    const INPUT_LOG: &str = "[Input]\n\
+        LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|\n\
+        |.........|\n\
+        |U..R...A.|\n\
+        |....S....|\n\
+        [/Input]\n";
+
+    #[test]
+    fn test_import() {
+        let data = zip(&[
+            (
+                "Header.txt",
+                "MovieVersion BizHawk v2.0\nPlatform GB\nCore Gambatte\n",
+            ),
+            ("Input Log.txt", INPUT_LOG),
+            (
+                "SyncSettings.json",
+                "{\"o\":{\"EnableBIOS\":false,\"ConsoleMode\":2,\"RealTimeRTC\":false}}",
+            ),
+        ]);
+        let movie = import(&data, &test_header()).unwrap();
+        assert_eq!(movie.model, Model::Cgb);
+        assert_eq!(
+            movie.frames,
+            vec![
+                ButtonState::empty(),
+                ButtonState::Up | ButtonState::Right | ButtonState::A,
+                ButtonState::Start,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let import_with = |header: &str, sync: &str, log: &str| {
+            let data = zip(&[
+                ("Header.txt", header),
+                ("Input Log.txt", log),
+                ("SyncSettings.json", sync),
+            ]);
+            import(&data, &test_header()).err()
+        };
+        let gambatte = "Platform GB\nCore Gambatte\n";
+
+        assert_eq!(
+            import_with("Platform GB\nCore SubGBHawk\n", "{}", INPUT_LOG),
+            Some(MovieError::Unsupported("core SubGBHawk".to_string()))
+        );
+        assert!(matches!(
+            import_with(gambatte, "{\"EnableBIOS\": true}", INPUT_LOG),
+            Some(MovieError::Unsupported(setting)) if setting.starts_with("EnableBIOS")
+        ));
+        assert!(matches!(
+            import_with(gambatte, "{\"RealTimeRTC\":true}", INPUT_LOG),
+            Some(MovieError::Unsupported(setting)) if setting.starts_with("RealTimeRTC")
+        ));
+        let reset =
+            "LogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|\n|.........|\n|........P|\n";
+        assert_eq!(
+            import_with(gambatte, "{}", reset),
+            Some(MovieError::Unsupported(
+                "power cycle on frame 1".to_string()
+            ))
+        );
+        assert_eq!(
+            import(b"not a zip file at all, no", &test_header()).err(),
+            Some(MovieError::BadMagic)
+        );
+    }
+}
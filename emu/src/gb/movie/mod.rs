@@ -0,0 +1,417 @@
+use std::{error::Error, fmt};
+
+use crate::gb::{
+    GameBoy,
+    cartridge::{Cartridge, CartridgeError, CartridgeHeader},
+    joypad::ButtonState,
+    model::Model,
+    state::StateError,
+};
+
+pub mod bk2;
+pub mod vbm;
+
+pub const MOVIE_MAGIC: &[u8; 4] = b"RRMV";
+pub const MOVIE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    Invalid(&'static str),
+    // Imported movies made with settings this emulator can't reproduce
+    Unsupported(String),
+    // The movie was recorded with a different game
+    WrongRom,
+    Cartridge(CartridgeError),
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::BadMagic => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::UnexpectedEof => write!(f, "movie file is truncated"),
+            MovieError::Invalid(field) => write!(f, "invalid value for {} in movie", field),
+            MovieError::Unsupported(setting) => write!(f, "unsupported movie setting: {}", setting),
+            MovieError::WrongRom => write!(f, "movie was recorded with a different ROM"),
+            MovieError::Cartridge(err) => write!(f, "{}", err),
+            MovieError::State(err) => write!(f, "movie start state: {}", err),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<CartridgeError> for MovieError {
+    fn from(err: CartridgeError) -> Self {
+        MovieError::Cartridge(err)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        MovieError::State(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieStart {
+    // A fresh machine with work RAM filled from the seed
+    PowerOn { ram_seed: u64 },
+    // Power on with the battery backed cartridge RAM preloaded
+    Sram(Vec<u8>),
+    SaveState(Vec<u8>),
+}
+
+// Joypad input for every frame from a known starting point. Since the RTC
+// only counts emulated cycles, replaying it always ends in the same state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub model: Model,
+    pub title: String,
+    pub header_checksum: u8,
+    pub start: MovieStart,
+    pub frames: Vec<ButtonState>,
+}
+
+impl Movie {
+    pub fn new(header: &CartridgeHeader, model: Model, start: MovieStart) -> Self {
+        Self {
+            model,
+            title: header.title.clone(),
+            header_checksum: header.header_checksum,
+            start,
+            frames: Vec::new(),
+        }
+    }
+
+    // Builds the machine as it was when recording started
+    pub fn start_machine(&self, rom: Vec<u8>) -> Result<GameBoy, MovieError> {
+        let mut cartridge = Cartridge::from_rom(rom)?;
+        let header = cartridge.header();
+        if header.title != self.title || header.header_checksum != self.header_checksum {
+            return Err(MovieError::WrongRom);
+        }
+
+        if let MovieStart::Sram(sram) = &self.start {
+            cartridge.load_ram(sram);
+        }
+        let mut gb = GameBoy::from_cartridge(cartridge, self.model);
+        match &self.start {
+            MovieStart::PowerOn { ram_seed } => gb.bus().borrow_mut().seed_ram(*ram_seed),
+            MovieStart::Sram(_) => {}
+            MovieStart::SaveState(state) => gb.load_state(state)?,
+        }
+        Ok(gb)
+    }
+
+    // Replays every frame and hands back the machine at the end
+    pub fn play(&self, rom: Vec<u8>) -> Result<GameBoy, MovieError> {
+        let mut gb = self.start_machine(rom)?;
+        let mut player = MoviePlayer::new(self);
+        while player.run_frame(&mut gb) {}
+        Ok(gb)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MOVIE_MAGIC);
+        out.extend_from_slice(&MOVIE_VERSION.to_le_bytes());
+        out.push(self.model as u8);
+        out.push(self.title.len() as u8);
+        out.extend_from_slice(self.title.as_bytes());
+        out.push(self.header_checksum);
+        match &self.start {
+            MovieStart::PowerOn { ram_seed } => {
+                out.push(0);
+                out.extend_from_slice(&ram_seed.to_le_bytes());
+            }
+            MovieStart::SaveState(state) => {
+                out.push(1);
+                out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                out.extend_from_slice(state);
+            }
+            MovieStart::Sram(sram) => {
+                out.push(2);
+                out.extend_from_slice(&(sram.len() as u32).to_le_bytes());
+                out.extend_from_slice(sram);
+            }
+        }
+        out.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        out.extend(self.frames.iter().map(|buttons| buttons.bits()));
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        if data.len() < MOVIE_MAGIC.len() || &data[..MOVIE_MAGIC.len()] != MOVIE_MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let mut reader = MovieReader {
+            data,
+            pos: MOVIE_MAGIC.len(),
+        };
+        let version = u16::from_le_bytes(reader.array()?);
+        if version == 0 || version > MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let model = match reader.u8()? {
+            0 => Model::Dmg0,
+            1 => Model::Dmg,
+            2 => Model::Mgb,
+            3 => Model::Sgb,
+            4 => Model::Sgb2,
+            5 => Model::Cgb,
+            6 => Model::Agb,
+            _ => return Err(MovieError::Invalid("model")),
+        };
+        let title_len = reader.u8()? as usize;
+        let title = String::from_utf8(reader.take(title_len)?.to_vec())
+            .map_err(|_| MovieError::Invalid("title"))?;
+        let header_checksum = reader.u8()?;
+        let start = match reader.u8()? {
+            0 => MovieStart::PowerOn {
+                ram_seed: u64::from_le_bytes(reader.array()?),
+            },
+            1 => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                MovieStart::SaveState(reader.take(len)?.to_vec())
+            }
+            2 => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                MovieStart::Sram(reader.take(len)?.to_vec())
+            }
+            _ => return Err(MovieError::Invalid("start")),
+        };
+        let frame_count = u32::from_le_bytes(reader.array()?) as usize;
+        let frames = reader
+            .take(frame_count)?
+            .iter()
+            .map(|&bits| ButtonState::from_bits_retain(bits))
+            .collect();
+
+        Ok(Self {
+            model,
+            title,
+            header_checksum,
+            start,
+            frames,
+        })
+    }
+}
+
+struct MovieReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MovieReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MovieError> {
+        let end = self.pos.checked_add(len).ok_or(MovieError::UnexpectedEof)?;
+        let slice = self
+            .data
+            .get(self.pos..end)
+            .ok_or(MovieError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, MovieError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], MovieError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+// Runs frames on a machine and writes down the input used for each
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    // Records from a freshly powered on machine
+    pub fn power_on(
+        rom: Vec<u8>,
+        model: Model,
+        ram_seed: u64,
+    ) -> Result<(GameBoy, Self), CartridgeError> {
+        let cartridge = Cartridge::from_rom(rom)?;
+        let movie = Movie::new(cartridge.header(), model, MovieStart::PowerOn { ram_seed });
+        let gb = GameBoy::from_cartridge(cartridge, model);
+        gb.bus().borrow_mut().seed_ram(ram_seed);
+        Ok((gb, Self { movie }))
+    }
+
+    // Records from wherever the machine is now
+    pub fn from_state(gb: &GameBoy) -> Self {
+        let bus = gb.bus().borrow();
+        let header = bus
+            .cartridge()
+            .expect("recording needs a cartridge")
+            .header();
+        Self {
+            movie: Movie::new(header, gb.model(), MovieStart::SaveState(gb.save_state())),
+        }
+    }
+
+    pub fn run_frame(&mut self, gb: &mut GameBoy, buttons: ButtonState) {
+        gb.set_buttons(buttons);
+        gb.run_frame();
+        self.movie.frames.push(buttons);
+    }
+
+    #[inline]
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    #[inline]
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct MoviePlayer<'a> {
+    movie: &'a Movie,
+    frame: usize,
+}
+
+impl<'a> MoviePlayer<'a> {
+    pub fn new(movie: &'a Movie) -> Self {
+        Self { movie, frame: 0 }
+    }
+
+    // Runs the next recorded frame, or returns false once the movie is over
+    pub fn run_frame(&mut self, gb: &mut GameBoy) -> bool {
+        let Some(&buttons) = self.movie.frames.get(self.frame) else {
+            return false;
+        };
+        gb.set_buttons(buttons);
+        gb.run_frame();
+        self.frame += 1;
+        true
+    }
+
+    #[inline]
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::gameboy::test_rom;
+
+    // Selects the d-pad and enables VBlank, then every frame adds the joypad
+    // lines into a running sum in work RAM: ld a, 0x01; ldh [0xFF], a;
+    // ld a, 0x20; ldh [0x00], a; ei; loop: ldh a, [0x00]; ld hl, 0xC000;
+    // add a, [hl]; add a, [hl]; ld [hl], a; ld [0x8000], a; halt; nop; jr loop
+    fn movie_rom() -> Vec<u8> {
+        let mut rom = test_rom(
+            &[
+                0x3E, 0x01, 0xE0, 0xFF, 0x3E, 0x20, 0xE0, 0x00, 0xFB, 0xF0, 0x00, 0x21, 0x00, 0xC0,
+                0x86, 0x86, 0x77, 0xEA, 0x00, 0x80, 0x76, 0x00, 0x18, 0xF1,
+            ],
+            2,
+        );
+        // VBlank handler just returns with interrupts enabled
+        rom[0x40] = 0xD9;
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        rom
+    }
+
+    fn inputs() -> Vec<ButtonState> {
+        (0..30)
+            .map(|i| match i % 3 {
+                0 => ButtonState::A,
+                1 => ButtonState::Right | ButtonState::Up,
+                _ => ButtonState::empty(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let (mut gb, mut recorder) = MovieRecorder::power_on(movie_rom(), Model::Dmg, 42).unwrap();
+        for buttons in inputs() {
+            recorder.run_frame(&mut gb, buttons);
+        }
+        let movie = recorder.finish();
+        assert_eq!(movie.frames.len(), 30);
+
+        let replayed = Movie::from_bytes(&movie.to_bytes())
+            .unwrap()
+            .play(movie_rom())
+            .unwrap();
+        assert_eq!(replayed.framebuffer_hash(), gb.framebuffer_hash());
+        assert_eq!(replayed.save_state(), gb.save_state());
+
+        // Another RAM seed starts the sum elsewhere and ends somewhere else
+        let mut other = movie.clone();
+        other.start = MovieStart::PowerOn { ram_seed: 7 };
+        let other = other.play(movie_rom()).unwrap();
+        assert_ne!(other.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn test_record_from_state() {
+        let mut gb = GameBoy::new(movie_rom()).unwrap();
+        for _ in 0..5 {
+            gb.run_frame();
+        }
+        let mut recorder = MovieRecorder::from_state(&gb);
+        for buttons in inputs() {
+            recorder.run_frame(&mut gb, buttons);
+        }
+        let movie = recorder.finish();
+
+        let replayed = movie.play(movie_rom()).unwrap();
+        assert_eq!(replayed.save_state(), gb.save_state());
+    }
+
+    #[test]
+    fn test_sram_start() {
+        let mut rom = movie_rom();
+        // MBC1 with RAM and a battery
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        let movie = Movie::new(&header, Model::Dmg, MovieStart::Sram(vec![0x42; 0x2000]));
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+
+        let gb = movie.start_machine(rom).unwrap();
+        let bus = gb.bus().borrow();
+        assert_eq!(bus.cartridge().unwrap().ram()[0x1FFF], 0x42);
+    }
+
+    #[test]
+    fn test_errors() {
+        let (_, recorder) = MovieRecorder::power_on(movie_rom(), Model::Dmg, 0).unwrap();
+        let bytes = recorder.movie().to_bytes();
+        assert_eq!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::UnexpectedEof)
+        );
+        assert_eq!(Movie::from_bytes(b"RRGB"), Err(MovieError::BadMagic));
+
+        let mut rom = movie_rom();
+        rom[0x134] = b'X';
+        assert_eq!(
+            recorder.movie().start_machine(rom).err(),
+            Some(MovieError::WrongRom)
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// VisualBoyAdvance(-M) .vbm movies: a 64 byte header, optional start data
+// and two bytes of input per controller per frame
+use crate::gb::{
+    cartridge::CartridgeHeader,
+    joypad::ButtonState,
+    model::Model,
+    movie::{Movie, MovieError, MovieStart},
+};
+
+const VBM_MAGIC: &[u8; 4] = b"VBM\x1A";
+const HEADER_SIZE: usize = 0x40;
+
+const START_SAVESTATE: u8 = 0b01;
+const START_SRAM: u8 = 0b10;
+
+const SYSTEM_GBA: u8 = 0b001;
+const SYSTEM_GBC: u8 = 0b010;
+const SYSTEM_SGB: u8 = 0b100;
+
+// Bit 2 of the options byte. Bit 0 asks for a BIOS file, which only GBA movies use.
+const OPTION_RTC: u8 = 0b00000100;
+
+// Input bits, in VBA's order
+const INPUT_BUTTONS: [(u16, ButtonState); 8] = [
+    (0x0001, ButtonState::A),
+    (0x0002, ButtonState::B),
+    (0x0004, ButtonState::Select),
+    (0x0008, ButtonState::Start),
+    (0x0010, ButtonState::Right),
+    (0x0020, ButtonState::Left),
+    (0x0040, ButtonState::Up),
+    (0x0080, ButtonState::Down),
+];
+const INPUT_RESET: u16 = 0x0C00;
+
+pub fn import(data: &[u8], header: &CartridgeHeader) -> Result<Movie, MovieError> {
+    if data.len() < VBM_MAGIC.len() || &data[..VBM_MAGIC.len()] != VBM_MAGIC {
+        return Err(MovieError::BadMagic);
+    }
+    if data.len() < HEADER_SIZE {
+        return Err(MovieError::UnexpectedEof);
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    let version = u32_at(0x04);
+    if version != 1 {
+        return Err(MovieError::UnsupportedVersion(version as u16));
+    }
+    let frame_count = u32_at(0x0C) as usize;
+    let start_flags = data[0x14];
+    let controllers = data[0x15];
+    let system = data[0x16];
+    let options = data[0x17];
+    let start_offset = u32_at(0x38) as usize;
+    let input_offset = u32_at(0x3C) as usize;
+
+    if system & SYSTEM_GBA != 0 {
+        return Err(MovieError::Unsupported("GBA movie".to_string()));
+    }
+    // VBA's RTC follows the host clock, so MBC3 movies made with it can't sync
+    if options & OPTION_RTC != 0 {
+        return Err(MovieError::Unsupported(
+            "recorded with the real-time clock enabled".to_string(),
+        ));
+    }
+    if controllers != 0b0001 {
+        return Err(MovieError::Unsupported(format!(
+            "controller flags {:#04X}, only controller 1 is supported",
+            controllers
+        )));
+    }
+
+    // The title field is 12 bytes, so longer titles only match on their start
+    let title: String = data[0x24..0x30]
+        .iter()
+        .take_while(|&&byte| byte != 0x00)
+        .map(|&byte| byte as char)
+        .collect();
+    if !header.title.starts_with(title.trim_end()) || data[0x31] != header.header_checksum {
+        return Err(MovieError::WrongRom);
+    }
+
+    let model = if system & SYSTEM_GBC != 0 {
+        Model::Cgb
+    } else if system & SYSTEM_SGB != 0 {
+        Model::Sgb
+    } else {
+        Model::Dmg
+    };
+
+    let start = match start_flags & (START_SAVESTATE | START_SRAM) {
+        0 => MovieStart::PowerOn { ram_seed: 0 },
+        START_SRAM => {
+            let sram = data
+                .get(start_offset..input_offset)
+                .ok_or(MovieError::Invalid("SRAM offset"))?;
+            MovieStart::Sram(sram.to_vec())
+        }
+        START_SAVESTATE => {
+            return Err(MovieError::Unsupported(
+                "starts from a VBA save state".to_string(),
+            ));
+        }
+        _ => return Err(MovieError::Invalid("start flags")),
+    };
+
+    let input = data.get(input_offset..).ok_or(MovieError::UnexpectedEof)?;
+    if input.len() < frame_count * 2 {
+        return Err(MovieError::UnexpectedEof);
+    }
+    let mut frames = Vec::with_capacity(frame_count);
+    for (frame, bytes) in input.chunks_exact(2).take(frame_count).enumerate() {
+        let bits = u16::from_le_bytes([bytes[0], bytes[1]]);
+        if bits & INPUT_RESET != 0 {
+            return Err(MovieError::Unsupported(format!("reset on frame {}", frame)));
+        }
+        let buttons = INPUT_BUTTONS
+            .iter()
+            .filter(|(bit, _)| bits & bit != 0)
+            .fold(ButtonState::empty(), |buttons, (_, button)| {
+                buttons | *button
+            });
+        frames.push(buttons);
+    }
+
+    let mut movie = Movie::new(header, model, start);
+    movie.frames = frames;
+    Ok(movie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_header() -> CartridgeHeader {
+        let mut rom = vec![0x00; 0x150];
+        rom[0x134..0x13C].copy_from_slice(b"VBMTITLE");
+        rom[0x14D] = 0x5A;
+        CartridgeHeader::parse(&rom).unwrap()
+    }
+
+    fn test_vbm(frames: &[u16]) -> Vec<u8> {
+        let mut data = vec![0x00; HEADER_SIZE];
+        data[..4].copy_from_slice(VBM_MAGIC);
+        data[0x04] = 1;
+        data[0x0C..0x10].copy_from_slice(&(frames.len() as u32).to_le_bytes());
+        data[0x15] = 0b0001;
+        data[0x24..0x2C].copy_from_slice(b"VBMTITLE");
+        data[0x31] = 0x5A;
+        data[0x3C] = HEADER_SIZE as u8;
+        for frame in frames {
+            data.extend_from_slice(&frame.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_import() {
+        let movie = import(&test_vbm(&[0x0000, 0x0011, 0x0048]), &test_header()).unwrap();
+        assert_eq!(movie.model, Model::Dmg);
+        assert_eq!(movie.start, MovieStart::PowerOn { ram_seed: 0 });
+        assert_eq!(
+            movie.frames,
+            vec![
+                ButtonState::empty(),
+                ButtonState::A | ButtonState::Right,
+                ButtonState::Start | ButtonState::Up,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut data = test_vbm(&[0x0000]);
+        data[0x14] = START_SAVESTATE;
+        assert!(matches!(
+            import(&data, &test_header()),
+            Err(MovieError::Unsupported(_))
+        ));
+
+        let data = test_vbm(&[0x0000, 0x0800]);
+        assert_eq!(
+            import(&data, &test_header()).err(),
+            Some(MovieError::Unsupported("reset on frame 1".to_string()))
+        );
+
+        let mut data = test_vbm(&[0x0000]);
+        data[0x17] = OPTION_RTC;
+        assert_eq!(
+            import(&data, &test_header()).err(),
+            Some(MovieError::Unsupported(
+                "recorded with the real-time clock enabled".to_string()
+            ))
+        );
+
+        // useBiosFile only applies to GBA movies
+        let mut data = test_vbm(&[0x0000]);
+        data[0x17] = 0b00000001;
+        assert!(import(&data, &test_header()).is_ok());
+
+        let mut data = test_vbm(&[0x0000]);
+        data[0x31] = 0x00;
+        assert_eq!(
+            import(&data, &test_header()).err(),
+            Some(MovieError::WrongRom)
+        );
+    }
+}
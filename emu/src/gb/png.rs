@@ -0,0 +1,80 @@
+// Minimal PNG writer for screenshots: 8-bit RGB, one IDAT chunk, no filtering
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use crate::gb::crc::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const COLOR_TYPE_RGB: u8 = 2;
+const COMPRESSION_LEVEL: u8 = 6;
+
+// Encodes packed RGB888 pixels, row by row from the top
+pub fn encode_rgb(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row_bytes = width as usize * 3;
+    assert_eq!(
+        rgb.len(),
+        row_bytes * height as usize,
+        "pixel data doesn't match the size"
+    );
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, color type, then default compression, filter and interlace
+    ihdr.extend_from_slice(&[8, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    // Each row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity((row_bytes + 1) * height as usize);
+    for row in rgb.chunks(row_bytes.max(1)) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(
+        &mut png,
+        b"IDAT",
+        &compress_to_vec_zlib(&raw, COMPRESSION_LEVEL),
+    );
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    #[test]
+    fn test_encode() {
+        // 2x2: red, green / blue, white
+        let rgb = [
+            0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let png = encode_rgb(2, 2, &rgb);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let raw = decompress_to_vec_zlib(&png[41..41 + idat_len]).unwrap();
+        assert_eq!(raw[0], 0);
+        assert_eq!(raw[1..7], rgb[..6]);
+        assert_eq!(raw[7], 0);
+        assert_eq!(raw.len(), 14);
+    }
+}
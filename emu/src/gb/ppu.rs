@@ -0,0 +1,781 @@
+use bitflags::bitflags;
+use num_enum::TryFromPrimitive;
+
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const LCDC_ADDR: usize = 0xFF40;
+pub const STAT_ADDR: usize = 0xFF41;
+pub const SCY_ADDR: usize = 0xFF42;
+pub const SCX_ADDR: usize = 0xFF43;
+pub const LY_ADDR: usize = 0xFF44;
+pub const LYC_ADDR: usize = 0xFF45;
+pub const BGP_ADDR: usize = 0xFF47;
+pub const OBP0_ADDR: usize = 0xFF48;
+pub const OBP1_ADDR: usize = 0xFF49;
+pub const WY_ADDR: usize = 0xFF4A;
+pub const WX_ADDR: usize = 0xFF4B;
+pub const BCPS_ADDR: usize = 0xFF68;
+pub const BCPD_ADDR: usize = 0xFF69;
+pub const OCPS_ADDR: usize = 0xFF6A;
+pub const OCPD_ADDR: usize = 0xFF6B;
+
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SCAN_DOTS: u32 = 80;
+const DRAWING_DOTS: u32 = 172;
+const LINES_PER_FRAME: u8 = 154;
+
+const VRAM_BANK_SIZE: usize = 0x2000;
+
+// Shades of the DMG screen, lightest first, as RGB555
+const DMG_SHADES: [u16; 4] = [0x7FFF, 0x5294, 0x294A, 0x0000];
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Lcdc: u8 {
+        const BgWindowEnable = 0b00000001;
+        const ObjEnable = 0b00000010;
+        const ObjSize = 0b00000100;
+        const BgTileMap = 0b00001000;
+        const BgWindowTileData = 0b00010000;
+        const WindowEnable = 0b00100000;
+        const WindowTileMap = 0b01000000;
+        const LcdEnable = 0b10000000;
+    }
+
+    // CGB background map attributes and object attributes share a layout
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TileAttributes: u8 {
+        const CgbPalette = 0b00000111;
+        const Bank = 0b00001000;
+        const DmgPalette = 0b00010000;
+        const XFlip = 0b00100000;
+        const YFlip = 0b01000000;
+        const Priority = 0b10000000;
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuEvent: u8 {
+        const VBlank = 0b0001;
+        const Stat = 0b0010;
+        const HBlank = 0b0100;
+    }
+}
+
+impl TileAttributes {
+    #[inline]
+    pub fn palette(&self) -> u8 {
+        self.bits() & TileAttributes::CgbPalette.bits()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+pub struct Ppu {
+    vram: Vec<u8>,
+    vram_bank: usize,
+    oam: [u8; 0xA0],
+    lcdc: Lcdc,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    bg_palette_ram: [u8; 64],
+    obj_palette_ram: [u8; 64],
+    bcps: u8,
+    ocps: u8,
+    cgb_mode: bool,
+    mode: PpuMode,
+    dot: u32,
+    window_line: u8,
+    stat_line: bool,
+    framebuffer: Vec<u16>,
+    shades: Vec<u8>,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self::with_cgb_mode(false)
+    }
+
+    pub fn new_cgb() -> Self {
+        Self::with_cgb_mode(true)
+    }
+
+    fn with_cgb_mode(cgb_mode: bool) -> Self {
+        Self {
+            vram: vec![0x00; VRAM_BANK_SIZE * 2],
+            vram_bank: 0,
+            oam: [0x00; 0xA0],
+            lcdc: Lcdc::empty(),
+            stat: 0x00,
+            scy: 0x00,
+            scx: 0x00,
+            ly: 0x00,
+            lyc: 0x00,
+            bgp: 0x00,
+            obp0: 0x00,
+            obp1: 0x00,
+            wy: 0x00,
+            wx: 0x00,
+            bg_palette_ram: [0xFF; 64],
+            obj_palette_ram: [0x00; 64],
+            bcps: 0x00,
+            ocps: 0x00,
+            cgb_mode,
+            mode: PpuMode::HBlank,
+            dot: 0,
+            window_line: 0,
+            stat_line: false,
+            framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    // RGB555 pixels, row by row
+    #[inline]
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    // DMG shades 0-3 after palette mapping, row by row. Unused in CGB mode.
+    #[inline]
+    pub fn shades(&self) -> &[u8] {
+        &self.shades
+    }
+
+    // The 4 KiB the screen shows when tiles are laid out in order from the
+    // top left of the background map, as seen by SGB VRAM transfers
+    pub fn screen_vram_data(&self) -> Vec<u8> {
+        let map_base = if self.lcdc.contains(Lcdc::BgTileMap) {
+            0x1C00
+        } else {
+            0x1800
+        };
+        (0..256)
+            .flat_map(|tile| {
+                let tile_index = self.vram[map_base + (tile / 20) * 32 + tile % 20];
+                let tile_addr = self.bg_tile_addr(tile_index) & 0x1FFF;
+                self.vram[tile_addr..tile_addr + 16].iter().copied()
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn mode(&self) -> PpuMode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    #[inline]
+    pub fn read_vram(&self, addr: usize) -> u8 {
+        self.vram[self.vram_bank * VRAM_BANK_SIZE + (addr & 0x1FFF)]
+    }
+
+    #[inline]
+    pub fn write_vram(&mut self, addr: usize, data: u8) {
+        self.vram[self.vram_bank * VRAM_BANK_SIZE + (addr & 0x1FFF)] = data;
+    }
+
+    #[inline]
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    #[inline]
+    pub fn set_vram_bank(&mut self, bank: usize) {
+        self.vram_bank = bank & 0b1;
+    }
+
+    #[inline]
+    pub fn read_oam(&self, addr: usize) -> u8 {
+        self.oam[addr - 0xFE00]
+    }
+
+    #[inline]
+    pub fn write_oam(&mut self, addr: usize, data: u8) {
+        self.oam[addr - 0xFE00] = data;
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            LCDC_ADDR => self.lcdc.bits(),
+            STAT_ADDR => {
+                let coincidence = ((self.ly == self.lyc) as u8) << 2;
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            SCY_ADDR => self.scy,
+            SCX_ADDR => self.scx,
+            LY_ADDR => self.ly,
+            LYC_ADDR => self.lyc,
+            BGP_ADDR => self.bgp,
+            OBP0_ADDR => self.obp0,
+            OBP1_ADDR => self.obp1,
+            WY_ADDR => self.wy,
+            WX_ADDR => self.wx,
+            BCPS_ADDR => 0x40 | self.bcps,
+            BCPD_ADDR => self.bg_palette_ram[(self.bcps & 0x3F) as usize],
+            OCPS_ADDR => 0x40 | self.ocps,
+            OCPD_ADDR => self.obj_palette_ram[(self.ocps & 0x3F) as usize],
+            _ => unreachable!("Invalid PPU address {:#06X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        match addr {
+            LCDC_ADDR => {
+                let new_lcdc = Lcdc::from_bits_truncate(data);
+                if self.lcdc.contains(Lcdc::LcdEnable) && !new_lcdc.contains(Lcdc::LcdEnable) {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.window_line = 0;
+                    self.mode = PpuMode::HBlank;
+                } else if !self.lcdc.contains(Lcdc::LcdEnable) && new_lcdc.contains(Lcdc::LcdEnable)
+                {
+                    self.mode = PpuMode::OamScan;
+                }
+                self.lcdc = new_lcdc;
+            }
+            STAT_ADDR => self.stat = data & 0x78,
+            SCY_ADDR => self.scy = data,
+            SCX_ADDR => self.scx = data,
+            LY_ADDR => {}
+            LYC_ADDR => self.lyc = data,
+            BGP_ADDR => self.bgp = data,
+            OBP0_ADDR => self.obp0 = data,
+            OBP1_ADDR => self.obp1 = data,
+            WY_ADDR => self.wy = data,
+            WX_ADDR => self.wx = data,
+            BCPS_ADDR => self.bcps = data & 0xBF,
+            BCPD_ADDR => Self::write_palette_data(&mut self.bg_palette_ram, &mut self.bcps, data),
+            OCPS_ADDR => self.ocps = data & 0xBF,
+            OCPD_ADDR => Self::write_palette_data(&mut self.obj_palette_ram, &mut self.ocps, data),
+            _ => unreachable!("Invalid PPU address {:#06X}", addr),
+        }
+    }
+
+    // Writes through a palette specification register, bumping its index if bit 7 is set
+    fn write_palette_data(palette_ram: &mut [u8; 64], spec: &mut u8, data: u8) {
+        palette_ram[(*spec & 0x3F) as usize] = data;
+        if *spec & 0x80 != 0 {
+            *spec = 0x80 | (spec.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    // Advances the PPU by the given number of dots
+    pub fn tick(&mut self, dots: u32) -> PpuEvent {
+        let mut events = PpuEvent::empty();
+        if !self.lcdc.contains(Lcdc::LcdEnable) {
+            return events;
+        }
+
+        for _ in 0..dots {
+            self.dot += 1;
+
+            if self.ly < SCREEN_HEIGHT as u8 {
+                if self.dot == OAM_SCAN_DOTS {
+                    self.mode = PpuMode::Drawing;
+                } else if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                    self.render_scanline();
+                    self.mode = PpuMode::HBlank;
+                    events |= PpuEvent::HBlank;
+                }
+            }
+
+            if self.dot == DOTS_PER_LINE {
+                self.dot = 0;
+                self.ly = (self.ly + 1) % LINES_PER_FRAME;
+
+                if self.ly == SCREEN_HEIGHT as u8 {
+                    self.mode = PpuMode::VBlank;
+                    events |= PpuEvent::VBlank;
+                } else if self.ly < SCREEN_HEIGHT as u8 {
+                    if self.ly == 0 {
+                        self.window_line = 0;
+                    }
+                    self.mode = PpuMode::OamScan;
+                }
+            }
+
+            if self.update_stat_line() {
+                events |= PpuEvent::Stat;
+            }
+        }
+
+        events
+    }
+
+    // Returns true on a rising edge of the STAT interrupt line
+    fn update_stat_line(&mut self) -> bool {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || match self.mode {
+                PpuMode::HBlank => self.stat & 0x08 != 0,
+                PpuMode::VBlank => self.stat & 0x10 != 0,
+                PpuMode::OamScan => self.stat & 0x20 != 0,
+                PpuMode::Drawing => false,
+            };
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        rising
+    }
+
+    fn tile_row(&self, bank: usize, tile_addr: usize, row: usize) -> (u8, u8) {
+        let addr = bank * VRAM_BANK_SIZE + (tile_addr & 0x1FFF) + row * 2;
+        (self.vram[addr], self.vram[addr + 1])
+    }
+
+    fn bg_tile_addr(&self, tile_index: u8) -> usize {
+        if self.lcdc.contains(Lcdc::BgWindowTileData) {
+            0x8000 + tile_index as usize * 16
+        } else {
+            (0x9000 + (tile_index as i8 as i32) * 16) as usize
+        }
+    }
+
+    #[inline]
+    fn color_index(low: u8, high: u8, bit: usize) -> u8 {
+        (((high >> bit) & 0b1) << 1) | ((low >> bit) & 0b1)
+    }
+
+    #[inline]
+    fn apply_palette(palette: u8, color_index: u8) -> u8 {
+        (palette >> (color_index * 2)) & 0b11
+    }
+
+    #[inline]
+    fn cgb_color(palette_ram: &[u8; 64], palette: u8, color_index: u8) -> u16 {
+        let offset = palette as usize * 8 + color_index as usize * 2;
+        u16::from_le_bytes([palette_ram[offset], palette_ram[offset + 1]]) & 0x7FFF
+    }
+
+    fn render_scanline(&mut self) {
+        let ly = self.ly as usize;
+        let mut bg_indices = [0u8; SCREEN_WIDTH];
+        let mut bg_attributes = [TileAttributes::empty(); SCREEN_WIDTH];
+
+        // On CGB the background is always drawn and LCDC bit 0 only decides priority
+        if self.cgb_mode || self.lcdc.contains(Lcdc::BgWindowEnable) {
+            let window_visible =
+                self.lcdc.contains(Lcdc::WindowEnable) && self.ly >= self.wy && self.wx <= 166;
+
+            for x in 0..SCREEN_WIDTH {
+                let in_window = window_visible && x + 7 >= self.wx as usize;
+                let (map_base, map_x, map_y) = if in_window {
+                    let map = if self.lcdc.contains(Lcdc::WindowTileMap) {
+                        0x9C00
+                    } else {
+                        0x9800
+                    };
+                    (map, x + 7 - self.wx as usize, self.window_line as usize)
+                } else {
+                    let map = if self.lcdc.contains(Lcdc::BgTileMap) {
+                        0x9C00
+                    } else {
+                        0x9800
+                    };
+                    (
+                        map,
+                        (x + self.scx as usize) & 0xFF,
+                        (ly + self.scy as usize) & 0xFF,
+                    )
+                };
+
+                let map_addr = (map_base & 0x1FFF) + (map_y / 8) * 32 + map_x / 8;
+                let tile_index = self.vram[map_addr];
+                // Attributes live at the same map position in VRAM bank 1
+                let attributes = if self.cgb_mode {
+                    TileAttributes::from_bits_retain(self.vram[VRAM_BANK_SIZE + map_addr])
+                } else {
+                    TileAttributes::empty()
+                };
+
+                let mut row = map_y % 8;
+                if attributes.contains(TileAttributes::YFlip) {
+                    row = 7 - row;
+                }
+                let mut bit = 7 - map_x % 8;
+                if attributes.contains(TileAttributes::XFlip) {
+                    bit = map_x % 8;
+                }
+
+                let bank = attributes.contains(TileAttributes::Bank) as usize;
+                let (low, high) = self.tile_row(bank, self.bg_tile_addr(tile_index), row);
+                bg_indices[x] = Self::color_index(low, high, bit);
+                bg_attributes[x] = attributes;
+            }
+
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
+
+        let line_start = ly * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            if self.cgb_mode {
+                self.framebuffer[line_start + x] = Self::cgb_color(
+                    &self.bg_palette_ram,
+                    bg_attributes[x].palette(),
+                    bg_indices[x],
+                );
+            } else {
+                self.put_dmg_shade(line_start + x, Self::apply_palette(self.bgp, bg_indices[x]));
+            }
+        }
+
+        if self.lcdc.contains(Lcdc::ObjEnable) {
+            self.render_objects(&bg_indices, &bg_attributes);
+        }
+    }
+
+    // Whether the background pixel covers an object pixel drawn over it
+    fn bg_has_priority(
+        &self,
+        bg_index: u8,
+        bg_attributes: TileAttributes,
+        obj: TileAttributes,
+    ) -> bool {
+        if bg_index == 0 {
+            return false;
+        }
+        if self.cgb_mode && !self.lcdc.contains(Lcdc::BgWindowEnable) {
+            return false;
+        }
+        bg_attributes.contains(TileAttributes::Priority) || obj.contains(TileAttributes::Priority)
+    }
+
+    fn render_objects(
+        &mut self,
+        bg_indices: &[u8; SCREEN_WIDTH],
+        bg_attributes: &[TileAttributes; SCREEN_WIDTH],
+    ) {
+        let ly = self.ly as i32;
+        let height = if self.lcdc.contains(Lcdc::ObjSize) {
+            16
+        } else {
+            8
+        };
+
+        // At most 10 objects per line, in OAM order
+        let mut objects: Vec<usize> = (0..40)
+            .filter(|i| {
+                let y = self.oam[i * 4] as i32 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(10)
+            .collect();
+
+        // DMG gives priority to lower X and then OAM index, CGB only to OAM index.
+        // Draw in reverse priority so the winner ends up on top.
+        if !self.cgb_mode {
+            objects.sort_by_key(|i| (self.oam[i * 4 + 1], *i));
+        }
+        for i in objects.into_iter().rev() {
+            let y = self.oam[i * 4] as i32 - 16;
+            let x = self.oam[i * 4 + 1] as i32 - 8;
+            let mut tile_index = self.oam[i * 4 + 2];
+            let attributes = TileAttributes::from_bits_retain(self.oam[i * 4 + 3]);
+
+            let mut row = ly - y;
+            if attributes.contains(TileAttributes::YFlip) {
+                row = height - 1 - row;
+            }
+            if height == 16 {
+                tile_index &= 0xFE;
+            }
+            let bank = (self.cgb_mode && attributes.contains(TileAttributes::Bank)) as usize;
+            let (low, high) = self.tile_row(bank, 0x8000 + tile_index as usize * 16, row as usize);
+
+            for px in 0..8 {
+                let screen_x = x + px;
+                if !(0..SCREEN_WIDTH as i32).contains(&screen_x) {
+                    continue;
+                }
+                let bit = if attributes.contains(TileAttributes::XFlip) {
+                    px
+                } else {
+                    7 - px
+                };
+                let color_index = Self::color_index(low, high, bit as usize);
+                if color_index == 0 {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                if self.bg_has_priority(bg_indices[screen_x], bg_attributes[screen_x], attributes) {
+                    continue;
+                }
+
+                let offset = self.ly as usize * SCREEN_WIDTH + screen_x;
+                if self.cgb_mode {
+                    self.framebuffer[offset] =
+                        Self::cgb_color(&self.obj_palette_ram, attributes.palette(), color_index);
+                } else {
+                    let palette = if attributes.contains(TileAttributes::DmgPalette) {
+                        self.obp1
+                    } else {
+                        self.obp0
+                    };
+                    self.put_dmg_shade(offset, Self::apply_palette(palette, color_index));
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn put_dmg_shade(&mut self, offset: usize, shade: u8) {
+        self.shades[offset] = shade;
+        self.framebuffer[offset] = DMG_SHADES[shade as usize];
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// CGB mode is fixed when the PPU is built, so the bus checks it instead
+impl SaveState for Ppu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.vram);
+        writer.put_u8(self.vram_bank as u8);
+        writer.put_bytes(&self.oam);
+        for register in [
+            self.lcdc.bits(),
+            self.stat,
+            self.scy,
+            self.scx,
+            self.ly,
+            self.lyc,
+            self.bgp,
+            self.obp0,
+            self.obp1,
+            self.wy,
+            self.wx,
+        ] {
+            writer.put_u8(register);
+        }
+        writer.put_bytes(&self.bg_palette_ram);
+        writer.put_bytes(&self.obj_palette_ram);
+        writer.put_u8(self.bcps);
+        writer.put_u8(self.ocps);
+        writer.put_u8(self.mode as u8);
+        writer.put_u32(self.dot);
+        writer.put_u8(self.window_line);
+        writer.put_bool(self.stat_line);
+        writer.put_u16s(&self.framebuffer);
+        writer.put_bytes(&self.shades);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes_into(&mut self.vram)?;
+        self.vram_bank = (reader.u8()? & 0b1) as usize;
+        reader.bytes_into(&mut self.oam)?;
+        self.lcdc = Lcdc::from_bits_retain(reader.u8()?);
+        for register in [
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+        ] {
+            *register = reader.u8()?;
+        }
+        reader.bytes_into(&mut self.bg_palette_ram)?;
+        reader.bytes_into(&mut self.obj_palette_ram)?;
+        self.bcps = reader.u8()?;
+        self.ocps = reader.u8()?;
+        self.mode = PpuMode::try_from(reader.u8()?).map_err(|_| StateError::Invalid("PPU mode"))?;
+        self.dot = reader.u32()?;
+        if self.dot >= DOTS_PER_LINE {
+            return Err(StateError::Invalid("PPU dot"));
+        }
+        self.window_line = reader.u8()?;
+        self.stat_line = reader.bool()?;
+        reader.u16s_into(&mut self.framebuffer)?;
+        reader.bytes_into(&mut self.shades)?;
+        if self.shades.iter().any(|&shade| shade > 3) {
+            return Err(StateError::Invalid("PPU shade"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write(LCDC_ADDR, 0x91);
+        ppu.write(BGP_ADDR, 0xE4);
+        ppu
+    }
+
+    #[test]
+    fn test_mode_timing() {
+        let mut ppu = enabled_ppu();
+        assert_eq!(ppu.mode(), PpuMode::OamScan);
+        ppu.tick(80);
+        assert_eq!(ppu.mode(), PpuMode::Drawing);
+        assert_eq!(ppu.tick(172), PpuEvent::HBlank);
+        assert_eq!(ppu.mode(), PpuMode::HBlank);
+        ppu.tick(204);
+        assert_eq!(ppu.ly(), 1);
+        assert_eq!(ppu.mode(), PpuMode::OamScan);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut ppu = enabled_ppu();
+        let events = ppu.tick(DOTS_PER_LINE * 144);
+        assert!(events.contains(PpuEvent::VBlank));
+        assert_eq!(ppu.mode(), PpuMode::VBlank);
+        ppu.tick(DOTS_PER_LINE * 10);
+        assert_eq!(ppu.ly(), 0);
+    }
+
+    #[test]
+    fn test_lyc_stat_interrupt() {
+        let mut ppu = enabled_ppu();
+        ppu.write(LYC_ADDR, 2);
+        ppu.write(STAT_ADDR, 0x40);
+        assert!(!ppu.tick(DOTS_PER_LINE).contains(PpuEvent::Stat));
+        assert!(ppu.tick(DOTS_PER_LINE).contains(PpuEvent::Stat));
+        assert_eq!(ppu.read(STAT_ADDR) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn test_render_background() {
+        let mut ppu = enabled_ppu();
+        // Tile 1 is solid color 3, placed at the top left of the map
+        for row in 0..16 {
+            ppu.write_vram(0x8010 + row, 0xFF);
+        }
+        ppu.write_vram(0x9800, 0x01);
+
+        ppu.tick(DOTS_PER_LINE);
+        assert_eq!(ppu.framebuffer()[0], DMG_SHADES[3]);
+        assert_eq!(ppu.framebuffer()[7], DMG_SHADES[3]);
+        assert_eq!(ppu.framebuffer()[8], DMG_SHADES[0]);
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut ppu = Ppu::new_cgb();
+        ppu.write(BCPS_ADDR, 0xBE);
+        ppu.write(BCPD_ADDR, 0x1F);
+        ppu.write(BCPD_ADDR, 0x00);
+        ppu.write(BCPD_ADDR, 0x42);
+        assert_eq!(ppu.read(BCPS_ADDR), 0xC1);
+        ppu.write(BCPS_ADDR, 0x3E);
+        assert_eq!(ppu.read(BCPD_ADDR), 0x1F);
+        ppu.write(BCPD_ADDR, 0x55);
+        assert_eq!(ppu.read(BCPS_ADDR), 0x7E);
+        ppu.write(BCPS_ADDR, 0x00);
+        assert_eq!(ppu.read(BCPD_ADDR), 0x42);
+    }
+
+    #[test]
+    fn test_render_cgb_attributes() {
+        let mut ppu = Ppu::new_cgb();
+        ppu.write(LCDC_ADDR, 0x91);
+
+        // Palette 2 color 1 is pure red
+        ppu.write(BCPS_ADDR, 0x80 | (2 * 8 + 2));
+        ppu.write(BCPD_ADDR, 0x1F);
+        ppu.write(BCPD_ADDR, 0x00);
+
+        // Tile 1 in bank 1 has its top row set to color 1 only in the leftmost pixel
+        ppu.set_vram_bank(1);
+        ppu.write_vram(0x8010, 0x80);
+        ppu.write_vram(0x9800, 0x02 | 0x08 | 0x20);
+        ppu.set_vram_bank(0);
+        ppu.write_vram(0x9800, 0x01);
+
+        ppu.tick(DOTS_PER_LINE);
+        // Flipped horizontally, so the set pixel lands at x = 7
+        assert_eq!(ppu.framebuffer()[7], 0x001F);
+        assert_eq!(ppu.framebuffer()[0], 0x7FFF);
+    }
+
+    #[test]
+    fn test_cgb_bg_priority() {
+        let mut ppu = Ppu::new_cgb();
+        ppu.write(LCDC_ADDR, 0x93);
+
+        ppu.write(OCPS_ADDR, 0x80 | 2);
+        ppu.write(OCPD_ADDR, 0xE0);
+        ppu.write(OCPD_ADDR, 0x03);
+
+        // Background tile 0 is color 3 everywhere and has the priority bit
+        for row in 0..16 {
+            ppu.write_vram(0x8000 + row, 0xFF);
+        }
+        ppu.set_vram_bank(1);
+        ppu.write_vram(0x9800, 0x80);
+        ppu.set_vram_bank(0);
+        // Object tile 1 is color 1 everywhere
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0xFF);
+        }
+        ppu.write_oam(0xFE00, 16);
+        ppu.write_oam(0xFE01, 8);
+        ppu.write_oam(0xFE02, 1);
+        ppu.write_oam(0xFE04, 16);
+        ppu.write_oam(0xFE05, 16);
+        ppu.write_oam(0xFE06, 1);
+
+        ppu.tick(DOTS_PER_LINE);
+        assert_eq!(ppu.framebuffer()[0], 0x7FFF);
+        assert_eq!(ppu.framebuffer()[8], 0x03E0);
+    }
+
+    #[test]
+    fn test_vram_banking() {
+        let mut ppu = Ppu::new();
+        ppu.write_vram(0x8000, 0x12);
+        ppu.set_vram_bank(1);
+        assert_eq!(ppu.read_vram(0x8000), 0x00);
+        ppu.write_vram(0x8000, 0x34);
+        ppu.set_vram_bank(0);
+        assert_eq!(ppu.read_vram(0x8000), 0x12);
+    }
+
+    #[test]
+    fn test_corrupt_state() {
+        let mut ppu = enabled_ppu();
+        ppu.shades[0] = 4;
+        let mut writer = StateWriter::new();
+        ppu.save_state(&mut writer);
+        let data = writer.into_bytes();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(
+            Ppu::new().load_state(&mut reader),
+            Err(StateError::Invalid("PPU shade"))
+        );
+    }
+}
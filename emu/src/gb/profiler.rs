@@ -0,0 +1,360 @@
+// Attributes M-cycles to the code that spent them: each instruction's cycles
+// go to its address, the ROM bank it ran from and the chain of calls that led
+// there. Names come from a symbol table at report time, so one profile can be
+// looked at with and without symbols.
+use std::{collections::HashMap, fmt::Write};
+
+use crate::gb::{
+    GameBoy,
+    call_stack::CallStack,
+    cpu::{CallEvent, registers::Register16Bit},
+    debugger::rom_bank,
+    gameboy::M_CYCLES_PER_FRAME,
+    symbols::SymbolTable,
+};
+
+// Stands in for the function that was running when profiling started
+const TOP_LEVEL: &str = "(top)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    // None outside ROM
+    pub bank: Option<usize>,
+    pub addr: u16,
+}
+
+impl Location {
+    fn name(self, symbols: &SymbolTable) -> String {
+        match (symbols.name_at(self.addr, self.bank), self.bank) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(bank)) if self.addr >= 0x4000 => format!("${:02X}:{:04X}", bank, self.addr),
+            (None, _) => format!("${:04X}", self.addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCost {
+    pub name: String,
+    // Spent in the function itself
+    pub self_cycles: u64,
+    // Including everything it called
+    pub total_cycles: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    call_stack: CallStack,
+    // Call site and function entry point of each frame, outermost first,
+    // interned so a step only hashes an index
+    stacks: Vec<Vec<(Location, Location)>>,
+    stack_ids: HashMap<Vec<(Location, Location)>, usize>,
+    current_stack: usize,
+    // Cycles per call stack and instruction
+    samples: HashMap<(usize, Location), u64>,
+    total_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let mut profiler = Self::default();
+        profiler.current_stack = profiler.intern(Vec::new());
+        profiler
+    }
+
+    // Runs one step and charges its cycles. Leaves the CPU's call logging on.
+    pub fn step(&mut self, gb: &mut GameBoy) -> u32 {
+        gb.cpu_mut().set_call_logging(true);
+        let pc = gb.cpu().registers().get_register_16bit(Register16Bit::PC);
+        let cycles = gb.step();
+
+        let mut location = Location {
+            bank: rom_bank(gb, pc),
+            addr: pc,
+        };
+        let events = gb.cpu().call_events();
+        let mut interrupted = false;
+        for &event in events {
+            // Dispatching an interrupt is the handler's cost, not the
+            // interrupted code's
+            if let CallEvent::Interrupt { vector, .. } = event {
+                location = Location {
+                    bank: rom_bank(gb, vector),
+                    addr: vector,
+                };
+                interrupted = true;
+            }
+            self.call_stack.apply(event, |addr| rom_bank(gb, addr));
+        }
+        // Otherwise calls are charged to the caller and returns to the callee
+        if !events.is_empty() {
+            let previous = self.current_stack;
+            self.current_stack = self.stack_id();
+            if !interrupted {
+                self.charge(previous, location, cycles);
+                return cycles;
+            }
+        }
+        self.charge(self.current_stack, location, cycles);
+        cycles
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    #[inline]
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    // Hottest instructions first
+    pub fn by_pc(&self) -> Vec<(Location, u64)> {
+        let mut costs: HashMap<Location, u64> = HashMap::new();
+        for (&(_, location), &cycles) in &self.samples {
+            *costs.entry(location).or_default() += cycles;
+        }
+        sorted(costs)
+    }
+
+    // Busiest banks first, with None for code outside ROM
+    pub fn by_bank(&self) -> Vec<(Option<usize>, u64)> {
+        let mut costs: HashMap<Option<usize>, u64> = HashMap::new();
+        for (&(_, location), &cycles) in &self.samples {
+            *costs.entry(location.bank).or_default() += cycles;
+        }
+        sorted(costs)
+    }
+
+    // Most expensive functions first. Functions are the calls made while
+    // profiling; symbols split them further by the labels the code sits under.
+    pub fn by_function(&self, symbols: &SymbolTable) -> Vec<FunctionCost> {
+        let mut costs: HashMap<String, FunctionCost> = HashMap::new();
+        for (names, cycles) in self.named_stacks(symbols) {
+            for (depth, name) in names.iter().enumerate() {
+                // Recursion only counts once towards the total
+                let first = !names[..depth].contains(name);
+                let cost = costs.entry(name.clone()).or_insert_with(|| FunctionCost {
+                    name: name.clone(),
+                    self_cycles: 0,
+                    total_cycles: 0,
+                });
+                if first {
+                    cost.total_cycles += cycles;
+                }
+                if depth == names.len() - 1 {
+                    cost.self_cycles += cycles;
+                }
+            }
+        }
+        let mut costs: Vec<FunctionCost> = costs.into_values().collect();
+        costs.sort_by(|a, b| {
+            b.self_cycles
+                .cmp(&a.self_cycles)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        costs
+    }
+
+    // Brendan Gregg's collapsed stack format, one "outer;inner cycles" line
+    // per stack, for flamegraph.pl, inferno or speedscope
+    pub fn collapsed(&self, symbols: &SymbolTable) -> String {
+        let mut lines: HashMap<String, u64> = HashMap::new();
+        for (names, cycles) in self.named_stacks(symbols) {
+            *lines.entry(names.join(";")).or_default() += cycles;
+        }
+        let mut lines: Vec<(String, u64)> = lines.into_iter().collect();
+        lines.sort();
+        lines
+            .into_iter()
+            .map(|(stack, cycles)| format!("{} {}\n", stack, cycles))
+            .collect()
+    }
+
+    // The top entries of each table, with the average cost per frame
+    pub fn report(&self, symbols: &SymbolTable, count: usize) -> String {
+        let frames = (self.total_cycles as f64 / M_CYCLES_PER_FRAME as f64).max(1.0);
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.total_cycles.max(1) as f64;
+        let mut out = format!(
+            "{} M-cycles over {:.1} frames\n\nSelf%  Total%  Cycles/frame  Function\n",
+            self.total_cycles,
+            self.total_cycles as f64 / M_CYCLES_PER_FRAME as f64
+        );
+        for cost in self.by_function(symbols).iter().take(count) {
+            writeln!(
+                out,
+                "{:5.1}  {:6.1}  {:12.0}  {}",
+                percent(cost.self_cycles),
+                percent(cost.total_cycles),
+                cost.self_cycles as f64 / frames,
+                cost.name
+            )
+            .unwrap();
+        }
+        out += "\n    %  Cycles/frame  Address\n";
+        for (location, cycles) in self.by_pc().iter().take(count) {
+            let name = symbols
+                .describe(location.addr, location.bank)
+                .unwrap_or_else(|| location.name(symbols));
+            writeln!(
+                out,
+                "{:5.1}  {:12.0}  {}",
+                percent(*cycles),
+                *cycles as f64 / frames,
+                name
+            )
+            .unwrap();
+        }
+        out += "\n    %  Bank\n";
+        for (bank, cycles) in self.by_bank() {
+            let bank = match bank {
+                Some(bank) => format!("{}", bank),
+                None => "RAM".to_string(),
+            };
+            writeln!(out, "{:5.1}  {}", percent(cycles), bank).unwrap();
+        }
+        out
+    }
+
+    fn charge(&mut self, stack: usize, location: Location, cycles: u32) {
+        *self.samples.entry((stack, location)).or_default() += cycles as u64;
+        self.total_cycles += cycles as u64;
+    }
+
+    fn stack_id(&mut self) -> usize {
+        let stack = self
+            .call_stack
+            .frames()
+            .iter()
+            .map(|frame| {
+                let call_site = Location {
+                    bank: frame.bank,
+                    addr: frame.call_site,
+                };
+                let target = Location {
+                    bank: frame.target_bank,
+                    addr: frame.target,
+                };
+                (call_site, target)
+            })
+            .collect();
+        self.intern(stack)
+    }
+
+    fn intern(&mut self, stack: Vec<(Location, Location)>) -> usize {
+        if let Some(&id) = self.stack_ids.get(&stack) {
+            return id;
+        }
+        let id = self.stacks.len();
+        self.stacks.push(stack.clone());
+        self.stack_ids.insert(stack, id);
+        id
+    }
+
+    // Each sample's stack as names, outermost first, ending with the function
+    // the instruction is in
+    fn named_stacks<'a>(
+        &'a self,
+        symbols: &'a SymbolTable,
+    ) -> impl Iterator<Item = (Vec<String>, u64)> + 'a {
+        self.samples.iter().map(|(&(stack, location), &cycles)| {
+            let mut names = vec![TOP_LEVEL.to_string()];
+            // Code under a label no call went through, e.g. the main loop or
+            // something jumped to, shows up as a function of its own
+            let push_label = |names: &mut Vec<String>, location: Location| {
+                if let Some(symbol) = symbols.containing(location.addr, location.bank) {
+                    let function = symbol.name.split('.').next().unwrap_or_default();
+                    if names.last().map(String::as_str) != Some(function) {
+                        names.push(function.to_string());
+                    }
+                }
+            };
+            for &(call_site, target) in &self.stacks[stack] {
+                push_label(&mut names, call_site);
+                names.push(target.name(symbols));
+            }
+            push_label(&mut names, location);
+            (names, cycles)
+        })
+    }
+}
+
+fn sorted<K: Ord>(costs: HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut costs: Vec<(K, u64)> = costs.into_iter().collect();
+    costs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    costs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::{cartridge::ROM_BANK_SIZE, gameboy::test_rom};
+
+    // Calls a busy loop in bank 2 and a short one in bank 0, forever
+    fn profiled_gb() -> GameBoy {
+        let program = crate::gb_asm!(
+            origin = 0x0100;
+            "ld a, 2",
+            "ld [$2000], a",
+            "Main: call $4000",
+            "call Short",
+            "jr Main",
+            "Short: ret",
+        );
+        let mut rom = test_rom(&program, 4);
+        let busy = crate::gb_asm!(origin = 0x4000; "ld b, 10", "Loop: dec b", "jr nz, Loop", "ret");
+        rom[ROM_BANK_SIZE * 2..ROM_BANK_SIZE * 2 + busy.len()].copy_from_slice(&busy);
+        GameBoy::new(rom).unwrap()
+    }
+
+    #[test]
+    fn test_attributes_cycles() {
+        let mut gb = profiled_gb();
+        let mut profiler = Profiler::new();
+        let mut cycles = 0;
+        while cycles < 10_000 {
+            cycles += profiler.step(&mut gb) as u64;
+        }
+        assert_eq!(profiler.total_cycles(), cycles);
+        assert_eq!(gb.cycles(), cycles);
+
+        // dec b; jr nz in bank 2 run ten times a call
+        let (hottest, _) = profiler.by_pc()[0];
+        assert_eq!(
+            hottest,
+            Location {
+                bank: Some(2),
+                addr: 0x4003,
+            }
+        );
+        assert_eq!(profiler.by_bank()[0].0, Some(2));
+
+        let symbols = SymbolTable::parse("00:0105 Main\n00:010D Short\n02:4000 Busy").unwrap();
+        let functions = profiler.by_function(&symbols);
+        assert_eq!(functions[0].name, "Busy");
+        let top = functions
+            .iter()
+            .find(|cost| cost.name == TOP_LEVEL)
+            .unwrap();
+        assert_eq!(top.total_cycles, cycles);
+        let main = functions.iter().find(|cost| cost.name == "Main").unwrap();
+        assert!(main.total_cycles > functions[0].total_cycles);
+
+        let collapsed = profiler.collapsed(&symbols);
+        assert!(collapsed.contains("(top);Main;Busy "));
+        assert!(collapsed.contains("(top);Main;Short "));
+        let total: u64 = collapsed
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, cycles);
+
+        // Without symbols functions are named by address and bank
+        assert!(
+            profiler
+                .collapsed(&SymbolTable::new())
+                .contains("(top);$02:4000 ")
+        );
+        assert!(profiler.report(&symbols, 5).contains("Busy"));
+    }
+}
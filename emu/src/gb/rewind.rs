@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+use crate::gb::state::{rle_decode, rle_encode};
+
+// Ring of save states where only the newest is kept whole. Each older state
+// is stored as an RLE'd XOR against the one after it, which is mostly zeros
+// since little changes from one frame to the next.
+pub struct RewindBuffer {
+    depth: usize,
+    newest: Option<Vec<u8>>,
+    // Back entry turns the newest state into the one before it
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    // Keeps enough history to go back `depth` snapshots
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            newest: None,
+            deltas: VecDeque::with_capacity(depth),
+        }
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.deltas.len() > depth {
+            self.deltas.pop_front();
+        }
+    }
+
+    // Snapshots that can currently be rewound
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    // Bytes held by the buffer, for keeping an eye on memory use
+    pub fn memory_used(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.take()
+            && self.depth > 0
+        {
+            if self.deltas.len() == self.depth {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode_delta(&state, &previous));
+        }
+        self.newest = Some(state);
+    }
+
+    // Steps back up to `snapshots` entries and returns the state reached along
+    // with how far it actually went. That state becomes the newest entry.
+    pub fn rewind(&mut self, snapshots: usize) -> Option<(&[u8], usize)> {
+        let mut state = self.newest.take()?;
+        let count = snapshots.min(self.deltas.len());
+        for _ in 0..count {
+            let delta = self.deltas.pop_back().unwrap();
+            state = apply_delta(&state, &delta);
+        }
+        let state = self.newest.insert(state);
+        Some((state, count))
+    }
+}
+
+// Older length, then the RLE'd XOR of both states padded to the longer one
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let len = newer.len().max(older.len());
+    let xor: Vec<u8> = (0..len)
+        .map(|i| newer.get(i).copied().unwrap_or(0) ^ older.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut delta = (older.len() as u32).to_le_bytes().to_vec();
+    delta.extend(rle_encode(&xor));
+    delta
+}
+
+fn apply_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let older_len = u32::from_le_bytes(delta[..4].try_into().unwrap()) as usize;
+    // Deltas are only ever made by encode_delta, so they always decode
+    let xor = rle_decode(&delta[4..]).expect("rewind delta is well formed");
+    let mut older: Vec<u8> = xor
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ newer.get(i).copied().unwrap_or(0))
+        .collect();
+    older.truncate(older_len);
+    older
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut buffer = RewindBuffer::new(3);
+        assert!(buffer.rewind(1).is_none());
+
+        let states: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| {
+                let mut state = vec![0x00; 100 + i as usize * 3];
+                state[10] = i;
+                state
+            })
+            .collect();
+        for state in &states {
+            buffer.push(state.clone());
+        }
+        assert_eq!(buffer.len(), 3);
+
+        let (state, count) = buffer.rewind(1).unwrap();
+        assert_eq!((state, count), (&states[3][..], 1));
+        // Only as far back as the depth allows
+        let (state, count) = buffer.rewind(10).unwrap();
+        assert_eq!((state, count), (&states[1][..], 2));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_deltas_are_small() {
+        let mut buffer = RewindBuffer::new(10);
+        let mut state = vec![0x55; 0x10000];
+        for i in 0..10 {
+            state[i * 100] = i as u8;
+            buffer.push(state.clone());
+        }
+        // Ten snapshots take far less than two whole states
+        assert!(buffer.memory_used() < state.len() * 2);
+
+        buffer.set_depth(2);
+        assert_eq!(buffer.len(), 2);
+    }
+}
@@ -0,0 +1,544 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::gb::{
+    cartridge::{CartridgeError, CartridgeHeader, MbcKind, ROM_BANK_SIZE},
+    cpu::{
+        cdl::{CdlFlags, CodeDataLog},
+        disasm::{Disassembly, Flow, disassemble},
+    },
+    symbols::SymbolTable,
+};
+
+// Runs of the same byte at least this long become a ds
+const MIN_FILL_RUN: usize = 8;
+const DATA_PER_LINE: usize = 16;
+
+const ENTRY_POINTS: [(u16, &str); 14] = [
+    (0x0000, "RST_00"),
+    (0x0008, "RST_08"),
+    (0x0010, "RST_10"),
+    (0x0018, "RST_18"),
+    (0x0020, "RST_20"),
+    (0x0028, "RST_28"),
+    (0x0030, "RST_30"),
+    (0x0038, "RST_38"),
+    (0x0040, "VBlankInterrupt"),
+    (0x0048, "LCDCInterrupt"),
+    (0x0050, "TimerOverflowInterrupt"),
+    (0x0058, "SerialTransferCompleteInterrupt"),
+    (0x0060, "JoypadTransitionInterrupt"),
+    (0x0100, "Boot"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteKind {
+    Data,
+    // First byte of an instruction of the given length
+    Code(u16),
+    CodeOperand,
+}
+
+// What a path knows about the registers used to switch ROM banks
+#[derive(Debug, Clone, Copy, Default)]
+struct PathState {
+    a: Option<u8>,
+    hl: Option<u16>,
+    selected_bank: Option<usize>,
+}
+
+// Follows code from the entry points, jumps and calls through the ROM's banks
+// and writes everything out as RGBDS source that assembles back to the same bytes
+pub struct RomDisassembler<'a> {
+    rom: &'a [u8],
+    header: CartridgeHeader,
+    kind: MbcKind,
+    bytes: Vec<ByteKind>,
+    // Keyed by ROM offset
+    labels: BTreeMap<usize, String>,
+    // ROM offset each traced jump or call resolved to, keyed by its own offset
+    targets: BTreeMap<usize, usize>,
+}
+
+impl<'a> RomDisassembler<'a> {
+    pub fn new(rom: &'a [u8]) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(rom)?;
+        let kind = header.mbc_kind()?;
+        let mut disassembler = Self {
+            rom,
+            header,
+            kind,
+            bytes: vec![ByteKind::Data; rom.len()],
+            labels: BTreeMap::new(),
+            targets: BTreeMap::new(),
+        };
+        for (addr, name) in ENTRY_POINTS {
+            disassembler.add_entry_point(0, addr, Some(name));
+        }
+        Ok(disassembler)
+    }
+
+    #[inline]
+    pub fn banks(&self) -> usize {
+        self.rom.len().div_ceil(ROM_BANK_SIZE)
+    }
+
+    // Traces code reachable from the address, e.g. from a symbol file or a
+    // jump table the tracer can't see through
+    pub fn add_entry_point(&mut self, bank: usize, addr: u16, name: Option<&str>) {
+        let Some(offset) = self.offset(bank, addr) else {
+            return;
+        };
+        if let Some(name) = name {
+            self.labels.insert(offset, name.to_string());
+        }
+        self.trace(offset, PathState::default());
+    }
+
+    // Labels inside an instruction are ignored since they'd never be defined
+    pub fn set_label(&mut self, bank: usize, addr: u16, name: &str) {
+        if let Some(offset) = self.offset(bank, addr)
+            && self.bytes[offset] != ByteKind::CodeOperand
+        {
+            self.labels.insert(offset, name.to_string());
+        }
+    }
+
+    // Names ROM addresses after a symbol file. Nothing is traced from them
+    // since plenty of symbols point at data.
+    pub fn add_symbols(&mut self, symbols: &SymbolTable) {
+        for symbol in symbols.iter() {
+            self.set_label(symbol.bank, symbol.addr, &symbol.name);
+        }
+    }
+
+    // Traces from every instruction a code/data log saw run, which reaches
+    // code behind jump tables and bank switches the tracer can't follow
+    pub fn add_code_data_log(&mut self, log: &CodeDataLog) {
+        for offset in 0..self.rom.len() {
+            let flags = log.flags(offset);
+            if !flags.contains(CdlFlags::Opcode) {
+                continue;
+            }
+            self.trace(offset, PathState::default());
+            let prefix = if flags.contains(CdlFlags::SubEntryPoint) {
+                "Call"
+            } else if flags.contains(CdlFlags::JumpTarget) {
+                "Jump"
+            } else {
+                continue;
+            };
+            let addr = match offset / ROM_BANK_SIZE {
+                0 => offset,
+                _ => ROM_BANK_SIZE + offset % ROM_BANK_SIZE,
+            };
+            if matches!(self.bytes[offset], ByteKind::Code(_)) {
+                self.labels.entry(offset).or_insert_with(|| {
+                    format!("{}_{:03X}_{:04X}", prefix, offset / ROM_BANK_SIZE, addr)
+                });
+            }
+        }
+    }
+
+    // Whether the byte was reached as the start of an instruction
+    pub fn is_code(&self, bank: usize, addr: u16) -> bool {
+        self.offset(bank, addr)
+            .is_some_and(|offset| matches!(self.bytes[offset], ByteKind::Code(_)))
+    }
+
+    pub fn label(&self, bank: usize, addr: u16) -> Option<&str> {
+        self.labels
+            .get(&self.offset(bank, addr)?)
+            .map(String::as_str)
+    }
+
+    fn offset(&self, bank: usize, addr: u16) -> Option<usize> {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF if bank > 0 => bank * ROM_BANK_SIZE + addr as usize - ROM_BANK_SIZE,
+            _ => return None,
+        };
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    // Where a jump from the given bank ends up, if it can be worked out statically
+    fn resolve(&self, from_bank: usize, target: u16, state: &PathState) -> Option<usize> {
+        let bank = match target {
+            0x0000..=0x3FFF => 0,
+            0x4000..=0x7FFF if from_bank > 0 => from_bank,
+            // Without a mapper the second bank is always there
+            0x4000..=0x7FFF if self.kind == MbcKind::RomOnly => 1,
+            0x4000..=0x7FFF => state.selected_bank?,
+            _ => return None,
+        };
+        self.offset(bank, target)
+    }
+
+    fn trace(&mut self, start: usize, state: PathState) {
+        let mut pending = vec![(start, state)];
+        while let Some((mut offset, mut state)) = pending.pop() {
+            loop {
+                if self.bytes[offset] != ByteKind::Data {
+                    break;
+                }
+                let bank = offset / ROM_BANK_SIZE;
+                let bank_end = (bank + 1) * ROM_BANK_SIZE;
+                let addr = if bank == 0 {
+                    offset as u16
+                } else {
+                    (ROM_BANK_SIZE + offset % ROM_BANK_SIZE) as u16
+                };
+
+                // Instructions can't straddle a section, and can't overlap another one
+                let Some(instruction) =
+                    disassemble(&self.rom[offset..bank_end.min(self.rom.len())], addr)
+                else {
+                    break;
+                };
+                let end = offset + instruction.length as usize;
+                if self.bytes[offset + 1..end]
+                    .iter()
+                    .any(|&kind| kind != ByteKind::Data)
+                {
+                    break;
+                }
+                self.bytes[offset] = ByteKind::Code(instruction.length);
+                self.bytes[offset + 1..end].fill(ByteKind::CodeOperand);
+                self.track_bank_switch(&self.rom[offset..end], &mut state);
+
+                let mut follow = |target: u16, prefix: &str, this: &mut Self| {
+                    if let Some(target_offset) = this.resolve(bank, target, &state) {
+                        this.labels.entry(target_offset).or_insert_with(|| {
+                            format!(
+                                "{}_{:03X}_{:04X}",
+                                prefix,
+                                target_offset / ROM_BANK_SIZE,
+                                target
+                            )
+                        });
+                        this.targets.insert(offset, target_offset);
+                        pending.push((target_offset, state));
+                    }
+                };
+                let continues = match instruction.flow {
+                    Flow::Continue => true,
+                    Flow::Jump {
+                        target,
+                        conditional,
+                    } => {
+                        follow(target, "Jump", self);
+                        conditional
+                    }
+                    // rst $38 is almost always 0xFF padding rather than a real call
+                    Flow::Call { .. } if self.rom[offset] == 0xFF => false,
+                    Flow::Call { target, .. } => {
+                        follow(target, "Call", self);
+                        true
+                    }
+                    Flow::Return { conditional } => conditional,
+                    Flow::JumpIndirect | Flow::Illegal => false,
+                };
+                if !continues || end >= bank_end.min(self.rom.len()) {
+                    break;
+                }
+                offset = end;
+            }
+        }
+        // Jumps into the middle of an instruction keep their numeric target
+        let bytes = &self.bytes;
+        self.labels
+            .retain(|&offset, _| bytes[offset] != ByteKind::CodeOperand);
+    }
+
+    // Spots the usual ld a, n / ld [$2000], a bank switch ahead of a far call
+    fn track_bank_switch(&self, bytes: &[u8], state: &mut PathState) {
+        let select = |value: u8| match self.kind {
+            MbcKind::Mbc1 | MbcKind::Mbc2 | MbcKind::Mbc3 => (value as usize).max(1),
+            _ => value as usize,
+        };
+        let is_bank_register = |addr: u16| (0x2000..=0x3FFF).contains(&addr);
+        match bytes {
+            [0x3E, value] => state.a = Some(*value),
+            [0xAF] => state.a = Some(0),
+            [0x21, low, high] => state.hl = Some(u16::from_le_bytes([*low, *high])),
+            [0xEA, low, high] if is_bank_register(u16::from_le_bytes([*low, *high])) => {
+                state.selected_bank = state.a.map(select);
+            }
+            [0x77] if state.hl.is_some_and(is_bank_register) => {
+                state.selected_bank = state.a.map(select);
+            }
+            [0x36, value] if state.hl.is_some_and(is_bank_register) => {
+                state.selected_bank = Some(select(*value));
+            }
+            // Stores of a elsewhere keep its value
+            [0xE0, _] | [0xEA, _, _] | [0x77] => {}
+            [0x22] | [0x32] => state.hl = None,
+            _ => {
+                state.a = None;
+                state.hl = None;
+            }
+        }
+    }
+
+    // Text of the instruction with jump and call targets swapped for labels.
+    // Encodings RGBDS would assemble differently are kept as raw bytes.
+    fn instruction_text(&self, instruction: &Disassembly, bytes: &[u8], bank: usize) -> String {
+        let keep_bytes = match bytes {
+            // RGBDS always emits stop as 10 00
+            [0x10, operand] => *operand != 0x00,
+            // Some assemblers turn these into ldh
+            [0xEA | 0xFA, _, 0xFF] => true,
+            _ => false,
+        };
+        if keep_bytes {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+            return format!("db {} ; {}", bytes.join(", "), instruction.text);
+        }
+
+        let offset = bank * ROM_BANK_SIZE + instruction.addr as usize % ROM_BANK_SIZE;
+        let label = match instruction.flow {
+            Flow::Jump { target, .. } | Flow::Call { target, .. } => self
+                .targets
+                .get(&offset)
+                .and_then(|target_offset| self.labels.get(target_offset))
+                .map(|label| (target, label)),
+            _ => None,
+        };
+        match label {
+            Some((target, label)) => {
+                match instruction.text.strip_suffix(&format!("${:04X}", target)) {
+                    Some(prefix) => format!("{}{}", prefix, label),
+                    None => instruction.text.clone(),
+                }
+            }
+            None => instruction.text.clone(),
+        }
+    }
+
+    // Source lines for the whole ROM as (ROM offset, bytes covered, text)
+    fn lines(&self) -> Vec<(usize, usize, String)> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let bank = offset / ROM_BANK_SIZE;
+            let bank_end = ((bank + 1) * ROM_BANK_SIZE).min(self.rom.len());
+            if let Some(label) = self.labels.get(&offset) {
+                lines.push((offset, 0, format!("{}:", label)));
+            }
+
+            if let ByteKind::Code(length) = self.bytes[offset] {
+                let addr = if bank == 0 {
+                    offset as u16
+                } else {
+                    (ROM_BANK_SIZE + offset % ROM_BANK_SIZE) as u16
+                };
+                let bytes = &self.rom[offset..offset + length as usize];
+                let instruction = disassemble(bytes, addr).unwrap();
+                let text = self.instruction_text(&instruction, bytes, bank);
+                lines.push((offset, bytes.len(), format!("    {}", text)));
+                offset += length as usize;
+                continue;
+            }
+
+            // Data runs up to the next code, label or bank boundary
+            let mut end = offset + 1;
+            while end < bank_end
+                && self.bytes[end] == ByteKind::Data
+                && !self.labels.contains_key(&end)
+            {
+                end += 1;
+            }
+            let data = &self.rom[offset..end];
+            let mut i = 0;
+            while i < data.len() {
+                let run = data[i..]
+                    .iter()
+                    .take_while(|&&byte| byte == data[i])
+                    .count();
+                if run >= MIN_FILL_RUN {
+                    lines.push((offset + i, run, format!("    ds {}, ${:02X}", run, data[i])));
+                    i += run;
+                    continue;
+                }
+                // Literal bytes up to the next long run
+                let mut literal_end = i;
+                while literal_end < data.len() && literal_end - i < DATA_PER_LINE {
+                    let run = data[literal_end..]
+                        .iter()
+                        .take_while(|&&byte| byte == data[literal_end])
+                        .count();
+                    if run >= MIN_FILL_RUN {
+                        break;
+                    }
+                    literal_end += 1;
+                }
+                let bytes: Vec<String> = data[i..literal_end]
+                    .iter()
+                    .map(|byte| format!("${:02X}", byte))
+                    .collect();
+                lines.push((
+                    offset + i,
+                    literal_end - i,
+                    format!("    db {}", bytes.join(", ")),
+                ));
+                i = literal_end;
+            }
+            offset = end;
+        }
+        lines
+    }
+
+    pub fn to_asm(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "; {} disassembled into RGBDS source",
+            self.header.title
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "; rgbasm -o game.o game.asm && rgblink -o game.gb game.o"
+        )
+        .unwrap();
+        writeln!(out, "; The header is kept as data, so rgbfix isn't needed").unwrap();
+
+        let mut section = None;
+        for (offset, _, text) in self.lines() {
+            let bank = offset / ROM_BANK_SIZE;
+            if section != Some(bank) {
+                section = Some(bank);
+                out.push('\n');
+                if bank == 0 {
+                    writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]").unwrap();
+                } else {
+                    writeln!(
+                        out,
+                        "SECTION \"ROM Bank ${:03X}\", ROMX[$4000], BANK[${:03X}]",
+                        bank, bank
+                    )
+                    .unwrap();
+                }
+                out.push('\n');
+            }
+            out.push_str(&text);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+pub fn disassemble_rom(rom: &[u8]) -> Result<String, CartridgeError> {
+    Ok(RomDisassembler::new(rom)?.to_asm())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::gameboy;
+
+    // MBC1 ROM with four banks, jumping from the entry point to the given
+    // code past the header. Everything after it is $FF like unused ROM.
+    fn disasm_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = gameboy::test_rom(&[0x00, 0xC3, 0x50, 0x01], 4);
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom[0x0150..].fill(0xFF);
+        rom[0x0150..0x0150 + code.len()].copy_from_slice(code);
+        rom
+    }
+
+    // Every byte of the ROM is covered exactly once, in order
+    fn assert_covers_rom(disassembler: &RomDisassembler) {
+        let mut next = 0;
+        for (offset, len, text) in disassembler.lines() {
+            assert_eq!(offset, next, "gap or overlap before {}", text);
+            next += len;
+        }
+        assert_eq!(next, disassembler.rom.len());
+    }
+
+    #[test]
+    fn test_follows_code() {
+        // ld a, 2; ld [$2000], a; call $4000; jr nz, -2 (to itself); ret
+        let mut rom = disasm_rom(&[
+            0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x20, 0xFE, 0xC9,
+        ]);
+        // Bank 2: ld b, c; ret
+        rom[ROM_BANK_SIZE * 2..ROM_BANK_SIZE * 2 + 2].copy_from_slice(&[0x41, 0xC9]);
+        let disassembler = RomDisassembler::new(&rom).unwrap();
+
+        assert!(disassembler.is_code(0, 0x0158));
+        assert!(disassembler.is_code(2, 0x4001));
+        assert!(!disassembler.is_code(1, 0x4000));
+        // The header isn't on any path
+        assert!(!disassembler.is_code(0, 0x0134));
+        assert_eq!(disassembler.label(0, 0x0150), Some("Jump_000_0150"));
+        assert_eq!(disassembler.label(2, 0x4000), Some("Call_002_4000"));
+        assert_covers_rom(&disassembler);
+
+        let asm = disassembler.to_asm();
+        assert!(asm.contains("SECTION \"ROM Bank $002\", ROMX[$4000], BANK[$002]"));
+        assert!(asm.contains("Boot:\n    nop\n    jp Jump_000_0150\n"));
+        assert!(asm.contains("    jr nz, Jump_000_0158\n"));
+        assert!(asm.contains("Call_002_4000:\n    ld b, c\n    ret\n"));
+        assert!(asm.contains("    ds 16382, $FF\n"));
+
+        let mut disassembler = RomDisassembler::new(&rom).unwrap();
+        disassembler
+            .add_symbols(&SymbolTable::parse("00:0150 Main\n02:4000 Blit\n00:C000 wRam").unwrap());
+        let asm = disassembler.to_asm();
+        assert!(asm.contains("Boot:\n    nop\n    jp Main\n"));
+        assert!(asm.contains("Blit:\n    ld b, c\n"));
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        // ld hl, $4000; jp hl
+        let mut rom = disasm_rom(&[0x21, 0x00, 0x40, 0xE9]);
+        // Bank 3: ld b, c; ret
+        rom[ROM_BANK_SIZE * 3..ROM_BANK_SIZE * 3 + 2].copy_from_slice(&[0x41, 0xC9]);
+        let mut disassembler = RomDisassembler::new(&rom).unwrap();
+        assert!(!disassembler.is_code(3, 0x4000));
+
+        let mut log = CodeDataLog::new(rom.len());
+        log.mark(
+            ROM_BANK_SIZE * 3,
+            CdlFlags::Code | CdlFlags::Opcode | CdlFlags::JumpTarget,
+        );
+        disassembler.add_code_data_log(&log);
+        assert!(disassembler.is_code(3, 0x4001));
+        assert_eq!(disassembler.label(3, 0x4000), Some("Jump_003_4000"));
+        assert_covers_rom(&disassembler);
+    }
+
+    #[test]
+    fn test_ambiguous_encodings() {
+        // ld [$FF44], a; stop with a nonzero operand
+        let rom = disasm_rom(&[0xEA, 0x44, 0xFF, 0x10, 0x01, 0x76]);
+        let disassembler = RomDisassembler::new(&rom).unwrap();
+        let asm = disassembler.to_asm();
+        assert!(asm.contains("    db $EA, $44, $FF ; ld [$FF44], a\n"));
+        assert!(asm.contains("    db $10, $01 ; stop\n"));
+        assert_covers_rom(&disassembler);
+    }
+
+    #[test]
+    fn test_overlapping_code() {
+        // jp $0151 lands inside the jp itself, so the second path stops there
+        let rom = disasm_rom(&[0xC3, 0x51, 0x01]);
+        let disassembler = RomDisassembler::new(&rom).unwrap();
+        assert!(disassembler.is_code(0, 0x0150));
+        assert!(!disassembler.is_code(0, 0x0151));
+        assert!(disassembler.to_asm().contains("    jp $0151\n"));
+        assert_covers_rom(&disassembler);
+    }
+
+    #[test]
+    fn test_reassembles() {
+        let mut rom = disasm_rom(&[
+            0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x20, 0xFE, 0x10, 0x01, 0xC9,
+        ]);
+        rom[ROM_BANK_SIZE * 2..ROM_BANK_SIZE * 2 + 4].copy_from_slice(&[0x18, 0xFE, 0xC9, 0x41]);
+        let source = disassemble_rom(&rom).unwrap();
+        assert_eq!(crate::gb::cpu::asm::assemble(&source, 0x0000).unwrap(), rom);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+// The MBC3 clock runs from its own 32768 Hz crystal, so it counts real time even in double speed
+pub const RTC_T_CYCLES_PER_SECOND: u32 = 4194304;
+
+const DH_DAY_HIGH: u8 = 0b00000001;
+const DH_HALT: u8 = 0b01000000;
+const DH_CARRY: u8 = 0b10000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8,
+}
+
+impl RtcRegisters {
+    fn read(&self, select: u8) -> u8 {
+        match select {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.day_low,
+            0x0C => self.day_high,
+            _ => unreachable!("Invalid RTC register {:#04X}", select),
+        }
+    }
+}
+
+// Clock driven purely by emulated cycles so runs stay deterministic
+pub struct Rtc {
+    current: RtcRegisters,
+    latched: RtcRegisters,
+    sub_second: u32,
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            current: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            sub_second: 0,
+            latch_armed: false,
+        }
+    }
+
+    #[inline]
+    pub fn read(&self, select: u8) -> u8 {
+        self.latched.read(select)
+    }
+
+    pub fn write(&mut self, select: u8, data: u8) {
+        match select {
+            0x08 => {
+                self.current.seconds = data & 0x3F;
+                self.sub_second = 0;
+            }
+            0x09 => self.current.minutes = data & 0x3F,
+            0x0A => self.current.hours = data & 0x1F,
+            0x0B => self.current.day_low = data,
+            0x0C => self.current.day_high = data & (DH_DAY_HIGH | DH_HALT | DH_CARRY),
+            _ => unreachable!("Invalid RTC register {:#04X}", select),
+        }
+    }
+
+    // Writing 0x00 then 0x01 copies the running clock into the readable registers
+    pub fn write_latch(&mut self, data: u8) {
+        if self.latch_armed && data == 0x01 {
+            self.latched = self.current;
+        }
+        self.latch_armed = data == 0x00;
+    }
+
+    pub fn tick(&mut self, t_cycles: u32) {
+        if self.current.day_high & DH_HALT != 0 {
+            return;
+        }
+        self.sub_second += t_cycles;
+        while self.sub_second >= RTC_T_CYCLES_PER_SECOND {
+            self.sub_second -= RTC_T_CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    #[inline]
+    pub fn registers(&self) -> RtcRegisters {
+        self.current
+    }
+
+    #[inline]
+    pub fn set_registers(&mut self, registers: RtcRegisters) {
+        self.current = registers;
+    }
+
+    fn advance_second(&mut self) {
+        let clock = &mut self.current;
+        // Out of range values count up to the register width before wrapping
+        clock.seconds = (clock.seconds + 1) & 0x3F;
+        if clock.seconds != 60 {
+            return;
+        }
+        clock.seconds = 0;
+        clock.minutes = (clock.minutes + 1) & 0x3F;
+        if clock.minutes != 60 {
+            return;
+        }
+        clock.minutes = 0;
+        clock.hours = (clock.hours + 1) & 0x1F;
+        if clock.hours != 24 {
+            return;
+        }
+        clock.hours = 0;
+
+        let day = ((((clock.day_high & DH_DAY_HIGH) as u16) << 8) | clock.day_low as u16) + 1;
+        clock.day_low = day as u8;
+        clock.day_high = (clock.day_high & !DH_DAY_HIGH) | ((day >> 8) as u8 & DH_DAY_HIGH);
+        if day > 0x1FF {
+            clock.day_high |= DH_CARRY;
+        }
+    }
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.seconds);
+        writer.put_u8(self.minutes);
+        writer.put_u8(self.hours);
+        writer.put_u8(self.day_low);
+        writer.put_u8(self.day_high);
+    }
+
+    fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Self {
+            seconds: reader.u8()?,
+            minutes: reader.u8()?,
+            hours: reader.u8()?,
+            day_low: reader.u8()?,
+            day_high: reader.u8()?,
+        })
+    }
+}
+
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.current.save_state(writer);
+        self.latched.save_state(writer);
+        writer.put_u32(self.sub_second);
+        writer.put_bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current = RtcRegisters::load_state(reader)?;
+        self.latched = RtcRegisters::load_state(reader)?;
+        self.sub_second = reader.u32()?;
+        if self.sub_second >= RTC_T_CYCLES_PER_SECOND {
+            return Err(StateError::Invalid("RTC sub-second counter"));
+        }
+        self.latch_armed = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latch() {
+        let mut rtc = Rtc::new();
+        rtc.tick(RTC_T_CYCLES_PER_SECOND * 61);
+        assert_eq!(rtc.read(0x08), 0);
+
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 1);
+        assert_eq!(rtc.read(0x09), 1);
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let mut rtc = Rtc::new();
+        rtc.set_registers(RtcRegisters {
+            seconds: 59,
+            minutes: 59,
+            hours: 23,
+            day_low: 0xFF,
+            day_high: DH_DAY_HIGH,
+        });
+        rtc.tick(RTC_T_CYCLES_PER_SECOND);
+        let registers = rtc.registers();
+        assert_eq!(registers.day_low, 0x00);
+        assert_eq!(registers.day_high, DH_CARRY);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0C, DH_HALT);
+        rtc.tick(RTC_T_CYCLES_PER_SECOND * 10);
+        assert_eq!(rtc.registers().seconds, 0);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const SB_ADDR: usize = 0xFF01;
+pub const SC_ADDR: usize = 0xFF02;
+
+const SC_TRANSFER_ENABLE: u8 = 0b10000000;
+const SC_INTERNAL_CLOCK: u8 = 0b00000001;
+
+// 8192 Hz internal clock, one bit every 512 T-cycles
+const T_CYCLES_PER_BIT: u32 = 512;
+pub const TRANSFER_T_CYCLES: u32 = T_CYCLES_PER_BIT * 8;
+
+pub trait LinkPartner {
+    // Called when this side clocks out a full byte on its internal clock.
+    // Returns the byte shifted in from the other end.
+    fn exchange(&mut self, outgoing: u8) -> u8;
+
+    // Called when this side starts clocking a transfer, TRANSFER_T_CYCLES
+    // before the matching exchange()
+    fn start_transfer(&mut self, _outgoing: u8) {}
+
+    // Called when a write to SC stops a transfer this side was clocking
+    fn cancel_transfer(&mut self) {}
+
+    // Called every tick with the T-cycles that passed, so partners can keep time
+    fn advance(&mut self, _t_cycles: u32) {}
+
+    // Called while this side waits on an external clock. Returns the byte
+    // shifted in if the other end clocked a transfer.
+    fn poll_external(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+pub struct NoCable;
+
+impl LinkPartner for NoCable {
+    #[inline]
+    fn exchange(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+pub struct CaptureSink {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureSink {
+    pub fn new() -> Self {
+        Self {
+            output: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub fn output(&self) -> Rc<RefCell<Vec<u8>>> {
+        Rc::clone(&self.output)
+    }
+}
+
+impl Default for CaptureSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkPartner for CaptureSink {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        self.output.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+struct LinkCableSide {
+    outgoing: u8,
+    incoming: Option<u8>,
+}
+
+// One end of an in-process cable joining two emulator instances
+pub struct LinkCableEnd {
+    sides: Rc<RefCell<[LinkCableSide; 2]>>,
+    index: usize,
+}
+
+impl LinkCableEnd {
+    pub fn pair() -> (LinkCableEnd, LinkCableEnd) {
+        let sides = Rc::new(RefCell::new([
+            LinkCableSide {
+                outgoing: 0xFF,
+                incoming: None,
+            },
+            LinkCableSide {
+                outgoing: 0xFF,
+                incoming: None,
+            },
+        ]));
+        (
+            LinkCableEnd {
+                sides: Rc::clone(&sides),
+                index: 0,
+            },
+            LinkCableEnd { sides, index: 1 },
+        )
+    }
+}
+
+impl LinkPartner for LinkCableEnd {
+    fn exchange(&mut self, outgoing: u8) -> u8 {
+        let mut sides = self.sides.borrow_mut();
+        let other = &mut sides[self.index ^ 1];
+        other.incoming = Some(outgoing);
+        other.outgoing
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let mut sides = self.sides.borrow_mut();
+        let own = &mut sides[self.index];
+        own.outgoing = outgoing;
+        own.incoming.take()
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    bit_counter: u32,
+    cycle_counter: u32,
+    partner: Box<dyn LinkPartner>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0x00,
+            control: 0x00,
+            bit_counter: 0,
+            cycle_counter: 0,
+            partner: Box::new(NoCable),
+        }
+    }
+
+    pub fn connect(&mut self, partner: Box<dyn LinkPartner>) {
+        self.partner = partner;
+    }
+
+    pub fn disconnect(&mut self) {
+        self.partner = Box::new(NoCable);
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            SB_ADDR => self.data,
+            SC_ADDR => 0x7E | self.control,
+            _ => unreachable!("Invalid serial address {:#06X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        match addr {
+            SB_ADDR => self.data = data,
+            SC_ADDR => {
+                if self.is_clocking() {
+                    self.partner.cancel_transfer();
+                }
+                self.control = data & (SC_TRANSFER_ENABLE | SC_INTERNAL_CLOCK);
+                self.bit_counter = 0;
+                self.cycle_counter = 0;
+                if self.is_clocking() {
+                    self.partner.start_transfer(self.data);
+                }
+            }
+            _ => unreachable!("Invalid serial address {:#06X}", addr),
+        }
+    }
+
+    #[inline]
+    fn is_clocking(&self) -> bool {
+        self.control & (SC_TRANSFER_ENABLE | SC_INTERNAL_CLOCK)
+            == SC_TRANSFER_ENABLE | SC_INTERNAL_CLOCK
+    }
+
+    // Keeps the link partner's time going while STOP has halted the serial clock
+    pub fn idle(&mut self, t_cycles: u32) {
+        self.partner.advance(t_cycles);
+    }
+
+    // Returns true when a transfer completes and the serial interrupt should be requested
+    pub fn tick(&mut self, t_cycles: u32) -> bool {
+        self.partner.advance(t_cycles);
+
+        if self.control & SC_TRANSFER_ENABLE == 0 {
+            return false;
+        }
+
+        if self.control & SC_INTERNAL_CLOCK == 0 {
+            return match self.partner.poll_external(self.data) {
+                Some(incoming) => {
+                    self.finish_transfer(incoming);
+                    true
+                }
+                None => false,
+            };
+        }
+
+        self.cycle_counter += t_cycles;
+        while self.cycle_counter >= T_CYCLES_PER_BIT {
+            self.cycle_counter -= T_CYCLES_PER_BIT;
+            self.bit_counter += 1;
+            if self.bit_counter == 8 {
+                let incoming = self.partner.exchange(self.data);
+                self.finish_transfer(incoming);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn finish_transfer(&mut self, incoming: u8) {
+        self.data = incoming;
+        self.control &= !SC_TRANSFER_ENABLE;
+        self.bit_counter = 0;
+        self.cycle_counter = 0;
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The link partner is part of the host setup, not the machine, so it isn't saved
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u8(self.data);
+        writer.put_u8(self.control);
+        writer.put_u32(self.bit_counter);
+        writer.put_u32(self.cycle_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.data = reader.u8()?;
+        self.control = reader.u8()? & (SC_TRANSFER_ENABLE | SC_INTERNAL_CLOCK);
+        self.bit_counter = reader.u32()?;
+        self.cycle_counter = reader.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_cable_transfer() {
+        let mut serial = Serial::new();
+        serial.write(SB_ADDR, 0x42);
+        serial.write(SC_ADDR, 0x81);
+        assert_eq!(serial.read(SC_ADDR), 0xFF);
+
+        assert!(!serial.tick(4095));
+        assert!(serial.tick(1));
+        assert_eq!(serial.read(SB_ADDR), 0xFF);
+        assert_eq!(serial.read(SC_ADDR), 0x7F);
+    }
+
+    #[test]
+    fn test_capture_sink() {
+        let mut serial = Serial::new();
+        let sink = CaptureSink::new();
+        let output = sink.output();
+        serial.connect(Box::new(sink));
+
+        for byte in b"Passed" {
+            serial.write(SB_ADDR, *byte);
+            serial.write(SC_ADDR, 0x81);
+            while !serial.tick(4) {}
+        }
+        assert_eq!(output.borrow().as_slice(), b"Passed");
+    }
+
+    #[test]
+    fn test_external_clock_waits() {
+        let mut serial = Serial::new();
+        serial.write(SB_ADDR, 0x42);
+        serial.write(SC_ADDR, 0x80);
+        assert!(!serial.tick(100000));
+        assert_eq!(serial.read(SC_ADDR), 0xFE);
+    }
+
+    #[test]
+    fn test_link_cable() {
+        let (end_a, end_b) = LinkCableEnd::pair();
+        let mut master = Serial::new();
+        let mut slave = Serial::new();
+        master.connect(Box::new(end_a));
+        slave.connect(Box::new(end_b));
+
+        slave.write(SB_ADDR, 0x18);
+        slave.write(SC_ADDR, 0x80);
+        assert!(!slave.tick(4));
+
+        master.write(SB_ADDR, 0x12);
+        master.write(SC_ADDR, 0x81);
+        while !master.tick(4) {}
+        assert!(slave.tick(4));
+
+        assert_eq!(master.read(SB_ADDR), 0x18);
+        assert_eq!(slave.read(SB_ADDR), 0x12);
+    }
+}
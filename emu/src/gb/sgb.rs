@@ -0,0 +1,753 @@
+use crate::gb::{
+    joypad::ButtonState,
+    ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH},
+    state::{SaveState, StateError, StateReader, StateWriter},
+};
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+// Top left corner of the Game Boy screen inside the border
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+
+const ATTR_COLUMNS: usize = SCREEN_WIDTH / 8;
+const ATTR_ROWS: usize = SCREEN_HEIGHT / 8;
+
+const PACKET_SIZE: usize = 16;
+// The low three bits of the first byte give the packet count
+const MAX_PACKETS: usize = 7;
+const BORDER_MAP_SIZE: usize = 32 * 28;
+
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x5294, 0x294A, 0x0000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SgbCommand {
+    Pal01 = 0x00,
+    Pal23 = 0x01,
+    Pal03 = 0x02,
+    Pal12 = 0x03,
+    AttrBlk = 0x04,
+    AttrLin = 0x05,
+    AttrDiv = 0x06,
+    AttrChr = 0x07,
+    MltReq = 0x11,
+    ChrTrn = 0x13,
+    PctTrn = 0x14,
+    MaskEn = 0x17,
+}
+
+impl TryFrom<u8> for SgbCommand {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Pal01),
+            0x01 => Ok(Self::Pal23),
+            0x02 => Ok(Self::Pal03),
+            0x03 => Ok(Self::Pal12),
+            0x04 => Ok(Self::AttrBlk),
+            0x05 => Ok(Self::AttrLin),
+            0x06 => Ok(Self::AttrDiv),
+            0x07 => Ok(Self::AttrChr),
+            0x11 => Ok(Self::MltReq),
+            0x13 => Ok(Self::ChrTrn),
+            0x14 => Ok(Self::PctTrn),
+            0x17 => Ok(Self::MaskEn),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenMask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VramTransfer {
+    BorderTiles { high_half: bool },
+    BorderMap,
+}
+
+pub struct Sgb {
+    // Bits of the packet being received, None until a reset pulse starts one
+    packet_bit: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+    waiting_for_idle: bool,
+    command: Vec<u8>,
+    packets_left: usize,
+
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; ATTR_COLUMNS * ATTR_ROWS],
+    mask: ScreenMask,
+    frozen_shades: Vec<u8>,
+
+    pending_transfer: Option<VramTransfer>,
+    border_tiles: Vec<u8>,
+    border_map: [u16; BORDER_MAP_SIZE],
+    border_palettes: [[u16; 16]; 4],
+
+    player_count: usize,
+    current_player: usize,
+    player_buttons: [ButtonState; 4],
+    last_p1: u8,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            packet_bit: None,
+            packet: [0x00; PACKET_SIZE],
+            waiting_for_idle: false,
+            command: Vec::new(),
+            packets_left: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; ATTR_COLUMNS * ATTR_ROWS],
+            mask: ScreenMask::Cancel,
+            frozen_shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            pending_transfer: None,
+            border_tiles: vec![0x00; 256 * 32],
+            border_map: [0x0000; BORDER_MAP_SIZE],
+            border_palettes: [[0x0000; 16]; 4],
+            player_count: 1,
+            current_player: 0,
+            player_buttons: [ButtonState::empty(); 4],
+            last_p1: 0x30,
+        }
+    }
+
+    // Watches the P1 select lines for packet pulses. Returns true if the
+    // multiplayer adapter moved on to another controller.
+    pub fn write_p1(&mut self, data: u8) -> bool {
+        let lines = data & 0x30;
+        let previous = std::mem::replace(&mut self.last_p1, lines);
+
+        match lines {
+            // Reset pulse starts a new packet
+            0x00 => {
+                self.packet_bit = Some(0);
+                self.packet = [0x00; PACKET_SIZE];
+                self.waiting_for_idle = true;
+                false
+            }
+            0x10 | 0x20 => {
+                if !self.waiting_for_idle {
+                    self.waiting_for_idle = true;
+                    self.receive_bit(lines == 0x10);
+                }
+                false
+            }
+            _ => {
+                self.waiting_for_idle = false;
+                // Releasing P15 outside a packet steps through the controllers
+                if self.packet_bit.is_none() && self.player_count > 1 && previous == 0x10 {
+                    self.current_player = (self.current_player + 1) % self.player_count;
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    // Low nibble of P1 while neither row is selected
+    #[inline]
+    pub fn joypad_id(&self) -> Option<u8> {
+        (self.player_count > 1).then(|| 0x0F - self.current_player as u8)
+    }
+
+    #[inline]
+    pub fn current_player(&self) -> usize {
+        self.current_player
+    }
+
+    #[inline]
+    pub fn player_buttons(&self, player: usize) -> ButtonState {
+        self.player_buttons[player]
+    }
+
+    #[inline]
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) {
+        self.player_buttons[player & 0b11] = buttons;
+    }
+
+    #[inline]
+    pub fn mask(&self) -> ScreenMask {
+        self.mask
+    }
+
+    // Called once per frame at VBlank, when the SGB samples the screen
+    pub fn on_vblank(&mut self, ppu: &Ppu) {
+        if self.mask != ScreenMask::Freeze {
+            self.frozen_shades.copy_from_slice(ppu.shades());
+        }
+
+        match self.pending_transfer.take() {
+            Some(VramTransfer::BorderTiles { high_half }) => {
+                let offset = if high_half { 0x1000 } else { 0x0000 };
+                self.border_tiles[offset..offset + 0x1000].copy_from_slice(&ppu.screen_vram_data());
+            }
+            Some(VramTransfer::BorderMap) => {
+                let data = ppu.screen_vram_data();
+                for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks_exact(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                for (i, bytes) in data[0x800..0x880].chunks_exact(2).enumerate() {
+                    self.border_palettes[i / 16][i % 16] = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            None => {}
+        }
+    }
+
+    // Composes the 256x224 SGB picture: border with the colorized game screen inside
+    pub fn frame(&self) -> Vec<u16> {
+        let color0 = self.palettes[0][0];
+        let mut frame = vec![color0; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    ScreenMask::Black => 0x0000,
+                    ScreenMask::Color0 => color0,
+                    ScreenMask::Cancel | ScreenMask::Freeze => {
+                        let palette = self.attributes[(y / 8) * ATTR_COLUMNS + x / 8];
+                        let shade = self.frozen_shades[y * SCREEN_WIDTH + x];
+                        self.palettes[palette as usize][shade as usize]
+                    }
+                };
+                frame[(GAME_Y + y) * SGB_SCREEN_WIDTH + GAME_X + x] = color;
+            }
+        }
+
+        self.draw_border(&mut frame);
+        frame
+    }
+
+    fn draw_border(&self, frame: &mut [u16]) {
+        for (i, entry) in self.border_map.iter().enumerate() {
+            let tile = (entry & 0xFF) as usize;
+            let palette = ((entry >> 10) & 0b111) as usize;
+            let x_flip = entry & 0x4000 != 0;
+            let y_flip = entry & 0x8000 != 0;
+            // Only palettes 4-7 are available to the border
+            let Some(colors) = palette.checked_sub(4).map(|p| &self.border_palettes[p]) else {
+                continue;
+            };
+
+            let tile_data = &self.border_tiles[tile * 32..tile * 32 + 32];
+            for row in 0..8 {
+                let src_row = if y_flip { 7 - row } else { row };
+                // SNES 4bpp: planes 0 and 1 interleaved, then planes 2 and 3
+                let planes = [
+                    tile_data[src_row * 2],
+                    tile_data[src_row * 2 + 1],
+                    tile_data[16 + src_row * 2],
+                    tile_data[16 + src_row * 2 + 1],
+                ];
+                for col in 0..8 {
+                    let bit = if x_flip { col } else { 7 - col };
+                    let color_index = planes.iter().enumerate().fold(0, |acc, (plane, byte)| {
+                        acc | (((byte >> bit) & 0b1) << plane)
+                    });
+                    if color_index == 0 {
+                        continue;
+                    }
+                    let x = (i % 32) * 8 + col;
+                    let y = (i / 32) * 8 + row;
+                    frame[y * SGB_SCREEN_WIDTH + x] = colors[color_index as usize];
+                }
+            }
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        let Some(index) = self.packet_bit else {
+            return;
+        };
+
+        if index == PACKET_SIZE * 8 {
+            // The stop bit has to be 0 for the packet to count
+            self.packet_bit = None;
+            if !bit {
+                self.receive_packet();
+            }
+            return;
+        }
+
+        if bit {
+            self.packet[index / 8] |= 1 << (index % 8);
+        }
+        self.packet_bit = Some(index + 1);
+    }
+
+    fn receive_packet(&mut self) {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = ((self.packet[0] as usize) & MAX_PACKETS).max(1);
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+
+        if self.packets_left == 0 {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        let Ok(command) = SgbCommand::try_from(data[0] >> 3) else {
+            return;
+        };
+
+        match command {
+            SgbCommand::Pal01 => self.set_palette_pair(0, 1, data),
+            SgbCommand::Pal23 => self.set_palette_pair(2, 3, data),
+            SgbCommand::Pal03 => self.set_palette_pair(0, 3, data),
+            SgbCommand::Pal12 => self.set_palette_pair(1, 2, data),
+            SgbCommand::AttrBlk => self.attr_blk(data),
+            SgbCommand::AttrLin => self.attr_lin(data),
+            SgbCommand::AttrDiv => self.attr_div(data),
+            SgbCommand::AttrChr => self.attr_chr(data),
+            SgbCommand::MltReq => {
+                self.player_count = match data[1] & 0b11 {
+                    0b01 => 2,
+                    0b11 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            SgbCommand::ChrTrn => {
+                self.pending_transfer = Some(VramTransfer::BorderTiles {
+                    high_half: data[1] & 0b1 != 0,
+                });
+            }
+            SgbCommand::PctTrn => self.pending_transfer = Some(VramTransfer::BorderMap),
+            SgbCommand::MaskEn => {
+                self.mask = match data[1] & 0b11 {
+                    0 => ScreenMask::Cancel,
+                    1 => ScreenMask::Freeze,
+                    2 => ScreenMask::Black,
+                    _ => ScreenMask::Color0,
+                };
+            }
+        }
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+
+        // Color 0 is shared by every palette
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let data_sets = (data[1] as usize).min(0x12);
+        for set in data[2..].chunks_exact(6).take(data_sets) {
+            let control = set[0] & 0b111;
+            let inside = set[1] & 0b11;
+            let line = (set[1] >> 2) & 0b11;
+            let outside = (set[1] >> 4) & 0b11;
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            // Changing only the inside or only the outside drags the line along with it
+            let line = match control {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                _ if control & 0b010 != 0 => Some(line),
+                _ => None,
+            };
+
+            for y in 0..ATTR_ROWS {
+                for x in 0..ATTR_COLUMNS {
+                    let on_or_in = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let strictly_in = x > x1 && x < x2 && y > y1 && y < y2;
+                    let palette = if strictly_in {
+                        (control & 0b001 != 0).then_some(inside)
+                    } else if on_or_in {
+                        line
+                    } else {
+                        (control & 0b100 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_COLUMNS + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let data_sets = data[1] as usize;
+        for set in data[2..].iter().take(data_sets) {
+            let line = (set & 0x1F) as usize;
+            let palette = (set >> 5) & 0b11;
+            if set & 0x80 != 0 {
+                if line < ATTR_ROWS {
+                    self.attributes[line * ATTR_COLUMNS..(line + 1) * ATTR_COLUMNS].fill(palette);
+                }
+            } else if line < ATTR_COLUMNS {
+                for y in 0..ATTR_ROWS {
+                    self.attributes[y * ATTR_COLUMNS + line] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0x40 != 0;
+        let coordinate = data[2] as usize;
+
+        for y in 0..ATTR_ROWS {
+            for x in 0..ATTR_COLUMNS {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_COLUMNS + x] = match position.cmp(&coordinate) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(ATTR_COLUMNS * ATTR_ROWS);
+        let vertical = data[5] & 0b1 != 0;
+
+        let palettes = data[6..]
+            .iter()
+            .flat_map(|byte| (0..4).map(move |i| (byte >> (6 - i * 2)) & 0b11));
+        for palette in palettes.take(count) {
+            if x >= ATTR_COLUMNS || y >= ATTR_ROWS {
+                break;
+            }
+            self.attributes[y * ATTR_COLUMNS + x] = palette;
+
+            if vertical {
+                y += 1;
+                if y == ATTR_ROWS {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == ATTR_COLUMNS {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Sgb {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bool(self.packet_bit.is_some());
+        writer.put_u8(self.packet_bit.unwrap_or(0) as u8);
+        writer.put_bytes(&self.packet);
+        writer.put_bool(self.waiting_for_idle);
+        writer.put_bytes(&self.command);
+        writer.put_u8(self.packets_left as u8);
+
+        for palette in &self.palettes {
+            writer.put_u16s(palette);
+        }
+        writer.put_bytes(&self.attributes);
+        writer.put_u8(self.mask as u8);
+        writer.put_bytes(&self.frozen_shades);
+
+        writer.put_u8(match self.pending_transfer {
+            None => 0,
+            Some(VramTransfer::BorderTiles { high_half: false }) => 1,
+            Some(VramTransfer::BorderTiles { high_half: true }) => 2,
+            Some(VramTransfer::BorderMap) => 3,
+        });
+        writer.put_bytes(&self.border_tiles);
+        writer.put_u16s(&self.border_map);
+        for palette in &self.border_palettes {
+            writer.put_u16s(palette);
+        }
+
+        writer.put_u8(self.player_count as u8);
+        writer.put_u8(self.current_player as u8);
+        for buttons in &self.player_buttons {
+            writer.put_u8(buttons.bits());
+        }
+        writer.put_u8(self.last_p1);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let receiving = reader.bool()?;
+        let packet_bit = reader.u8()? as usize;
+        if packet_bit > PACKET_SIZE * 8 {
+            return Err(StateError::Invalid("SGB packet bit"));
+        }
+        self.packet_bit = receiving.then_some(packet_bit);
+        reader.bytes_into(&mut self.packet)?;
+        self.waiting_for_idle = reader.bool()?;
+        self.command = reader.bytes()?;
+        self.packets_left = reader.u8()? as usize;
+        // A command is only kept between packets, with at least one to come
+        let received = self.command.len() / PACKET_SIZE;
+        if !self.command.len().is_multiple_of(PACKET_SIZE)
+            || (received == 0) != (self.packets_left == 0)
+            || received + self.packets_left > MAX_PACKETS
+        {
+            return Err(StateError::Invalid("SGB command length"));
+        }
+
+        for palette in &mut self.palettes {
+            reader.u16s_into(palette)?;
+        }
+        reader.bytes_into(&mut self.attributes)?;
+        if self.attributes.iter().any(|&palette| palette > 3) {
+            return Err(StateError::Invalid("SGB attribute palette"));
+        }
+        self.mask = match reader.u8()? {
+            0 => ScreenMask::Cancel,
+            1 => ScreenMask::Freeze,
+            2 => ScreenMask::Black,
+            3 => ScreenMask::Color0,
+            _ => return Err(StateError::Invalid("SGB screen mask")),
+        };
+        reader.bytes_into(&mut self.frozen_shades)?;
+        if self.frozen_shades.iter().any(|&shade| shade > 3) {
+            return Err(StateError::Invalid("SGB frozen shade"));
+        }
+
+        self.pending_transfer = match reader.u8()? {
+            0 => None,
+            1 => Some(VramTransfer::BorderTiles { high_half: false }),
+            2 => Some(VramTransfer::BorderTiles { high_half: true }),
+            3 => Some(VramTransfer::BorderMap),
+            _ => return Err(StateError::Invalid("SGB VRAM transfer")),
+        };
+        reader.bytes_into(&mut self.border_tiles)?;
+        reader.u16s_into(&mut self.border_map)?;
+        for palette in &mut self.border_palettes {
+            reader.u16s_into(palette)?;
+        }
+
+        self.player_count = reader.u8()? as usize;
+        self.current_player = reader.u8()? as usize;
+        if !matches!(self.player_count, 1 | 2 | 4) || self.current_player >= self.player_count {
+            return Err(StateError::Invalid("SGB player count"));
+        }
+        for buttons in &mut self.player_buttons {
+            *buttons = ButtonState::from_bits_retain(reader.u8()?);
+        }
+        self.last_p1 = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends a single packet the way games do, through P1 writes
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; PACKET_SIZE]) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for byte in packet {
+            for bit in 0..8 {
+                sgb.write_p1(if byte & (1 << bit) != 0 { 0x10 } else { 0x20 });
+                sgb.write_p1(0x30);
+            }
+        }
+        sgb.write_p1(0x20);
+        sgb.write_p1(0x30);
+    }
+
+    fn packet(bytes: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0x00; PACKET_SIZE];
+        packet[..bytes.len()].copy_from_slice(bytes);
+        packet
+    }
+
+    #[test]
+    fn test_pal01() {
+        let mut sgb = Sgb::new();
+        send_packet(
+            &mut sgb,
+            &packet(&[
+                0x01, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00, 0x11, 0x11, 0x22, 0x22, 0x33,
+                0x33,
+            ]),
+        );
+        assert_eq!(sgb.palettes[0], [0x001F, 0x03E0, 0x7C00, 0x0000]);
+        assert_eq!(sgb.palettes[1], [0x001F, 0x1111, 0x2222, 0x3333]);
+        assert_eq!(sgb.palettes[3][0], 0x001F);
+    }
+
+    #[test]
+    fn test_bad_stop_bit_drops_packet() {
+        let mut sgb = Sgb::new();
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for _ in 0..=PACKET_SIZE * 8 {
+            sgb.write_p1(0x10);
+            sgb.write_p1(0x30);
+        }
+        assert_eq!(sgb.palettes[0], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_attr_blk() {
+        let mut sgb = Sgb::new();
+        // Inside only, palette 2, from (2, 2) to (5, 5)
+        send_packet(
+            &mut sgb,
+            &packet(&[0x21, 0x01, 0x01, 0x02, 0x02, 0x02, 0x05, 0x05]),
+        );
+        assert_eq!(sgb.attributes[3 * ATTR_COLUMNS + 3], 2);
+        // The line follows the inside palette
+        assert_eq!(sgb.attributes[2 * ATTR_COLUMNS + 2], 2);
+        assert_eq!(sgb.attributes[ATTR_COLUMNS + 1], 0);
+    }
+
+    #[test]
+    fn test_attr_lin_and_div() {
+        let mut sgb = Sgb::new();
+        // Vertical division at x = 10: left palette 1, line palette 2, right palette 3
+        send_packet(&mut sgb, &packet(&[0x31, 0x27, 0x0A]));
+        assert_eq!(sgb.attributes[9], 1);
+        assert_eq!(sgb.attributes[10], 2);
+        assert_eq!(sgb.attributes[11], 3);
+
+        // Horizontal line at y = 4 with palette 3
+        send_packet(&mut sgb, &packet(&[0x29, 0x01, 0x80 | 0x60 | 0x04]));
+        assert_eq!(sgb.attributes[4 * ATTR_COLUMNS], 3);
+        assert_eq!(sgb.attributes[4 * ATTR_COLUMNS + 19], 3);
+    }
+
+    #[test]
+    fn test_attr_chr() {
+        let mut sgb = Sgb::new();
+        // Five cells from (18, 0) left to right, wrapping to the next row
+        send_packet(
+            &mut sgb,
+            &packet(&[0x39, 18, 0, 5, 0, 0, 0b01_10_11_01, 0b11_000000]),
+        );
+        assert_eq!(sgb.attributes[18], 1);
+        assert_eq!(sgb.attributes[19], 2);
+        assert_eq!(sgb.attributes[ATTR_COLUMNS], 3);
+        assert_eq!(sgb.attributes[ATTR_COLUMNS + 1], 1);
+        assert_eq!(sgb.attributes[ATTR_COLUMNS + 2], 3);
+    }
+
+    #[test]
+    fn test_mask_en() {
+        let mut sgb = Sgb::new();
+        send_packet(&mut sgb, &packet(&[0xB9, 0x02]));
+        assert_eq!(sgb.mask(), ScreenMask::Black);
+        let frame = sgb.frame();
+        assert_eq!(frame[GAME_Y * SGB_SCREEN_WIDTH + GAME_X], 0x0000);
+        assert_eq!(frame[0], DEFAULT_PALETTE[0]);
+    }
+
+    #[test]
+    fn test_mlt_req() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.joypad_id(), None);
+        send_packet(&mut sgb, &packet(&[0x89, 0x01]));
+        assert_eq!(sgb.joypad_id(), Some(0x0F));
+        sgb.write_p1(0x10);
+        assert!(sgb.write_p1(0x30));
+        assert_eq!(sgb.joypad_id(), Some(0x0E));
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.current_player(), 0);
+    }
+
+    #[test]
+    fn test_border_transfer() {
+        let mut sgb = Sgb::new();
+        let mut ppu = Ppu::new();
+        ppu.write(crate::gb::ppu::LCDC_ADDR, 0x91);
+        // Games lay tiles 0-255 out across the screen for a transfer
+        for tile in 0..256 {
+            ppu.write_vram(0x9800 + (tile / 20) * 32 + tile % 20, tile as u8);
+        }
+
+        // Tile 0 is plane 0 set everywhere, shown through map entry 0 on palette 4
+        for row in 0..8 {
+            ppu.write_vram(0x8000 + row * 2, 0xFF);
+        }
+        send_packet(&mut sgb, &packet(&[0x99, 0x00]));
+        sgb.on_vblank(&ppu);
+
+        for addr in 0x8000..0x9000 {
+            ppu.write_vram(addr, 0x00);
+        }
+        // Map entry 0 -> tile 0, palette 4; palette 4 color 1 is blue
+        ppu.write_vram(0x8001, 0x10);
+        ppu.write_vram(0x8802, 0x00);
+        ppu.write_vram(0x8803, 0x7C);
+        send_packet(&mut sgb, &packet(&[0xA1]));
+        sgb.on_vblank(&ppu);
+
+        let frame = sgb.frame();
+        assert_eq!(frame[0], 0x7C00);
+        assert_eq!(frame[8], DEFAULT_PALETTE[0]);
+    }
+
+    #[test]
+    fn test_corrupt_state() {
+        let load = |sgb: &Sgb| {
+            let mut writer = StateWriter::new();
+            sgb.save_state(&mut writer);
+            let data = writer.into_bytes();
+            let mut reader = StateReader::new(&data).unwrap();
+            Sgb::new().load_state(&mut reader)
+        };
+
+        let mut sgb = Sgb::new();
+        assert_eq!(load(&sgb), Ok(()));
+        sgb.attributes[5] = 4;
+        assert_eq!(
+            load(&sgb),
+            Err(StateError::Invalid("SGB attribute palette"))
+        );
+
+        let mut sgb = Sgb::new();
+        sgb.frozen_shades[100] = 0xFF;
+        assert_eq!(load(&sgb), Err(StateError::Invalid("SGB frozen shade")));
+
+        // Halfway through a two packet command is fine, more than seven isn't
+        let mut sgb = Sgb::new();
+        sgb.command = vec![0x00; PACKET_SIZE];
+        sgb.packets_left = 1;
+        assert_eq!(load(&sgb), Ok(()));
+        sgb.packets_left = MAX_PACKETS;
+        assert_eq!(load(&sgb), Err(StateError::Invalid("SGB command length")));
+        sgb.command.clear();
+        assert_eq!(load(&sgb), Err(StateError::Invalid("SGB command length")));
+    }
+}
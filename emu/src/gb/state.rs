@@ -0,0 +1,307 @@
+use std::{error::Error, fmt};
+
+pub const STATE_MAGIC: &[u8; 4] = b"RRGB";
+
+// Bump whenever a field is added or changes meaning. Loading reads older
+// versions by checking `reader.version()` where the layout differs and
+// filling in a sensible default for fields the old version didn't have.
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    // A field held a value the machine can't be in
+    Invalid(&'static str),
+    // The state belongs to a different cartridge or model
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::UnexpectedEof => write!(f, "save state is truncated"),
+            StateError::Invalid(field) => write!(f, "invalid value for {} in save state", field),
+            StateError::Mismatch(what) => write!(f, "save state is for a different {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+// Little endian field writer. Byte blocks are run-length encoded since most
+// of memory is long runs of the same value.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(STATE_MAGIC);
+        buf.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        Self { buf }
+    }
+
+    #[inline]
+    pub fn put_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    #[inline]
+    pub fn put_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    #[inline]
+    pub fn put_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn put_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn put_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_bytes(&mut self, data: &[u8]) {
+        let encoded = rle_encode(data);
+        self.put_u32(data.len() as u32);
+        self.put_u32(encoded.len() as u32);
+        self.buf.extend_from_slice(&encoded);
+    }
+
+    pub fn put_u16s(&mut self, data: &[u16]) {
+        let bytes: Vec<u8> = data.iter().flat_map(|value| value.to_le_bytes()).collect();
+        self.put_bytes(&bytes);
+    }
+
+    #[inline]
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        if data.len() < STATE_MAGIC.len() + 2 || &data[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let mut reader = Self {
+            data,
+            pos: STATE_MAGIC.len(),
+            version: 0,
+        };
+        let version = reader.u16()?;
+        if version == 0 || version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        reader.version = version;
+        Ok(reader)
+    }
+
+    // Format version the state was written with
+    #[inline]
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::UnexpectedEof)?;
+        let slice = self
+            .data
+            .get(self.pos..end)
+            .ok_or(StateError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    #[inline]
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    #[inline]
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    #[inline]
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u32()? as usize;
+        let encoded_len = self.u32()? as usize;
+        let encoded = self.take(encoded_len)?;
+        let data = rle_decode(encoded)?;
+        if data.len() != len {
+            return Err(StateError::Invalid("byte block length"));
+        }
+        Ok(data)
+    }
+
+    // Reads a byte block that must exactly fill the destination
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let data = self.bytes()?;
+        if data.len() != dest.len() {
+            return Err(StateError::Invalid("byte block length"));
+        }
+        dest.copy_from_slice(&data);
+        Ok(())
+    }
+
+    pub fn u16s_into(&mut self, dest: &mut [u16]) -> Result<(), StateError> {
+        let data = self.bytes()?;
+        if data.len() != dest.len() * 2 {
+            return Err(StateError::Invalid("word block length"));
+        }
+        for (value, bytes) in dest.iter_mut().zip(data.chunks_exact(2)) {
+            *value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(())
+    }
+}
+
+// Control bytes below 0x80 are followed by that many plus one literal bytes.
+// From 0x80 up, the next byte repeats (control & 0x7F) + MIN_RUN times.
+const MIN_RUN: usize = 3;
+const MAX_RUN: usize = 0x7F + MIN_RUN;
+const MAX_LITERALS: usize = 0x80;
+
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut literals_start = 0;
+    let mut i = 0;
+
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(MAX_LITERALS) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&byte| byte == data[i])
+            .count();
+        if run >= MIN_RUN {
+            flush_literals(&mut out, &data[literals_start..i]);
+            out.push(0x80 | (run - MIN_RUN) as u8);
+            out.push(data[i]);
+            i += run;
+            literals_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    flush_literals(&mut out, &data[literals_start..]);
+    out
+}
+
+pub fn rle_decode(data: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+        if control < 0x80 {
+            let literals = data
+                .get(i..i + control + 1)
+                .ok_or(StateError::UnexpectedEof)?;
+            out.extend_from_slice(literals);
+            i += control + 1;
+        } else {
+            let byte = *data.get(i).ok_or(StateError::UnexpectedEof)?;
+            out.extend(std::iter::repeat_n(byte, (control & 0x7F) + MIN_RUN));
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rle_round_trip() {
+        let mut data = vec![0x00; 1000];
+        data.extend(0..=255u8);
+        data.extend([0x42, 0x42, 0x17, 0x17, 0x17]);
+        let encoded = rle_encode(&data);
+        assert!(encoded.len() < 300);
+        assert_eq!(rle_decode(&encoded).unwrap(), data);
+        assert_eq!(rle_decode(&rle_encode(&[])).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_fields_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.put_u8(0x12);
+        writer.put_bool(true);
+        writer.put_u16(0x3456);
+        writer.put_u64(0x0123456789ABCDEF);
+        writer.put_bytes(&[0xAA; 64]);
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::new(&bytes).unwrap();
+        assert_eq!(reader.version(), STATE_VERSION);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u64(), Ok(0x0123456789ABCDEF));
+        assert_eq!(reader.bytes(), Ok(vec![0xAA; 64]));
+        assert_eq!(reader.u8(), Err(StateError::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        assert_eq!(StateReader::new(b"nope").err(), Some(StateError::BadMagic));
+        let mut future = STATE_MAGIC.to_vec();
+        future.extend_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            StateReader::new(&future).err(),
+            Some(StateError::UnsupportedVersion(STATE_VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,303 @@
+// Symbol tables in the no$gmb format rgblink writes with -n:
+//
+//   ; comments run to the end of the line
+//   00:0150 Main
+//   02:4000 Bank2Routine
+//   00:C000 wBuffer
+//
+// Banks matter in the switchable regions, where the same address holds a
+// different symbol for each mapped bank.
+use std::{collections::HashMap, error::Error, fmt, ops::RangeInclusive};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for SymbolError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub bank: usize,
+    pub addr: u16,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    // Sorted by address, then bank
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| SymbolError {
+                line: number + 1,
+                message: message.to_string(),
+            };
+            let (location, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| error("expected bank:address name"))?;
+            let (bank, addr) = location
+                .split_once(':')
+                .ok_or_else(|| error("expected bank:address"))?;
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| error("invalid bank"))?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| error("invalid address"))?;
+            symbols.push(Symbol {
+                bank,
+                addr,
+                name: name.trim().to_string(),
+            });
+        }
+
+        // Sorting once keeps big files fast; the last of a name wins as with insert
+        let by_name: HashMap<String, Symbol> = symbols
+            .into_iter()
+            .map(|symbol| (symbol.name.clone(), symbol))
+            .collect();
+        let mut symbols: Vec<Symbol> = by_name.values().cloned().collect();
+        symbols.sort_by(|a, b| (a.addr, a.bank, &a.name).cmp(&(b.addr, b.bank, &b.name)));
+        Ok(Self { symbols, by_name })
+    }
+
+    // A later symbol with the same name replaces the earlier one
+    pub fn insert(&mut self, bank: usize, addr: u16, name: &str) {
+        let symbol = Symbol {
+            bank,
+            addr,
+            name: name.to_string(),
+        };
+        if self
+            .by_name
+            .insert(name.to_string(), symbol.clone())
+            .is_some()
+        {
+            self.symbols.retain(|existing| existing.name != name);
+        }
+        let index = self
+            .symbols
+            .partition_point(|existing| (existing.addr, existing.bank) <= (addr, bank));
+        self.symbols.insert(index, symbol);
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // In address order
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name)
+    }
+
+    // The symbol defined exactly at the address. In switchable regions the
+    // bank must match; elsewhere it only breaks ties.
+    pub fn name_at(&self, addr: u16, bank: Option<usize>) -> Option<&str> {
+        let start = self.symbols.partition_point(|symbol| symbol.addr < addr);
+        let candidates = self.symbols[start..]
+            .iter()
+            .take_while(|symbol| symbol.addr == addr);
+        self.pick(candidates, addr, bank)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    // Names an address relative to the closest symbol before it in the same
+    // region, e.g. "Main+$1A"
+    pub fn describe(&self, addr: u16, bank: Option<usize>) -> Option<String> {
+        let symbol = self.containing(addr, bank)?;
+        Some(match addr - symbol.addr {
+            0 => symbol.name.clone(),
+            offset => format!("{}+${:X}", symbol.name, offset),
+        })
+    }
+
+    // The closest symbol at or before the address in the same region. With
+    // an unknown bank, switchable ROM falls back to any bank like name_at.
+    pub fn containing(&self, addr: u16, bank: Option<usize>) -> Option<&Symbol> {
+        let region = region(addr)?;
+        let end = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let nearest = self.symbols[..end]
+            .iter()
+            .rev()
+            .take_while(|symbol| region.contains(&symbol.addr))
+            .filter(|symbol| !is_banked(addr) || bank.is_none_or(|bank| symbol.bank == bank))
+            .map(|symbol| symbol.addr)
+            .next()?;
+        let start = self.symbols.partition_point(|symbol| symbol.addr < nearest);
+        let candidates = self.symbols[start..end].iter();
+        self.pick(candidates, nearest, bank)
+    }
+
+    // Replaces each $XXXX address in disassembly with the symbol there, using
+    // `bank` for the bank mapped at an address
+    pub fn annotate(&self, text: &str, bank: impl Fn(u16) -> Option<usize>) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            let digits = &rest[start + 1..];
+            let length = digits
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(digits.len());
+            let name = (length == 4)
+                .then(|| u16::from_str_radix(&digits[..4], 16).ok())
+                .flatten()
+                .and_then(|addr| self.name_at(addr, bank(addr)));
+            match name {
+                Some(name) => out.push_str(name),
+                None => out.push_str(&rest[start..start + 1 + length]),
+            }
+            rest = &digits[length..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn pick<'a>(
+        &self,
+        candidates: impl Iterator<Item = &'a Symbol>,
+        addr: u16,
+        bank: Option<usize>,
+    ) -> Option<&'a Symbol> {
+        let mut fallback = None;
+        for symbol in candidates {
+            if bank == Some(symbol.bank) {
+                return Some(symbol);
+            }
+            fallback = fallback.or(Some(symbol));
+        }
+        // An unknown bank in a switchable region could be any of them
+        fallback.filter(|_| !is_banked(addr) || bank.is_none())
+    }
+}
+
+// Regions symbols don't cross when describing an address
+fn region(addr: u16) -> Option<RangeInclusive<u16>> {
+    Some(match addr {
+        0x0000..=0x3FFF => 0x0000..=0x3FFF,
+        0x4000..=0x7FFF => 0x4000..=0x7FFF,
+        0x8000..=0x9FFF => 0x8000..=0x9FFF,
+        0xA000..=0xBFFF => 0xA000..=0xBFFF,
+        0xC000..=0xCFFF => 0xC000..=0xCFFF,
+        0xD000..=0xDFFF => 0xD000..=0xDFFF,
+        0xFF80..=0xFFFE => 0xFF80..=0xFFFE,
+        _ => return None,
+    })
+}
+
+// Whether the address is in switchable ROM, where banks must match
+fn is_banked(addr: u16) -> bool {
+    (0x4000..=0x7FFF).contains(&addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 BankOneRoutine
+02:4000 BankTwoRoutine
+00:C000 wBuffer
+00:FF80 hVBlankFlag
+";
+
+    #[test]
+    fn test_parse() {
+        let symbols = SymbolTable::parse(SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(
+            symbols.lookup("BankTwoRoutine"),
+            Some(&Symbol {
+                bank: 2,
+                addr: 0x4000,
+                name: "BankTwoRoutine".to_string(),
+            })
+        );
+        assert_eq!(symbols.lookup("Main.loop").unwrap().addr, 0x0158);
+        assert_eq!(
+            SymbolTable::parse("00:0150 Main\n0150 Oops").unwrap_err(),
+            SymbolError {
+                line: 2,
+                message: "expected bank:address".to_string(),
+            }
+        );
+        assert!(SymbolTable::parse("zz:0150 Main").is_err());
+    }
+
+    #[test]
+    fn test_bank_aware_lookup() {
+        let symbols = SymbolTable::parse(SYM).unwrap();
+        assert_eq!(symbols.name_at(0x4000, Some(2)), Some("BankTwoRoutine"));
+        assert_eq!(symbols.name_at(0x4000, Some(3)), None);
+        assert_eq!(symbols.name_at(0x0150, Some(2)), Some("Main"));
+        assert_eq!(symbols.name_at(0xC000, None), Some("wBuffer"));
+
+        assert_eq!(
+            symbols.describe(0x015A, Some(0)).as_deref(),
+            Some("Main.loop+$2")
+        );
+        assert_eq!(
+            symbols.describe(0x4010, Some(1)).as_deref(),
+            Some("BankOneRoutine+$10")
+        );
+        assert_eq!(symbols.describe(0x4010, Some(3)), None);
+        // Without a bank, switchable ROM takes whichever symbol name_at does
+        assert_eq!(symbols.name_at(0x4000, None), Some("BankOneRoutine"));
+        assert_eq!(
+            symbols.describe(0x4010, None).as_deref(),
+            Some("BankOneRoutine+$10")
+        );
+        // Symbols don't reach across regions
+        assert_eq!(symbols.describe(0x8000, None), None);
+        assert_eq!(symbols.describe(0xD000, Some(1)), None);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let mut symbols = SymbolTable::parse(SYM).unwrap();
+        let bank = |addr| (addr >= 0x4000).then_some(2);
+        assert_eq!(symbols.annotate("call $4000", bank), "call BankTwoRoutine");
+        assert_eq!(symbols.annotate("ld a, [$C000]", bank), "ld a, [wBuffer]");
+        assert_eq!(symbols.annotate("ld a, $40", bank), "ld a, $40");
+        assert_eq!(symbols.annotate("jp nz, $0151", bank), "jp nz, $0151");
+        assert_eq!(
+            symbols.annotate("ldh a, [$FF80]", bank),
+            "ldh a, [hVBlankFlag]"
+        );
+
+        symbols.insert(0, 0x0151, "Main");
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.annotate("jp $0151", bank), "jp Main");
+        assert_eq!(symbols.annotate("jp $0150", bank), "jp $0150");
+    }
+}
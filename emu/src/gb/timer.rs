@@ -0,0 +1,171 @@
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const DIV_ADDR: usize = 0xFF04;
+pub const TIMA_ADDR: usize = 0xFF05;
+pub const TMA_ADDR: usize = 0xFF06;
+pub const TAC_ADDR: usize = 0xFF07;
+
+const TAC_ENABLE: u8 = 0b100;
+
+pub struct Timer {
+    // DIV is the upper byte of this T-cycle counter
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA reads 0x00 for one M-cycle after overflowing before TMA is loaded
+    reload_pending: bool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            counter: 0x0000,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0x00,
+            reload_pending: false,
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match addr {
+            DIV_ADDR => (self.counter >> 8) as u8,
+            TIMA_ADDR => self.tima,
+            TMA_ADDR => self.tma,
+            TAC_ADDR => 0xF8 | self.tac,
+            _ => unreachable!("Invalid timer address {:#06X}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: usize, data: u8) {
+        let old_signal = self.signal();
+        match addr {
+            DIV_ADDR => self.counter = 0x0000,
+            TIMA_ADDR => {
+                // Writing during the reload delay cancels the reload
+                self.tima = data;
+                self.reload_pending = false;
+            }
+            TMA_ADDR => self.tma = data,
+            TAC_ADDR => self.tac = data & 0b111,
+            _ => unreachable!("Invalid timer address {:#06X}", addr),
+        }
+        // Resetting DIV or changing TAC can pull the signal low and tick TIMA early
+        if old_signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    // Advances the timer by one M-cycle. Returns true when the timer interrupt should be requested.
+    pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        if std::mem::take(&mut self.reload_pending) {
+            self.tima = self.tma;
+            interrupt = true;
+        }
+
+        let old_signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if old_signal && !self.signal() {
+            self.increment_tima();
+        }
+        interrupt
+    }
+
+    #[inline]
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    #[inline]
+    pub fn set_counter(&mut self, counter: u16) {
+        self.counter = counter;
+    }
+
+    // TIMA counts falling edges of the selected counter bit ANDed with the enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
+            _ => unreachable!(),
+        };
+        self.tac & TAC_ENABLE != 0 && (self.counter >> bit) & 0b1 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.reload_pending = overflow;
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_u16(self.counter);
+        writer.put_u8(self.tima);
+        writer.put_u8(self.tma);
+        writer.put_u8(self.tac);
+        writer.put_bool(self.reload_pending);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()? & 0b111;
+        self.reload_pending = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        for _ in 0..64 {
+            timer.tick();
+        }
+        assert_eq!(timer.read(DIV_ADDR), 0x01);
+        timer.write(DIV_ADDR, 0x42);
+        assert_eq!(timer.read(DIV_ADDR), 0x00);
+    }
+
+    #[test]
+    fn test_overflow_reload() {
+        let mut timer = Timer::new();
+        timer.write(TMA_ADDR, 0xF0);
+        timer.write(TIMA_ADDR, 0xFF);
+        // 16 T-cycles per increment
+        timer.write(TAC_ADDR, 0b101);
+
+        for _ in 0..4 {
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read(TIMA_ADDR), 0x00);
+        assert!(timer.tick());
+        assert_eq!(timer.read(TIMA_ADDR), 0xF0);
+    }
+
+    #[test]
+    fn test_div_reset_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write(TAC_ADDR, 0b101);
+        timer.tick();
+        timer.tick();
+        // Bit 3 of the counter is set, so clearing it ticks TIMA
+        timer.write(DIV_ADDR, 0x00);
+        assert_eq!(timer.read(TIMA_ADDR), 0x01);
+    }
+}
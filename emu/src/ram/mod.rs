@@ -1,5 +1,7 @@
 use num_traits::{Bounded, PrimInt, sign::Unsigned};
 
+use crate::gb::state::{SaveState, StateError, StateReader, StateWriter};
+
 pub trait WordSize: Unsigned + Bounded + PrimInt {}
 impl WordSize for u8 {}
 
@@ -25,6 +27,16 @@ impl<RamWordSize: WordSize> Ram<RamWordSize> {
     }
 }
 
+impl SaveState for Ram<u8> {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.put_bytes(&self.arr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.bytes_into(&mut self.arr)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,205 @@
+// Runs Blargg's cpu_instrs, instr_timing and mem_timing test ROMs and checks
+// what they print over the link cable.
+//
+// The ROMs aren't part of the repository. Put them under tests/roms/blargg
+// (any layout, e.g. an unpacked gb-test-roms checkout) or point BLARGG_ROMS at
+// a directory holding them. Tests whose ROM can't be found are skipped.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use emu::gb::{GameBoy, gameboy::test_rom, serial::CaptureSink};
+
+const ROMS_ENV: &str = "BLARGG_ROMS";
+// Frames to keep running after "Failed" so the details make it into the output
+const FAILURE_FRAMES: u32 = 60;
+
+enum Outcome {
+    Passed(String),
+    Failed(String),
+    TimedOut(String),
+}
+
+fn roms_dir() -> PathBuf {
+    match env::var_os(ROMS_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms/blargg"),
+    }
+}
+
+// Looks for the file by name anywhere under the directory
+fn find_rom(dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut entries: Vec<_> = fs::read_dir(dir).ok()?.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in &entries {
+        let path = entry.path();
+        if path.is_file() && entry.file_name() == name {
+            return Some(path);
+        }
+    }
+    entries
+        .iter()
+        .filter(|entry| entry.path().is_dir())
+        .find_map(|entry| find_rom(&entry.path(), name))
+}
+
+fn run_rom(rom: Vec<u8>, max_frames: u32) -> Outcome {
+    let mut gb = GameBoy::new(rom).expect("ROM should load");
+    let sink = CaptureSink::new();
+    let serial = sink.output();
+    gb.connect_serial(Box::new(sink));
+
+    let text = || String::from_utf8_lossy(&serial.borrow()).into_owned();
+    for _ in 0..max_frames {
+        gb.run_frame();
+        let output = text();
+        if output.contains("Passed") {
+            return Outcome::Passed(output);
+        }
+        if output.contains("Failed") {
+            for _ in 0..FAILURE_FRAMES {
+                gb.run_frame();
+            }
+            return Outcome::Failed(text());
+        }
+    }
+    Outcome::TimedOut(text())
+}
+
+fn blargg(name: &str, max_frames: u32) {
+    let dir = roms_dir();
+    let Some(path) = find_rom(&dir, name) else {
+        eprintln!("skipping {}: not found under {}", name, dir.display());
+        return;
+    };
+    let rom = fs::read(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    match run_rom(rom, max_frames) {
+        Outcome::Passed(_) => {}
+        Outcome::Failed(output) => panic!("{} failed:\n{}", name, output),
+        Outcome::TimedOut(output) => panic!(
+            "{} didn't finish in {} frames:\n{}",
+            name, max_frames, output
+        ),
+    }
+}
+
+#[test]
+fn cpu_instrs_01_special() {
+    blargg("01-special.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_02_interrupts() {
+    blargg("02-interrupts.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_03_op_sp_hl() {
+    blargg("03-op sp,hl.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_04_op_r_imm() {
+    blargg("04-op r,imm.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_05_op_rp() {
+    blargg("05-op rp.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_06_ld_r_r() {
+    blargg("06-ld r,r.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_07_jr_jp_call_ret_rst() {
+    blargg("07-jr,jp,call,ret,rst.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_08_misc_instrs() {
+    blargg("08-misc instrs.gb", 600);
+}
+
+#[test]
+fn cpu_instrs_09_op_r_r() {
+    blargg("09-op r,r.gb", 900);
+}
+
+#[test]
+fn cpu_instrs_10_bit_ops() {
+    blargg("10-bit ops.gb", 900);
+}
+
+#[test]
+fn cpu_instrs_11_op_a_hl() {
+    blargg("11-op a,(hl).gb", 1200);
+}
+
+#[test]
+fn instr_timing() {
+    blargg("instr_timing.gb", 600);
+}
+
+#[test]
+fn mem_timing_01_read_timing() {
+    blargg("01-read_timing.gb", 600);
+}
+
+#[test]
+fn mem_timing_02_write_timing() {
+    blargg("02-write_timing.gb", 600);
+}
+
+#[test]
+fn mem_timing_03_modify_timing() {
+    blargg("03-modify_timing.gb", 600);
+}
+
+#[test]
+fn test_find_rom() {
+    let dir = env::temp_dir().join(format!("blargg-find-{}", std::process::id()));
+    let nested = dir.join("cpu_instrs/individual");
+    fs::create_dir_all(&nested).unwrap();
+    fs::write(nested.join("01-special.gb"), [0]).unwrap();
+
+    assert_eq!(
+        find_rom(&dir, "01-special.gb"),
+        Some(nested.join("01-special.gb"))
+    );
+    assert_eq!(find_rom(&dir, "instr_timing.gb"), None);
+    assert_eq!(find_rom(&dir.join("missing"), "01-special.gb"), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_serial_result() {
+    // Prints "Passed" over serial the way the ROMs do, one byte at a time
+    let program = emu::gb_asm!(
+        origin = 0x0100;
+        "ld hl, $0150",
+        "Next: ld a, [hl+]",
+        "and a",
+        "jr z, Done",
+        "ldh [$01], a",
+        "ld a, $81",
+        "ldh [$02], a",
+        "Wait: ldh a, [$02]",
+        "bit 7, a",
+        "jr nz, Wait",
+        "jr Next",
+        "Done: jr Done",
+    );
+    let mut rom = test_rom(&program, 2);
+    rom[0x150..0x157].copy_from_slice(b"Passed\0");
+    assert!(matches!(run_rom(rom.clone(), 60), Outcome::Passed(output) if output == "Passed"));
+
+    rom[0x150..0x157].copy_from_slice(b"Failed\0");
+    assert!(matches!(run_rom(rom.clone(), 60), Outcome::Failed(output) if output == "Failed"));
+
+    rom[0x150] = 0;
+    assert!(matches!(run_rom(rom, 5), Outcome::TimedOut(output) if output.is_empty()));
+}